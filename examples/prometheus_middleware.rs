@@ -30,7 +30,8 @@ mod prometheus_example {
         let config = PrometheusConfig {
             metrics_server_addr: Some(server_addr),
             pushgateway_config: None,
-            moving_average_window_size: None,
+            runtime_metrics: Some(host.runtime_metrics_source()),
+            ..Default::default()
         };
         host.add_middleware(PrometheusMiddleware::new(config).unwrap());
 
@@ -3,7 +3,7 @@ use crate::Result;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
-use wascap::jwt::Token;
+use wascap::jwt::{Claims, Token};
 
 /// An actor is a WebAssembly module that conforms to the waSCC protocols and can securely
 /// consume capabilities exposed by native or portable capability providers
@@ -66,4 +66,15 @@ impl Actor {
             None => vec![],
         }
     }
+
+    /// Obtain the full set of claims embedded in the actor's signed token
+    pub fn claims(&self) -> Claims<wascap::jwt::Actor> {
+        self.token.claims.clone()
+    }
+
+    /// Checks that the actor's token is currently usable, i.e. it has not expired and is not
+    /// being used before its "not before" time. A host refuses to run actors that fail this check
+    pub fn validate(&self) -> Result<()> {
+        authz::enforce_validation(&self.token.jwt)
+    }
 }
@@ -0,0 +1,650 @@
+//! # Admin API
+//!
+//! An HTTP server that exposes a running host's inventory and lets local tooling manage it,
+//! enabled with the `admin_api` feature. Every endpoint other than the liveness and readiness
+//! checks requires an `Authorization: Bearer <token>` header carrying the configured token.
+//!
+//! | Method   | Path                                  | Description                                     |
+//! |----------|---------------------------------------|-------------------------------------------------|
+//! | `GET`    | `/healthz`                            | Liveness, always `200` while serving            |
+//! | `GET`    | `/readyz`                             | `200` once ready and connected, else `503`      |
+//! | `GET`    | `/actors`                             | Lists the actors running in the host            |
+//! | `POST`   | `/actors`                             | Adds the actor module in the request body       |
+//! | `PUT`    | `/actors/{actor}`                     | Live-updates an actor with the request body     |
+//! | `DELETE` | `/actors/{actor}`                     | Removes an actor                                |
+//! | `POST`   | `/actors/{actor}/call/{operation}`    | Invokes an actor, the body is the payload       |
+//! | `GET`    | `/capabilities`                       | Lists the native capability providers           |
+//! | `POST`   | `/capabilities`                       | Loads a provider, `{"path": .., "binding": ..}` |
+//! | `DELETE` | `/capabilities/{capid}/{binding}`     | Removes a native capability provider            |
+//! | `GET`    | `/bindings`                           | Lists bindings, with secret values redacted     |
+//! | `PUT`    | `/bindings/{actor}/{capid}/{binding}` | Sets a binding, `{"values": .., "secrets": ..}` |
+//! | `DELETE` | `/bindings/{actor}/{capid}/{binding}` | Removes a binding                               |
+//!
+//! Listings and errors are JSON, errors in the form `{"error": "..."}`.
+//!
+//! The server only listens on loopback addresses unless `allow_remote` is set, since requests
+//! are served over plain HTTP. Loading a native capability provider runs its code in the host
+//! process, so `POST /capabilities` is refused unless a `provider_dir` is configured, and then
+//! only loads provider files (libraries or `.par` archives) inside that directory. The request's
+//! `path` is relative to it.
+//!
+//! ```no_run
+//! # use wascc_host::Host;
+//! # use wascc_host::admin::{AdminConfig, AdminServer};
+//! let host = Host::new();
+//! let admin = AdminServer::start(
+//!     host.clone(),
+//!     AdminConfig {
+//!         addr: ([127, 0, 0, 1], 9090).into(),
+//!         token: "s3cr3t".to_string(),
+//!         allow_remote: false,
+//!         provider_dir: Some("/opt/wascc/providers".into()),
+//!     },
+//! )
+//! .unwrap();
+//! // ... add actors and capabilities
+//! admin.set_ready(true);
+//! ```
+
+use crate::{errors, Actor, Host, NativeCapability, Result};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// Configuration of the admin API server
+#[derive(Clone)]
+pub struct AdminConfig {
+    /// The address the server listens on. Must be a loopback address unless `allow_remote` is set
+    pub addr: SocketAddr,
+    /// The bearer token clients must present. Must not be empty
+    pub token: String,
+    /// Allows listening on a non-loopback address. Requests, including the bearer token, are
+    /// sent over plain HTTP
+    pub allow_remote: bool,
+    /// The directory from which capability providers can be loaded. Providers can't be loaded
+    /// through the API if this isn't set
+    pub provider_dir: Option<PathBuf>,
+}
+
+/// A running admin API server. The server stops when this is dropped
+pub struct AdminServer {
+    addr: SocketAddr,
+    ready: Arc<AtomicBool>,
+    server_handle: Option<JoinHandle<()>>,
+    server_kill_switch: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+#[derive(Serialize)]
+struct ActorSummary {
+    id: String,
+    name: String,
+    issuer: String,
+    capabilities: Vec<String>,
+    tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CapabilitySummary {
+    capid: String,
+    binding: String,
+    name: String,
+    version: String,
+    revision: u32,
+}
+
+#[derive(Serialize)]
+struct BindingSummary {
+    actor: String,
+    capability: String,
+    binding: String,
+    values: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct IdResponse {
+    id: String,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse<'a> {
+    error: &'a str,
+}
+
+#[derive(Deserialize)]
+struct AddCapabilityRequest {
+    path: String,
+    binding: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct SetBindingRequest {
+    #[serde(default)]
+    values: HashMap<String, String>,
+    #[serde(default)]
+    secrets: Vec<String>,
+}
+
+struct AdminState {
+    host: Host,
+    token: String,
+    provider_dir: Option<PathBuf>,
+    ready: Arc<AtomicBool>,
+}
+
+impl AdminServer {
+    /// Starts serving the admin API for the given host on a dedicated thread. Returns an error
+    /// if the token is empty, the address isn't a loopback address and remote access isn't
+    /// allowed, the provider directory doesn't exist, or the address can't be bound
+    pub fn start(host: Host, config: AdminConfig) -> Result<AdminServer> {
+        if config.token.is_empty() {
+            return Err(errors::new(errors::ErrorKind::MiscHost(
+                "The admin API requires a bearer token".into(),
+            )));
+        }
+        if !config.addr.ip().is_loopback() {
+            if !config.allow_remote {
+                return Err(errors::new(errors::ErrorKind::MiscHost(format!(
+                    "The admin API only listens on loopback addresses unless remote access is allowed, not {}",
+                    config.addr
+                ))));
+            }
+            warn!(
+                "The admin API is listening on non-loopback address {} over plain HTTP",
+                config.addr
+            );
+        }
+        let provider_dir = match config.provider_dir {
+            Some(dir) => Some(dir.canonicalize().map_err(|e| {
+                errors::new(errors::ErrorKind::MiscHost(format!(
+                    "Invalid admin API provider directory {}: {}",
+                    dir.display(),
+                    e
+                )))
+            })?),
+            None => None,
+        };
+        let ready = Arc::new(AtomicBool::new(false));
+        let state = Arc::new(AdminState {
+            host,
+            token: config.token,
+            provider_dir,
+            ready: ready.clone(),
+        });
+        let (server_kill_switch, mut server_kill_switch_rx) = tokio::sync::oneshot::channel();
+        let (bound_s, bound_r) = std::sync::mpsc::channel();
+        let addr = config.addr;
+
+        let server_handle = std::thread::spawn(move || {
+            let mut rt = tokio::runtime::Runtime::new().expect("Failed to create tokio runtime");
+            rt.block_on(async {
+                // Binding needs the runtime, so the outcome is reported back to `start`
+                let builder = match Server::try_bind(&addr) {
+                    Ok(b) => b,
+                    Err(e) => {
+                        let _ = bound_s.send(Err(format!("Failed to bind admin API: {}", e)));
+                        return;
+                    }
+                };
+                let server = builder.serve(make_service_fn(move |_| {
+                    let state = state.clone();
+                    async move {
+                        Ok::<_, hyper::error::Error>(service_fn(move |req| {
+                            serve_request(req, state.clone())
+                        }))
+                    }
+                }));
+                let _ = bound_s.send(Ok(server.local_addr()));
+                tokio::select! {
+                    res = server => {
+                        if let Err(e) = res {
+                            error!("Admin API server error: {}", e);
+                        }
+                    }
+                    _ = (&mut server_kill_switch_rx) => {}
+                }
+            })
+        });
+
+        match bound_r.recv() {
+            Ok(Ok(addr)) => {
+                info!("Admin API listening on {}", addr);
+                Ok(AdminServer {
+                    addr,
+                    ready,
+                    server_handle: Some(server_handle),
+                    server_kill_switch: Some(server_kill_switch),
+                })
+            }
+            Ok(Err(e)) => {
+                let _ = server_handle.join();
+                Err(e.into())
+            }
+            Err(_) => Err("Admin API server thread terminated unexpectedly"
+                .to_string()
+                .into()),
+        }
+    }
+
+    /// The address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Marks the host as ready (or not) to handle work. The readiness endpoint only succeeds
+    /// while the host is ready and connected to its lattice
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::SeqCst);
+    }
+}
+
+impl Drop for AdminServer {
+    fn drop(&mut self) {
+        if let Some(kill_switch) = self.server_kill_switch.take() {
+            if kill_switch.send(()).is_err() {
+                error!("Error terminating the admin API server");
+            }
+        }
+
+        if let Some(thread_handle) = self.server_handle.take() {
+            if thread_handle.join().is_err() {
+                error!("Error terminating the admin API server thread");
+            }
+        }
+    }
+}
+
+async fn serve_request(
+    req: Request<Body>,
+    state: Arc<AdminState>,
+) -> hyper::error::Result<Response<Body>> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/healthz") => return Ok(json(StatusCode::OK, &"ok")),
+        (&Method::GET, "/readyz") => {
+            return Ok(
+                if state.ready.load(Ordering::SeqCst) && state.host.connected() {
+                    json(StatusCode::OK, &"ready")
+                } else {
+                    json(StatusCode::SERVICE_UNAVAILABLE, &"not ready")
+                },
+            )
+        }
+        _ => {}
+    }
+    if !authorized(&req, &state.token) {
+        let mut res = error(StatusCode::UNAUTHORIZED, "Missing or invalid bearer token");
+        res.headers_mut()
+            .insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+        return Ok(res);
+    }
+
+    let method = req.method().clone();
+    let segments: Vec<String> = req
+        .uri()
+        .path()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect();
+    let body = hyper::body::to_bytes(req.into_body()).await?.to_vec();
+    // Host operations block until the host has handled them, so they run off the server thread
+    Ok(blocking(move || {
+        let path: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+        handle(&state, &method, &path, &body)
+    })
+    .await)
+}
+
+fn handle(state: &AdminState, method: &Method, path: &[&str], body: &[u8]) -> Response<Body> {
+    let host = &state.host;
+    match (method, path) {
+        (&Method::GET, ["actors"]) => {
+            let actors: Vec<_> = host
+                .actors()
+                .into_iter()
+                .map(|(id, claims)| {
+                    let md = claims.metadata.unwrap_or_default();
+                    ActorSummary {
+                        id,
+                        name: md.name.unwrap_or_default(),
+                        issuer: claims.issuer,
+                        capabilities: md.caps.unwrap_or_default(),
+                        tags: md.tags.unwrap_or_default(),
+                    }
+                })
+                .collect();
+            json(StatusCode::OK, &actors)
+        }
+        (&Method::POST, ["actors"]) => match Actor::from_slice(body) {
+            Ok(actor) => {
+                let id = actor.public_key();
+                if host.claims_for_actor(&id).is_some() {
+                    return error(StatusCode::CONFLICT, "Actor is already running");
+                }
+                result(
+                    host.add_actor(actor),
+                    json(StatusCode::CREATED, &IdResponse { id }),
+                )
+            }
+            Err(e) => error(StatusCode::BAD_REQUEST, &e.to_string()),
+        },
+        (&Method::PUT, ["actors", id]) => match Actor::from_slice(body) {
+            Ok(actor) => {
+                if actor.public_key() != *id {
+                    return error(
+                        StatusCode::BAD_REQUEST,
+                        "The new module's public key doesn't match the actor being replaced",
+                    );
+                }
+                if host.claims_for_actor(id).is_none() {
+                    return not_found("No such actor");
+                }
+                result(host.replace_actor(actor), empty(StatusCode::NO_CONTENT))
+            }
+            Err(e) => error(StatusCode::BAD_REQUEST, &e.to_string()),
+        },
+        (&Method::DELETE, ["actors", id]) => {
+            if host.claims_for_actor(id).is_none() {
+                return not_found("No such actor");
+            }
+            result(host.remove_actor(id), empty(StatusCode::NO_CONTENT))
+        }
+        (&Method::POST, ["actors", id, "call", operation]) => {
+            if host.claims_for_actor(id).is_none() {
+                return not_found("No such actor");
+            }
+            match host.call_actor(id, operation, body) {
+                Ok(res) => Response::builder()
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "application/octet-stream")
+                    .body(Body::from(res))
+                    .unwrap(),
+                Err(e) => host_error(e),
+            }
+        }
+        (&Method::GET, ["capabilities"]) => {
+            let caps: Vec<_> = host
+                .capabilities()
+                .into_iter()
+                .map(|((binding, capid), d)| CapabilitySummary {
+                    capid,
+                    binding,
+                    name: d.name,
+                    version: d.version,
+                    revision: d.revision,
+                })
+                .collect();
+            json(StatusCode::OK, &caps)
+        }
+        (&Method::POST, ["capabilities"]) => {
+            let req: AddCapabilityRequest = match serde_json::from_slice(body) {
+                Ok(r) => r,
+                Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+            };
+            let path = match provider_path(state.provider_dir.as_deref(), &req.path) {
+                Ok(p) => p,
+                Err((status, msg)) => return error(status, &msg),
+            };
+            match NativeCapability::from_path(&path, req.binding) {
+                Ok(cap) => {
+                    let summary = CapabilitySummary {
+                        capid: cap.id(),
+                        binding: cap.binding_name.to_string(),
+                        name: cap.name(),
+                        version: cap.descriptor.version.to_string(),
+                        revision: cap.descriptor.revision,
+                    };
+                    result(
+                        host.add_native_capability(cap),
+                        json(StatusCode::CREATED, &summary),
+                    )
+                }
+                Err(e) => error(StatusCode::BAD_REQUEST, &e.to_string()),
+            }
+        }
+        (&Method::DELETE, ["capabilities", capid, binding]) => {
+            if !host
+                .capabilities()
+                .contains_key(&(binding.to_string(), capid.to_string()))
+            {
+                return not_found("No such capability provider");
+            }
+            result(
+                host.remove_native_capability(capid, Some(binding.to_string())),
+                empty(StatusCode::NO_CONTENT),
+            )
+        }
+        (&Method::GET, ["bindings"]) => {
+            let bindings: Vec<_> = host
+                .bindings()
+                .into_iter()
+                .map(|((actor, capability, binding), values)| BindingSummary {
+                    actor,
+                    capability,
+                    binding,
+                    values,
+                })
+                .collect();
+            json(StatusCode::OK, &bindings)
+        }
+        (&Method::PUT, ["bindings", actor, capid, binding]) => {
+            let req: SetBindingRequest = if body.is_empty() {
+                SetBindingRequest::default()
+            } else {
+                match serde_json::from_slice(body) {
+                    Ok(r) => r,
+                    Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+                }
+            };
+            result(
+                host.set_binding_with_secrets(
+                    actor,
+                    capid,
+                    Some(binding.to_string()),
+                    req.values,
+                    &req.secrets,
+                ),
+                empty(StatusCode::NO_CONTENT),
+            )
+        }
+        (&Method::DELETE, ["bindings", actor, capid, binding]) => result(
+            host.remove_binding(actor, capid, Some(binding.to_string())),
+            empty(StatusCode::NO_CONTENT),
+        ),
+        _ => not_found("Not found"),
+    }
+}
+
+fn authorized(req: &Request<Body>, token: &str) -> bool {
+    let expected = format!("Bearer {}", token);
+    req.headers().get(AUTHORIZATION).map_or(false, |v| {
+        ring::constant_time::verify_slices_are_equal(v.as_bytes(), expected.as_bytes()).is_ok()
+    })
+}
+
+async fn blocking<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (s, r) = futures::channel::oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = s.send(f());
+    });
+    r.await.expect("Admin API request handler terminated")
+}
+
+fn result(res: Result<()>, ok: Response<Body>) -> Response<Body> {
+    match res {
+        Ok(_) => ok,
+        Err(e) => host_error(e),
+    }
+}
+
+fn host_error(e: errors::Error) -> Response<Body> {
+    let status = match e.kind() {
+        errors::ErrorKind::Authorization(_) => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error(status, &e.to_string())
+}
+
+fn not_found(msg: &str) -> Response<Body> {
+    error(StatusCode::NOT_FOUND, msg)
+}
+
+fn error(status: StatusCode, msg: &str) -> Response<Body> {
+    json(status, &ErrorResponse { error: msg })
+}
+
+fn empty(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn json(status: StatusCode, value: &impl Serialize) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(value).unwrap()))
+        .unwrap()
+}
+
+// Resolves the path of a provider to load, relative to the provider directory. Paths that lead
+// outside of the directory are refused
+fn provider_path(
+    dir: Option<&Path>,
+    path: &str,
+) -> std::result::Result<PathBuf, (StatusCode, String)> {
+    let dir = dir.ok_or_else(|| {
+        (
+            StatusCode::FORBIDDEN,
+            "Loading capability providers is disabled".to_string(),
+        )
+    })?;
+    let full = dir.join(path).canonicalize().map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid provider path {}: {}", path, e),
+        )
+    })?;
+    if full.starts_with(dir) && full.is_file() {
+        Ok(full)
+    } else {
+        Err((
+            StatusCode::FORBIDDEN,
+            format!("Provider {} is not in the provider directory", path),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{provider_path, AdminConfig, AdminServer};
+    use crate::Host;
+
+    #[test]
+    fn requires_token_except_for_health_checks() -> Result<(), reqwest::Error> {
+        let host = Host::new();
+        let admin = AdminServer::start(
+            host,
+            AdminConfig {
+                addr: ([127, 0, 0, 1], 0).into(),
+                token: "s3cr3t".to_string(),
+                allow_remote: false,
+                provider_dir: None,
+            },
+        )
+        .unwrap();
+        let url = format!("http://{}", admin.local_addr());
+        let client = reqwest::blocking::Client::new();
+
+        let res = client.get(&format!("{}/healthz", url)).send()?;
+        assert_eq!(200, res.status().as_u16());
+        let res = client.get(&format!("{}/readyz", url)).send()?;
+        assert_eq!(503, res.status().as_u16());
+        admin.set_ready(true);
+        let res = client.get(&format!("{}/readyz", url)).send()?;
+        assert_eq!(200, res.status().as_u16());
+
+        let res = client.get(&format!("{}/actors", url)).send()?;
+        assert_eq!(401, res.status().as_u16());
+        let res = client
+            .get(&format!("{}/actors", url))
+            .bearer_auth("wrong")
+            .send()?;
+        assert_eq!(401, res.status().as_u16());
+
+        let res = client
+            .get(&format!("{}/actors", url))
+            .bearer_auth("s3cr3t")
+            .send()?;
+        assert_eq!(200, res.status().as_u16());
+        assert_eq!("[]", res.text()?);
+        let res = client
+            .delete(&format!("{}/actors/Mxxx", url))
+            .bearer_auth("s3cr3t")
+            .send()?;
+        assert_eq!(404, res.status().as_u16());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_empty_token() {
+        let res = AdminServer::start(
+            Host::new(),
+            AdminConfig {
+                addr: ([127, 0, 0, 1], 0).into(),
+                token: String::new(),
+                allow_remote: false,
+                provider_dir: None,
+            },
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn refuses_remote_addresses_unless_allowed() {
+        let res = AdminServer::start(
+            Host::new(),
+            AdminConfig {
+                addr: ([0, 0, 0, 0], 0).into(),
+                token: "s3cr3t".to_string(),
+                allow_remote: false,
+                provider_dir: None,
+            },
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn loads_providers_only_from_provider_dir() {
+        let dir = std::env::temp_dir().join(format!("wascc-admin-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("provider.so"), b"").unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        assert_eq!(403, provider_path(None, "provider.so").unwrap_err().0);
+        assert_eq!(
+            dir.join("provider.so"),
+            provider_path(Some(&dir), "provider.so").unwrap()
+        );
+        assert_eq!(400, provider_path(Some(&dir), "missing.so").unwrap_err().0);
+        let outside = std::env::current_exe().unwrap();
+        let outside = outside.to_str().unwrap();
+        assert_eq!(403, provider_path(Some(&dir), outside).unwrap_err().0);
+        let escape = format!("../{}", dir.file_name().unwrap().to_str().unwrap());
+        assert_eq!(403, provider_path(Some(&dir), &escape).unwrap_err().0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
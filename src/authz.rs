@@ -21,6 +21,13 @@ pub trait Authorizer: Sync + Send {
     /// including the operation that occurs during `bind_actor`. Developers should be aware of this because
     /// if `set_authorizer` is done _after_ actor binding, it could potentially allow an unauthorized binding.
     fn can_invoke(&self, claims: &Claims<Actor>, target: &WasccEntity, operation: &str) -> bool;
+    /// This check is performed for every invocation a native capability provider dispatches to an actor,
+    /// after the host has verified that the actor is bound to that provider (identified by the
+    /// `origin` entity). Overriding this allows restricting which operations a provider may drive on an
+    /// actor. The default implementation allows any dispatch to a bound actor.
+    fn can_dispatch(&self, _origin: &WasccEntity, _actor: &str, _operation: &str) -> bool {
+        true
+    }
 }
 
 pub(crate) struct DefaultAuthorizer {}
@@ -130,8 +137,114 @@ pub(crate) fn unregister_claims(claims_map: ClaimsMap, subject: &str) {
     }
 }
 
+/// The metadata embedded in a host identity token. A host token is a JWT whose subject is
+/// the host's public key (its host ID) and whose issuer is an operator or account key trusted
+/// by the lattice. Hosts that cannot present such a token are not allowed to participate in the
+/// lattice when the lattice has been configured with trusted issuers.
+#[cfg(feature = "lattice")]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HostIdentity {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+#[cfg(feature = "lattice")]
+impl wascap::jwt::WascapEntity for HostIdentity {
+    fn name(&self) -> String {
+        self.name
+            .as_ref()
+            .map_or("Anonymous".to_string(), |n| n.to_string())
+    }
+}
+
+/// Issues a host identity token for the given host ID, signed by the supplied operator or
+/// account key. The resulting JWT can be handed to a host with `HostBuilder::with_host_token`
+#[cfg(feature = "lattice")]
+pub fn issue_host_token(issuer: &KeyPair, host_id: &str, name: Option<String>) -> Result<String> {
+    let claims = ClaimsBuilder::<HostIdentity>::new()
+        .issuer(&issuer.public_key())
+        .subject(host_id)
+        .with_metadata(HostIdentity { name })
+        .build();
+    claims.encode(issuer).map_err(|e| e.into())
+}
+
+/// Verifies that a host identity token is valid, current, was issued for the given host ID, and
+/// was signed by one of the trusted operator or account keys
+#[cfg(feature = "lattice")]
+pub(crate) fn validate_host_token(
+    token: &str,
+    host_id: &str,
+    trusted_issuers: &[String],
+) -> Result<()> {
+    let v = validate_token::<HostIdentity>(token)?;
+    if v.expired {
+        return Err(errors::new(errors::ErrorKind::Authorization(
+            "Host identity token expired".to_string(),
+        )));
+    }
+    if v.cannot_use_yet {
+        return Err(errors::new(errors::ErrorKind::Authorization(format!(
+            "Host identity token cannot be used before {}",
+            v.not_before_human
+        ))));
+    }
+    if !v.signature_valid {
+        return Err(errors::new(errors::ErrorKind::Authorization(
+            "Host identity token signature invalid".to_string(),
+        )));
+    }
+    let claims = Claims::<HostIdentity>::decode(token)?;
+    if claims.subject != host_id {
+        return Err(errors::new(errors::ErrorKind::Authorization(format!(
+            "Host identity token was issued for {}, not {}",
+            claims.subject, host_id
+        ))));
+    }
+    if !trusted_issuers.contains(&claims.issuer) {
+        return Err(errors::new(errors::ErrorKind::Authorization(format!(
+            "Host identity token issuer {} is not trusted by this lattice",
+            claims.issuer
+        ))));
+    }
+    Ok(())
+}
+
 impl Host {
     pub(crate) fn check_auth(&self, token: &Token<wascap::jwt::Actor>) -> bool {
         self.authorizer.read().unwrap().can_load(&token.claims)
     }
 }
+
+#[cfg(feature = "lattice")]
+#[cfg(test)]
+mod test {
+    use super::{issue_host_token, validate_host_token};
+    use wascap::prelude::KeyPair;
+
+    #[test]
+    fn host_token_round_trip() {
+        let operator = KeyPair::new_operator();
+        let host = KeyPair::new_server();
+        let token = issue_host_token(&operator, &host.public_key(), None).unwrap();
+
+        assert!(validate_host_token(&token, &host.public_key(), &[operator.public_key()]).is_ok());
+    }
+
+    #[test]
+    fn host_token_rejects_wrong_host_and_issuer() {
+        let operator = KeyPair::new_operator();
+        let rogue = KeyPair::new_operator();
+        let host = KeyPair::new_server();
+        let other_host = KeyPair::new_server();
+        let token = issue_host_token(&operator, &host.public_key(), None).unwrap();
+
+        // A token can't be replayed by a different host
+        assert!(
+            validate_host_token(&token, &other_host.public_key(), &[operator.public_key()])
+                .is_err()
+        );
+        // and the issuer must be trusted
+        assert!(validate_host_token(&token, &host.public_key(), &[rogue.public_key()]).is_err());
+    }
+}
@@ -1,8 +1,17 @@
-use std::path::PathBuf;
+#[cfg(unix)]
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
-use wascc_host::{Host, HostBuilder, HostManifest};
+use wascc_codec::capabilities::OperationDirection;
+use wascc_host::admin::{AdminConfig, AdminServer};
+#[cfg(unix)]
+use wascc_host::control::{ControlClient, ControlServer};
+use wascc_host::{Actor, Host, HostBuilder, HostManifest, InprocBus, NativeCapability};
 
 #[macro_use]
 extern crate log;
@@ -15,6 +24,106 @@ about = "A general-purpose waSCC runtime host")]
 struct Cli {
     #[structopt(flatten)]
     command: CliCommand,
+    #[structopt(subcommand)]
+    subcommand: Option<Subcommand>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+enum Subcommand {
+    /// Print the claims embedded in a signed actor module and whether they are valid
+    #[structopt(name = "inspect")]
+    Inspect {
+        /// Path to the actor module (.wasm)
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Print the descriptor of a native capability provider
+    #[structopt(name = "inspect-provider")]
+    InspectProvider {
+        /// Path to the provider's plugin library or provider archive (.par)
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Check a host manifest, its actors, capability providers and bindings without starting a host
+    #[structopt(name = "validate")]
+    Validate {
+        /// Path to the host manifest
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Whether to expand environment variables in the host manifest
+        #[structopt(short = "e", long = "expand-env")]
+        expand_env: bool,
+    },
+    /// Operate a running host through its control socket
+    #[structopt(name = "ctl")]
+    Ctl {
+        /// Path to the host's control socket
+        #[structopt(
+            short = "s",
+            long = "socket",
+            env = "WASCC_CONTROL_SOCKET",
+            parse(from_os_str)
+        )]
+        socket: PathBuf,
+        #[structopt(subcommand)]
+        command: CtlCommand,
+    },
+}
+
+#[derive(Debug, Clone, StructOpt)]
+enum CtlCommand {
+    /// Invoke an operation on an actor and write its response to stdout
+    #[structopt(name = "call")]
+    Call {
+        /// Public key of the actor
+        actor: String,
+        /// Name of the operation
+        operation: String,
+        /// Path to a file containing the payload. The payload is empty if not set
+        #[structopt(short = "p", long = "payload", parse(from_os_str))]
+        payload: Option<PathBuf>,
+    },
+    /// List the host's actors, capability providers and bindings
+    #[structopt(name = "inventory")]
+    Inventory,
+    /// Bind an actor to a capability provider
+    #[structopt(name = "bind")]
+    Bind {
+        /// Public key of the actor
+        actor: String,
+        /// Capability ID of the provider
+        capability: String,
+        /// Binding name of the provider
+        #[structopt(short = "b", long = "binding")]
+        binding: Option<String>,
+        /// Configuration value, as KEY=VALUE. Can be repeated
+        #[structopt(short = "v", long = "value", parse(try_from_str = parse_key_value))]
+        values: Vec<(String, String)>,
+        /// Configuration key whose value is secret. Can be repeated
+        #[structopt(long = "secret")]
+        secrets: Vec<String>,
+    },
+    /// Remove the binding between an actor and a capability provider
+    #[structopt(name = "unbind")]
+    Unbind {
+        /// Public key of the actor
+        actor: String,
+        /// Capability ID of the provider
+        capability: String,
+        /// Binding name of the provider
+        #[structopt(short = "b", long = "binding")]
+        binding: Option<String>,
+    },
+    /// Live-update a running actor with a new version of its module
+    #[structopt(name = "replace")]
+    Replace {
+        /// Path to the new actor module (.wasm)
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Gracefully shut the host down
+    #[structopt(name = "shutdown")]
+    Shutdown,
 }
 
 #[derive(Debug, Clone, StructOpt)]
@@ -25,19 +134,109 @@ struct CliCommand {
     /// Whether to expand environment variables in the host manifest
     #[structopt(short = "e", long = "expand-env")]
     expand_env: bool,
+    /// Seed of the server key used as the host's identity
+    #[structopt(long = "host-seed", env = "WASCC_HOST_SEED", hide_env_values = true)]
+    host_seed: Option<String>,
+    /// Path to a file containing the host's seed. Created with a new seed if it doesn't exist
+    #[structopt(
+        long = "host-seed-file",
+        env = "WASCC_HOST_SEED_FILE",
+        parse(from_os_str),
+        conflicts_with = "host-seed"
+    )]
+    host_seed_file: Option<PathBuf>,
+    /// Run with the in-process message bus, without joining a lattice
+    #[structopt(long = "inproc", env = "WASCC_INPROC")]
+    inproc: bool,
+    /// Address of the admin API, e.g. 127.0.0.1:9090. The admin API is disabled if not set
+    #[structopt(
+        long = "admin-addr",
+        env = "WASCC_ADMIN_ADDR",
+        requires = "admin-token"
+    )]
+    admin_addr: Option<SocketAddr>,
+    /// Bearer token required by the admin API
+    #[structopt(
+        long = "admin-token",
+        env = "WASCC_ADMIN_TOKEN",
+        hide_env_values = true
+    )]
+    admin_token: Option<String>,
+    /// Allow the admin API to listen on a non-loopback address. Requests are sent over plain HTTP
+    #[structopt(long = "admin-allow-remote", env = "WASCC_ADMIN_ALLOW_REMOTE")]
+    admin_allow_remote: bool,
+    /// Directory from which the admin API may load capability providers. Disabled if not set
+    #[structopt(
+        long = "admin-provider-dir",
+        env = "WASCC_ADMIN_PROVIDER_DIR",
+        parse(from_os_str)
+    )]
+    admin_provider_dir: Option<PathBuf>,
+    /// Path of a Unix domain socket on which the host accepts `ctl` commands. Disabled if not set
+    #[structopt(
+        long = "control-socket",
+        env = "WASCC_CONTROL_SOCKET",
+        parse(from_os_str)
+    )]
+    control_socket: Option<PathBuf>,
 }
 
 #[cfg(feature = "manifest")]
 fn main() -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let args = Cli::from_args();
-    let cmd = args.command;
+    let Cli {
+        command: cmd,
+        subcommand,
+    } = Cli::from_args();
+    match subcommand {
+        Some(Subcommand::Inspect { path }) => return inspect(&path),
+        Some(Subcommand::InspectProvider { path }) => return inspect_provider(&path),
+        Some(Subcommand::Validate { path, expand_env }) => return validate(&path, expand_env),
+        Some(Subcommand::Ctl { socket, command }) => return ctl(&socket, command),
+        None => {}
+    }
     let _ = env_logger::Builder::from_env(
         env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "wascc_host=info"),
     )
     .format_module_path(false)
     .try_init();
 
-    let host = HostBuilder::new().build();
+    let mut builder = HostBuilder::new();
+    if let Some(ref seed) = cmd.host_seed {
+        builder = builder.with_host_seed(seed)?;
+    } else if let Some(ref path) = cmd.host_seed_file {
+        builder = builder.with_host_seed_file(path)?;
+    }
+    if cmd.inproc {
+        builder = builder.with_bus(InprocBus::new());
+    }
+    let lattice = cfg!(feature = "lattice") && !cmd.inproc;
+    let host = builder.build();
+    info!("Host ID: {}", host.id());
+
+    let admin = match (cmd.admin_addr, cmd.admin_token) {
+        (Some(addr), Some(token)) => Some(AdminServer::start(
+            host.clone(),
+            AdminConfig {
+                addr,
+                token,
+                allow_remote: cmd.admin_allow_remote,
+                provider_dir: cmd.admin_provider_dir,
+            },
+        )?),
+        _ => None,
+    };
+
+    let (term_s, term_r) = std::sync::mpsc::channel();
+    #[cfg(unix)]
+    let control = match cmd.control_socket {
+        Some(ref path) => {
+            let term_s = std::sync::Mutex::new(term_s.clone());
+            Some(ControlServer::start(host.clone(), path, move || {
+                let _ = term_s.lock().unwrap().send(());
+            })?)
+        }
+        None => None,
+    };
 
     if let Some(ref mp) = cmd.manifest_path {
         let manifest = HostManifest::from_path(mp, cmd.expand_env)?;
@@ -45,14 +244,14 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
         info!("Processed and applied host manifest");
     } else {
         info!("Starting without manifest");
-        #[cfg(not(feature = "lattice"))]
-        {
+        if !lattice {
             error!("Started without manifest and without lattice. This host cannot launch actors or providers. Shutting down.");
             return Err("Started without manifest or lattice - unusable host".into());
         }
     }
-
-    let (term_s, term_r) = std::sync::mpsc::channel();
+    if let Some(ref admin) = admin {
+        admin.set_ready(true);
+    }
 
     ctrlc::set_handler(move || {
         term_s.send(()).unwrap();
@@ -62,11 +261,171 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
     term_r.recv().expect("Failed awaiting termination signal");
 
     info!("Shutting down host");
+    drop(admin);
+    #[cfg(unix)]
+    drop(control);
     host.shutdown()?;
 
     Ok(())
 }
 
+fn inspect(path: &Path) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let actor = Actor::from_file(path)?;
+    let claims = actor.claims();
+    let md = claims.metadata.clone().unwrap_or_default();
+    println!("Actor:         {}", actor.public_key());
+    println!("Name:          {}", actor.name());
+    println!("Issuer:        {}", actor.issuer());
+    println!("Capabilities:  {}", actor.capabilities().join(", "));
+    println!("Tags:          {}", actor.tags().join(", "));
+    println!(
+        "Version:       {} (revision {})",
+        md.ver.unwrap_or_else(|| "none".to_string()),
+        md.rev.unwrap_or(0)
+    );
+    println!("Module hash:   {}", md.module_hash);
+    println!("Issued at:     {}", claims.issued_at);
+    println!("Not before:    {}", timestamp(claims.not_before));
+    println!("Expires:       {}", timestamp(claims.expires));
+    match actor.validate() {
+        Ok(_) => {
+            println!("Validation:    valid");
+            Ok(())
+        }
+        Err(e) => {
+            println!("Validation:    invalid - {}", e);
+            Err("Actor is not valid".into())
+        }
+    }
+}
+
+fn inspect_provider(
+    path: &Path,
+) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let provider = NativeCapability::from_path(path, None)?;
+    let d = provider.descriptor();
+    println!("Capability:    {}", d.id);
+    println!("Name:          {}", d.name);
+    println!("Version:       {} (revision {})", d.version, d.revision);
+    println!("Description:   {}", d.long_description);
+    println!("Operations:");
+    for op in &d.supported_operations {
+        let direction = match op.direction {
+            OperationDirection::ToActor => "to actor",
+            OperationDirection::ToProvider => "to provider",
+            OperationDirection::Both => "both",
+        };
+        println!("  {} ({}) - {}", op.name, direction, op.doctext);
+    }
+    Ok(())
+}
+
+fn validate(
+    path: &Path,
+    expand_env: bool,
+) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let manifest = HostManifest::from_path(path, expand_env)?;
+    match manifest.validate() {
+        Ok(_) => {
+            println!("{} is valid", path.display());
+            Ok(())
+        }
+        Err(problems) => {
+            for p in &problems {
+                println!("  - {}", p);
+            }
+            Err(format!("Found {} problem(s) in {}", problems.len(), path.display()).into())
+        }
+    }
+}
+
+#[cfg(unix)]
+fn ctl(
+    socket: &Path,
+    command: CtlCommand,
+) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let client = ControlClient::new(socket);
+    match command {
+        CtlCommand::Call {
+            actor,
+            operation,
+            payload,
+        } => {
+            let payload = match payload {
+                Some(path) => std::fs::read(path)?,
+                None => vec![],
+            };
+            let res = client.call_actor(&actor, &operation, &payload)?;
+            std::io::stdout().write_all(&res)?;
+        }
+        CtlCommand::Inventory => {
+            let inv = client.inventory()?;
+            println!("Actors:");
+            for a in inv.actors {
+                println!("  {} {} [{}]", a.id, a.name, a.capabilities.join(", "));
+            }
+            println!("Capabilities:");
+            for c in inv.capabilities {
+                println!("  {},{} {} v{}", c.capid, c.binding, c.name, c.version);
+            }
+            println!("Bindings:");
+            for b in inv.bindings {
+                let mut values: Vec<_> = b
+                    .values
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect();
+                values.sort();
+                println!(
+                    "  {} -> {},{} {}",
+                    b.actor,
+                    b.capability,
+                    b.binding,
+                    values.join(" ")
+                );
+            }
+        }
+        CtlCommand::Bind {
+            actor,
+            capability,
+            binding,
+            values,
+            secrets,
+        } => {
+            let values: HashMap<String, String> = values.into_iter().collect();
+            client.set_binding(&actor, &capability, binding, values, &secrets)?;
+        }
+        CtlCommand::Unbind {
+            actor,
+            capability,
+            binding,
+        } => client.remove_binding(&actor, &capability, binding)?,
+        CtlCommand::Replace { path } => client.replace_actor(&std::fs::read(path)?)?,
+        CtlCommand::Shutdown => client.shutdown()?,
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn ctl(
+    _socket: &Path,
+    _command: CtlCommand,
+) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    Err("The control socket is only supported on Unix".into())
+}
+
+fn parse_key_value(s: &str) -> std::result::Result<(String, String), String> {
+    match s.find('=') {
+        Some(i) => Ok((s[..i].to_string(), s[i + 1..].to_string())),
+        None => Err(format!("Expected KEY=VALUE, got {}", s)),
+    }
+}
+
+// Formats a claims timestamp, given in seconds since the Unix epoch
+fn timestamp(secs: Option<u64>) -> String {
+    secs.map_or("-".to_string(), |s| format!("{} (Unix time)", s))
+}
+
 #[cfg(not(feature = "manifest"))]
 fn main() -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
     println!(
@@ -1,29 +1,51 @@
+use super::MessageBus;
 use crate::errors;
 use crate::{Invocation, InvocationResponse, Result};
 use crossbeam::{Receiver, Sender};
 use std::{collections::HashMap, sync::RwLock};
 
-pub(crate) struct InprocBus {
+/// A message bus that delivers invocations between actors and capability providers
+/// running within the same host process
+pub struct InprocBus {
     subscriptions: RwLock<HashMap<String, (Sender<Invocation>, Receiver<InvocationResponse>)>>,
+    strict: bool,
+}
+
+impl Default for InprocBus {
+    fn default() -> Self {
+        InprocBus::new()
+    }
 }
 
 impl InprocBus {
     pub fn new() -> Self {
-        info!("Initialized Message Bus (internal)");
+        Self::with_validation(false)
+    }
+
+    /// Creates an in-process bus that validates the antiforgery claims of every invocation it
+    /// delivers, and requires invocations to be validated again after pre-invoke middleware
+    pub fn strict() -> Self {
+        Self::with_validation(true)
+    }
+
+    fn with_validation(strict: bool) -> Self {
+        info!(
+            "Initialized Message Bus (internal{})",
+            if strict { ", strict antiforgery" } else { "" }
+        );
         InprocBus {
             subscriptions: RwLock::new(HashMap::new()),
+            strict,
         }
     }
+}
 
-    pub fn disconnect(&self) {
-        // No-op
-    }
-
-    pub fn subscribe(
+impl MessageBus for InprocBus {
+    fn subscribe(
         &self,
         subject: &str,
-        sender: crossbeam::Sender<Invocation>,
-        receiver: crossbeam::Receiver<InvocationResponse>,
+        sender: Sender<Invocation>,
+        receiver: Receiver<InvocationResponse>,
     ) -> Result<()> {
         self.subscriptions
             .write()
@@ -32,16 +54,19 @@ impl InprocBus {
         Ok(())
     }
 
-    pub fn nqsubscribe(
+    fn nqsubscribe(
         &self,
         subject: &str,
-        sender: crossbeam::Sender<Invocation>,
-        receiver: crossbeam::Receiver<InvocationResponse>,
+        sender: Sender<Invocation>,
+        receiver: Receiver<InvocationResponse>,
     ) -> Result<()> {
         self.subscribe(subject, sender, receiver)
     }
 
-    pub fn invoke(&self, subject: &str, inv: Invocation) -> Result<InvocationResponse> {
+    fn invoke(&self, subject: &str, inv: Invocation) -> Result<InvocationResponse> {
+        if self.strict {
+            inv.validate_antiforgery()?;
+        }
         match self.subscriptions.read().unwrap().get(subject) {
             Some(s) => {
                 s.0.send(inv).unwrap();
@@ -55,7 +80,7 @@ impl InprocBus {
         }
     }
 
-    pub fn unsubscribe(&self, subject: &str) -> Result<()> {
+    fn unsubscribe(&self, subject: &str) -> Result<()> {
         self.subscriptions
             .write()
             .unwrap()
@@ -63,28 +88,11 @@ impl InprocBus {
         Ok(())
     }
 
-    pub fn actor_subject(&self, actor: &str) -> String {
-        super::actor_subject(None, actor)
-    }
-
-    pub(crate) fn provider_subject(&self, capid: &str, binding: &str) -> String {
-        super::provider_subject(None, capid, binding)
-    }
-
-    pub(crate) fn inventory_wildcard_subject(&self) -> String {
-        super::inventory_wildcard_subject(None)
-    }
-
-    pub(crate) fn event_subject(&self) -> String {
-        super::event_subject(None)
+    fn disconnect(&self) {
+        // No-op
     }
 
-    pub(crate) fn provider_subject_bound_actor(
-        &self,
-        capid: &str,
-        binding: &str,
-        calling_actor: &str,
-    ) -> String {
-        super::provider_subject_bound_actor(None, capid, binding, calling_actor)
+    fn validates_invocations(&self) -> bool {
+        self.strict
     }
 }
@@ -1,5 +1,7 @@
+use crate::metrics::RuntimeStats;
+use crate::secrets::{SecretPolicy, REDACTED_VALUE};
 use crate::{BindingsList, NativeCapability, RouteKey};
-use crate::{Invocation, InvocationResponse, Result};
+use crate::{Invocation, InvocationResponse, Result, WasccEntity};
 use crossbeam::{Receiver, Sender};
 use crossbeam_channel as channel;
 use latticeclient::{
@@ -9,38 +11,624 @@ use latticeclient::{
     },
     BusEvent, CloudEvent,
 };
-use nats;
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
 use wascap::jwt::{Actor, Claims};
 use wascc_codec::{capabilities::CapabilityDescriptor, deserialize, serialize};
 
 const LATTICE_HOST_KEY: &str = "LATTICE_HOST";
-// env var name
+// env var name, may contain a comma-separated list of server URLs
 const DEFAULT_LATTICE_HOST: &str = "127.0.0.1";
 // default mode is anonymous via loopback
 const LATTICE_RPC_TIMEOUT_KEY: &str = "LATTICE_RPC_TIMEOUT_MILLIS";
 const DEFAULT_LATTICE_RPC_TIMEOUT_MILLIS: u64 = 600;
 const LATTICE_CREDSFILE_KEY: &str = "LATTICE_CREDS_FILE";
+const LATTICE_NKEY_SEED_KEY: &str = "LATTICE_NKEY_SEED";
+const LATTICE_USER_KEY: &str = "LATTICE_USER";
+const LATTICE_PASSWORD_KEY: &str = "LATTICE_PASSWORD";
+const LATTICE_TOKEN_KEY: &str = "LATTICE_TOKEN";
+const LATTICE_TLS_REQUIRED_KEY: &str = "LATTICE_TLS_REQUIRED";
+const LATTICE_TLS_CA_KEY: &str = "LATTICE_TLS_CA";
+const LATTICE_TLS_CERT_KEY: &str = "LATTICE_TLS_CERT";
+const LATTICE_TLS_KEY_KEY: &str = "LATTICE_TLS_KEY";
+const LATTICE_HOST_TOKEN_KEY: &str = "LATTICE_HOST_TOKEN";
+const LATTICE_TRUSTED_ISSUERS_KEY: &str = "LATTICE_TRUSTED_ISSUERS";
+const LATTICE_CONNECT_RETRIES_KEY: &str = "LATTICE_CONNECT_RETRIES";
+const DEFAULT_LATTICE_CONNECT_RETRIES: u32 = 5;
+const LATTICE_RECONNECT_BACKOFF_KEY: &str = "LATTICE_RECONNECT_BACKOFF_MILLIS";
+const DEFAULT_LATTICE_RECONNECT_BACKOFF_MILLIS: u64 = 250;
+const LATTICE_RECONNECT_BACKOFF_MAX_KEY: &str = "LATTICE_RECONNECT_BACKOFF_MAX_MILLIS";
+const DEFAULT_LATTICE_RECONNECT_BACKOFF_MAX_MILLIS: u64 = 10_000;
+
+// Control plane subject suffixes used to propagate the lattice-wide host block list
+const BLOCKLIST_ADD: &str = "blocklist.add";
+const BLOCKLIST_REMOVE: &str = "blocklist.remove";
+const BLOCKLIST_CLEAR: &str = "blocklist.clear";
+// Control plane subject suffix on which hosts answer requests for their current block list, so
+// that a host joining the lattice starts with the same list as its peers
+const BLOCKLIST_SYNC: &str = "blocklist.sync";
+// Control plane subject suffix on which a host answers requests for its identity token
+const HOST_IDENTITY: &str = "identity";
+// Control plane subject suffix on which a host answers signed requests for the unredacted
+// configuration of one of its bindings
+const BINDING_CONFIG: &str = "binding.config";
+
+// How long a verified (or failed) host identity is cached before it is checked again
+const VERIFIED_HOST_TTL_SECS: u64 = 300;
+const UNVERIFIED_HOST_TTL_SECS: u64 = 30;
+// How many host identities are cached. Host IDs arrive from the network, so the cache is bounded
+const VERIFIED_HOSTS_CAPACITY: usize = 1024;
+// Limits on the identity token requests a host makes to verify its peers, so that invocations
+// from a flood of made-up host IDs can't stall the host with network requests. Requests beyond
+// the rate limit are delayed, not dropped
+const MAX_PENDING_VERIFICATIONS: usize = 64;
+const MAX_VERIFICATIONS_PER_SEC: usize = 20;
+// How often the hosts in the lattice inventory are verified ahead of their invocations
+const HOST_DISCOVERY_INTERVAL_SECS: u64 = 60;
+
+// How many recently received invocation claims are remembered to detect hosts that sign
+// contradictory claims for the same invocation
+const SEEN_INVOCATIONS_CAPACITY: usize = 10_000;
 
 const TERM_BACKOFF_MAX_TRIES: u8 = 3;
 const TERM_BACKOFF_DELAY_MS: u64 = 50;
 
-use crate::inthost::{CORELABEL_ARCH, CORELABEL_OS};
+use super::seal::{self, SealPolicy, SealingKeys};
+use super::transport::{
+    ConnectionState, Connector, DisconnectCallback, Message, NatsTransport, ReconnectingTransport,
+    Subscription, Transport,
+};
+use super::{LatticeAdmin, MessageBus};
+use crate::inthost::{CORELABEL_ARCH, CORELABEL_OS, CORELABEL_SEALKEY};
+use crate::lru::LruCache;
 use latticeclient::controlplane::{
     LaunchProviderCommand, ProviderAuctionRequest, ProviderAuctionResponse,
     TerminateProviderCommand, LAUNCH_PROVIDER, PROVIDER_AUCTION_REQ, TERMINATE_PROVIDER,
 };
 use latticeclient::*;
-use nats::Message;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use wapc::WasiParams;
 use wascap::prelude::KeyPair;
 
+/// A host that has been added to the lattice-wide block list, along with the host that
+/// reported it and the reason it was blocked. A host is only blocked when it has provably
+/// misbehaved: the evidence holds two invocation claims tokens, both validly signed by the
+/// blocked host, that give the same invocation different contents. Every host checks the
+/// evidence for itself before accepting a block list entry
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BlockedHost {
+    pub host_id: String,
+    pub reporter: String,
+    pub reason: String,
+    #[serde(default)]
+    pub evidence: Vec<String>,
+}
+
+/// A security-related event published on the lattice's `{prefix}.events.security` subject. These
+/// events are kept off of the main event subject so that consumers of the standard bus events
+/// are unaffected
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum SecurityEvent {
+    AntiforgeryCheckFailed {
+        host: String,
+        origin_host: String,
+        invocation_id: String,
+        reason: String,
+    },
+    HostBlocked(BlockedHost),
+    HostUnblocked {
+        host_id: String,
+    },
+    BlockListCleared,
+}
+
+/// Published on the lattice's `{prefix}.events.connection` subject when a host loses or regains
+/// its connection to the lattice (or first connects, if it started while the lattice was
+/// unreachable). A host usually can't publish while it's disconnected, in which case its
+/// `Disconnected` event is published when it reconnects, just before its `Reconnected` event
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ConnectionEvent {
+    Disconnected {
+        host: String,
+        /// When the connection was lost, in milliseconds since the Unix epoch
+        disconnected_at_ms: u128,
+    },
+    Reconnected {
+        host: String,
+        downtime_ms: u128,
+        resubscribed: usize,
+    },
+}
+
+pub(crate) type BlockList = Arc<RwLock<HashMap<String, BlockedHost>>>;
+
+// The claims tokens of recently received invocations, keyed by issuing host and invocation ID
+type SeenInvocations = Arc<RwLock<LruCache<String, String>>>;
+
+// The bus's connection to the lattice, which is taken and closed when the host disconnects
+pub(crate) type LatticeConnection = Arc<RwLock<Option<Arc<dyn Transport>>>>;
+
+/// Host identity settings used when joining a lattice. If no trusted issuers are configured,
+/// host identity is not enforced
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IdentityConfig {
+    pub token: Option<String>,
+    pub trusted_issuers: Vec<String>,
+}
+
+impl IdentityConfig {
+    pub(crate) fn from_env() -> IdentityConfig {
+        IdentityConfig {
+            token: std::env::var(LATTICE_HOST_TOKEN_KEY)
+                .ok()
+                .filter(|t| !t.is_empty()),
+            trusted_issuers: get_env(LATTICE_TRUSTED_ISSUERS_KEY, "")
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+        }
+    }
+}
+
+/// How many times the initial lattice connection is retried, and the exponential backoff between
+/// attempts to connect and reconnect
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionPolicy {
+    pub connect_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl ConnectionPolicy {
+    pub(crate) fn from_env() -> ConnectionPolicy {
+        ConnectionPolicy {
+            connect_retries: get_env(LATTICE_CONNECT_RETRIES_KEY, "")
+                .parse()
+                .unwrap_or(DEFAULT_LATTICE_CONNECT_RETRIES),
+            initial_backoff: Duration::from_millis(
+                get_env(LATTICE_RECONNECT_BACKOFF_KEY, "")
+                    .parse()
+                    .unwrap_or(DEFAULT_LATTICE_RECONNECT_BACKOFF_MILLIS),
+            ),
+            max_backoff: Duration::from_millis(
+                get_env(LATTICE_RECONNECT_BACKOFF_MAX_KEY, "")
+                    .parse()
+                    .unwrap_or(DEFAULT_LATTICE_RECONNECT_BACKOFF_MAX_MILLIS),
+            ),
+        }
+    }
+
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2_u32.saturating_pow(attempt.min(16));
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |b| std::cmp::min(b, self.max_backoff))
+    }
+}
+
+/// The credentials a host uses to authenticate with the lattice's NATS servers
+#[derive(Clone)]
+pub enum LatticeAuth {
+    Anonymous,
+    /// A NATS credentials file containing a user JWT and nkey seed
+    CredentialsFile(PathBuf),
+    /// A user nkey seed, used to sign the server's authentication challenge
+    NKey(String),
+    UserPassword { user: String, password: String },
+    Token(String),
+}
+
+// Secrets are left out so that they don't end up in logs
+impl std::fmt::Debug for LatticeAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LatticeAuth::Anonymous => write!(f, "Anonymous"),
+            LatticeAuth::CredentialsFile(path) => write!(f, "CredentialsFile({:?})", path),
+            LatticeAuth::NKey(_) => write!(f, "NKey(..)"),
+            LatticeAuth::UserPassword { user, .. } => write!(f, "UserPassword({}, ..)", user),
+            LatticeAuth::Token(_) => write!(f, "Token(..)"),
+        }
+    }
+}
+
+// Where and how the host connects to the lattice's NATS servers
+#[derive(Clone)]
+pub(crate) struct NatsOptions {
+    pub servers: Vec<String>,
+    pub auth: LatticeAuth,
+    pub tls_required: bool,
+    pub tls_ca: Option<PathBuf>,
+    pub tls_client_cert: Option<(PathBuf, PathBuf)>,
+}
+
+impl NatsOptions {
+    pub(crate) fn from_env() -> NatsOptions {
+        let auth = if let Some(creds) = get_credsfile() {
+            LatticeAuth::CredentialsFile(PathBuf::from(creds))
+        } else if let Some(seed) = get_nonempty_env(LATTICE_NKEY_SEED_KEY) {
+            LatticeAuth::NKey(seed)
+        } else if let Some(user) = get_nonempty_env(LATTICE_USER_KEY) {
+            LatticeAuth::UserPassword {
+                user,
+                password: get_env(LATTICE_PASSWORD_KEY, ""),
+            }
+        } else if let Some(token) = get_nonempty_env(LATTICE_TOKEN_KEY) {
+            LatticeAuth::Token(token)
+        } else {
+            LatticeAuth::Anonymous
+        };
+        let tls_client_cert = match (
+            get_nonempty_env(LATTICE_TLS_CERT_KEY),
+            get_nonempty_env(LATTICE_TLS_KEY_KEY),
+        ) {
+            (Some(cert), Some(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+            _ => None,
+        };
+        NatsOptions {
+            servers: get_env(LATTICE_HOST_KEY, DEFAULT_LATTICE_HOST)
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            auth,
+            tls_required: get_env(LATTICE_TLS_REQUIRED_KEY, "false")
+                .parse()
+                .unwrap_or(false),
+            tls_ca: get_nonempty_env(LATTICE_TLS_CA_KEY).map(PathBuf::from),
+            tls_client_cert,
+        }
+    }
+
+    fn to_nats(&self) -> std::io::Result<nats::Options> {
+        let opts = match self.auth {
+            LatticeAuth::Anonymous => nats::Options::new(),
+            LatticeAuth::CredentialsFile(ref path) => nats::Options::with_credentials(path),
+            LatticeAuth::NKey(ref seed) => {
+                let kp = KeyPair::from_seed(seed).map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Invalid lattice nkey seed: {}", e),
+                    )
+                })?;
+                nats::Options::with_nkey(&kp.public_key(), move |nonce| {
+                    kp.sign(nonce).unwrap_or_default()
+                })
+            }
+            LatticeAuth::UserPassword {
+                ref user,
+                ref password,
+            } => nats::Options::with_user_pass(user, password),
+            LatticeAuth::Token(ref token) => nats::Options::with_token(token),
+        };
+        // Supplying any TLS material implies that TLS is required
+        let mut opts = opts.tls_required(
+            self.tls_required || self.tls_ca.is_some() || self.tls_client_cert.is_some(),
+        );
+        if let Some(ref ca) = self.tls_ca {
+            opts = opts.add_root_certificate(ca);
+        }
+        if let Some((ref cert, ref key)) = self.tls_client_cert {
+            opts = opts.client_cert(cert, key);
+        }
+        Ok(opts)
+    }
+}
+
+// Verifies and caches the identity tokens presented by the hosts in the lattice. Tokens are
+// requested by a background verifier, which also verifies the hosts in the lattice inventory
+// before they invoke anything, so invocations only wait for hosts that haven't been seen yet
+pub(crate) struct HostIdentities {
+    nc: LatticeConnection,
+    ns: Option<String>,
+    config: IdentityConfig,
+    req_timeout: Duration,
+    participating: bool,
+    verified: RwLock<LruCache<String, (bool, Instant)>>,
+    requests: Mutex<VerificationRequests>,
+    // signalled when a host is queued for verification, and when a verification completes
+    queued: Condvar,
+    completed: Condvar,
+}
+
+// Hosts waiting to be verified, and the number of token requests made in the current one-second
+// window
+struct VerificationRequests {
+    pending: HashSet<String>,
+    window: Instant,
+    count: usize,
+}
+
+impl VerificationRequests {
+    fn new() -> VerificationRequests {
+        VerificationRequests {
+            pending: HashSet::new(),
+            window: Instant::now(),
+            count: 0,
+        }
+    }
+
+    // Queues the host for verification, returning whether it's pending. Only fails when the
+    // queue is full
+    fn queue(&mut self, host_id: &str) -> bool {
+        if self.pending.contains(host_id) {
+            return true;
+        }
+        if self.pending.len() >= MAX_PENDING_VERIFICATIONS {
+            return false;
+        }
+        self.pending.insert(host_id.to_string())
+    }
+
+    // Reserves a token request in the current window. If the rate limit has been reached,
+    // returns how long to wait for the next window instead
+    fn throttle(&mut self) -> Option<Duration> {
+        let elapsed = self.window.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            self.window = Instant::now();
+            self.count = 0;
+        } else if self.count >= MAX_VERIFICATIONS_PER_SEC {
+            return Some(Duration::from_secs(1) - elapsed);
+        }
+        self.count += 1;
+        None
+    }
+}
+
+impl HostIdentities {
+    fn new(
+        nc: LatticeConnection,
+        ns: Option<String>,
+        config: IdentityConfig,
+        host_id: &str,
+        req_timeout: Duration,
+    ) -> HostIdentities {
+        let mut verified = LruCache::new(VERIFIED_HOSTS_CAPACITY);
+        let participating = if config.trusted_issuers.is_empty() {
+            true
+        } else {
+            match config.token {
+                Some(ref t) => {
+                    match crate::authz::validate_host_token(t, host_id, &config.trusted_issuers) {
+                        Ok(_) => {
+                            info!("Host identity token verified");
+                            verified.insert(host_id.to_string(), (true, Instant::now()));
+                            true
+                        }
+                        Err(e) => {
+                            error!("Host identity token rejected: {}. This host will not participate in the lattice.", e);
+                            false
+                        }
+                    }
+                }
+                None => {
+                    error!("Lattice requires a host identity token but none was supplied. This host will not participate in the lattice.");
+                    false
+                }
+            }
+        };
+        HostIdentities {
+            nc,
+            ns,
+            config,
+            req_timeout,
+            participating,
+            verified: RwLock::new(verified),
+            requests: Mutex::new(VerificationRequests::new()),
+            queued: Condvar::new(),
+            completed: Condvar::new(),
+        }
+    }
+
+    // Whether this host holds a valid identity token (or identity isn't enforced)
+    fn participating(&self) -> bool {
+        self.participating
+    }
+
+    // Whether hosts must present an identity token signed by a trusted issuer
+    fn enforced(&self) -> bool {
+        !self.config.trusted_issuers.is_empty()
+    }
+
+    // Returns true if the given host has presented a valid host identity token. When host identity
+    // is not enforced, all hosts are trusted. A host that hasn't been verified yet is queued for
+    // the background verifier, and the caller waits for the result
+    fn is_trusted(&self, host_id: &str) -> bool {
+        if !self.enforced() {
+            return true;
+        }
+        if let Some(ok) = self.cached(host_id) {
+            return ok;
+        }
+        let deadline = Instant::now() + self.req_timeout * 2;
+        let mut requests = self.requests.lock().unwrap();
+        if !requests.queue(host_id) {
+            warn!(
+                "Too many pending host identity verifications, not verifying host {}",
+                host_id
+            );
+            return false;
+        }
+        self.queued.notify_one();
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!(
+                    "Timed out waiting for verification of host {}, which continues in the background",
+                    host_id
+                );
+                return false;
+            }
+            requests = self
+                .completed
+                .wait_timeout(requests, deadline - now)
+                .unwrap()
+                .0;
+            if let Some(ok) = self.cached(host_id) {
+                return ok;
+            }
+        }
+    }
+
+    // Returns the cached verification result for the host, if it hasn't expired. Never waits
+    // for a verification, but queues one if there's no result
+    fn verified(&self, host_id: &str) -> Option<bool> {
+        if !self.enforced() {
+            return Some(true);
+        }
+        let ok = self.cached(host_id);
+        if ok.is_none() {
+            self.refresh(host_id, Duration::from_secs(0));
+        }
+        ok
+    }
+
+    fn cached(&self, host_id: &str) -> Option<bool> {
+        match self.verified.write().unwrap().get(&host_id.to_string()) {
+            Some((ok, at)) if at.elapsed() < verification_ttl(*ok) => Some(*ok),
+            _ => None,
+        }
+    }
+
+    // Queues the host for verification unless it has a cached result that's still valid for
+    // the given time
+    fn refresh(&self, host_id: &str, valid_for: Duration) {
+        let expiring = match self.verified.write().unwrap().get(&host_id.to_string()) {
+            Some((ok, at)) => at.elapsed() + valid_for >= verification_ttl(*ok),
+            None => true,
+        };
+        if expiring && self.requests.lock().unwrap().queue(host_id) {
+            self.queued.notify_one();
+        }
+    }
+
+    // Waits up to the given time for a host to be queued for verification, then waits for the
+    // rate limit to allow a token request
+    fn next_pending(&self, timeout: Duration) -> Option<String> {
+        let mut requests = self.requests.lock().unwrap();
+        if requests.pending.is_empty() {
+            requests = self.queued.wait_timeout(requests, timeout).unwrap().0;
+        }
+        let host_id = requests.pending.iter().next().cloned()?;
+        while let Some(wait) = requests.throttle() {
+            requests = self.queued.wait_timeout(requests, wait).unwrap().0;
+        }
+        Some(host_id)
+    }
+
+    // Requests and validates a host's identity token, caching the result. Only called by the
+    // background verifier
+    fn verify(&self, host_id: &str) {
+        let ok = match self.request_token(host_id) {
+            Ok(token) => match crate::authz::validate_host_token(
+                &token,
+                host_id,
+                &self.config.trusted_issuers,
+            ) {
+                Ok(_) => true,
+                Err(e) => {
+                    warn!("Host {} presented an invalid identity token: {}", host_id, e);
+                    false
+                }
+            },
+            Err(e) => {
+                warn!("Failed to obtain identity token for host {}: {}", host_id, e);
+                false
+            }
+        };
+        self.complete(host_id, ok);
+    }
+
+    // Caches the result of a host's verification and wakes the callers waiting for it
+    fn complete(&self, host_id: &str, ok: bool) {
+        self.verified
+            .write()
+            .unwrap()
+            .insert(host_id.to_string(), (ok, Instant::now()));
+        self.requests.lock().unwrap().pending.remove(host_id);
+        self.completed.notify_all();
+    }
+
+    fn request_token(&self, host_id: &str) -> Result<String> {
+        let subject = format!(
+            "{}.{}.{}.{}",
+            super::nsprefix(self.ns.as_ref().map(String::as_str)),
+            CPLANE_PREFIX,
+            host_id,
+            HOST_IDENTITY
+        );
+        let lock = self.nc.read().unwrap();
+        match lock.as_ref() {
+            Some(nc) => {
+                let resp = nc.request(&subject, b"", self.req_timeout)?;
+                Ok(String::from_utf8_lossy(&resp.data).to_string())
+            }
+            None => Err("No lattice connection".to_string().into()),
+        }
+    }
+}
+
+fn verification_ttl(verified: bool) -> Duration {
+    Duration::from_secs(if verified {
+        VERIFIED_HOST_TTL_SECS
+    } else {
+        UNVERIFIED_HOST_TTL_SECS
+    })
+}
+
+// Verifies the identities of the hosts queued by `HostIdentities` and, at a regular interval,
+// of every host in the lattice inventory whose verification is missing or about to expire, so
+// that peers are verified before they invoke anything. Stops once the bus has been dropped
+fn spawn_identity_verifier(identities: Weak<HostIdentities>, inventory: Inventory) {
+    thread::spawn(move || {
+        let interval = Duration::from_secs(HOST_DISCOVERY_INTERVAL_SECS);
+        let mut discovered: Option<Instant> = None;
+        while let Some(identities) = identities.upgrade() {
+            let due = match discovered {
+                Some(at) => at.elapsed() >= interval,
+                None => true,
+            };
+            if due {
+                match inventory.hosts() {
+                    Ok(hosts) => {
+                        for hp in hosts {
+                            identities.refresh(&hp.id, interval);
+                        }
+                    }
+                    Err(e) => warn!("Failed to query host inventory for identity checks: {}", e),
+                }
+                discovered = Some(Instant::now());
+            }
+            if let Some(host_id) = identities.next_pending(Duration::from_secs(1)) {
+                identities.verify(&host_id);
+            }
+        }
+    });
+}
+
+// Control plane messages that originate from hosts (rather than from lattice clients) are
+// signed with the host key so that receiving hosts can verify the sender
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct SignedHostMessage {
+    host_id: String,
+    payload: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+// A request for the unredacted configuration of a binding. If the requesting host has payload
+// sealing enabled, it includes its sealing key so the configuration can be sealed for it
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct BindingConfigRequest {
+    actor: String,
+    capid: String,
+    binding: String,
+    sealkey: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) enum ControlCommand {
     TerminateActor(TerminateCommand),
@@ -50,19 +638,32 @@ pub(crate) enum ControlCommand {
 }
 
 pub(crate) struct DistributedBus {
-    nc: Arc<RwLock<Option<nats::Connection>>>,
-    subs: Arc<RwLock<HashMap<String, nats::subscription::Handler>>>,
+    nc: LatticeConnection,
+    subs: Arc<RwLock<HashMap<String, Box<dyn Subscription>>>>,
     terminators: Arc<RwLock<HashMap<String, Sender<bool>>>>,
     req_timeout: Duration,
     host_id: String,
-    lc: Arc<RwLock<latticeclient::Client>>,
-    pub(crate) ns: Option<String>,
+    inventory: Inventory,
+    ns: Option<String>,
     claims: Arc<RwLock<HashMap<String, Claims<Actor>>>>,
+    // this host's own bindings, with their unredacted configuration
+    bindings: Arc<RwLock<BindingsList>>,
+    blocklist: BlockList,
+    identities: Arc<HostIdentities>,
+    host_seed: String,
+    strict: bool,
+    sealing: Option<Arc<SealingKeys>>,
+    seen: SeenInvocations,
+    stats: Arc<RuntimeStats>,
 }
 
 impl DistributedBus {
     pub fn new(
         host_id: String,
+        host_seed: String,
+        identity: IdentityConfig,
+        strict: bool,
+        seal: SealPolicy,
         claims: Arc<RwLock<HashMap<String, Claims<Actor>>>>,
         caps: Arc<RwLock<HashMap<RouteKey, CapabilityDescriptor>>>,
         bindings: Arc<RwLock<BindingsList>>,
@@ -72,15 +673,52 @@ impl DistributedBus {
         cplane_s: Sender<ControlCommand>,
         authz: Arc<RwLock<Box<dyn crate::authz::Authorizer>>>,
         image_map: Arc<RwLock<HashMap<String, String>>>,
+        secrets: Arc<RwLock<SecretPolicy>>,
+        connector: Connector,
+        policy: ConnectionPolicy,
+        stats: Arc<RuntimeStats>,
     ) -> Self {
-        let con = get_connection();
-        let to = get_timeout();
-        let lc = Arc::new(RwLock::new(latticeclient::Client::with_connection(
-            con.clone(),
-            to,
+        let (con, states) = ReconnectingTransport::start(connector, policy);
+        spawn_connection_monitor(
+            Arc::downgrade(&con),
+            states,
+            host_id.to_string(),
             ns.clone(),
-        )));
+        );
+        let to = get_timeout();
+        let con: Arc<dyn Transport> = con;
         let nc = Arc::new(RwLock::new(Some(con)));
+        let inventory = Inventory {
+            nc: nc.clone(),
+            ns: ns.clone(),
+            timeout: to,
+        };
+        let blocklist = Arc::new(RwLock::new(HashMap::new()));
+        let identities = Arc::new(HostIdentities::new(
+            nc.clone(),
+            ns.clone(),
+            identity,
+            &host_id,
+            to,
+        ));
+        if identities.enforced() {
+            spawn_identity_verifier(Arc::downgrade(&identities), inventory.clone());
+        }
+        let sealing = if seal.applies_to(ns.as_deref()) {
+            let keys = SealingKeys::from_host_seed(&host_id, &host_seed);
+            labels
+                .write()
+                .unwrap()
+                .insert(CORELABEL_SEALKEY.to_string(), keys.advertised_key());
+            if identities.enforced() {
+                info!("Lattice invocation payloads will be sealed");
+            } else {
+                error!("Lattice invocation payloads will be sealed, but host identity is not enforced, so no other host's sealing key can be verified. Configure trusted issuers to seal payloads between hosts.");
+            }
+            Some(Arc::new(keys))
+        } else {
+            None
+        };
 
         info!(
             "Initialized Lattice Message Bus ({})",
@@ -94,6 +732,7 @@ impl DistributedBus {
         spawn_controlplane_handler(
             nc.clone(),
             host_id.clone(),
+            host_seed.clone(),
             claims.clone(),
             bindings.clone(),
             caps.clone(),
@@ -102,8 +741,20 @@ impl DistributedBus {
             cplane_s,
             authz,
             image_map.clone(),
+            blocklist.clone(),
+            identities.clone(),
+            stats.clone(),
         )
         .unwrap();
+        spawn_blocklist_sync(
+            nc.clone(),
+            ns.clone(),
+            host_id.to_string(),
+            host_seed.to_string(),
+            blocklist.clone(),
+            identities.clone(),
+            to,
+        );
 
         spawn_inventory_handler(
             nc.clone(),
@@ -115,6 +766,7 @@ impl DistributedBus {
             labels,
             ns.clone(),
             image_map.clone(),
+            secrets,
         )
         .unwrap();
         DistributedBus {
@@ -123,13 +775,130 @@ impl DistributedBus {
             terminators,
             req_timeout: to,
             host_id,
-            lc,
+            inventory,
             ns: ns.clone(),
             claims,
+            bindings,
+            blocklist,
+            identities,
+            host_seed,
+            strict,
+            sealing,
+            seen: Arc::new(RwLock::new(LruCache::new(SEEN_INVOCATIONS_CAPACITY))),
+            stats,
+        }
+    }
+
+    // Sends a signed request for the unredacted configuration of a binding to the host that
+    // holds it. If payload sealing is enabled, the reply is sealed for this host
+    fn request_binding_config(
+        &self,
+        host: &str,
+        binding: &Binding,
+    ) -> Result<HashMap<String, String>> {
+        let req = BindingConfigRequest {
+            actor: binding.actor.to_string(),
+            capid: binding.capability_id.to_string(),
+            binding: binding.binding_name.to_string(),
+            sealkey: self.sealing.as_ref().map(|k| k.public_key()),
+        };
+        let subject = format!(
+            "{}.{}.{}.{}",
+            super::nsprefix(self.ns.as_ref().map(String::as_str)),
+            CPLANE_PREFIX,
+            host,
+            BINDING_CONFIG
+        );
+        let payload = sign_host_message(&self.host_seed, &serde_json::to_vec(&req).unwrap());
+        let resp = match self.nc.read().unwrap().as_ref() {
+            Some(nc) => nc.request(&subject, &payload, self.req_timeout)?,
+            None => return Err("No lattice connection".to_string().into()),
+        };
+        let data = match self.sealing {
+            Some(ref keys) => keys.open(&resp.data, self.host_id.as_bytes())?,
+            None => resp.data,
+        };
+        let config: Option<HashMap<String, String>> =
+            serde_json::from_slice(&data).map_err(|e| {
+                crate::errors::new(crate::errors::ErrorKind::Serialization(format!("{}", e)))
+            })?;
+        config.ok_or_else(|| {
+            "Host refused the request or does not hold the requested binding"
+                .to_string()
+                .into()
+        })
+    }
+
+    fn request(&self, subject: &str, inv: Invocation) -> Result<InvocationResponse> {
+        let resp = self.nc.read().unwrap().as_ref().unwrap().request(
+            &subject,
+            &serialize(inv)?,
+            self.req_timeout,
+        )?;
+        let ir: InvocationResponse = deserialize(&resp.data)?;
+        Ok(ir)
+    }
+
+    // Seals the payload for the verified hosts known to run the invocation's target. If the
+    // receiving host isn't among them, the known keys are out of date, so they're refreshed
+    // and the invocation is sent once more
+    fn invoke_sealed(
+        &self,
+        subject: &str,
+        inv: Invocation,
+        keys: &SealingKeys,
+    ) -> Result<InvocationResponse> {
+        let target = inv.target.url();
+        if keys.needs_refresh(&target) {
+            refresh_seal_keys(&self.inventory, keys, &self.identities);
         }
+        let mut sealed = inv.clone();
+        sealed.msg = seal::seal(&inv.msg, inv.id.as_bytes(), &keys.recipients(&target))?;
+        let mut ir = self.request(subject, sealed.clone())?;
+        if ir
+            .error
+            .as_ref()
+            .map_or(false, |e| e.contains(seal::NOT_A_RECIPIENT))
+        {
+            refresh_seal_keys(&self.inventory, keys, &self.identities);
+            sealed.msg = seal::seal(&inv.msg, inv.id.as_bytes(), &keys.recipients(&target))?;
+            ir = self.request(subject, sealed)?;
+        }
+        if seal::is_sealed(&ir.msg) {
+            ir.msg = keys.open(&ir.msg, inv.id.as_bytes())?;
+        } else if !ir.msg.is_empty() {
+            return Err(crate::errors::new(crate::errors::ErrorKind::MiscHost(
+                "Received an unsealed invocation response while payload sealing is enabled"
+                    .to_string(),
+            )));
+        }
+        Ok(ir)
     }
 
-    pub fn disconnect(&self) {
+    fn invocation_guard(&self) -> InvocationGuard {
+        InvocationGuard {
+            nc: self.nc.clone(),
+            ns: self.ns.clone(),
+            host_id: self.host_id.to_string(),
+            host_seed: self.host_seed.to_string(),
+            blocklist: self.blocklist.clone(),
+            identities: self.identities.clone(),
+            inventory: self.inventory.clone(),
+            sealing: self.sealing.clone(),
+            seen: self.seen.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl MessageBus for DistributedBus {
+    /// Indicates whether invocations are re-checked for tampering after middleware has run.
+    /// Invocations arriving from the lattice are always checked for forgery
+    fn validates_invocations(&self) -> bool {
+        self.strict
+    }
+
+    fn disconnect(&self) {
         // Terminate the control plane command handler
         let cpsubject = format!(
             "{}.{}.{}",
@@ -149,15 +918,14 @@ impl DistributedBus {
         }
         let _ = self.publish_event(BusEvent::HostStopped(self.host_id.to_string()));
         std::thread::sleep(Duration::from_millis(300));
-        let mut lock = self.nc.write().unwrap();
-        let conn = lock.take();
+        let conn = self.nc.write().unwrap().take();
         if let Some(nc) = conn {
             nc.close();
         }
     }
 
-    pub fn instance_count(&self, actor: &str) -> Result<usize> {
-        match self.lc.read().unwrap().get_actors() {
+    fn instance_count(&self, actor: &str) -> Result<usize> {
+        match self.inventory.actors() {
             Ok(res) => {
                 let count = res.values().into_iter().fold(0, |acc, x| {
                     acc + x
@@ -172,8 +940,8 @@ impl DistributedBus {
         }
     }
 
-    pub fn discover_claims(&self, actor: &str) -> Option<Claims<wascap::jwt::Actor>> {
-        let res = match self.lc.read().unwrap().get_actors() {
+    fn discover_claims(&self, actor: &str) -> Option<Claims<wascap::jwt::Actor>> {
+        let res = match self.inventory.actors() {
             Ok(res) => {
                 let flattened = res.values().into_iter().flatten().collect::<Vec<_>>();
                 let mut claims = flattened.into_iter().filter(|c| c.subject == actor).take(1);
@@ -191,62 +959,45 @@ impl DistributedBus {
         }
     }
 
-    pub fn query_bindings(&self) -> Result<Vec<latticeclient::Binding>> {
-        match self.lc.read().unwrap().get_bindings() {
-            Ok(r) => {
-                let v: Vec<_> = r.values().fold(vec![], |mut acc, x| {
-                    acc.extend_from_slice(x);
-                    acc
-                });
-                Ok(v)
-            }
-            Err(e) => Err(format!("Failed to query bindings from lattice : {}", e).into()),
-        }
-    }
-
-    pub fn subscribe(
+    fn subscribe(
         &self,
         subject: &str,
         sender: Sender<Invocation>,
         receiver: Receiver<InvocationResponse>,
     ) -> Result<()> {
-        let sub = self
-            .nc
-            .read()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .queue_subscribe(subject, subject)?
-            .with_handler(move |msg| {
-                handle_invocation(&msg, sender.clone(), receiver.clone());
+        let guard = self.invocation_guard();
+        let sub = self.nc.read().unwrap().as_ref().unwrap().subscribe(
+            subject,
+            Some(subject),
+            Box::new(move |msg| {
+                handle_invocation(&msg, sender.clone(), receiver.clone(), &guard);
                 Ok(())
-            });
+            }),
+        )?;
         self.subs.write().unwrap().insert(subject.to_string(), sub);
         Ok(())
     }
 
-    pub fn nqsubscribe(
+    fn nqsubscribe(
         &self,
         subject: &str,
         sender: Sender<Invocation>,
         receiver: Receiver<InvocationResponse>,
     ) -> Result<()> {
-        let sub = self
-            .nc
-            .read()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .subscribe(subject)?
-            .with_handler(move |msg| {
-                handle_invocation(&msg, sender.clone(), receiver.clone());
+        let guard = self.invocation_guard();
+        let sub = self.nc.read().unwrap().as_ref().unwrap().subscribe(
+            subject,
+            None,
+            Box::new(move |msg| {
+                handle_invocation(&msg, sender.clone(), receiver.clone(), &guard);
                 Ok(())
-            });
+            }),
+        )?;
         self.subs.write().unwrap().insert(subject.to_string(), sub);
         Ok(())
     }
 
-    pub fn invoke(&self, subject: &str, inv: Invocation) -> Result<InvocationResponse> {
+    fn invoke(&self, subject: &str, inv: Invocation) -> Result<InvocationResponse> {
         if self.nc.read().unwrap().as_ref().is_none() {
             error!(
                 "Attempted bus invoke with no bus connection: {} {:?}->{:?}",
@@ -256,24 +1007,118 @@ impl DistributedBus {
                 "Attempted a bus invocation without a live bus connection".to_string(),
             )))
         } else {
-            let resp = self.nc.read().unwrap().as_ref().unwrap().request_timeout(
-                &subject,
-                &serialize(inv)?,
-                self.req_timeout,
-            )?;
-            let ir: InvocationResponse = deserialize(&resp.data)?;
-            Ok(ir)
+            match self.sealing {
+                Some(ref keys) => self.invoke_sealed(subject, inv, keys),
+                None => self.request(subject, inv),
+            }
         }
     }
 
-    pub fn unsubscribe(&self, subject: &str) -> Result<()> {
+    fn unsubscribe(&self, subject: &str) -> Result<()> {
         if let Some(sub) = self.subs.write().unwrap().remove(subject) {
             sub.unsubscribe()?;
         }
         Ok(())
     }
 
-    pub fn publish_event(&self, event: BusEvent) -> Result<()> {
+    fn namespace(&self) -> Option<String> {
+        self.ns.clone()
+    }
+
+    fn connected(&self) -> bool {
+        self.nc
+            .read()
+            .unwrap()
+            .as_ref()
+            .map_or(false, |nc| nc.is_connected())
+    }
+}
+
+impl LatticeAdmin for DistributedBus {
+    /// Queries the bindings in the lattice. Inventory responses have secret configuration values
+    /// redacted, so the full configuration of any binding with redacted values is taken from this
+    /// host's own bindings or, if this host doesn't hold the binding, requested directly from the
+    /// host that does. Other hosts only answer that request when host identity is enforced, so
+    /// without trusted issuers such a binding can only be re-established by a host that holds it
+    fn query_bindings(&self) -> Result<Vec<latticeclient::Binding>> {
+        match self.inventory.bindings() {
+            Ok(r) => {
+                let mut v = Vec::new();
+                for (host, bindings) in r {
+                    for b in bindings {
+                        if b.configuration.values().any(|v| v == REDACTED_VALUE) {
+                            if let Some(configuration) = local_binding_config(&self.bindings, &b) {
+                                v.push(Binding { configuration, ..b });
+                            } else if !self.identities.enforced() {
+                                error!(
+                                    "Cannot re-establish binding {},{} for {}: its configuration holds secret values, which host {} only releases when host identity is enforced. Configure trusted issuers to re-establish it from this host.",
+                                    b.capability_id, b.binding_name, b.actor, host
+                                );
+                            } else {
+                                match self.request_binding_config(&host, &b) {
+                                    Ok(configuration) => v.push(Binding { configuration, ..b }),
+                                    Err(e) => error!(
+                                        "Failed to obtain configuration of binding {},{} for {} from host {}: {}",
+                                        b.capability_id, b.binding_name, b.actor, host, e
+                                    ),
+                                }
+                            }
+                        } else {
+                            v.push(b);
+                        }
+                    }
+                }
+                Ok(v)
+            }
+            Err(e) => Err(format!("Failed to query bindings from lattice : {}", e).into()),
+        }
+    }
+
+    /// Returns the hosts currently in the lattice-wide block list
+    fn blocked_hosts(&self) -> Vec<BlockedHost> {
+        self.blocklist.read().unwrap().values().cloned().collect()
+    }
+
+    /// Removes a single host from the block list of every host in the lattice. Other hosts only
+    /// accept the removal when host identity is enforced and this host's identity is verified
+    fn unblock_host(&self, host_id: &str) -> Result<()> {
+        self.blocklist.write().unwrap().remove(host_id);
+        publish_host_control(
+            &self.nc,
+            self.ns.as_ref().map(String::as_str),
+            &self.host_seed,
+            BLOCKLIST_REMOVE,
+            host_id.as_bytes(),
+        )?;
+        publish_security_event(
+            &self.nc,
+            self.ns.as_ref().map(String::as_str),
+            SecurityEvent::HostUnblocked {
+                host_id: host_id.to_string(),
+            },
+        )
+    }
+
+    /// Empties the block list of every host in the lattice. Other hosts only accept the request
+    /// when host identity is enforced and this host's identity is verified
+    fn clear_blocklist(&self) -> Result<()> {
+        self.blocklist.write().unwrap().clear();
+        publish_host_control(
+            &self.nc,
+            self.ns.as_ref().map(String::as_str),
+            &self.host_seed,
+            BLOCKLIST_CLEAR,
+            &[],
+        )?;
+        publish_security_event(
+            &self.nc,
+            self.ns.as_ref().map(String::as_str),
+            SecurityEvent::BlockListCleared,
+        )
+    }
+
+    /// Publishes a lattice event as a CloudEvent on this host's event subject
+    fn publish_event(&self, event: BusEvent) -> Result<()> {
         let cloud_event = CloudEvent::from(event);
         let payload = match serde_json::to_vec(&cloud_event) {
             Ok(p) => p,
@@ -284,46 +1129,279 @@ impl DistributedBus {
             }
         };
         let lock = self.nc.read().unwrap();
-        if let Some(ref nc) = lock.as_ref() {
-            nc.publish(&*self.event_subject(), &payload)?;
+        if let Some(nc) = lock.as_ref() {
+            nc.publish(&self.event_subject(), &payload)?;
             nc.flush()?;
         }
         Ok(())
     }
+}
+
+// Logs changes in the state of the lattice connection and publishes connection events. The
+// event for a lost connection is held back until it can be published
+fn spawn_connection_monitor(
+    nc: std::sync::Weak<ReconnectingTransport>,
+    states: Receiver<ConnectionState>,
+    host_id: String,
+    ns: Option<String>,
+) {
+    thread::spawn(move || {
+        let subject = connection_event_subject(ns.as_deref());
+        let publish = |event: &ConnectionEvent| match nc.upgrade() {
+            Some(nc) => nc
+                .publish(&subject, &serde_json::to_vec(event).unwrap())
+                .is_ok(),
+            None => false,
+        };
+        let mut lost = Instant::now();
+        let mut pending = None;
+        for state in states.iter() {
+            match state {
+                ConnectionState::Disconnected => {
+                    warn!("Host {} is disconnected from the lattice", host_id);
+                    lost = Instant::now();
+                    let event = ConnectionEvent::Disconnected {
+                        host: host_id.to_string(),
+                        disconnected_at_ms: SystemTime::now()
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_millis(),
+                    };
+                    if !publish(&event) {
+                        pending = Some(event);
+                    }
+                }
+                ConnectionState::Reconnected { resubscribed } => {
+                    if let Some(event) = pending.take() {
+                        publish(&event);
+                    }
+                    publish(&ConnectionEvent::Reconnected {
+                        host: host_id.to_string(),
+                        downtime_ms: lost.elapsed().as_millis(),
+                        resubscribed,
+                    });
+                }
+            }
+        }
+    });
+}
+
+pub(crate) fn controlplane_wildcard_subject(ns: Option<&str>) -> String {
+    format!("{}.{}.>", super::nsprefix(ns), CPLANE_PREFIX) // e.g. wasmbus.control.* or wasmbus.control.Nxxx.*
+}
+
+pub(crate) fn security_event_subject(ns: Option<&str>) -> String {
+    format!("{}.security", super::event_subject(ns))
+}
+
+pub(crate) fn connection_event_subject(ns: Option<&str>) -> String {
+    format!("{}.connection", super::event_subject(ns))
+}
 
-    pub fn actor_subject(&self, actor: &str) -> String {
-        super::actor_subject(self.ns.as_ref().map(String::as_str), actor)
+fn publish_host_control(
+    nc: &LatticeConnection,
+    ns: Option<&str>,
+    host_seed: &str,
+    suffix: &str,
+    payload: &[u8],
+) -> Result<()> {
+    let subject = format!("{}.{}.{}", super::nsprefix(ns), CPLANE_PREFIX, suffix);
+    if let Some(ref nc) = nc.read().unwrap().as_ref() {
+        nc.publish(&subject, &sign_host_message(host_seed, payload))?;
     }
+    Ok(())
+}
+
+fn sign_host_message(host_seed: &str, payload: &[u8]) -> Vec<u8> {
+    let key = KeyPair::from_seed(host_seed).unwrap();
+    let shm = SignedHostMessage {
+        host_id: key.public_key(),
+        payload: payload.to_vec(),
+        signature: key.sign(payload).unwrap(),
+    };
+    serde_json::to_vec(&shm).unwrap()
+}
+
+// Returns the unredacted configuration of a binding if this host holds it
+fn local_binding_config(
+    bindings: &Arc<RwLock<BindingsList>>,
+    binding: &Binding,
+) -> Option<HashMap<String, String>> {
+    bindings
+        .read()
+        .unwrap()
+        .get(&(
+            binding.actor.to_string(),
+            binding.capability_id.to_string(),
+            binding.binding_name.to_string(),
+        ))
+        .map(|c| c.values.clone())
+}
 
-    pub(crate) fn provider_subject(&self, capid: &str, binding: &str) -> String {
-        super::provider_subject(self.ns.as_ref().map(String::as_str), capid, binding)
+// Answers a binding configuration request from another host. Unredacted values are only
+// released to hosts whose identity has been verified against a trusted issuer, so every request
+// is refused (answered with no configuration) unless host identity is enforced. The reply is
+// sealed if the requesting host supplied a sealing key
+fn binding_config_reply(
+    data: &[u8],
+    identities: &HostIdentities,
+    bindings: &Arc<RwLock<BindingsList>>,
+) -> std::result::Result<Vec<u8>, std::io::Error> {
+    let none: Option<HashMap<String, String>> = None;
+    if !identities.enforced() {
+        warn!("Refusing binding configuration request - host identity is not enforced");
+        return Ok(serde_json::to_vec(&none)?);
     }
+    let (requester, payload) = match open_host_message(data, identities) {
+        Some(opened) => opened,
+        None => return Ok(serde_json::to_vec(&none)?),
+    };
+    let req: BindingConfigRequest = serde_json::from_slice(&payload)?;
+    info!(
+        "Host {} requested configuration of binding {},{} for {}",
+        requester, req.capid, req.binding, req.actor
+    );
+    let config = bindings
+        .read()
+        .unwrap()
+        .get(&(req.actor, req.capid, req.binding))
+        .map(|c| c.values.clone());
+    let data = serde_json::to_vec(&config)?;
+    match req.sealkey.and_then(|k| seal::decode_public_key(&k)) {
+        Some(pk) => {
+            let mut recipients = HashMap::new();
+            recipients.insert(requester.to_string(), pk);
+            seal::seal(&data, requester.as_bytes(), &recipients)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)))
+        }
+        None => Ok(data),
+    }
+}
 
-    pub(crate) fn inventory_wildcard_subject(&self) -> String {
-        super::inventory_wildcard_subject(self.ns.as_ref().map(String::as_str))
+// Verifies the signature on a host-originated control plane message and the identity of
+// the host that sent it, returning the sending host and the payload
+fn open_host_message(data: &[u8], identities: &HostIdentities) -> Option<(String, Vec<u8>)> {
+    let shm: SignedHostMessage = serde_json::from_slice(data).ok()?;
+    let sender = KeyPair::from_public_key(&shm.host_id).ok()?;
+    if sender.verify(&shm.payload, &shm.signature).is_err() {
+        warn!(
+            "Ignoring control plane message with invalid signature from {}",
+            shm.host_id
+        );
+        None
+    } else if !identities.is_trusted(&shm.host_id) {
+        warn!(
+            "Ignoring control plane message from unverified host {}",
+            shm.host_id
+        );
+        None
+    } else {
+        Some((shm.host_id, shm.payload))
     }
+}
 
-    pub(crate) fn event_subject(&self) -> String {
-        super::event_subject(self.ns.as_ref().map(String::as_str))
+// Block list removals affect every host in the lattice and carry no evidence that can be checked,
+// so they're only accepted from hosts whose identity has been verified against a trusted issuer
+fn open_admin_message(data: &[u8], identities: &HostIdentities) -> Option<(String, Vec<u8>)> {
+    if identities.enforced() {
+        open_host_message(data, identities)
+    } else {
+        warn!("Ignoring block list change from another host - host identity is not enforced");
+        None
     }
+}
 
-    pub(crate) fn provider_subject_bound_actor(
-        &self,
-        capid: &str,
-        binding: &str,
-        calling_actor: &str,
-    ) -> String {
-        super::provider_subject_bound_actor(
-            self.ns.as_ref().map(String::as_str),
-            capid,
-            binding,
-            calling_actor,
-        )
+// Adds a host reported by a peer to the block list once its evidence has been checked
+fn accept_blocked_host(blocklist: &BlockList, host_id: &str, bh: BlockedHost) -> bool {
+    if bh.host_id == host_id {
+        warn!(
+            "Host {} reported this host for: {}. Ignoring block request for self.",
+            bh.reporter, bh.reason
+        );
+        false
+    } else if !proves_equivocation(&bh.host_id, &bh.evidence) {
+        warn!(
+            "Host {} reported host {} without proof of misbehavior. Ignoring.",
+            bh.reporter, bh.host_id
+        );
+        false
+    } else if blocklist.read().unwrap().contains_key(&bh.host_id) {
+        false
+    } else {
+        warn!(
+            "Adding host {} to the lattice block list: {}",
+            bh.host_id, bh.reason
+        );
+        let mut blocklist = blocklist.write().unwrap();
+        blocklist.insert(bh.host_id.to_string(), bh);
+        true
     }
 }
 
-pub(crate) fn controlplane_wildcard_subject(ns: Option<&str>) -> String {
-    format!("{}.{}.>", super::nsprefix(ns), CPLANE_PREFIX) // e.g. wasmbus.control.* or wasmbus.control.Nxxx.*
+// Requests the block lists of the hosts already in the lattice, so that a host that joins late
+// doesn't accept invocations from hosts its peers have blocked. Runs in the background because
+// replies are gathered until the request times out
+fn spawn_blocklist_sync(
+    nc: LatticeConnection,
+    ns: Option<String>,
+    host_id: String,
+    host_seed: String,
+    blocklist: BlockList,
+    identities: Arc<HostIdentities>,
+    timeout: Duration,
+) {
+    thread::spawn(move || {
+        let subject = format!(
+            "{}.{}.{}",
+            super::nsprefix(ns.as_deref()),
+            CPLANE_PREFIX,
+            BLOCKLIST_SYNC
+        );
+        let conn = nc.read().unwrap().clone();
+        let replies = match conn {
+            Some(nc) => nc.request_all(&subject, &sign_host_message(&host_seed, &[]), timeout),
+            None => return,
+        };
+        let replies = match replies {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Failed to synchronize the lattice block list: {}", e);
+                return;
+            }
+        };
+        let mut added = 0;
+        for reply in replies {
+            match open_host_message(&reply.data, &identities) {
+                Some((sender, payload)) if sender != host_id => {
+                    let entries: Vec<BlockedHost> =
+                        serde_json::from_slice(&payload).unwrap_or_default();
+                    for bh in entries {
+                        if accept_blocked_host(&blocklist, &host_id, bh) {
+                            added += 1;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if added > 0 {
+            info!("Synchronized {} block list entries from the lattice", added);
+        }
+    });
+}
+
+fn publish_security_event(
+    nc: &LatticeConnection,
+    ns: Option<&str>,
+    event: SecurityEvent,
+) -> Result<()> {
+    let payload = serde_json::to_vec(&event).map_err(|e| {
+        crate::errors::new(crate::errors::ErrorKind::Serialization(format!("{}", e)))
+    })?;
+    if let Some(ref nc) = nc.read().unwrap().as_ref() {
+        nc.publish(&security_event_subject(ns), &payload)?;
+    }
+    Ok(())
 }
 
 pub(crate) fn spawn_controlplane(
@@ -346,10 +1424,12 @@ pub(crate) fn spawn_controlplane(
     let auth = host.authorizer.clone();
     let image_map = host.image_map.clone();
     let labels = host.labels.clone();
+    let stats = host.stats.clone();
+    let lattice = host.lattice.clone();
 
     let subject = format!(
         "{}.{}.{}",
-        super::nsprefix(bus.ns.as_ref().map(String::as_str)),
+        super::nsprefix(bus.namespace().as_deref()),
         latticeclient::controlplane::CPLANE_PREFIX,
         hk.public_key()
     );
@@ -399,7 +1479,7 @@ pub(crate) fn spawn_controlplane(
                                     let _ = crate::spawns::spawn_actor(wg, a.token.claims.clone(), a.bytes,
                                         None, actor, binding.clone(), bus.clone(), mids.clone(),
                                         caps.clone(), bindings.clone(), claimsmap.clone(), terminators.clone(),
-                                        key, auth.clone(), image_map.clone(), Some(cmd.actor_id.to_string()));
+                                        key, auth.clone(), image_map.clone(), Some(cmd.actor_id.to_string()), stats.clone(), lattice.clone());
 
 
                                 },
@@ -431,7 +1511,7 @@ pub(crate) fn spawn_controlplane(
                                 info!("Acknowledged provider start request.");
                             }
                             match crate::inthost::fetch_provider(&cmd.provider_ref, &cmd.binding_name, labels.clone()) {
-                                Ok((p, c)) => {
+                                Ok(p) => {
                                     if caps
                                        .read()
                                        .unwrap()
@@ -456,6 +1536,9 @@ pub(crate) fn spawn_controlplane(
                                         plugins.clone(),
                                         wg.clone(),
                                         Arc::new(key),
+                                        auth.clone(),
+                                        stats.clone(),
+                                        lattice.clone(),
                                     );
                                     wg.wait();
                                 },
@@ -479,8 +1562,9 @@ pub(crate) fn spawn_controlplane(
 // This thread handles control plane commands or demands, e.g. "launch actor" and "launch provider"
 // It also responds to provider and actor auctions
 fn spawn_controlplane_handler(
-    nc: Arc<RwLock<Option<nats::Connection>>>,
+    nc: LatticeConnection,
     host_id: String,
+    host_seed: String,
     claims: Arc<RwLock<HashMap<String, Claims<Actor>>>>,
     bindings: Arc<RwLock<BindingsList>>,
     caps: Arc<RwLock<HashMap<RouteKey, CapabilityDescriptor>>>,
@@ -489,6 +1573,9 @@ fn spawn_controlplane_handler(
     cplane_s: Sender<ControlCommand>,
     authz: Arc<RwLock<Box<dyn crate::authz::Authorizer>>>,
     image_map: Arc<RwLock<HashMap<String, String>>>,
+    blocklist: BlockList,
+    identities: Arc<HostIdentities>,
+    stats: Arc<RuntimeStats>,
 ) -> Result<()> {
     let subject = controlplane_wildcard_subject(ns.as_ref().map(String::as_str));
     let lbs = labels.clone();
@@ -498,9 +1585,41 @@ fn spawn_controlplane_handler(
         .unwrap()
         .as_ref()
         .unwrap()
-        .subscribe(&subject)?
-        .with_handler(move |msg| {
-            if msg.subject.ends_with(LAUNCH_ACTOR) && msg.subject.contains(&host_id) {
+        .subscribe(&subject, None, Box::new(move |msg| {
+            if msg.subject.ends_with(HOST_IDENTITY) && msg.subject.contains(&host_id) {
+                let token = identities.config.token.clone().unwrap_or_default();
+                let _ = msg.respond(token.as_bytes());
+            } else if msg.subject.ends_with(BLOCKLIST_ADD) {
+                if let Some((sender, payload)) = open_host_message(&msg.data, &identities) {
+                    let bh: BlockedHost = serde_json::from_slice(&payload)?;
+                    if bh.reporter != sender {
+                        warn!("Host {} attempted to submit a block list entry on behalf of {}. Ignoring.", sender, bh.reporter);
+                    } else {
+                        accept_blocked_host(&blocklist, &host_id, bh);
+                    }
+                }
+            } else if msg.subject.ends_with(BLOCKLIST_REMOVE) {
+                if let Some((_sender, payload)) = open_admin_message(&msg.data, &identities) {
+                    let blocked = String::from_utf8_lossy(&payload).to_string();
+                    if blocklist.write().unwrap().remove(&blocked).is_some() {
+                        info!("Removed host {} from the lattice block list", blocked);
+                    }
+                }
+            } else if msg.subject.ends_with(BLOCKLIST_CLEAR) {
+                if open_admin_message(&msg.data, &identities).is_some() {
+                    info!("Clearing the lattice block list");
+                    blocklist.write().unwrap().clear();
+                }
+            } else if msg.subject.ends_with(BLOCKLIST_SYNC) {
+                if open_host_message(&msg.data, &identities).is_some() {
+                    let blocked: Vec<BlockedHost> = blocklist.read().unwrap().values().cloned().collect();
+                    let _ = msg.respond(sign_host_message(&host_seed, &serde_json::to_vec(&blocked)?));
+                }
+            } else if !identities.participating() {
+                trace!("Ignoring control plane message - this host has no valid identity token");
+            } else if msg.subject.ends_with(BINDING_CONFIG) && msg.subject.contains(&host_id) {
+                msg.respond(binding_config_reply(&msg.data, &identities, &bindings)?)?;
+            } else if msg.subject.ends_with(LAUNCH_ACTOR) && msg.subject.contains(&host_id) {
                 // schedule the actor
                 let lc: LaunchCommand = serde_json::from_slice(&msg.data).unwrap();
                 cplane_s.send(ControlCommand::StartActor(lc, msg)).unwrap();
@@ -527,10 +1646,13 @@ fn spawn_controlplane_handler(
                 let req: ProviderAuctionRequest = serde_json::from_slice(&msg.data)?;
                 if image_map.read().unwrap().contains_key(&req.provider_ref) {
                     trace!("Skipping provider auction response - provider is in local image map");
+                    stats.auction_request(false);
                 } else {
                     if !host_satifies_constraints(labels.clone(), &req.constraints) {
                         trace!("Skipping provider auction response - host does not satisfy constraints.");
+                        stats.auction_request(false);
                     } else {
+                        stats.auction_request(true);
                         let ar = ProviderAuctionResponse {
                           provider_ref: req.provider_ref.to_string(),
                             host_id: host_id.to_string(),
@@ -543,10 +1665,13 @@ fn spawn_controlplane_handler(
                 let req: LaunchAuctionRequest = serde_json::from_slice(&msg.data)?;
                 if image_map.read().unwrap().contains_key(&req.actor_id) {
                     trace!("Skipping auction response - actor already running locally.");
+                    stats.auction_request(false);
                 } else {
                     if !host_satifies_constraints(labels.clone(), &req.constraints) {
                         trace!("Skipping auction response - host does not satisfy constraints.");
+                        stats.auction_request(false);
                     } else {
+                        stats.auction_request(true);
                         let ar = LaunchAuctionResponse {
                             host_id: host_id.to_string(),
                         };
@@ -556,7 +1681,7 @@ fn spawn_controlplane_handler(
                 }
             }
             Ok(())
-        });
+        }))?;
     Ok(())
 }
 
@@ -578,7 +1703,7 @@ fn host_satifies_constraints(
 }
 
 fn spawn_inventory_handler(
-    nc: Arc<RwLock<Option<nats::Connection>>>,
+    nc: LatticeConnection,
     host_id: String,
     claims: Arc<RwLock<HashMap<String, Claims<Actor>>>>,
     bindings: Arc<RwLock<BindingsList>>,
@@ -587,6 +1712,7 @@ fn spawn_inventory_handler(
     labels: Arc<RwLock<HashMap<String, String>>>,
     ns: Option<String>,
     image_map: Arc<RwLock<HashMap<String, String>>>,
+    secrets: Arc<RwLock<SecretPolicy>>,
 ) -> Result<()> {
     let lbs = labels.clone();
     let subject = super::inventory_wildcard_subject(ns.as_ref().map(String::as_str));
@@ -596,15 +1722,14 @@ fn spawn_inventory_handler(
         .unwrap()
         .as_ref()
         .unwrap()
-        .subscribe(&subject)?
-        .with_handler(move |msg| {
+        .subscribe(&subject, None, Box::new(move |msg| {
             trace!("Handling Inventory Request");
             if msg.subject.contains(INVENTORY_HOSTS) {
                 respond_with_host(msg, host_id.to_string(), started, lbs.clone())
             } else if msg.subject.contains(INVENTORY_ACTORS) {
                 respond_with_actors(msg, host_id.to_string(), claims.clone())
             } else if msg.subject.contains(INVENTORY_BINDINGS) {
-                respond_with_bindings(msg, host_id.to_string(), bindings.clone())
+                respond_with_bindings(msg, host_id.to_string(), bindings.clone(), &secrets)
             } else if msg.subject.contains(INVENTORY_CAPABILITIES) {
                 respond_with_caps(msg, host_id.to_string(), caps.clone())
             } else {
@@ -613,12 +1738,12 @@ fn spawn_inventory_handler(
                     "Bad inventory topic!",
                 ))
             }
-        });
+        }))?;
     Ok(())
 }
 
 fn respond_with_host(
-    msg: nats::Message,
+    msg: Message,
     host_id: String,
     started: SystemTime,
     labels: Arc<RwLock<HashMap<String, String>>>,
@@ -632,7 +1757,7 @@ fn respond_with_host(
 }
 
 fn respond_with_actors(
-    msg: nats::Message,
+    msg: Message,
     host: String,
     claims: Arc<RwLock<HashMap<String, Claims<Actor>>>>,
 ) -> std::result::Result<(), std::io::Error> {
@@ -649,18 +1774,20 @@ fn respond_with_actors(
 }
 
 fn respond_with_bindings(
-    msg: nats::Message,
+    msg: Message,
     host: String,
     bindings: Arc<RwLock<BindingsList>>,
+    secrets: &Arc<RwLock<SecretPolicy>>,
 ) -> std::result::Result<(), std::io::Error> {
     let mut items = Vec::<Binding>::new();
     let lock = bindings.read().unwrap();
+    let secrets = secrets.read().unwrap();
     for (k, v) in lock.iter() {
         items.push(Binding {
             actor: k.0.to_string(),
             capability_id: k.1.to_string(),
             binding_name: k.2.to_string(),
-            configuration: v.values.clone(),
+            configuration: secrets.redact(k, &v.values),
         });
     }
     let ir = InventoryResponse::Bindings {
@@ -672,7 +1799,7 @@ fn respond_with_bindings(
 }
 
 fn respond_with_caps(
-    msg: nats::Message,
+    msg: Message,
     host: String,
     caps: Arc<RwLock<HashMap<RouteKey, CapabilityDescriptor>>>,
 ) -> std::result::Result<(), std::io::Error> {
@@ -691,31 +1818,377 @@ fn respond_with_caps(
         .map_err(|e| e.into())
 }
 
+// Gathers inventory from every host in the namespace by sending an inventory request and
+// collecting the replies that arrive within the RPC timeout
+#[derive(Clone)]
+struct Inventory {
+    nc: LatticeConnection,
+    ns: Option<String>,
+    timeout: Duration,
+}
+
+impl Inventory {
+    fn query(&self, kind: &str) -> Result<Vec<InventoryResponse>> {
+        let subject = format!("{}.{}", super::nsprefix(self.ns.as_deref()), kind);
+        // Don't hold the lock while waiting on replies, which would block disconnection
+        let nc = self.nc.read().unwrap().clone();
+        let replies = match nc {
+            Some(nc) => nc.request_all(&subject, &[], self.timeout)?,
+            None => return Err("No lattice connection".to_string().into()),
+        };
+        Ok(replies
+            .iter()
+            .filter_map(|m| serde_json::from_slice(&m.data).ok())
+            .collect())
+    }
+
+    fn hosts(&self) -> Result<Vec<HostProfile>> {
+        Ok(self
+            .query(INVENTORY_HOSTS)?
+            .into_iter()
+            .filter_map(|ir| match ir {
+                InventoryResponse::Host(hp) => Some(hp),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn actors(&self) -> Result<HashMap<String, Vec<Claims<Actor>>>> {
+        Ok(self
+            .query(INVENTORY_ACTORS)?
+            .into_iter()
+            .filter_map(|ir| match ir {
+                InventoryResponse::Actors { host, actors } => Some((host, actors)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn bindings(&self) -> Result<HashMap<String, Vec<Binding>>> {
+        Ok(self
+            .query(INVENTORY_BINDINGS)?
+            .into_iter()
+            .filter_map(|ir| match ir {
+                InventoryResponse::Bindings { host, bindings } => Some((host, bindings)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn capabilities(&self) -> Result<HashMap<String, Vec<HostedCapability>>> {
+        Ok(self
+            .query(INVENTORY_CAPABILITIES)?
+            .into_iter()
+            .filter_map(|ir| match ir {
+                InventoryResponse::Capabilities { host, capabilities } => {
+                    Some((host, capabilities))
+                }
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+// The state needed by an invocation subscription to enforce (and contribute to)
+// the lattice-wide block list
+struct InvocationGuard {
+    nc: LatticeConnection,
+    ns: Option<String>,
+    host_id: String,
+    host_seed: String,
+    blocklist: BlockList,
+    identities: Arc<HostIdentities>,
+    inventory: Inventory,
+    sealing: Option<Arc<SealingKeys>>,
+    seen: SeenInvocations,
+    stats: Arc<RuntimeStats>,
+}
+
+impl InvocationGuard {
+    fn is_blocked(&self, host_id: &str) -> bool {
+        self.blocklist.read().unwrap().contains_key(host_id)
+    }
+
+    // Opens a sealed invocation payload. Hosts with sealing enabled refuse unsealed payloads,
+    // and hosts without it refuse sealed ones rather than treating them as forgeries
+    fn unseal(&self, inv: Invocation) -> std::result::Result<Invocation, (Invocation, String)> {
+        let sealed = seal::is_sealed(&inv.msg);
+        match self.sealing {
+            Some(ref keys) if sealed => match keys.open(&inv.msg, inv.id.as_bytes()) {
+                Ok(msg) => Ok(Invocation { msg, ..inv }),
+                Err(e) => Err((inv, format!("{}", e))),
+            },
+            Some(_) => Err((
+                inv,
+                "Unsealed invocation payloads are not accepted in this namespace".to_string(),
+            )),
+            None if sealed => Err((
+                inv,
+                "This host does not have payload sealing enabled".to_string(),
+            )),
+            None => Ok(inv),
+        }
+    }
+
+    // Seals a response payload so that only the host that sent the invocation can read it
+    fn seal_response(&self, origin_host: &str, mut resp: InvocationResponse) -> InvocationResponse {
+        let keys = match self.sealing {
+            Some(ref keys) if !resp.msg.is_empty() => keys,
+            _ => return resp,
+        };
+        let pk = keys.peer(origin_host).or_else(|| {
+            refresh_seal_keys(&self.inventory, keys, &self.identities);
+            keys.peer(origin_host)
+        });
+        let mut recipients = HashMap::new();
+        if let Some(pk) = pk {
+            recipients.insert(origin_host.to_string(), pk);
+        }
+        match seal::seal(&resp.msg, resp.invocation_id.as_bytes(), &recipients) {
+            Ok(msg) => resp.msg = msg,
+            Err(e) => {
+                resp.msg = vec![];
+                resp.error = Some(format!("Failed to seal response: {}", e));
+            }
+        }
+        resp
+    }
+
+    // Publishes the antiforgery failure. Expired, tampered or replayed invocations can all be
+    // produced by anyone who has seen a valid invocation, so the origin host is never blocked
+    // because of them
+    fn report_antiforgery_failure(&self, inv: &Invocation, reason: &str) {
+        self.stats.antiforgery_failure();
+        let _ = publish_security_event(
+            &self.nc,
+            self.ns.as_ref().map(String::as_str),
+            SecurityEvent::AntiforgeryCheckFailed {
+                host: self.host_id.to_string(),
+                origin_host: inv.host_id.to_string(),
+                invocation_id: inv.id.to_string(),
+                reason: reason.to_string(),
+            },
+        );
+    }
+
+    // Remembers the claims of every invocation signed by its origin host. Returns the evidence
+    // of misbehavior if that host has already signed different claims for the same invocation
+    fn check_equivocation(&self, inv: &Invocation) -> Option<Vec<String>> {
+        if !is_signed_by(&inv.encoded_claims, &inv.host_id) {
+            return None;
+        }
+        let key = format!("{}.{}", inv.host_id, inv.id);
+        let mut seen = self.seen.write().unwrap();
+        match seen.get(&key).cloned() {
+            Some(prev) => {
+                let evidence = vec![prev, inv.encoded_claims.to_string()];
+                if proves_equivocation(&inv.host_id, &evidence) {
+                    Some(evidence)
+                } else {
+                    None
+                }
+            }
+            None => {
+                seen.insert(key, inv.encoded_claims.to_string());
+                None
+            }
+        }
+    }
+
+    // Adds the origin host of an invocation to the block list of every host in the lattice
+    fn block_origin_host(&self, inv: &Invocation, evidence: Vec<String>) {
+        if inv.host_id == self.host_id {
+            return;
+        }
+        let ns = self.ns.as_ref().map(String::as_str);
+        let bh = BlockedHost {
+            host_id: inv.host_id.to_string(),
+            reporter: self.host_id.to_string(),
+            reason: format!("Signed contradictory claims for invocation {}", inv.id),
+            evidence,
+        };
+        self.blocklist
+            .write()
+            .unwrap()
+            .insert(bh.host_id.to_string(), bh.clone());
+        let _ = publish_host_control(
+            &self.nc,
+            ns,
+            &self.host_seed,
+            BLOCKLIST_ADD,
+            &serde_json::to_vec(&bh).unwrap(),
+        );
+        let _ = publish_security_event(&self.nc, ns, SecurityEvent::HostBlocked(bh));
+    }
+}
+
+// Whether the token is a well-formed invocation claims token issued by, and validly signed with
+// the key of, the given host. Expiry is not considered
+fn is_signed_by(token: &str, host_id: &str) -> bool {
+    if !host_id.starts_with('N') || token.split('.').count() != 3 {
+        return false;
+    }
+    match wascap::jwt::validate_token::<wascap::prelude::Invocation>(token) {
+        Ok(vr) => {
+            vr.signature_valid
+                && Claims::<wascap::prelude::Invocation>::decode(token)
+                    .map(|c| c.issuer == host_id)
+                    .unwrap_or(false)
+        }
+        Err(_) => false,
+    }
+}
+
+// Proof that a host misbehaved: two claims tokens, both signed by the host, that give the same
+// invocation a different target, origin or hash. Relays can tamper with, delay or replay an
+// invocation, but they can't produce a second validly signed token, so nobody but the host
+// itself can be the source of such evidence
+fn proves_equivocation(host_id: &str, evidence: &[String]) -> bool {
+    let claims: Vec<_> = evidence
+        .iter()
+        .filter(|t| is_signed_by(t, host_id))
+        .filter_map(|t| Claims::<wascap::prelude::Invocation>::decode(t).ok())
+        .collect();
+    match claims.as_slice() {
+        [a, b] => {
+            a.subject == b.subject
+                && match (&a.metadata, &b.metadata) {
+                    (Some(x), Some(y)) => {
+                        x.invocation_hash != y.invocation_hash
+                            || x.target_url != y.target_url
+                            || x.origin_url != y.origin_url
+                    }
+                    _ => false,
+                }
+        }
+        _ => false,
+    }
+}
+
 // This function is invoked any time an invocation is _received_ by the message bus
 fn handle_invocation(
-    msg: &nats::Message,
+    msg: &Message,
     sender: Sender<Invocation>,
     receiver: Receiver<InvocationResponse>,
+    guard: &InvocationGuard,
 ) {
     let inv = invocation_from_msg(msg);
-    //TODO: when we implement the issue, check that the invocation's origin host is not in the block list
-    if let Err(e) = inv.validate_antiforgery() {
-        error!("Invocation Antiforgery check failure: {}", e);
-        let inv_r = InvocationResponse::error(&inv, &format!("Antiforgery check failure: {}", e));
+    if guard.is_blocked(&inv.host_id) {
+        warn!(
+            "Rejecting invocation {} from blocked host {}",
+            inv.id, inv.host_id
+        );
+        let inv_r = InvocationResponse::error(
+            &inv,
+            &format!("Origin host {} is in the lattice block list", inv.host_id),
+        );
         msg.respond(serialize(inv_r).unwrap()).unwrap();
-    // TODO: when we implement the issue, publish an antiforgery check event on wasmbus.events
-    // TODO: when we implement the issue, add the host origin of the invocation to the global lattice block list
     } else {
-        if let Ok(()) = sender.send(inv) {
-            let inv_r = receiver.recv().unwrap();
+        let inv = match guard.unseal(inv) {
+            Ok(inv) => inv,
+            Err((inv, e)) => {
+                warn!("Rejecting invocation {}: {}", inv.id, e);
+                let inv_r = InvocationResponse::error(&inv, &e);
+                msg.respond(serialize(inv_r).unwrap()).unwrap();
+                return;
+            }
+        };
+        // The local checks run first, since verifying the origin host's identity may require a
+        // network request
+        if let Some(evidence) = guard.check_equivocation(&inv) {
+            error!(
+                "Host {} signed contradictory claims for invocation {}",
+                inv.host_id, inv.id
+            );
+            let inv_r = InvocationResponse::error(
+                &inv,
+                "Antiforgery check failure: contradictory invocation claims",
+            );
+            msg.respond(serialize(inv_r).unwrap()).unwrap();
+            guard.block_origin_host(&inv, evidence);
+        } else if let Err(e) = inv.validate_antiforgery() {
+            error!("Invocation Antiforgery check failure: {}", e);
+            let inv_r =
+                InvocationResponse::error(&inv, &format!("Antiforgery check failure: {}", e));
+            msg.respond(serialize(inv_r).unwrap()).unwrap();
+            guard.report_antiforgery_failure(&inv, &format!("{}", e));
+        } else if !guard.identities.is_trusted(&inv.host_id) {
+            warn!(
+                "Rejecting invocation {} from host {} without a valid identity token",
+                inv.id, inv.host_id
+            );
+            let inv_r = InvocationResponse::error(
+                &inv,
+                &format!(
+                    "Origin host {} has no valid host identity token",
+                    inv.host_id
+                ),
+            );
             msg.respond(serialize(inv_r).unwrap()).unwrap();
         } else {
-            warn!("Received invocation but its destination thread is no longer running.");
+            let origin_host = inv.host_id.to_string();
+            if let Ok(()) = sender.send(inv) {
+                let inv_r = guard.seal_response(&origin_host, receiver.recv().unwrap());
+                msg.respond(serialize(inv_r).unwrap()).unwrap();
+            } else {
+                warn!("Received invocation but its destination thread is no longer running.");
+            }
+        }
+    }
+}
+
+// Replaces the cached sealing keys with those advertised in the inventory profiles of the
+// hosts in the namespace. A key is only accepted if it's signed by the host that advertises it
+// and that host's identity token has been verified against a trusted issuer, so a rogue
+// inventory responder can't receive the content keys of sealed payloads
+fn refresh_seal_keys(inventory: &Inventory, keys: &SealingKeys, identities: &HostIdentities) {
+    let hosts = match inventory.hosts() {
+        Ok(hosts) => hosts,
+        Err(e) => {
+            warn!("Failed to query host inventory for sealing keys: {}", e);
+            return;
         }
+    };
+    let peers = hosts
+        .iter()
+        .filter_map(|hp| {
+            let pk = hp
+                .labels
+                .get(CORELABEL_SEALKEY)
+                .and_then(|k| seal::verify_advertised_key(&hp.id, k));
+            match pk {
+                Some(pk) if identities.enforced() && identities.verified(&hp.id) == Some(true) => {
+                    Some((hp.id.to_string(), pk))
+                }
+                Some(_) => {
+                    trace!("Ignoring sealing key of unverified host {}", hp.id);
+                    None
+                }
+                None => None,
+            }
+        })
+        .collect();
+    let mut hosted: HashMap<String, HashSet<String>> = HashMap::new();
+    for (host, actors) in inventory.actors().unwrap_or_default() {
+        hosted.entry(host).or_default().extend(
+            actors
+                .iter()
+                .map(|c| WasccEntity::Actor(c.subject.to_string()).url()),
+        );
     }
+    for (host, caps) in inventory.capabilities().unwrap_or_default() {
+        hosted.entry(host).or_default().extend(caps.iter().map(|c| {
+            WasccEntity::Capability {
+                capid: c.descriptor.id.to_string(),
+                binding: c.binding_name.to_string(),
+            }
+            .url()
+        }));
+    }
+    keys.update_peers(peers, hosted);
 }
 
-fn invocation_from_msg(msg: &nats::Message) -> Invocation {
+fn invocation_from_msg(msg: &Message) -> Invocation {
     let i: Invocation = deserialize(&msg.data).unwrap();
     i
 }
@@ -724,6 +2197,10 @@ fn get_credsfile() -> Option<String> {
     std::env::var(LATTICE_CREDSFILE_KEY).ok()
 }
 
+fn get_nonempty_env(var: &str) -> Option<String> {
+    std::env::var(var).ok().filter(|v| !v.is_empty())
+}
+
 fn get_env(var: &str, default: &str) -> String {
     match std::env::var(var) {
         Ok(val) => {
@@ -737,16 +2214,27 @@ fn get_env(var: &str, default: &str) -> String {
     }
 }
 
-fn get_connection() -> nats::Connection {
-    let host = get_env(LATTICE_HOST_KEY, DEFAULT_LATTICE_HOST);
-    info!("Lattice Host: {}", host);
-    let mut opts = if let Some(creds) = get_credsfile() {
-        nats::Options::with_credentials(creds)
-    } else {
-        nats::Options::new()
-    };
-    opts = opts.with_name("waSCC Lattice");
-    opts.connect(&host).unwrap()
+/// Creates a connector for the NATS servers described by the options. When more than one
+/// server is given, the client fails over between them
+pub(crate) fn nats_connector(options: NatsOptions) -> Connector {
+    Box::new(move |on_disconnect| get_connection(&options, on_disconnect))
+}
+
+// Reconnection is handled by the host, which re-establishes its subscriptions on a fresh
+// connection, so the client's own reconnect logic is disabled
+fn get_connection(
+    options: &NatsOptions,
+    on_disconnect: DisconnectCallback,
+) -> std::io::Result<Arc<dyn Transport>> {
+    let servers = options.servers.join(",");
+    info!("Lattice Host: {}", servers);
+    let opts = options
+        .to_nats()?
+        .with_name("waSCC Lattice")
+        .max_reconnects(0)
+        .disconnect_callback(move || on_disconnect());
+    let nc = opts.connect(&servers)?;
+    Ok(Arc::new(NatsTransport::new(nc)))
 }
 
 fn get_timeout() -> Duration {
@@ -761,3 +2249,243 @@ fn get_timeout() -> Duration {
         Err(_) => Duration::from_millis(DEFAULT_LATTICE_RPC_TIMEOUT_MILLIS),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        accept_blocked_host, binding_config_reply, local_binding_config, proves_equivocation,
+        sign_host_message, BindingConfigRequest, BlockedHost, ConnectionPolicy, HostIdentities,
+        IdentityConfig, LatticeAuth, VerificationRequests, MAX_PENDING_VERIFICATIONS,
+        MAX_VERIFICATIONS_PER_SEC,
+    };
+    use crate::inthost::InvocationSigner;
+    use crate::secrets::REDACTED_VALUE;
+    use latticeclient::Binding;
+    use crate::{Invocation, WasccEntity};
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+    use std::time::Duration;
+    use wascap::prelude::KeyPair;
+    use wascc_codec::core::CapabilityConfiguration;
+
+    #[test]
+    fn limits_identity_verification_requests() {
+        let mut requests = VerificationRequests::new();
+        assert!(requests.queue("H0"));
+        assert!(requests.queue("H0"));
+        for i in 1..MAX_PENDING_VERIFICATIONS {
+            assert!(requests.queue(&format!("H{}", i)));
+        }
+        assert!(!requests.queue("Hx"));
+        assert!(requests.queue("H1"));
+
+        // Requests over the rate limit wait for the next window rather than being refused
+        let mut requests = VerificationRequests::new();
+        for _ in 0..MAX_VERIFICATIONS_PER_SEC {
+            assert!(requests.throttle().is_none());
+        }
+        let wait = requests.throttle().unwrap();
+        assert!(wait > Duration::from_secs(0) && wait <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn unverified_hosts_wait_for_background_verification() {
+        let identities = Arc::new(HostIdentities::new(
+            Arc::new(RwLock::new(None)),
+            None,
+            IdentityConfig {
+                token: None,
+                trusted_issuers: vec![KeyPair::new_operator().public_key()],
+            },
+            &KeyPair::new_server().public_key(),
+            Duration::from_secs(5),
+        ));
+        let peer = KeyPair::new_server().public_key();
+        assert_eq!(None, identities.verified(&peer));
+
+        let verifier = identities.clone();
+        let handle = std::thread::spawn(move || {
+            let host_id = verifier.next_pending(Duration::from_secs(1)).unwrap();
+            verifier.complete(&host_id, true);
+        });
+        assert!(identities.is_trusted(&peer));
+        assert_eq!(Some(true), identities.verified(&peer));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let policy = ConnectionPolicy {
+            connect_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+        };
+        assert_eq!(Duration::from_millis(100), policy.backoff(0));
+        assert_eq!(Duration::from_millis(400), policy.backoff(2));
+        assert_eq!(Duration::from_millis(1000), policy.backoff(4));
+        assert_eq!(Duration::from_millis(1000), policy.backoff(u32::MAX));
+
+        let huge = ConnectionPolicy {
+            connect_retries: 3,
+            initial_backoff: Duration::from_secs(u64::MAX / 2),
+            max_backoff: Duration::from_secs(60),
+        };
+        assert_eq!(Duration::from_secs(60), huge.backoff(20));
+    }
+
+    #[test]
+    fn auth_debug_omits_secrets() {
+        let auth = LatticeAuth::UserPassword {
+            user: "host".to_string(),
+            password: "hunter2".to_string(),
+        };
+        assert_eq!("UserPassword(host, ..)", format!("{:?}", auth));
+        let auth = LatticeAuth::Token("s3cr3t".to_string());
+        assert!(!format!("{:?}", auth).contains("s3cr3t"));
+    }
+
+    #[test]
+    fn unverified_hosts_get_no_binding_config() {
+        let mut values = HashMap::new();
+        values.insert("PASSWORD".to_string(), "hunter2".to_string());
+        let mut bindings = HashMap::new();
+        bindings.insert(
+            (
+                "Mactor".to_string(),
+                "wascc:keyvalue".to_string(),
+                "default".to_string(),
+            ),
+            CapabilityConfiguration {
+                module: "Mactor".to_string(),
+                values,
+            },
+        );
+        let bindings = Arc::new(RwLock::new(bindings));
+        let req = BindingConfigRequest {
+            actor: "Mactor".to_string(),
+            capid: "wascc:keyvalue".to_string(),
+            binding: "default".to_string(),
+            sealkey: None,
+        };
+        let requester = KeyPair::new_server();
+        let data = sign_host_message(
+            &requester.seed().unwrap(),
+            &serde_json::to_vec(&req).unwrap(),
+        );
+        let host = KeyPair::new_server();
+        let trusted_issuer = KeyPair::new_operator();
+
+        // Neither without host identity enforcement nor for a requester whose identity
+        // can't be verified are any values released
+        for config in vec![
+            IdentityConfig::default(),
+            IdentityConfig {
+                token: None,
+                trusted_issuers: vec![trusted_issuer.public_key()],
+            },
+        ] {
+            let identities = HostIdentities::new(
+                Arc::new(RwLock::new(None)),
+                None,
+                config,
+                &host.public_key(),
+                Duration::from_millis(50),
+            );
+            let reply = binding_config_reply(&data, &identities, &bindings).unwrap();
+            let config: Option<HashMap<String, String>> = serde_json::from_slice(&reply).unwrap();
+            assert!(config.is_none());
+        }
+    }
+
+    #[test]
+    fn redacted_bindings_use_local_config() {
+        let mut values = HashMap::new();
+        values.insert("PASSWORD".to_string(), "hunter2".to_string());
+        let mut bindings = HashMap::new();
+        bindings.insert(
+            (
+                "Mactor".to_string(),
+                "wascc:keyvalue".to_string(),
+                "default".to_string(),
+            ),
+            CapabilityConfiguration {
+                module: "Mactor".to_string(),
+                values: values.clone(),
+            },
+        );
+        let bindings = Arc::new(RwLock::new(bindings));
+        let mut redacted = HashMap::new();
+        redacted.insert("PASSWORD".to_string(), REDACTED_VALUE.to_string());
+        let binding = Binding {
+            actor: "Mactor".to_string(),
+            capability_id: "wascc:keyvalue".to_string(),
+            binding_name: "default".to_string(),
+            configuration: redacted,
+        };
+
+        assert_eq!(Some(values), local_binding_config(&bindings, &binding));
+        let other = Binding {
+            binding_name: "other".to_string(),
+            ..binding
+        };
+        assert_eq!(None, local_binding_config(&bindings, &other));
+    }
+
+    #[test]
+    fn blocks_only_on_contradictory_claims() {
+        let host = KeyPair::new_server();
+        let inv = Invocation::new(
+            &host,
+            WasccEntity::Actor("Mactor".to_string()),
+            WasccEntity::Capability {
+                capid: "wascc:keyvalue".to_string(),
+                binding: "default".to_string(),
+            },
+            "Get",
+            b"key".to_vec(),
+        );
+
+        // Tampering with or replaying a signed invocation leaves its claims untouched
+        let tampered = Invocation {
+            msg: b"other".to_vec(),
+            ..inv.clone()
+        };
+        let evidence = vec![
+            inv.encoded_claims.to_string(),
+            tampered.encoded_claims.to_string(),
+        ];
+        assert!(!proves_equivocation(&host.public_key(), &evidence));
+
+        // Claims signed by someone else don't implicate the host
+        let other = KeyPair::new_server();
+        let forged = InvocationSigner::new(&other.seed().unwrap()).sign(tampered.clone());
+        let evidence = vec![
+            inv.encoded_claims.to_string(),
+            forged.encoded_claims.to_string(),
+        ];
+        assert!(!proves_equivocation(&host.public_key(), &evidence));
+
+        let blocklist = Arc::new(RwLock::new(HashMap::new()));
+        let reporter = other.public_key();
+        let report = |evidence| BlockedHost {
+            host_id: host.public_key(),
+            reporter: reporter.to_string(),
+            reason: "test".to_string(),
+            evidence,
+        };
+        let forged = report(evidence);
+        assert!(!accept_blocked_host(&blocklist, &reporter, forged));
+        assert!(!accept_blocked_host(&blocklist, &reporter, report(vec![])));
+        assert!(blocklist.read().unwrap().is_empty());
+
+        // Only the host itself can sign different claims for the same invocation
+        let resigned = InvocationSigner::new(&host.seed().unwrap()).sign(tampered);
+        let evidence = vec![
+            inv.encoded_claims.to_string(),
+            resigned.encoded_claims.to_string(),
+        ];
+        assert!(proves_equivocation(&host.public_key(), &evidence));
+        assert!(accept_blocked_host(&blocklist, &reporter, report(evidence)));
+        assert!(blocklist.read().unwrap().contains_key(&host.public_key()));
+    }
+}
@@ -1,5 +1,8 @@
+use crate::{Invocation, InvocationResponse, Result};
+use crossbeam::{Receiver, Sender};
 #[cfg(feature = "lattice")]
-use crossbeam::Sender;
+use latticeclient::BusEvent;
+use wascap::jwt::{Actor, Claims};
 
 pub const URL_SCHEME: &str = "wasmbus";
 
@@ -10,29 +13,136 @@ use std::collections::HashMap;
 #[cfg(feature = "lattice")]
 use std::sync::{Arc, RwLock};
 #[cfg(feature = "lattice")]
-use wascap::jwt::{Actor, Claims};
-#[cfg(feature = "lattice")]
 use wascc_codec::capabilities::CapabilityDescriptor;
 
-#[cfg(not(feature = "lattice"))]
 pub(crate) mod inproc;
 #[cfg(feature = "lattice")]
 pub(crate) mod lattice;
+#[cfg(feature = "lattice")]
+pub(crate) mod seal;
+pub(crate) mod strict;
+#[cfg(feature = "lattice")]
+pub(crate) mod transport;
+
+pub use inproc::InprocBus;
+#[cfg(feature = "lattice")]
+pub use transport::LatticeBroker;
+
+/// The transport over which a host delivers invocations to actors and capability providers.
+/// The host subscribes to a subject for every actor, provider and actor-provider binding it
+/// runs, and invokes those subjects to deliver invocations. Hosts use the in-process bus by
+/// default, or the lattice bus when built with the `lattice` feature, but any implementation
+/// can be supplied with `HostBuilder::with_bus`.
+pub trait MessageBus: Send + Sync {
+    /// Subscribes to a subject. Invocations received on the subject are sent to `sender`, and
+    /// the response for each is read from `receiver`. When multiple hosts subscribe to the
+    /// same subject, each invocation should be delivered to only one of them
+    fn subscribe(
+        &self,
+        subject: &str,
+        sender: Sender<Invocation>,
+        receiver: Receiver<InvocationResponse>,
+    ) -> Result<()>;
+
+    /// Subscribes to a subject such that every subscriber receives every invocation
+    fn nqsubscribe(
+        &self,
+        subject: &str,
+        sender: Sender<Invocation>,
+        receiver: Receiver<InvocationResponse>,
+    ) -> Result<()>;
+
+    /// Delivers an invocation to the subscriber of a subject and waits for its response
+    fn invoke(&self, subject: &str, inv: Invocation) -> Result<InvocationResponse>;
+
+    fn unsubscribe(&self, subject: &str) -> Result<()>;
+
+    /// Called when the host shuts down
+    fn disconnect(&self);
+
+    /// The namespace that prefixes all of this bus's subjects, if any
+    fn namespace(&self) -> Option<String> {
+        None
+    }
 
-#[cfg(not(feature = "lattice"))]
-pub(crate) use inproc::InprocBus as MessageBus;
+    /// Indicates whether the bus is currently able to reach other hosts. Invocations made while
+    /// a bus is disconnected fail immediately
+    fn connected(&self) -> bool {
+        true
+    }
+
+    /// Indicates whether invocations are checked for forgery or tampering after the
+    /// pre-invoke middleware has run
+    fn validates_invocations(&self) -> bool {
+        false
+    }
+
+    /// The number of running instances of an actor visible on the bus, not including
+    /// instances on this host that have already been removed
+    fn instance_count(&self, _actor: &str) -> Result<usize> {
+        Ok(0)
+    }
+
+    /// Looks up the claims of an actor running anywhere that is reachable by the bus
+    fn discover_claims(&self, _actor: &str) -> Option<Claims<Actor>> {
+        None
+    }
 
+    fn actor_subject(&self, actor: &str) -> String {
+        actor_subject(self.namespace().as_deref(), actor)
+    }
+
+    fn provider_subject(&self, capid: &str, binding: &str) -> String {
+        provider_subject(self.namespace().as_deref(), capid, binding)
+    }
+
+    fn provider_subject_bound_actor(
+        &self,
+        capid: &str,
+        binding: &str,
+        calling_actor: &str,
+    ) -> String {
+        provider_subject_bound_actor(self.namespace().as_deref(), capid, binding, calling_actor)
+    }
+
+    fn inventory_wildcard_subject(&self) -> String {
+        inventory_wildcard_subject(self.namespace().as_deref())
+    }
+
+    fn event_subject(&self) -> String {
+        event_subject(self.namespace().as_deref())
+    }
+}
+
+/// Administration of the lattice that a host's bus is connected to. This is kept apart from
+/// `MessageBus` so that the shape of that trait doesn't depend on the `lattice` feature. Only
+/// the default lattice bus implements it; hosts built with `HostBuilder::with_bus` have no
+/// lattice to administer
 #[cfg(feature = "lattice")]
-pub(crate) use lattice::DistributedBus as MessageBus;
+pub(crate) trait LatticeAdmin: Send + Sync {
+    /// Returns the bindings known to every host in the lattice
+    fn query_bindings(&self) -> Result<Vec<latticeclient::Binding>>;
+
+    /// Returns the hosts in the lattice-wide block list
+    fn blocked_hosts(&self) -> Vec<lattice::BlockedHost>;
+
+    /// Removes a host from the lattice-wide block list
+    fn unblock_host(&self, host_id: &str) -> Result<()>;
+
+    /// Removes every host from the lattice-wide block list
+    fn clear_blocklist(&self) -> Result<()>;
 
-#[cfg(not(feature = "lattice"))]
-pub(crate) fn new() -> MessageBus {
-    inproc::InprocBus::new()
+    /// Publishes a lattice event
+    fn publish_event(&self, event: BusEvent) -> Result<()>;
 }
 
 #[cfg(feature = "lattice")]
 pub(crate) fn new(
     host_id: String,
+    host_seed: String,
+    identity: lattice::IdentityConfig,
+    strict: bool,
+    seal: seal::SealPolicy,
     claims: Arc<RwLock<HashMap<String, Claims<Actor>>>>,
     caps: Arc<RwLock<HashMap<RouteKey, CapabilityDescriptor>>>,
     bindings: Arc<RwLock<BindingsList>>,
@@ -42,9 +152,17 @@ pub(crate) fn new(
     cplane_s: Sender<lattice::ControlCommand>,
     authz: Arc<RwLock<Box<dyn crate::authz::Authorizer>>>,
     image_map: Arc<RwLock<HashMap<String, String>>>,
-) -> MessageBus {
+    secrets: Arc<RwLock<crate::secrets::SecretPolicy>>,
+    connector: transport::Connector,
+    policy: lattice::ConnectionPolicy,
+    stats: Arc<crate::metrics::RuntimeStats>,
+) -> lattice::DistributedBus {
     lattice::DistributedBus::new(
         host_id,
+        host_seed,
+        identity,
+        strict,
+        seal,
         claims,
         caps,
         bindings,
@@ -54,6 +172,10 @@ pub(crate) fn new(
         cplane_s,
         authz,
         image_map,
+        secrets,
+        connector,
+        policy,
+        stats,
     )
 }
 
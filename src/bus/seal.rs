@@ -0,0 +1,452 @@
+// Optional end-to-end sealing of invocation and response payloads exchanged between hosts
+// in a lattice. Every host derives a curve25519 key pair from its host seed and advertises the
+// public half, signed with its host key, through the `hostcore.sealkey` label in its inventory
+// profile. Advertised keys are only used once the signature checks out and the host's identity
+// token has been verified against a trusted issuer. An invocation payload is encrypted with a
+// random content key, and that content key is wrapped for each verified host that runs the
+// invocation's target, since queue subscriptions mean the sender cannot know which of them will
+// receive the invocation. Responses are sealed only for the host that sent the invocation.
+
+use crate::errors::{self, ErrorKind};
+use crate::Result;
+use data_encoding::HEXLOWER;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use wascap::prelude::KeyPair;
+use wascc_codec::{deserialize, serialize};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub(crate) const LATTICE_SEAL_PAYLOADS_KEY: &str = "LATTICE_SEAL_PAYLOADS";
+
+// Names the unnamed namespace in the list of namespaces that have sealing enabled
+const DEFAULT_NAMESPACE: &str = "default";
+
+// Prefix that marks an invocation or response payload as sealed
+const SEAL_MAGIC: &[u8] = b"wascc.sealed.v1:";
+const KEY_DERIVATION_SALT: &[u8] = b"wascc-seal-key-v1";
+const KEY_WRAP_SALT: &[u8] = b"wascc-seal-wrap-v1";
+const KEY_LEN: usize = 32;
+
+// How long the public keys discovered from host inventory are cached
+const PEER_KEY_TTL_SECS: u64 = 30;
+// How soon the keys may be refreshed again when an invocation's target has no known recipients
+const MIN_REFRESH_INTERVAL_MILLIS: u64 = 1000;
+
+/// Error message returned by a host that cannot find its own wrapped key in a sealed payload.
+/// Senders use it to detect that their view of the lattice's keys is stale
+pub(crate) const NOT_A_RECIPIENT: &str = "Host is not a recipient of the sealed payload";
+
+/// The lattice namespaces in which payloads are sealed. A host only seals payloads if sealing
+/// is enabled for the namespace it joins
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SealPolicy {
+    Disabled,
+    AllNamespaces,
+    Namespaces(Vec<String>),
+}
+
+impl SealPolicy {
+    /// Reads `LATTICE_SEAL_PAYLOADS`, which is either `true` (or `1`) to seal payloads in every
+    /// namespace, or a comma-separated list of namespaces, where `default` is the unnamed one
+    pub(crate) fn from_env() -> SealPolicy {
+        let v = std::env::var(LATTICE_SEAL_PAYLOADS_KEY).unwrap_or_default();
+        match v.trim().to_lowercase().as_str() {
+            "1" | "true" => SealPolicy::AllNamespaces,
+            "" | "0" | "false" => SealPolicy::Disabled,
+            _ => SealPolicy::Namespaces(
+                v.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Adds a namespace to those in which payloads are sealed
+    pub(crate) fn with_namespace(self, ns: &str) -> SealPolicy {
+        match self {
+            SealPolicy::AllNamespaces => SealPolicy::AllNamespaces,
+            SealPolicy::Disabled => SealPolicy::Namespaces(vec![ns.to_string()]),
+            SealPolicy::Namespaces(mut list) => {
+                if !list.iter().any(|n| n == ns) {
+                    list.push(ns.to_string());
+                }
+                SealPolicy::Namespaces(list)
+            }
+        }
+    }
+
+    pub(crate) fn applies_to(&self, ns: Option<&str>) -> bool {
+        match self {
+            SealPolicy::Disabled => false,
+            SealPolicy::AllNamespaces => true,
+            SealPolicy::Namespaces(list) => {
+                let ns = ns.unwrap_or(DEFAULT_NAMESPACE);
+                list.iter().any(|n| n == ns)
+            }
+        }
+    }
+}
+
+pub(crate) fn is_sealed(msg: &[u8]) -> bool {
+    msg.starts_with(SEAL_MAGIC)
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SealedPayload {
+    ephemeral: Vec<u8>,
+    // Host ID -> content key wrapped for that host
+    recipients: HashMap<String, Vec<u8>>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+struct OkmLen(usize);
+
+impl hkdf::KeyType for OkmLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn derive(salt: &[u8], ikm: &[u8], info: &[u8]) -> [u8; KEY_LEN] {
+    let mut out = [0u8; KEY_LEN];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, salt)
+        .extract(ikm)
+        .expand(&[info], OkmLen(KEY_LEN))
+        .unwrap()
+        .fill(&mut out)
+        .unwrap();
+    out
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    SystemRandom::new()
+        .fill(&mut buf)
+        .map_err(|_| seal_error("Failed to generate random bytes"))?;
+    Ok(buf)
+}
+
+fn seal_error(msg: &str) -> errors::Error {
+    errors::new(ErrorKind::MiscHost(msg.to_string()))
+}
+
+fn aead_key(key: &[u8]) -> Result<LessSafeKey> {
+    UnboundKey::new(&CHACHA20_POLY1305, key)
+        .map(LessSafeKey::new)
+        .map_err(|_| seal_error("Invalid sealing key"))
+}
+
+fn encrypt(key: &[u8], nonce: &[u8], aad: &[u8], plain: &[u8]) -> Result<Vec<u8>> {
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| seal_error("Bad nonce"))?;
+    let mut buf = plain.to_vec();
+    aead_key(key)?
+        .seal_in_place_append_tag(nonce, Aad::from(aad), &mut buf)
+        .map_err(|_| seal_error("Failed to seal payload"))?;
+    Ok(buf)
+}
+
+fn decrypt(key: &[u8], nonce: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| seal_error("Bad nonce"))?;
+    let mut buf = sealed.to_vec();
+    let plain = aead_key(key)?
+        .open_in_place(nonce, Aad::from(aad), &mut buf)
+        .map_err(|_| seal_error("Failed to open sealed payload"))?;
+    Ok(plain.to_vec())
+}
+
+// The key that wraps a content key for one recipient. The ephemeral key is new for every
+// sealed payload, so the wrapping key is never reused and a zero nonce is safe.
+fn wrapping_key(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> [u8; KEY_LEN] {
+    let mut info = ephemeral.as_bytes().to_vec();
+    info.extend_from_slice(recipient.as_bytes());
+    derive(KEY_WRAP_SALT, shared, &info)
+}
+
+fn public_key_from_bytes(bytes: &[u8]) -> Option<PublicKey> {
+    if bytes.len() != KEY_LEN {
+        return None;
+    }
+    let mut raw = [0u8; KEY_LEN];
+    raw.copy_from_slice(bytes);
+    Some(PublicKey::from(raw))
+}
+
+pub(crate) fn decode_public_key(encoded: &str) -> Option<PublicKey> {
+    HEXLOWER
+        .decode(encoded.as_bytes())
+        .ok()
+        .and_then(|b| public_key_from_bytes(&b))
+}
+
+/// Checks that an advertised sealing key (the encoded key and a signature over it, separated by
+/// a `.`) was signed by the host that advertises it, and returns the key if so
+pub(crate) fn verify_advertised_key(host_id: &str, advertised: &str) -> Option<PublicKey> {
+    let mut parts = advertised.splitn(2, '.');
+    let (key, sig) = (parts.next()?, parts.next()?);
+    let sig = HEXLOWER.decode(sig.as_bytes()).ok()?;
+    KeyPair::from_public_key(host_id)
+        .ok()?
+        .verify(key.as_bytes(), &sig)
+        .ok()?;
+    decode_public_key(key)
+}
+
+/// Seals a payload so that only the listed hosts can open it. The additional data (the
+/// invocation ID) is bound to the ciphertext so a sealed payload can't be replayed
+/// inside a different invocation
+pub(crate) fn seal(
+    msg: &[u8],
+    aad: &[u8],
+    recipients: &HashMap<String, PublicKey>,
+) -> Result<Vec<u8>> {
+    if recipients.is_empty() {
+        return Err(seal_error("No recipient keys available to seal payload"));
+    }
+    let mut eph_raw = [0u8; KEY_LEN];
+    eph_raw.copy_from_slice(&random_bytes(KEY_LEN)?);
+    let ephemeral = StaticSecret::from(eph_raw);
+    let eph_public = PublicKey::from(&ephemeral);
+
+    let content_key = random_bytes(KEY_LEN)?;
+    let nonce = random_bytes(NONCE_LEN)?;
+    let ciphertext = encrypt(&content_key, &nonce, aad, msg)?;
+
+    let zero_nonce = [0u8; NONCE_LEN];
+    let mut wrapped = HashMap::new();
+    for (host, pk) in recipients {
+        let shared = ephemeral.diffie_hellman(pk);
+        let kek = wrapping_key(shared.as_bytes(), &eph_public, pk);
+        wrapped.insert(host.to_string(), encrypt(&kek, &zero_nonce, aad, &content_key)?);
+    }
+
+    let payload = SealedPayload {
+        ephemeral: eph_public.as_bytes().to_vec(),
+        recipients: wrapped,
+        nonce,
+        ciphertext,
+    };
+    let mut out = SEAL_MAGIC.to_vec();
+    out.extend_from_slice(&serialize(payload)?);
+    Ok(out)
+}
+
+/// This host's sealing key pair, plus the verified public keys of the other hosts in the
+/// namespace and the actors and capability providers each of them runs
+pub(crate) struct SealingKeys {
+    host_id: String,
+    secret: StaticSecret,
+    public: PublicKey,
+    advertised: String,
+    peers: RwLock<Peers>,
+}
+
+#[derive(Default)]
+struct Peers {
+    keys: HashMap<String, PublicKey>,
+    // Host ID -> URLs of the entities (actors and capability providers) the host runs
+    hosted: HashMap<String, HashSet<String>>,
+    refreshed: Option<Instant>,
+}
+
+impl SealingKeys {
+    /// Derives the sealing key pair from the host seed, so a host started with a persistent
+    /// seed keeps the same sealing key across restarts
+    pub(crate) fn from_host_seed(host_id: &str, seed: &str) -> SealingKeys {
+        let secret = StaticSecret::from(derive(KEY_DERIVATION_SALT, seed.as_bytes(), b"x25519"));
+        let public = PublicKey::from(&secret);
+        let encoded = HEXLOWER.encode(public.as_bytes());
+        let signature = KeyPair::from_seed(seed)
+            .and_then(|kp| kp.sign(encoded.as_bytes()))
+            .map(|sig| HEXLOWER.encode(&sig))
+            .unwrap_or_default();
+        let mut peers = Peers::default();
+        peers.keys.insert(host_id.to_string(), public);
+        SealingKeys {
+            host_id: host_id.to_string(),
+            secret,
+            public,
+            advertised: format!("{}.{}", encoded, signature),
+            peers: RwLock::new(peers),
+        }
+    }
+
+    pub(crate) fn public_key(&self) -> String {
+        HEXLOWER.encode(self.public.as_bytes())
+    }
+
+    /// The public key signed with the host key, as advertised in the host's inventory profile
+    pub(crate) fn advertised_key(&self) -> String {
+        self.advertised.to_string()
+    }
+
+    /// Indicates whether the cached peer keys should be refreshed from host inventory, either
+    /// because they're out of date or because no known host runs the given target
+    pub(crate) fn needs_refresh(&self, target_url: &str) -> bool {
+        let peers = self.peers.read().unwrap();
+        match peers.refreshed {
+            Some(t) if t.elapsed() > Duration::from_secs(PEER_KEY_TTL_SECS) => true,
+            Some(t) => {
+                t.elapsed() > Duration::from_millis(MIN_REFRESH_INTERVAL_MILLIS)
+                    && !peers.hosted.values().any(|h| h.contains(target_url))
+            }
+            None => true,
+        }
+    }
+
+    /// Replaces the cached peer keys, and the entities each peer runs, with those discovered
+    /// from host inventory. Only keys of verified hosts may be supplied
+    pub(crate) fn update_peers(
+        &self,
+        keys: HashMap<String, PublicKey>,
+        hosted: HashMap<String, HashSet<String>>,
+    ) {
+        let mut keys = keys;
+        keys.insert(self.host_id.to_string(), self.public);
+        *self.peers.write().unwrap() = Peers {
+            keys,
+            hosted,
+            refreshed: Some(Instant::now()),
+        };
+    }
+
+    /// The keys of the hosts that run the target of an invocation, which are the only hosts
+    /// that can receive it
+    pub(crate) fn recipients(&self, target_url: &str) -> HashMap<String, PublicKey> {
+        let peers = self.peers.read().unwrap();
+        peers
+            .keys
+            .iter()
+            .filter(|(host, _)| {
+                peers
+                    .hosted
+                    .get(host.as_str())
+                    .map_or(false, |h| h.contains(target_url))
+            })
+            .map(|(host, pk)| (host.to_string(), *pk))
+            .collect()
+    }
+
+    pub(crate) fn peer(&self, host_id: &str) -> Option<PublicKey> {
+        self.peers.read().unwrap().keys.get(host_id).cloned()
+    }
+
+    /// Opens a payload that was sealed for (among others) this host
+    pub(crate) fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if !is_sealed(sealed) {
+            return Err(seal_error("Payload is not sealed"));
+        }
+        let payload: SealedPayload = deserialize(&sealed[SEAL_MAGIC.len()..])?;
+        let wrapped = match payload.recipients.get(&self.host_id) {
+            Some(w) => w,
+            None => return Err(seal_error(NOT_A_RECIPIENT)),
+        };
+        let eph_public = public_key_from_bytes(&payload.ephemeral)
+            .ok_or_else(|| seal_error("Invalid ephemeral key in sealed payload"))?;
+        let shared = self.secret.diffie_hellman(&eph_public);
+        let kek = wrapping_key(shared.as_bytes(), &eph_public, &self.public);
+        let content_key = decrypt(&kek, &[0u8; NONCE_LEN], aad, wrapped)?;
+        decrypt(&content_key, &payload.nonce, aad, &payload.ciphertext)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        decode_public_key, is_sealed, seal, verify_advertised_key, SealPolicy, SealingKeys,
+        NOT_A_RECIPIENT,
+    };
+    use std::collections::{HashMap, HashSet};
+    use wascap::prelude::KeyPair;
+
+    fn keys() -> SealingKeys {
+        let kp = KeyPair::new_server();
+        SealingKeys::from_host_seed(&kp.public_key(), &kp.seed().unwrap())
+    }
+
+    #[test]
+    fn seal_round_trip() {
+        let alice = keys();
+        let bob = keys();
+        let mut recipients = HashMap::new();
+        recipients.insert(bob.host_id.to_string(), bob.public);
+
+        let sealed = seal(b"hello world", b"inv1", &recipients).unwrap();
+        assert!(is_sealed(&sealed));
+        assert_eq!(bob.open(&sealed, b"inv1").unwrap(), b"hello world".to_vec());
+        assert!(bob.open(&sealed, b"inv2").is_err());
+        let e = alice.open(&sealed, b"inv1").unwrap_err();
+        assert!(format!("{}", e).contains(NOT_A_RECIPIENT));
+    }
+
+    #[test]
+    fn sealing_key_is_stable_and_encodable() {
+        let kp = KeyPair::new_server();
+        let seed = kp.seed().unwrap();
+        let a = SealingKeys::from_host_seed(&kp.public_key(), &seed);
+        let b = SealingKeys::from_host_seed(&kp.public_key(), &seed);
+        assert_eq!(a.public_key(), b.public_key());
+        assert_eq!(
+            decode_public_key(&a.public_key()).unwrap().as_bytes(),
+            a.public.as_bytes()
+        );
+    }
+
+    #[test]
+    fn advertised_key_is_bound_to_host() {
+        let kp = KeyPair::new_server();
+        let keys = SealingKeys::from_host_seed(&kp.public_key(), &kp.seed().unwrap());
+        let advertised = keys.advertised_key();
+        assert_eq!(
+            verify_advertised_key(&kp.public_key(), &advertised)
+                .unwrap()
+                .as_bytes(),
+            keys.public.as_bytes()
+        );
+
+        // A key advertised under another host's ID, or without a signature, is rejected
+        let other = KeyPair::new_server();
+        assert!(verify_advertised_key(&other.public_key(), &advertised).is_none());
+        assert!(verify_advertised_key(&kp.public_key(), &keys.public_key()).is_none());
+    }
+
+    #[test]
+    fn seals_only_for_hosts_running_the_target() {
+        let alice = keys();
+        let bob = keys();
+        let carol = keys();
+        let mut peers = HashMap::new();
+        peers.insert(bob.host_id.to_string(), bob.public);
+        peers.insert(carol.host_id.to_string(), carol.public);
+        let mut hosted = HashMap::new();
+        let mut targets = HashSet::new();
+        targets.insert("wasmbus://Mactor".to_string());
+        hosted.insert(bob.host_id.to_string(), targets);
+        alice.update_peers(peers, hosted);
+
+        let recipients = alice.recipients("wasmbus://Mactor");
+        assert_eq!(1, recipients.len());
+        assert!(recipients.contains_key(&bob.host_id));
+        assert!(alice.recipients("wasmbus://Mother").is_empty());
+        // Having just been refreshed, the keys aren't refreshed again for an unknown target
+        assert!(!alice.needs_refresh("wasmbus://Mother"));
+        assert!(!alice.needs_refresh("wasmbus://Mactor"));
+    }
+
+    #[test]
+    fn sealing_is_configured_per_namespace() {
+        assert!(!SealPolicy::Disabled.applies_to(None));
+        assert!(SealPolicy::AllNamespaces.applies_to(Some("prod")));
+        let policy = SealPolicy::Disabled
+            .with_namespace("prod")
+            .with_namespace("default");
+        assert!(policy.applies_to(Some("prod")));
+        assert!(policy.applies_to(None));
+        assert!(!policy.applies_to(Some("dev")));
+    }
+}
@@ -0,0 +1,117 @@
+// Strict antiforgery mode for message buses supplied with `HostBuilder::with_bus` that don't
+// validate invocations themselves. The wrapped bus only delivers invocations whose signed claims
+// are valid, and reports that it validates invocations so that they're checked again after the
+// pre-invoke middleware has run.
+
+use super::MessageBus;
+use crate::{Invocation, InvocationResponse, Result};
+use crossbeam::{Receiver, Sender};
+use std::sync::Arc;
+use wascap::jwt::{Actor, Claims};
+
+pub(crate) struct StrictBus {
+    inner: Arc<dyn MessageBus>,
+}
+
+impl StrictBus {
+    pub(crate) fn new(inner: Arc<dyn MessageBus>) -> StrictBus {
+        info!("Enforcing strict antiforgery on the supplied message bus");
+        StrictBus { inner }
+    }
+}
+
+impl MessageBus for StrictBus {
+    fn subscribe(
+        &self,
+        subject: &str,
+        sender: Sender<Invocation>,
+        receiver: Receiver<InvocationResponse>,
+    ) -> Result<()> {
+        self.inner.subscribe(subject, sender, receiver)
+    }
+
+    fn nqsubscribe(
+        &self,
+        subject: &str,
+        sender: Sender<Invocation>,
+        receiver: Receiver<InvocationResponse>,
+    ) -> Result<()> {
+        self.inner.nqsubscribe(subject, sender, receiver)
+    }
+
+    fn invoke(&self, subject: &str, inv: Invocation) -> Result<InvocationResponse> {
+        inv.validate_antiforgery()?;
+        self.inner.invoke(subject, inv)
+    }
+
+    fn unsubscribe(&self, subject: &str) -> Result<()> {
+        self.inner.unsubscribe(subject)
+    }
+
+    fn disconnect(&self) {
+        self.inner.disconnect()
+    }
+
+    fn namespace(&self) -> Option<String> {
+        self.inner.namespace()
+    }
+
+    fn connected(&self) -> bool {
+        self.inner.connected()
+    }
+
+    fn validates_invocations(&self) -> bool {
+        true
+    }
+
+    fn instance_count(&self, actor: &str) -> Result<usize> {
+        self.inner.instance_count(actor)
+    }
+
+    fn discover_claims(&self, actor: &str) -> Option<Claims<Actor>> {
+        self.inner.discover_claims(actor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StrictBus;
+    use crate::bus::{InprocBus, MessageBus};
+    use crate::{Invocation, InvocationResponse, WasccEntity};
+    use std::sync::Arc;
+    use wascap::prelude::KeyPair;
+
+    #[test]
+    fn rejects_tampered_invocations() {
+        let inner = InprocBus::new();
+        let (inv_s, inv_r) = crossbeam_channel::unbounded();
+        let (resp_s, resp_r) = crossbeam_channel::unbounded();
+        inner
+            .subscribe("wasmbus.actor.Mxxx", inv_s, resp_r)
+            .unwrap();
+        let bus = StrictBus::new(Arc::new(inner));
+        assert!(bus.validates_invocations());
+
+        let inv = Invocation::new(
+            &KeyPair::new_server(),
+            WasccEntity::Actor("Mxxx".to_string()),
+            WasccEntity::Actor("Mxxx".to_string()),
+            "testing",
+            b"abc".to_vec(),
+        );
+        let mut tampered = inv.clone();
+        tampered.msg = b"xyz".to_vec();
+        assert!(bus.invoke("wasmbus.actor.Mxxx", tampered).is_err());
+        assert!(inv_r.is_empty());
+
+        resp_s
+            .send(InvocationResponse::success(&inv, vec![]))
+            .unwrap();
+        assert!(bus
+            .invoke("wasmbus.actor.Mxxx", inv)
+            .unwrap()
+            .error
+            .is_none());
+        assert_eq!(1, inv_r.len());
+    }
+}
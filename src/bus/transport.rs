@@ -0,0 +1,852 @@
+// The connection a distributed bus uses to reach the other hosts in its lattice. Hosts
+// normally connect to a NATS server, but hosts in the same process can instead share an
+// in-memory broker, which is how lattice behavior is tested without any outside services.
+
+use super::lattice::ConnectionPolicy;
+use crossbeam::{Receiver, Sender};
+use crossbeam_channel as channel;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub(crate) type Handler = Box<dyn Fn(Message) -> io::Result<()> + Send + Sync + 'static>;
+
+type Responder = Arc<dyn Fn(&[u8]) -> io::Result<()> + Send + Sync>;
+
+pub(crate) type DisconnectCallback = Box<dyn Fn() + Send + Sync + 'static>;
+
+/// Opens a new connection to the lattice. The callback is invoked if that connection is lost
+pub(crate) type Connector =
+    Box<dyn Fn(DisconnectCallback) -> io::Result<Arc<dyn Transport>> + Send + Sync + 'static>;
+
+pub(crate) trait Transport: Send + Sync {
+    fn publish(&self, subject: &str, data: &[u8]) -> io::Result<()>;
+
+    /// Sends a request and waits for the first reply
+    fn request(&self, subject: &str, data: &[u8], timeout: Duration) -> io::Result<Message>;
+
+    /// Sends a request and gathers every reply that arrives before the timeout elapses
+    fn request_all(
+        &self,
+        subject: &str,
+        data: &[u8],
+        timeout: Duration,
+    ) -> io::Result<Vec<Message>>;
+
+    /// Subscribes to a subject, which may contain `*` and `>` wildcards. Subscribers that share
+    /// a queue group name have each message delivered to only one member of the group
+    fn subscribe(
+        &self,
+        subject: &str,
+        queue: Option<&str>,
+        handler: Handler,
+    ) -> io::Result<Box<dyn Subscription>>;
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    fn close(&self);
+}
+
+pub(crate) trait Subscription: Send + Sync {
+    fn unsubscribe(self: Box<Self>) -> io::Result<()>;
+}
+
+/// A message received from the lattice, which can be answered if the sender expects a reply
+#[derive(Clone)]
+pub(crate) struct Message {
+    pub subject: String,
+    pub data: Vec<u8>,
+    responder: Option<Responder>,
+}
+
+impl Message {
+    pub(crate) fn respond(&self, data: impl AsRef<[u8]>) -> io::Result<()> {
+        match self.responder {
+            Some(ref r) => r(data.as_ref()),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No reply subject available",
+            )),
+        }
+    }
+}
+
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Message")
+            .field("subject", &self.subject)
+            .field("data", &self.data.len())
+            .field("reply", &self.responder.is_some())
+            .finish()
+    }
+}
+
+pub(crate) struct NatsTransport {
+    nc: nats::Connection,
+}
+
+impl NatsTransport {
+    pub(crate) fn new(nc: nats::Connection) -> NatsTransport {
+        NatsTransport { nc }
+    }
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "Not connected to the lattice")
+}
+
+fn from_nats(nc: &nats::Connection, msg: nats::Message) -> Message {
+    let responder = msg.reply.map(|reply| {
+        let nc = nc.clone();
+        Arc::new(move |data: &[u8]| nc.publish(&reply, data)) as Responder
+    });
+    Message {
+        subject: msg.subject,
+        data: msg.data,
+        responder,
+    }
+}
+
+impl Transport for NatsTransport {
+    fn publish(&self, subject: &str, data: &[u8]) -> io::Result<()> {
+        self.nc.publish(subject, data)
+    }
+
+    fn request(&self, subject: &str, data: &[u8], timeout: Duration) -> io::Result<Message> {
+        self.nc
+            .request_timeout(subject, data, timeout)
+            .map(|m| from_nats(&self.nc, m))
+    }
+
+    fn request_all(
+        &self,
+        subject: &str,
+        data: &[u8],
+        timeout: Duration,
+    ) -> io::Result<Vec<Message>> {
+        let inbox = self.nc.new_inbox();
+        let sub = self.nc.subscribe(&inbox)?;
+        self.nc.publish_request(subject, &inbox, data)?;
+        let deadline = Instant::now() + timeout;
+        let mut replies = vec![];
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match sub.next_timeout(remaining) {
+                Ok(m) => replies.push(from_nats(&self.nc, m)),
+                Err(_) => break,
+            }
+        }
+        let _ = sub.unsubscribe();
+        Ok(replies)
+    }
+
+    fn subscribe(
+        &self,
+        subject: &str,
+        queue: Option<&str>,
+        handler: Handler,
+    ) -> io::Result<Box<dyn Subscription>> {
+        let sub = match queue {
+            Some(q) => self.nc.queue_subscribe(subject, q)?,
+            None => self.nc.subscribe(subject)?,
+        };
+        let nc = self.nc.clone();
+        let h = sub.with_handler(move |m| handler(from_nats(&nc, m)));
+        Ok(Box::new(NatsSubscription(h)))
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.nc.flush()
+    }
+
+    fn close(&self) {
+        self.nc.clone().close();
+    }
+}
+
+struct NatsSubscription(nats::subscription::Handler);
+
+impl Subscription for NatsSubscription {
+    fn unsubscribe(self: Box<Self>) -> io::Result<()> {
+        self.0.unsubscribe()
+    }
+}
+
+/// Changes in the state of a reconnecting transport's connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ConnectionState {
+    Disconnected,
+    Reconnected { resubscribed: usize },
+}
+
+// A subscription that is re-created on every new connection
+struct Resubscription {
+    subject: String,
+    queue: Option<String>,
+    handler: Arc<Handler>,
+    live: Option<Box<dyn Subscription>>,
+}
+
+impl Resubscription {
+    fn establish(&mut self, nc: &Arc<dyn Transport>) -> io::Result<()> {
+        let handler = self.handler.clone();
+        self.live = Some(nc.subscribe(
+            &self.subject,
+            self.queue.as_deref(),
+            Box::new(move |m| handler(m)),
+        )?);
+        Ok(())
+    }
+}
+
+/// A transport that survives the loss of its underlying connection. When a connection is lost,
+/// a new one is opened with exponential backoff and every subscription made through this
+/// transport is re-established on it. Requests and publications made while disconnected fail
+/// immediately rather than waiting for a timeout
+pub(crate) struct ReconnectingTransport {
+    connector: Connector,
+    policy: ConnectionPolicy,
+    current: RwLock<Option<Arc<dyn Transport>>>,
+    generation: AtomicU64,
+    subs: Arc<Mutex<HashMap<u64, Resubscription>>>,
+    next_id: AtomicU64,
+    closed: AtomicBool,
+    lost_s: Sender<u64>,
+    state_s: Sender<ConnectionState>,
+}
+
+impl ReconnectingTransport {
+    /// Makes the initial connection, retrying as many times as the policy allows. If the
+    /// lattice still can't be reached, the transport starts out disconnected and keeps trying
+    /// in the background. State changes are reported on the returned receiver
+    pub(crate) fn start(
+        connector: Connector,
+        policy: ConnectionPolicy,
+    ) -> (Arc<ReconnectingTransport>, Receiver<ConnectionState>) {
+        let (lost_s, lost_r): (Sender<u64>, Receiver<u64>) = channel::unbounded();
+        let (state_s, state_r): (Sender<ConnectionState>, Receiver<ConnectionState>) =
+            channel::unbounded();
+        let rt = Arc::new(ReconnectingTransport {
+            connector,
+            policy,
+            current: RwLock::new(None),
+            generation: AtomicU64::new(0),
+            subs: Arc::new(Mutex::new(HashMap::new())),
+            next_id: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            lost_s,
+            state_s,
+        });
+        let mut attempt = 0;
+        loop {
+            match rt.connect_once() {
+                Ok(_) => break,
+                Err(e) if attempt < rt.policy.connect_retries => {
+                    let delay = rt.policy.backoff(attempt);
+                    warn!(
+                        "Failed to connect to the lattice ({}), retrying in {}ms",
+                        e,
+                        delay.as_millis()
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to connect to the lattice ({}). The host will start disconnected and keep trying.",
+                        e
+                    );
+                    // Generation 0 is never connected, so this starts a background reconnect
+                    let _ = rt.lost_s.send(0);
+                    break;
+                }
+            }
+        }
+        let weak = Arc::downgrade(&rt);
+        thread::spawn(move || supervise(weak, lost_r));
+        (rt, state_r)
+    }
+
+    fn connect_once(&self) -> io::Result<()> {
+        let generation = self.generation.load(Ordering::SeqCst) + 1;
+        let lost_s = self.lost_s.clone();
+        let nc = (self.connector)(Box::new(move || {
+            let _ = lost_s.send(generation);
+        }))?;
+        self.generation.store(generation, Ordering::SeqCst);
+        *self.current.write().unwrap() = Some(nc);
+        Ok(())
+    }
+
+    // Re-creates every registered subscription on the current connection
+    fn resubscribe(&self) -> usize {
+        let nc = match self.current.read().unwrap().clone() {
+            Some(nc) => nc,
+            None => return 0,
+        };
+        let mut subs = self.subs.lock().unwrap();
+        let mut resubscribed = 0;
+        for sub in subs.values_mut() {
+            match sub.establish(&nc) {
+                Ok(_) => resubscribed += 1,
+                Err(e) => error!("Failed to re-establish subscription {}: {}", sub.subject, e),
+            }
+        }
+        resubscribed
+    }
+
+    fn connection(&self) -> io::Result<Arc<dyn Transport>> {
+        self.current
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(not_connected)
+    }
+}
+
+// Waits for notice that a connection has been lost, then reconnects with backoff until it
+// succeeds or the transport is closed
+fn supervise(rt: Weak<ReconnectingTransport>, lost_r: Receiver<u64>) {
+    for generation in lost_r.iter() {
+        let rt = match rt.upgrade() {
+            Some(rt) => rt,
+            None => break,
+        };
+        if rt.closed.load(Ordering::SeqCst) {
+            break;
+        }
+        if generation != rt.generation.load(Ordering::SeqCst) {
+            // A stale notice from a connection that has already been replaced
+            continue;
+        }
+        warn!("Lost connection to the lattice, reconnecting");
+        if let Some(old) = rt.current.write().unwrap().take() {
+            old.close();
+        }
+        let _ = rt.state_s.send(ConnectionState::Disconnected);
+        let mut attempt = 0;
+        while !rt.closed.load(Ordering::SeqCst) {
+            thread::sleep(rt.policy.backoff(attempt));
+            match rt.connect_once() {
+                Ok(_) => {
+                    let resubscribed = rt.resubscribe();
+                    info!(
+                        "Reconnected to the lattice, re-established {} subscriptions",
+                        resubscribed
+                    );
+                    let _ = rt
+                        .state_s
+                        .send(ConnectionState::Reconnected { resubscribed });
+                    break;
+                }
+                Err(e) => {
+                    trace!("Lattice reconnect attempt failed: {}", e);
+                    attempt = attempt.saturating_add(1);
+                }
+            }
+        }
+    }
+}
+
+impl Transport for ReconnectingTransport {
+    fn publish(&self, subject: &str, data: &[u8]) -> io::Result<()> {
+        self.connection()?.publish(subject, data)
+    }
+
+    fn request(&self, subject: &str, data: &[u8], timeout: Duration) -> io::Result<Message> {
+        self.connection()?.request(subject, data, timeout)
+    }
+
+    fn request_all(
+        &self,
+        subject: &str,
+        data: &[u8],
+        timeout: Duration,
+    ) -> io::Result<Vec<Message>> {
+        self.connection()?.request_all(subject, data, timeout)
+    }
+
+    // Subscriptions made while disconnected are established once the connection is restored
+    fn subscribe(
+        &self,
+        subject: &str,
+        queue: Option<&str>,
+        handler: Handler,
+    ) -> io::Result<Box<dyn Subscription>> {
+        let mut sub = Resubscription {
+            subject: subject.to_string(),
+            queue: queue.map(|q| q.to_string()),
+            handler: Arc::new(handler),
+            live: None,
+        };
+        if let Some(nc) = self.current.read().unwrap().clone() {
+            sub.establish(&nc)?;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.subs.lock().unwrap().insert(id, sub);
+        Ok(Box::new(ReconnectingSubscription {
+            subs: self.subs.clone(),
+            id,
+        }))
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.connection()?.flush()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.current.read().unwrap().is_some()
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        if let Some(nc) = self.current.write().unwrap().take() {
+            nc.close();
+        }
+        // Wake the supervisor so that it notices the transport is closed
+        let _ = self.lost_s.send(u64::MAX);
+    }
+}
+
+struct ReconnectingSubscription {
+    subs: Arc<Mutex<HashMap<u64, Resubscription>>>,
+    id: u64,
+}
+
+impl Subscription for ReconnectingSubscription {
+    fn unsubscribe(self: Box<Self>) -> io::Result<()> {
+        let sub = self.subs.lock().unwrap().remove(&self.id);
+        match sub.and_then(|s| s.live) {
+            Some(live) => live.unsubscribe(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An in-memory message broker that stands in for the NATS server connecting the hosts of a
+/// lattice. Every host built with the same broker (see `HostBuilder::with_lattice_broker`)
+/// joins the same lattice, with support for queue groups, inventory requests, control plane
+/// auctions and events. Intended for testing lattice behavior within a single process.
+#[derive(Clone, Default)]
+pub struct LatticeBroker {
+    inner: Arc<Broker>,
+}
+
+impl LatticeBroker {
+    pub fn new() -> LatticeBroker {
+        LatticeBroker::default()
+    }
+
+    /// Publishes a message to every matching subscriber
+    pub fn publish(&self, subject: &str, data: &[u8]) {
+        self.inner.deliver(subject, data, None);
+    }
+
+    /// Sends a request and waits for the first reply
+    pub fn request(&self, subject: &str, data: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        self.inner.request(subject, data, timeout).map(|m| m.data)
+    }
+
+    /// Sends a request and gathers the replies of every subscriber that answers before
+    /// the timeout elapses
+    pub fn request_all(&self, subject: &str, data: &[u8], timeout: Duration) -> Vec<Vec<u8>> {
+        self.inner
+            .request_all(subject, data, timeout)
+            .into_iter()
+            .map(|m| m.data)
+            .collect()
+    }
+
+    /// Subscribes to a subject, which may contain `*` and `>` wildcards. The subject and
+    /// payload of every matching message is sent to the returned receiver
+    pub fn subscribe(&self, subject: &str) -> Receiver<(String, Vec<u8>)> {
+        let (s, r): (Sender<(String, Vec<u8>)>, Receiver<(String, Vec<u8>)>) = channel::unbounded();
+        self.inner.add_subscription(
+            subject,
+            None,
+            Box::new(move |m| {
+                let _ = s.send((m.subject, m.data));
+                Ok(())
+            }),
+        );
+        r
+    }
+
+    /// Drops every host's connection to the broker, as if the lattice server had restarted.
+    /// Hosts reconnect and re-establish their subscriptions on their own
+    pub fn interrupt(&self) {
+        let conns: Vec<_> = self.inner.conns.lock().unwrap().drain(..).collect();
+        for conn in conns.iter().filter_map(|c| c.upgrade()) {
+            conn.drop_connection();
+        }
+    }
+
+    // Each host gets its own connection so that closing it only drops that host's subscriptions
+    pub(crate) fn connector(&self) -> Connector {
+        let broker = self.inner.clone();
+        Box::new(move |on_disconnect| {
+            let conn: Arc<dyn Transport> = broker.connect(on_disconnect);
+            Ok(conn)
+        })
+    }
+}
+
+#[derive(Default)]
+struct Broker {
+    next_id: AtomicU64,
+    subs: RwLock<HashMap<u64, MemorySubscriber>>,
+    conns: Mutex<Vec<Weak<MemoryConnection>>>,
+}
+
+struct MemorySubscriber {
+    subject: String,
+    queue: Option<String>,
+    sender: Sender<Message>,
+}
+
+impl Broker {
+    fn connect(self: &Arc<Self>, on_disconnect: DisconnectCallback) -> Arc<MemoryConnection> {
+        let conn = Arc::new(MemoryConnection {
+            broker: self.clone(),
+            subs: Mutex::new(vec![]),
+            closed: AtomicBool::new(false),
+            on_disconnect,
+        });
+        let mut conns = self.conns.lock().unwrap();
+        conns.retain(|c| c.strong_count() > 0);
+        conns.push(Arc::downgrade(&conn));
+        conn
+    }
+
+    // Messages for a subscription are handled in order on a thread of their own, like
+    // subscription handlers on a NATS connection. The thread exits when the subscription
+    // is removed and its channel disconnects
+    fn add_subscription(&self, subject: &str, queue: Option<&str>, handler: Handler) -> u64 {
+        let (s, r): (Sender<Message>, Receiver<Message>) = channel::unbounded();
+        thread::spawn(move || {
+            for m in r.iter() {
+                if let Err(e) = handler(m) {
+                    error!("In-memory lattice subscription handler failed: {}", e);
+                }
+            }
+        });
+        self.add_subscriber(subject, queue, s)
+    }
+
+    fn add_subscriber(&self, subject: &str, queue: Option<&str>, sender: Sender<Message>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.subs.write().unwrap().insert(
+            id,
+            MemorySubscriber {
+                subject: subject.to_string(),
+                queue: queue.map(|q| q.to_string()),
+                sender,
+            },
+        );
+        id
+    }
+
+    fn remove(&self, id: u64) {
+        self.subs.write().unwrap().remove(&id);
+    }
+
+    // Delivers a message to every matching subscriber that isn't in a queue group, and to one
+    // randomly chosen member of each matching queue group. Returns the number of deliveries
+    fn deliver(self: &Arc<Self>, subject: &str, data: &[u8], reply: Option<String>) -> usize {
+        let recipients = {
+            let subs = self.subs.read().unwrap();
+            let mut recipients = vec![];
+            let mut groups: HashMap<&str, Vec<&Sender<Message>>> = HashMap::new();
+            for sub in subs
+                .values()
+                .filter(|s| subject_matches(&s.subject, subject))
+            {
+                match sub.queue {
+                    Some(ref q) => groups
+                        .entry(q.as_str())
+                        .or_insert_with(Vec::new)
+                        .push(&sub.sender),
+                    None => recipients.push(sub.sender.clone()),
+                }
+            }
+            for members in groups.values() {
+                let i = rand::random::<usize>() % members.len();
+                recipients.push(members[i].clone());
+            }
+            recipients
+        };
+        let responder = reply.map(|reply| {
+            let broker = Arc::downgrade(self);
+            Arc::new(move |data: &[u8]| respond_via(&broker, &reply, data)) as Responder
+        });
+        let msg = Message {
+            subject: subject.to_string(),
+            data: data.to_vec(),
+            responder,
+        };
+        recipients
+            .iter()
+            .filter(|s| s.send(msg.clone()).is_ok())
+            .count()
+    }
+
+    fn request(
+        self: &Arc<Self>,
+        subject: &str,
+        data: &[u8],
+        timeout: Duration,
+    ) -> io::Result<Message> {
+        let (inbox, r) = self.inbox();
+        let delivered = self.deliver(subject, data, Some(inbox.0.to_string()));
+        let res = if delivered == 0 {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No subscribers for {}", subject),
+            ))
+        } else {
+            r.recv_timeout(timeout)
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Request timed out"))
+        };
+        self.remove(inbox.1);
+        res
+    }
+
+    // Stops waiting early once every subscriber the request was delivered to has replied
+    fn request_all(
+        self: &Arc<Self>,
+        subject: &str,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Vec<Message> {
+        let (inbox, r) = self.inbox();
+        let delivered = self.deliver(subject, data, Some(inbox.0.to_string()));
+        let deadline = Instant::now() + timeout;
+        let mut replies = vec![];
+        while replies.len() < delivered {
+            match deadline
+                .checked_duration_since(Instant::now())
+                .and_then(|remaining| r.recv_timeout(remaining).ok())
+            {
+                Some(m) => replies.push(m),
+                None => break,
+            }
+        }
+        self.remove(inbox.1);
+        replies
+    }
+
+    fn inbox(&self) -> ((String, u64), Receiver<Message>) {
+        let (s, r): (Sender<Message>, Receiver<Message>) = channel::unbounded();
+        let subject = format!("_INBOX.{}", uuid::Uuid::new_v4().to_simple());
+        let id = self.add_subscriber(&subject, None, s);
+        ((subject, id), r)
+    }
+}
+
+fn respond_via(broker: &Weak<Broker>, reply: &str, data: &[u8]) -> io::Result<()> {
+    match broker.upgrade() {
+        Some(b) => {
+            b.deliver(reply, data, None);
+            Ok(())
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "In-memory lattice broker no longer exists",
+        )),
+    }
+}
+
+// Subject tokens are dot-separated. `*` matches any single token and `>` matches
+// one or more trailing tokens
+fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut subject_tokens = subject.split('.');
+    for p in pattern.split('.') {
+        match (p, subject_tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (p, Some(s)) if p == s => {}
+            _ => return false,
+        }
+    }
+    subject_tokens.next().is_none()
+}
+
+struct MemoryConnection {
+    broker: Arc<Broker>,
+    subs: Mutex<Vec<u64>>,
+    closed: AtomicBool,
+    on_disconnect: DisconnectCallback,
+}
+
+impl MemoryConnection {
+    fn check(&self) -> io::Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            Err(not_connected())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn drop_connection(&self) {
+        self.close();
+        (self.on_disconnect)();
+    }
+}
+
+impl Transport for MemoryConnection {
+    fn publish(&self, subject: &str, data: &[u8]) -> io::Result<()> {
+        self.check()?;
+        self.broker.deliver(subject, data, None);
+        Ok(())
+    }
+
+    fn request(&self, subject: &str, data: &[u8], timeout: Duration) -> io::Result<Message> {
+        self.check()?;
+        self.broker.request(subject, data, timeout)
+    }
+
+    fn request_all(
+        &self,
+        subject: &str,
+        data: &[u8],
+        timeout: Duration,
+    ) -> io::Result<Vec<Message>> {
+        self.check()?;
+        Ok(self.broker.request_all(subject, data, timeout))
+    }
+
+    fn subscribe(
+        &self,
+        subject: &str,
+        queue: Option<&str>,
+        handler: Handler,
+    ) -> io::Result<Box<dyn Subscription>> {
+        self.check()?;
+        let id = self.broker.add_subscription(subject, queue, handler);
+        self.subs.lock().unwrap().push(id);
+        Ok(Box::new(MemorySubscription {
+            broker: self.broker.clone(),
+            id,
+        }))
+    }
+
+    fn is_connected(&self) -> bool {
+        !self.closed.load(Ordering::SeqCst)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        for id in self.subs.lock().unwrap().drain(..) {
+            self.broker.remove(id);
+        }
+    }
+}
+
+struct MemorySubscription {
+    broker: Arc<Broker>,
+    id: u64,
+}
+
+impl Subscription for MemorySubscription {
+    fn unsubscribe(self: Box<Self>) -> io::Result<()> {
+        self.broker.remove(self.id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        subject_matches, ConnectionState, LatticeBroker, ReconnectingTransport, Transport,
+    };
+    use crate::bus::lattice::ConnectionPolicy;
+    use std::time::Duration;
+
+    #[test]
+    fn subject_wildcards() {
+        assert!(subject_matches("wasmbus.actor.Mxxx", "wasmbus.actor.Mxxx"));
+        assert!(!subject_matches("wasmbus.actor.Mxxx", "wasmbus.actor.Myyy"));
+        assert!(subject_matches(
+            "wasmbus.inventory.*",
+            "wasmbus.inventory.hosts"
+        ));
+        assert!(!subject_matches("wasmbus.inventory.*", "wasmbus.inventory"));
+        assert!(!subject_matches(
+            "wasmbus.inventory.*",
+            "wasmbus.inventory.a.b"
+        ));
+        assert!(subject_matches(
+            "wasmbus.control.>",
+            "wasmbus.control.Nxxx.identity"
+        ));
+        assert!(!subject_matches("wasmbus.control.>", "wasmbus.control"));
+        assert!(!subject_matches("wasmbus.actor", "wasmbus.actor.Mxxx"));
+    }
+
+    #[test]
+    fn queue_group_delivers_once() {
+        let broker = LatticeBroker::new();
+        let a = broker.inner.connect(Box::new(|| {}));
+        let b = broker.inner.connect(Box::new(|| {}));
+        for c in &[&a, &b] {
+            c.subscribe(
+                "test.queue",
+                Some("test.queue"),
+                Box::new(|m| m.respond(b"pong")),
+            )
+            .unwrap();
+        }
+        let replies = broker.request_all("test.queue", b"ping", Duration::from_millis(200));
+        assert_eq!(1, replies.len());
+        assert_eq!(b"pong".to_vec(), replies[0]);
+
+        a.close();
+        b.close();
+        assert!(broker
+            .request("test.queue", b"ping", Duration::from_millis(200))
+            .is_err());
+    }
+
+    #[test]
+    fn resubscribes_after_interruption() {
+        let broker = LatticeBroker::new();
+        let policy = ConnectionPolicy {
+            connect_retries: 0,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+        };
+        let (rt, states) = ReconnectingTransport::start(broker.connector(), policy);
+        rt.subscribe("test.echo", None, Box::new(|m| m.respond(&m.data)))
+            .unwrap();
+        let timeout = Duration::from_millis(200);
+        assert_eq!(
+            b"hi".to_vec(),
+            broker.request("test.echo", b"hi", timeout).unwrap()
+        );
+
+        broker.interrupt();
+        assert_eq!(
+            ConnectionState::Disconnected,
+            states.recv_timeout(timeout).unwrap()
+        );
+        assert_eq!(
+            ConnectionState::Reconnected { resubscribed: 1 },
+            states.recv_timeout(timeout).unwrap()
+        );
+        assert!(rt.is_connected());
+        assert_eq!(
+            b"hi".to_vec(),
+            broker.request("test.echo", b"hi", timeout).unwrap()
+        );
+        rt.close();
+    }
+}
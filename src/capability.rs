@@ -1,7 +1,12 @@
 use crate::Result;
 use libloading::Library;
 use libloading::Symbol;
+use provider_archive::ProviderArchive;
 use std::ffi::OsStr;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use uuid::Uuid;
 use wascc_codec::{
     capabilities::{CapabilityDescriptor, CapabilityProvider, OP_GET_CAPABILITY_DESCRIPTOR},
     deserialize, SYSTEM_ACTOR,
@@ -53,6 +58,58 @@ impl NativeCapability {
         })
     }
 
+    /// Reads a capability provider from a file, which is treated as a provider archive if its
+    /// extension is `.par` and as a plugin library otherwise
+    pub fn from_path(path: impl AsRef<Path>, binding_target_name: Option<String>) -> Result<Self> {
+        let is_archive = path
+            .as_ref()
+            .extension()
+            .map_or(false, |e| e.to_string_lossy().to_lowercase() == "par");
+        if is_archive {
+            NativeCapability::from_archive_file(path, binding_target_name)
+        } else {
+            NativeCapability::from_file(path.as_ref(), binding_target_name)
+        }
+    }
+
+    /// Reads a capability provider from a provider archive (`.par` file), loading the plugin
+    /// library the archive contains for this host's CPU architecture and operating system
+    pub fn from_archive_file(
+        path: impl AsRef<Path>,
+        binding_target_name: Option<String>,
+    ) -> Result<Self> {
+        let mut buf = Vec::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+        let par = ProviderArchive::try_load(&buf)?;
+        let target = format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS);
+        NativeCapability::from_archive(&par, &target, binding_target_name)
+    }
+
+    // Loads the plugin library for the given target (`{arch}-{os}`) from a provider archive
+    pub(crate) fn from_archive(
+        par: &ProviderArchive,
+        target: &str,
+        binding_target_name: Option<String>,
+    ) -> Result<Self> {
+        let bytes = par.target_bytes(target).ok_or_else(|| {
+            format!(
+                "Provider archive has no library for {} (targets: {})",
+                target,
+                par.targets().join(", ")
+            )
+        })?;
+        let library = std::env::temp_dir().join(format!(
+            "{}.{}",
+            Uuid::new_v4(),
+            std::env::consts::DLL_EXTENSION
+        ));
+        std::fs::write(&library, bytes)?;
+        let res = NativeCapability::from_file(&library, binding_target_name);
+        // A loaded library stays mapped after its file is removed (other than on Windows)
+        let _ = std::fs::remove_file(&library);
+        res
+    }
+
     /// This function is to be used for _capability embedding_. If you are building a custom
     /// waSCC host and have a fixed set of capabilities that you want to always be available
     /// to actors, then you can declare a dependency on the capability provider, enable
@@ -0,0 +1,474 @@
+//! # Control Socket
+//!
+//! Operates a running host from the same machine over a Unix domain socket, enabled with the
+//! `control_socket` feature. A `ControlServer` serves a host on a socket file that only the user
+//! running the host can access (the socket is created in a private directory and only moved to
+//! its path once its permissions are restricted), and a `ControlClient` (used by `wascc-host ctl`) calls actors,
+//! lists the host's inventory, sets and removes bindings, live-updates actors and requests a
+//! graceful shutdown through it.
+//!
+//! Each connection carries a single command and its response, each a line of JSON. At most 16
+//! connections are served at a time, and each must send its command within 10 seconds.
+
+use crate::{errors, Actor, Host, Result};
+use data_encoding::BASE64;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// The number of connections served at the same time. Further connections are refused
+const MAX_CONNECTIONS: usize = 16;
+// How long a client has to send its command once connected
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The actors, capability providers and bindings of a host
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Inventory {
+    pub actors: Vec<InventoryActor>,
+    pub capabilities: Vec<InventoryCapability>,
+    pub bindings: Vec<InventoryBinding>,
+}
+
+/// An actor running in a host
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InventoryActor {
+    pub id: String,
+    pub name: String,
+    pub capabilities: Vec<String>,
+}
+
+/// A native capability provider running in a host
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InventoryCapability {
+    pub capid: String,
+    pub binding: String,
+    pub name: String,
+    pub version: String,
+}
+
+/// A binding known to a host. Secret configuration values are redacted
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InventoryBinding {
+    pub actor: String,
+    pub capability: String,
+    pub binding: String,
+    pub values: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum ControlCommand {
+    CallActor {
+        actor: String,
+        operation: String,
+        payload: String,
+    },
+    Inventory,
+    SetBinding {
+        actor: String,
+        capability: String,
+        binding: Option<String>,
+        values: HashMap<String, String>,
+        secrets: Vec<String>,
+    },
+    RemoveBinding {
+        actor: String,
+        capability: String,
+        binding: Option<String>,
+    },
+    ReplaceActor {
+        module: String,
+    },
+    Shutdown,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+enum ControlResponse {
+    Ok,
+    Payload { payload: String },
+    Inventory(Inventory),
+    Error { message: String },
+}
+
+/// Serves a host's control socket. The socket file is removed when this is dropped
+pub struct ControlServer {
+    path: PathBuf,
+    stopping: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ControlServer {
+    /// Listens for control commands on a Unix domain socket at the given path, replacing a
+    /// stale socket file left behind by a previous host. The server doesn't shut the host down
+    /// itself: `on_shutdown` is called when a client requests a graceful shutdown
+    pub fn start(
+        host: Host,
+        path: impl AsRef<Path>,
+        on_shutdown: impl Fn() + Send + Sync + 'static,
+    ) -> Result<ControlServer> {
+        let path = path.as_ref().to_path_buf();
+        if let Ok(md) = std::fs::symlink_metadata(&path) {
+            if !md.file_type().is_socket() {
+                return Err(format!("{} exists and is not a socket", path.display()).into());
+            }
+            if UnixStream::connect(&path).is_ok() {
+                return Err(format!("Control socket {} is already in use", path.display()).into());
+            }
+            std::fs::remove_file(&path)?;
+        }
+        let listener = bind_private(&path)?;
+
+        let stopping = Arc::new(AtomicBool::new(false));
+        let stopping2 = stopping.clone();
+        let on_shutdown: Arc<dyn Fn() + Send + Sync> = Arc::new(on_shutdown);
+        let active = Arc::new(AtomicUsize::new(0));
+        let handle = std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopping2.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                            active.fetch_sub(1, Ordering::SeqCst);
+                            warn!("Refusing control socket connection: too many connections");
+                            let _ = write_message(
+                                &stream,
+                                &ControlResponse::Error {
+                                    message: "Too many control socket connections".into(),
+                                },
+                            );
+                            continue;
+                        }
+                        let host = host.clone();
+                        let on_shutdown = on_shutdown.clone();
+                        let active = active.clone();
+                        std::thread::spawn(move || {
+                            if let Err(e) = serve_connection(&stream, &host, on_shutdown.as_ref()) {
+                                error!("Control socket connection failed: {}", e);
+                            }
+                            active.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    }
+                    Err(e) => error!("Control socket error: {}", e),
+                }
+            }
+        });
+        info!("Control socket listening on {}", path.display());
+
+        Ok(ControlServer {
+            path,
+            stopping,
+            handle: Some(handle),
+        })
+    }
+}
+
+// Binds the socket in a new directory that only this user can access, restricts the socket's
+// permissions and only then moves it to its path, so that no other user can ever connect to it
+fn bind_private(path: &Path) -> Result<UnixListener> {
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let dir = parent.join(format!(".wascc-ctl-{}", uuid::Uuid::new_v4()));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp = dir.join("ctl.sock");
+    let res = UnixListener::bind(&tmp).and_then(|listener| {
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&tmp, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&tmp);
+    let _ = std::fs::remove_dir(&dir);
+    Ok(res?)
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // Wake the listener up so it notices it's stopping
+        let _ = UnixStream::connect(&self.path);
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("Error terminating the control socket thread");
+            }
+        }
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A client of a host's control socket
+pub struct ControlClient {
+    path: PathBuf,
+}
+
+impl ControlClient {
+    pub fn new(path: impl AsRef<Path>) -> ControlClient {
+        ControlClient {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Invokes an operation on an actor running in the host, returning the actor's response
+    pub fn call_actor(&self, actor: &str, operation: &str, payload: &[u8]) -> Result<Vec<u8>> {
+        match self.request(&ControlCommand::CallActor {
+            actor: actor.to_string(),
+            operation: operation.to_string(),
+            payload: BASE64.encode(payload),
+        })? {
+            ControlResponse::Payload { payload } => decode(&payload),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Lists the actors, capability providers and bindings of the host
+    pub fn inventory(&self) -> Result<Inventory> {
+        match self.request(&ControlCommand::Inventory)? {
+            ControlResponse::Inventory(inv) => Ok(inv),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Binds an actor to a capability provider, the same as `Host::set_binding_with_secrets`
+    pub fn set_binding(
+        &self,
+        actor: &str,
+        capid: &str,
+        binding_name: Option<String>,
+        config: HashMap<String, String>,
+        secret_keys: &[String],
+    ) -> Result<()> {
+        self.request_ok(&ControlCommand::SetBinding {
+            actor: actor.to_string(),
+            capability: capid.to_string(),
+            binding: binding_name,
+            values: config,
+            secrets: secret_keys.to_vec(),
+        })
+    }
+
+    /// Removes a binding between an actor and a capability provider
+    pub fn remove_binding(
+        &self,
+        actor: &str,
+        capid: &str,
+        binding_name: Option<String>,
+    ) -> Result<()> {
+        self.request_ok(&ControlCommand::RemoveBinding {
+            actor: actor.to_string(),
+            capability: capid.to_string(),
+            binding: binding_name,
+        })
+    }
+
+    /// Live-updates the running actor that has the same public key as the given signed module
+    pub fn replace_actor(&self, module: &[u8]) -> Result<()> {
+        self.request_ok(&ControlCommand::ReplaceActor {
+            module: BASE64.encode(module),
+        })
+    }
+
+    /// Asks the host process to shut down gracefully
+    pub fn shutdown(&self) -> Result<()> {
+        self.request_ok(&ControlCommand::Shutdown)
+    }
+
+    fn request_ok(&self, cmd: &ControlCommand) -> Result<()> {
+        match self.request(cmd)? {
+            ControlResponse::Ok => Ok(()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn request(&self, cmd: &ControlCommand) -> Result<ControlResponse> {
+        let stream = UnixStream::connect(&self.path)?;
+        write_message(&stream, cmd)?;
+        match read_message(&stream)? {
+            Some(ControlResponse::Error { message }) => {
+                Err(errors::new(errors::ErrorKind::MiscHost(message)))
+            }
+            Some(res) => Ok(res),
+            None => Err(unexpected_response()),
+        }
+    }
+}
+
+fn serve_connection(stream: &UnixStream, host: &Host, on_shutdown: &dyn Fn()) -> Result<()> {
+    stream.set_read_timeout(Some(COMMAND_TIMEOUT))?;
+    let res = match read_message(stream) {
+        Ok(Some(cmd)) => handle(host, cmd, on_shutdown),
+        // e.g. the connection that wakes up a stopping server
+        Ok(None) => return Ok(()),
+        Err(e) => ControlResponse::Error {
+            message: e.to_string(),
+        },
+    };
+    write_message(stream, &res)
+}
+
+fn handle(host: &Host, cmd: ControlCommand, on_shutdown: &dyn Fn()) -> ControlResponse {
+    let res = match cmd {
+        ControlCommand::CallActor {
+            actor,
+            operation,
+            payload,
+        } => decode(&payload)
+            .and_then(|p| host.call_actor(&actor, &operation, &p))
+            .map(|r| ControlResponse::Payload {
+                payload: BASE64.encode(&r),
+            }),
+        ControlCommand::Inventory => Ok(ControlResponse::Inventory(inventory(host))),
+        ControlCommand::SetBinding {
+            actor,
+            capability,
+            binding,
+            values,
+            secrets,
+        } => host
+            .set_binding_with_secrets(&actor, &capability, binding, values, &secrets)
+            .map(|_| ControlResponse::Ok),
+        ControlCommand::RemoveBinding {
+            actor,
+            capability,
+            binding,
+        } => host
+            .remove_binding(&actor, &capability, binding)
+            .map(|_| ControlResponse::Ok),
+        ControlCommand::ReplaceActor { module } => decode(&module)
+            .and_then(|m| Actor::from_slice(&m))
+            .and_then(|actor| {
+                if host.claims_for_actor(&actor.public_key()).is_none() {
+                    Err(format!("Actor {} is not running in this host", actor.public_key()).into())
+                } else {
+                    host.replace_actor(actor)
+                }
+            })
+            .map(|_| ControlResponse::Ok),
+        ControlCommand::Shutdown => {
+            info!("Shutdown requested through the control socket");
+            on_shutdown();
+            Ok(ControlResponse::Ok)
+        }
+    };
+    res.unwrap_or_else(|e| ControlResponse::Error {
+        message: e.to_string(),
+    })
+}
+
+fn inventory(host: &Host) -> Inventory {
+    Inventory {
+        actors: host
+            .actors()
+            .into_iter()
+            .map(|(id, claims)| {
+                let md = claims.metadata.unwrap_or_default();
+                InventoryActor {
+                    id,
+                    name: md.name.unwrap_or_default(),
+                    capabilities: md.caps.unwrap_or_default(),
+                }
+            })
+            .collect(),
+        capabilities: host
+            .capabilities()
+            .into_iter()
+            .map(|((binding, capid), d)| InventoryCapability {
+                capid,
+                binding,
+                name: d.name,
+                version: d.version,
+            })
+            .collect(),
+        bindings: host
+            .bindings()
+            .into_iter()
+            .map(|((actor, capability, binding), values)| InventoryBinding {
+                actor,
+                capability,
+                binding,
+                values,
+            })
+            .collect(),
+    }
+}
+
+fn read_message<T: DeserializeOwned>(stream: &UnixStream) -> Result<Option<T>> {
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+    serde_json::from_str(&line)
+        .map(Some)
+        .map_err(|e| errors::new(errors::ErrorKind::Serialization(e.to_string())))
+}
+
+fn write_message(mut stream: &UnixStream, msg: &impl Serialize) -> Result<()> {
+    let mut buf = serde_json::to_vec(msg)
+        .map_err(|e| errors::new(errors::ErrorKind::Serialization(e.to_string())))?;
+    buf.push(b'\n');
+    stream.write_all(&buf)?;
+    Ok(())
+}
+
+fn decode(data: &str) -> Result<Vec<u8>> {
+    BASE64
+        .decode(data.as_bytes())
+        .map_err(|e| errors::new(errors::ErrorKind::Serialization(e.to_string())))
+}
+
+fn unexpected_response() -> errors::Error {
+    errors::new(errors::ErrorKind::MiscHost(
+        "Unexpected response from the control socket".into(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{inventory, ControlClient, ControlServer};
+    use crate::Host;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn serves_inventory_and_shutdown() {
+        let path = std::env::temp_dir().join(format!("wascc-ctl-{}.sock", uuid::Uuid::new_v4()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let shutdown2 = shutdown.clone();
+        let host = Host::new();
+        let server = ControlServer::start(host.clone(), &path, move || {
+            shutdown2.store(true, Ordering::SeqCst)
+        })
+        .unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        let client = ControlClient::new(&path);
+
+        let inv = client.inventory().unwrap();
+        assert_eq!(inventory(&host), inv);
+        assert!(inv.actors.is_empty());
+        assert!(inv.bindings.is_empty());
+        let err = client
+            .call_actor("Mxxx", "HandleRequest", b"hi")
+            .unwrap_err();
+        assert!(err.to_string().ends_with("No such actor"));
+        assert!(client.replace_actor(b"not a module").is_err());
+
+        client.shutdown().unwrap();
+        assert!(shutdown.load(Ordering::SeqCst));
+
+        drop(server);
+        assert!(!path.exists());
+    }
+}
@@ -1,5 +1,11 @@
 use crate::bus::MessageBus;
+use crate::errors::{self, ErrorKind};
 use crate::inthost::{Invocation, WasccEntity};
+use crate::trace;
+use crate::Authorizer;
+use crossbeam::Sender;
+use std::collections::HashMap;
+use std::sync::RwLock;
 use std::{error::Error, sync::Arc};
 
 use wascap::prelude::KeyPair;
@@ -10,21 +16,41 @@ use wascc_codec::capabilities::Dispatcher;
 /// is one way, and is _not_ used for the guest module to send commands to capabilities
 #[derive(Clone)]
 pub(crate) struct WasccNativeDispatcher {
-    bus: Arc<MessageBus>,
+    bus: Arc<dyn MessageBus>,
     capid: String,
     binding: String,
     hk: Arc<KeyPair>,
+    terminators: Arc<RwLock<HashMap<String, Sender<bool>>>>,
+    authorizer: Arc<RwLock<Box<dyn Authorizer>>>,
 }
 
 impl WasccNativeDispatcher {
-    pub fn new(hk: Arc<KeyPair>, bus: Arc<MessageBus>, capid: &str, binding: &str) -> Self {
+    pub fn new(
+        hk: Arc<KeyPair>,
+        bus: Arc<dyn MessageBus>,
+        capid: &str,
+        binding: &str,
+        terminators: Arc<RwLock<HashMap<String, Sender<bool>>>>,
+        authorizer: Arc<RwLock<Box<dyn Authorizer>>>,
+    ) -> Self {
         WasccNativeDispatcher {
             bus,
             capid: capid.to_string(),
             binding: binding.to_string(),
             hk,
+            terminators,
+            authorizer,
         }
     }
+
+    // An actor is bound to this provider instance when the private actor-provider
+    // subscription created by a successful bind is running
+    fn is_bound(&self, actor: &str) -> bool {
+        let subject = self
+            .bus
+            .provider_subject_bound_actor(&self.capid, &self.binding, actor);
+        self.terminators.read().unwrap().contains_key(&subject)
+    }
 }
 
 impl Dispatcher for WasccNativeDispatcher {
@@ -40,16 +66,39 @@ impl Dispatcher for WasccNativeDispatcher {
             op,
             msg.len()
         );
-        let inv = Invocation::new(
+        let origin = WasccEntity::Capability {
+            capid: self.capid.to_string(),
+            binding: self.binding.to_string(),
+        };
+        if !self.is_bound(actor) {
+            return Err(Box::new(errors::new(ErrorKind::Authorization(format!(
+                "Provider {},{} attempted to dispatch '{}' to actor {} that is not bound to it - PERMISSION DENIED.",
+                self.capid, self.binding, op, actor
+            )))));
+        }
+        if !self
+            .authorizer
+            .read()
+            .unwrap()
+            .can_dispatch(&origin, actor, op)
+        {
+            return Err(Box::new(errors::new(ErrorKind::Authorization(format!(
+                "Provider {},{} attempted to dispatch '{}' to actor {} - Authorizer denied access",
+                self.capid, self.binding, op, actor
+            )))));
+        }
+        let mut inv = Invocation::new(
             &self.hk,
-            WasccEntity::Capability {
-                capid: self.capid.to_string(),
-                binding: self.binding.to_string(),
-            },
+            origin,
             WasccEntity::Actor(actor.to_string()),
             op,
             msg.to_vec(),
         );
+        // Requests that arrived at the provider as part of a trace continue it, rather than
+        // starting a new one
+        if let Some(remote) = trace::from_dispatch(op, msg) {
+            inv.trace_context = remote.child();
+        }
         let tgt_sub = self.bus.actor_subject(actor);
         let resp = self.bus.invoke(&tgt_sub, inv);
 
@@ -62,3 +111,91 @@ impl Dispatcher for WasccNativeDispatcher {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::WasccNativeDispatcher;
+    use crate::bus::{InprocBus, MessageBus};
+    use crate::{Authorizer, Invocation, InvocationResponse, WasccEntity};
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+    use wascap::jwt::{Actor, Claims};
+    use wascap::prelude::KeyPair;
+    use wascc_codec::capabilities::Dispatcher;
+
+    struct DenyOperation(&'static str);
+
+    impl Authorizer for DenyOperation {
+        fn can_load(&self, _claims: &Claims<Actor>) -> bool {
+            true
+        }
+
+        fn can_invoke(&self, _claims: &Claims<Actor>, _target: &WasccEntity, _op: &str) -> bool {
+            true
+        }
+
+        fn can_dispatch(&self, _origin: &WasccEntity, _actor: &str, operation: &str) -> bool {
+            operation != self.0
+        }
+    }
+
+    #[test]
+    fn denied_dispatches_never_reach_the_actor() {
+        let bus = Arc::new(InprocBus::new());
+        let (inv_s, inv_r) = crossbeam_channel::unbounded();
+        let (resp_s, resp_r) = crossbeam_channel::unbounded();
+        bus.subscribe(&bus.actor_subject("Mxxx"), inv_s, resp_r)
+            .unwrap();
+        let terminators = Arc::new(RwLock::new(HashMap::new()));
+        let authorizer: Box<dyn Authorizer> = Box::new(DenyOperation("Forbidden"));
+        let dispatcher = WasccNativeDispatcher::new(
+            Arc::new(KeyPair::new_server()),
+            bus.clone(),
+            "wascc:testing",
+            "default",
+            terminators.clone(),
+            Arc::new(RwLock::new(authorizer)),
+        );
+
+        let e = dispatcher.dispatch("Mxxx", "Allowed", b"abc").unwrap_err();
+        assert!(e
+            .to_string()
+            .contains("is not bound to it - PERMISSION DENIED"));
+        assert!(inv_r.is_empty());
+
+        let (term_s, _term_r) = crossbeam_channel::unbounded();
+        terminators.write().unwrap().insert(
+            bus.provider_subject_bound_actor("wascc:testing", "default", "Mxxx"),
+            term_s,
+        );
+        let e = dispatcher
+            .dispatch("Mxxx", "Forbidden", b"abc")
+            .unwrap_err();
+        assert!(e.to_string().contains("Authorizer denied access"));
+        assert!(inv_r.is_empty());
+
+        let inv = Invocation::new(
+            &KeyPair::new_server(),
+            WasccEntity::Actor("Mxxx".to_string()),
+            WasccEntity::Actor("Mxxx".to_string()),
+            "Allowed",
+            vec![],
+        );
+        resp_s
+            .send(InvocationResponse::success(&inv, b"xyz".to_vec()))
+            .unwrap();
+        assert_eq!(
+            b"xyz".to_vec(),
+            dispatcher.dispatch("Mxxx", "Allowed", b"abc").unwrap()
+        );
+        let delivered = inv_r.try_recv().unwrap();
+        assert_eq!("Allowed", delivered.operation);
+        assert_eq!(
+            WasccEntity::Capability {
+                capid: "wascc:testing".to_string(),
+                binding: "default".to_string(),
+            },
+            delivered.origin
+        );
+    }
+}
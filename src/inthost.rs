@@ -8,10 +8,9 @@ use ring::digest::{Context, Digest, SHA256};
 use crate::bus;
 use crate::bus::MessageBus;
 use crate::BindingsList;
-use crate::{authz, errors, Actor, Authorizer, NativeCapability, RouteKey};
+use crate::{authz, errors, Actor, Authorizer, NativeCapability, RouteKey, TraceContext};
 use errors::ErrorKind;
 use provider_archive::ProviderArchive;
-use std::str::FromStr;
 use std::{
     collections::HashMap,
     io::Read,
@@ -29,17 +28,26 @@ use wascc_codec::{
 pub(crate) const CORELABEL_ARCH: &str = "hostcore.arch";
 pub(crate) const CORELABEL_OS: &str = "hostcore.os";
 pub(crate) const CORELABEL_OSFAMILY: &str = "hostcore.osfamily";
+pub(crate) const CORELABEL_SEALKEY: &str = "hostcore.sealkey";
+
+// Prefixes the hashed bytes of invocations that carry headers
+const HEADERS_HASH_TAG: &[u8] = b"wascc.invocation.headers.v1\0";
 
 pub(crate) const OCI_VAR_USER: &str = "OCI_REGISTRY_USER";
 pub(crate) const OCI_VAR_PASSWORD: &str = "OCI_REGISTRY_PASSWORD";
 
 #[allow(dead_code)]
-pub(crate) const RESTRICTED_LABELS: [&str; 3] = [CORELABEL_OSFAMILY, CORELABEL_ARCH, CORELABEL_OS];
+pub(crate) const RESTRICTED_LABELS: [&str; 4] = [
+    CORELABEL_OSFAMILY,
+    CORELABEL_ARCH,
+    CORELABEL_OS,
+    CORELABEL_SEALKEY,
+];
 
 // Unsubscribes all of the private actor-provider comms subjects
 pub(crate) fn unsub_all_bindings(
     bindings: Arc<RwLock<BindingsList>>,
-    bus: Arc<MessageBus>,
+    bus: Arc<dyn MessageBus>,
     capid: &str,
 ) {
     bindings
@@ -84,6 +92,35 @@ impl Host {
 }
 
 /// In the case of a portable capability provider, obtain its capability descriptor
+// Downloads an image from an OCI registry. This build of the host has no OCI registry
+// client, so remote actor and provider references can't be resolved
+pub(crate) fn fetch_oci_bytes(img: &str) -> Result<Vec<u8>> {
+    Err(errors::new(ErrorKind::MiscHost(format!(
+        "Cannot download {}: this host was built without an OCI registry client",
+        img
+    ))))
+}
+
+/// Downloads an actor from an OCI registry
+pub(crate) fn fetch_actor(actor_ref: &str) -> Result<Actor> {
+    Actor::from_slice(&fetch_oci_bytes(actor_ref)?)
+}
+
+/// Downloads a provider archive from an OCI registry and loads the plugin library for the
+/// architecture and operating system named in the host's labels
+pub(crate) fn fetch_provider(
+    provider_ref: &str,
+    binding_name: &str,
+    labels: Arc<RwLock<HashMap<String, String>>>,
+) -> Result<NativeCapability> {
+    let par = ProviderArchive::try_load(&fetch_oci_bytes(provider_ref)?)?;
+    let target = {
+        let labels = labels.read().unwrap();
+        format!("{}-{}", labels[CORELABEL_ARCH], labels[CORELABEL_OS])
+    };
+    NativeCapability::from_archive(&par, &target, Some(binding_name.to_string()))
+}
+
 pub(crate) fn get_descriptor(host: &mut WapcHost) -> Result<CapabilityDescriptor> {
     let msg = wascc_codec::core::HealthRequest { placeholder: false }; // TODO: eventually support sending an empty slice for this
     let res = host.call(OP_GET_CAPABILITY_DESCRIPTOR, &serialize(&msg)?)?;
@@ -102,7 +139,7 @@ pub(crate) fn remove_cap(
 /// as soon as it is pulled off the channel for the target actor
 pub(crate) fn replace_actor(
     hostkey: &KeyPair,
-    bus: Arc<MessageBus>,
+    bus: Arc<dyn MessageBus>,
     new_actor: Actor,
 ) -> Result<()> {
     let public_key = new_actor.token.claims.subject;
@@ -142,19 +179,16 @@ fn gen_liveupdate_invocation(hostkey: &KeyPair, target: &str, bytes: Vec<u8>) ->
 /// to each of the capabilities
 pub(crate) fn deconfigure_actor(
     hostkey: KeyPair,
-    bus: Arc<MessageBus>,
+    bus: Arc<dyn MessageBus>,
     bindings: Arc<RwLock<BindingsList>>,
     key: &str,
 ) {
-    #[cfg(feature = "lattice")]
-    {
-        // Don't remove the bindings for this actor unless it's the last instance in the lattice
-        if let Ok(i) = bus.instance_count(key) {
-            if i > 0 {
-                // This is 0 because the actor being removed has already been taken out of the claims map, so bus queries will not see the local instance
-                info!("Actor instance terminated at scale > 1, bypassing binding removal.");
-                return;
-            }
+    // Don't remove the bindings for this actor unless it's the last instance in the lattice
+    if let Ok(i) = bus.instance_count(key) {
+        if i > 0 {
+            // This is 0 because the actor being removed has already been taken out of the claims map, so bus queries will not see the local instance
+            info!("Actor instance terminated at scale > 1, bypassing binding removal.");
+            return;
         }
     }
     let cfg = CapabilityConfiguration {
@@ -228,10 +262,38 @@ pub struct Invocation {
     pub id: String,
     pub encoded_claims: String,
     pub host_id: String,
+    /// The span this invocation represents in a distributed trace. The trace context is not
+    /// covered by the invocation's signed claims. Middleware may change it: the calls an actor
+    /// makes while handling the invocation are child spans of the context the invocation
+    /// carries when it reaches the actor, after every pre-invoke and invoke hook has run
+    #[cfg_attr(feature = "lattice", serde(default = "TraceContext::new_root"))]
+    pub trace_context: TraceContext,
+    /// Metadata such as a tenant ID, correlation ID or deadline. Headers are covered by the
+    /// invocation's signed claims, and are passed on to the calls an actor makes while handling
+    /// the invocation
+    #[cfg_attr(feature = "lattice", serde(default))]
+    pub headers: HashMap<String, String>,
+}
+
+// The trace context and headers of the invocation a guest is handling, which carry over to
+// the calls the guest makes while handling it
+#[derive(Debug, Clone)]
+pub(crate) struct CallContext {
+    trace_context: TraceContext,
+    headers: HashMap<String, String>,
+}
+
+impl CallContext {
+    pub(crate) fn from_invocation(inv: &Invocation) -> CallContext {
+        CallContext {
+            trace_context: inv.trace_context.clone(),
+            headers: inv.headers.clone(),
+        }
+    }
 }
 
 /// Represents an invocation target - either an actor or a bound capability provider
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "lattice", derive(serde::Serialize, serde::Deserialize))]
 pub enum WasccEntity {
     Actor(String),
@@ -259,26 +321,45 @@ impl Invocation {
         target: WasccEntity,
         op: &str,
         msg: Vec<u8>,
+    ) -> Invocation {
+        Invocation::new_with_headers(hostkey, origin, target, op, msg, HashMap::new())
+    }
+
+    /// Creates a signed invocation carrying the given headers
+    pub fn new_with_headers(
+        hostkey: &KeyPair,
+        origin: WasccEntity,
+        target: WasccEntity,
+        op: &str,
+        msg: Vec<u8>,
+        headers: HashMap<String, String>,
     ) -> Invocation {
         let subject = format!("{}", Uuid::new_v4());
         let issuer = hostkey.public_key();
-        let target_url = format!("{}/{}", target.url(), op);
-        let claims = Claims::<wascap::prelude::Invocation>::new(
-            issuer.to_string(),
-            subject.to_string(),
-            &target_url,
-            &origin.url(),
-            &invocation_hash(&target_url, &origin.url(), &msg),
-        );
-        Invocation {
+        let mut inv = Invocation {
             origin,
             target,
             operation: op.to_string(),
             msg,
             id: subject,
-            encoded_claims: claims.encode(&hostkey).unwrap(),
+            encoded_claims: String::new(),
             host_id: issuer.to_string(),
-        }
+            trace_context: TraceContext::new_root(),
+            headers,
+        };
+        inv.encoded_claims = inv.encode_claims(hostkey);
+        inv
+    }
+
+    fn encode_claims(&self, hostkey: &KeyPair) -> String {
+        let claims = Claims::<wascap::prelude::Invocation>::new(
+            hostkey.public_key(),
+            self.id.to_string(),
+            &self.target_url(),
+            &self.origin_url(),
+            &self.hash(),
+        );
+        claims.encode(hostkey).unwrap()
     }
 
     pub fn origin_url(&self) -> String {
@@ -290,7 +371,12 @@ impl Invocation {
     }
 
     pub fn hash(&self) -> String {
-        invocation_hash(&self.target_url(), &self.origin_url(), &self.msg)
+        invocation_hash(
+            &self.target_url(),
+            &self.origin_url(),
+            &self.msg,
+            &self.headers,
+        )
     }
 
     pub fn validate_antiforgery(&self) -> Result<()> {
@@ -342,6 +428,35 @@ impl Invocation {
     }
 }
 
+/// Re-signs invocations on behalf of a host. Middleware that modifies the origin, target,
+/// operation or payload of an invocation must re-sign it, otherwise the invocation will
+/// fail its antiforgery check when the host is running in strict mode. Obtain a signer
+/// from `Host::invocation_signer`
+#[derive(Clone)]
+pub struct InvocationSigner {
+    seed: String,
+}
+
+impl InvocationSigner {
+    pub(crate) fn new(seed: &str) -> InvocationSigner {
+        InvocationSigner {
+            seed: seed.to_string(),
+        }
+    }
+
+    /// Produces a new set of signed claims for the invocation in its current state. The
+    /// invocation ID is preserved so that responses can still be correlated
+    pub fn sign(&self, inv: Invocation) -> Invocation {
+        let key = KeyPair::from_seed(&self.seed).unwrap();
+        let mut inv = Invocation {
+            host_id: key.public_key(),
+            ..inv
+        };
+        inv.encoded_claims = inv.encode_claims(&key);
+        inv
+    }
+}
+
 /// The response to an invocation
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "lattice", derive(serde::Serialize, serde::Deserialize))]
@@ -372,12 +487,13 @@ impl InvocationResponse {
 pub(crate) fn wapc_host_callback(
     hostkey: KeyPair,
     claims: Claims<wascap::jwt::Actor>,
-    bus: Arc<MessageBus>,
+    bus: Arc<dyn MessageBus>,
     binding: &str,
     namespace: &str,
     operation: &str,
     payload: &[u8],
     authorizer: Arc<RwLock<Box<dyn Authorizer>>>,
+    parent: Option<CallContext>,
 ) -> std::result::Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
     trace!(
         "Guest {} invoking {}:{}",
@@ -394,6 +510,7 @@ pub(crate) fn wapc_host_callback(
         namespace,
         operation,
         payload,
+        parent,
     );
 
     if !authz::can_invoke(&claims, capability_id, operation) {
@@ -457,6 +574,7 @@ fn invocation_from_callback(
     ns: &str,
     op: &str,
     payload: &[u8],
+    parent: Option<CallContext>,
 ) -> Invocation {
     let binding = if bd.trim().is_empty() {
         // Some actor SDKs may not specify a binding field by default
@@ -472,13 +590,28 @@ fn invocation_from_callback(
             capid: ns.to_string(),
         }
     };
-    Invocation::new(
-        hostkey,
-        WasccEntity::Actor(origin.to_string()),
-        target,
-        op,
-        payload.to_vec(),
-    )
+    match parent {
+        // Calls made while handling an invocation carry its headers and are child spans of it
+        Some(parent) => {
+            let mut inv = Invocation::new_with_headers(
+                hostkey,
+                WasccEntity::Actor(origin.to_string()),
+                target,
+                op,
+                payload.to_vec(),
+                parent.headers,
+            );
+            inv.trace_context = parent.trace_context.child();
+            inv
+        }
+        None => Invocation::new(
+            hostkey,
+            WasccEntity::Actor(origin.to_string()),
+            target,
+            op,
+            payload.to_vec(),
+        ),
+    }
 }
 
 pub(crate) fn gen_config_invocation(
@@ -554,16 +687,93 @@ fn sha256_digest<R: Read>(mut reader: R) -> Result<Digest> {
     Ok(context.finish())
 }
 
-pub fn invocation_hash(target_url: &str, origin_url: &str, msg: &[u8]) -> String {
+pub fn invocation_hash(
+    target_url: &str,
+    origin_url: &str,
+    msg: &[u8],
+    headers: &HashMap<String, String>,
+) -> String {
     use std::io::Write;
     let mut cleanbytes: Vec<u8> = Vec::new();
-    cleanbytes.write(origin_url.as_bytes()).unwrap();
-    cleanbytes.write(target_url.as_bytes()).unwrap();
-    cleanbytes.write(msg).unwrap();
+    if headers.is_empty() {
+        // An invocation without headers hashes the same as it did before headers existed, so
+        // hosts of either version can validate it
+        cleanbytes.write_all(origin_url.as_bytes()).unwrap();
+        cleanbytes.write_all(target_url.as_bytes()).unwrap();
+        cleanbytes.write_all(msg).unwrap();
+    } else {
+        // Every field is length-prefixed so that bytes can't be moved between the payload
+        // and the headers (or between header keys and values) without changing the hash. The
+        // tag keeps this layout from ever matching that of an invocation without headers,
+        // which starts with the origin URL
+        cleanbytes.write_all(HEADERS_HASH_TAG).unwrap();
+        let mut keys: Vec<_> = headers.keys().collect();
+        keys.sort();
+        let mut fields = vec![origin_url.as_bytes(), target_url.as_bytes(), msg];
+        for k in keys {
+            fields.push(k.as_bytes());
+            fields.push(headers[k].as_bytes());
+        }
+        for field in fields {
+            cleanbytes
+                .write_all(&(field.len() as u64).to_be_bytes())
+                .unwrap();
+            cleanbytes.write_all(field).unwrap();
+        }
+    }
     let digest = sha256_digest(cleanbytes.as_slice()).unwrap();
     HEXUPPER.encode(digest.as_ref())
 }
 
+/// Ensures that the supplied seed is a valid server (node) nkey seed, which is the only
+/// kind of key a host can use to sign invocations
+pub(crate) fn validate_host_seed(seed: &str) -> Result<()> {
+    match KeyPair::from_seed(seed.trim()) {
+        Ok(kp) if kp.public_key().starts_with('N') => Ok(()),
+        Ok(_) => Err(errors::new(ErrorKind::MiscHost(
+            "Host seed must be a server nkey seed (prefix SN)".into(),
+        ))),
+        Err(e) => Err(errors::new(ErrorKind::MiscHost(format!(
+            "Invalid host seed: {}",
+            e
+        )))),
+    }
+}
+
+/// Reads the host seed from the given file. If the file does not exist, a new server key is
+/// generated and its seed is written to the file so that the host ID survives restarts
+pub(crate) fn read_or_create_host_seed(path: &std::path::Path) -> Result<String> {
+    if path.exists() {
+        let seed = std::fs::read_to_string(path)?.trim().to_string();
+        validate_host_seed(&seed)?;
+        Ok(seed)
+    } else {
+        let seed = KeyPair::new_server().seed().unwrap();
+        write_seed_file(path, &seed)?;
+        info!("Generated new host seed in {}", path.display());
+        Ok(seed)
+    }
+}
+
+#[cfg(unix)]
+fn write_seed_file(path: &std::path::Path, seed: &str) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(seed.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_seed_file(path: &std::path::Path, seed: &str) -> Result<()> {
+    std::fs::write(path, seed)?;
+    Ok(())
+}
+
 pub(crate) fn detect_core_host_labels() -> HashMap<String, String> {
     let mut hm = HashMap::new();
     hm.insert(
@@ -585,10 +795,39 @@ pub(crate) fn detect_core_host_labels() -> HashMap<String, String> {
 
 #[cfg(test)]
 mod test {
-    use super::Invocation;
+    use super::{invocation_hash, validate_host_seed, Invocation};
     use crate::WasccEntity;
+    use std::collections::HashMap;
     use wascap::prelude::KeyPair;
 
+    #[test]
+    fn resigned_invocation_passes_antiforgery() {
+        let hk = KeyPair::new_server();
+        let signer = super::InvocationSigner::new(&hk.seed().unwrap());
+        let inv = Invocation::new(
+            &hk,
+            WasccEntity::Actor("test".to_string()),
+            WasccEntity::Actor("target".to_string()),
+            "test",
+            b"hello".to_vec(),
+        );
+        let id = inv.id.to_string();
+        let mut tampered = inv.clone();
+        tampered.msg = b"goodbye".to_vec();
+        assert!(tampered.validate_antiforgery().is_err());
+
+        let resigned = signer.sign(tampered);
+        assert!(resigned.validate_antiforgery().is_ok());
+        assert_eq!(resigned.id, id);
+    }
+
+    #[test]
+    fn host_seed_must_be_server_key() {
+        assert!(validate_host_seed(&KeyPair::new_server().seed().unwrap()).is_ok());
+        assert!(validate_host_seed(&KeyPair::new_module().seed().unwrap()).is_err());
+        assert!(validate_host_seed("SNOTAREALSEED").is_err());
+    }
+
     #[test]
     fn invocation_antiforgery() {
         let hostkey = KeyPair::new_server();
@@ -624,4 +863,56 @@ mod test {
             "wasmbus://wascc/messaging/default/OP_TESTING"
         );
     }
+    #[test]
+    fn headers_are_covered_by_antiforgery() {
+        let hostkey = KeyPair::new_server();
+        let mut headers = HashMap::new();
+        headers.insert("tenant".to_string(), "acme".to_string());
+        let inv = Invocation::new_with_headers(
+            &hostkey,
+            WasccEntity::Actor("testing".into()),
+            WasccEntity::Actor("target".into()),
+            "OP_TESTING",
+            vec![1, 2, 3, 4],
+            headers,
+        );
+        assert!(inv.validate_antiforgery().is_ok());
+
+        let mut tampered = inv.clone();
+        tampered
+            .headers
+            .insert("tenant".to_string(), "other".to_string());
+        assert!(tampered.validate_antiforgery().is_err());
+
+        let plain = Invocation::new(
+            &hostkey,
+            WasccEntity::Actor("testing".into()),
+            WasccEntity::Actor("target".into()),
+            "OP_TESTING",
+            vec![1, 2, 3, 4],
+        );
+        assert_ne!(plain.hash(), inv.hash());
+    }
+
+    #[test]
+    fn headers_cannot_be_moved_into_payload() {
+        let target = "wasmbus://M1/Op";
+        let origin = "wasmbus://M2";
+        let mut headers = HashMap::new();
+        headers.insert("k".to_string(), "v".to_string());
+        let stripped = invocation_hash(target, origin, b"M", &HashMap::new());
+        assert_ne!(invocation_hash(target, origin, b"M", &headers), stripped);
+        assert_ne!(
+            invocation_hash(target, origin, b"M", &headers),
+            invocation_hash(target, origin, b"Mk\0v\0", &HashMap::new())
+        );
+
+        let mut forged = HashMap::new();
+        forged.insert("k".to_string(), "v\0k2\0v2".to_string());
+        headers.insert("k2".to_string(), "v2".to_string());
+        assert_ne!(
+            invocation_hash(target, origin, b"M", &headers),
+            invocation_hash(target, origin, b"M", &forged)
+        );
+    }
 }
@@ -68,18 +68,27 @@ extern crate log;
 extern crate crossbeam;
 
 mod actor;
+#[cfg(feature = "admin_api")]
+pub mod admin;
 mod authz;
 mod bus;
 mod capability;
+#[cfg(all(unix, feature = "control_socket"))]
+pub mod control;
 mod dispatch;
 pub mod errors;
 mod extras;
 mod inthost;
+mod lru;
 #[cfg(feature = "manifest")]
 mod manifest;
+mod metrics;
 pub mod middleware;
 mod plugins;
+mod retry;
+mod secrets;
 mod spawns;
+mod trace;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const REVISION: u32 = 2;
@@ -88,7 +97,11 @@ pub type Result<T> = std::result::Result<T, errors::Error>;
 
 pub use actor::Actor;
 pub use capability::NativeCapability;
-pub use inthost::{Invocation, InvocationResponse, WasccEntity};
+pub use inthost::{Invocation, InvocationResponse, InvocationSigner, WasccEntity};
+pub use metrics::{RuntimeMetrics, RuntimeMetricsSource};
+pub use retry::{RetryPolicy, ANY_OPERATION};
+pub use secrets::REDACTED_VALUE;
+pub use trace::TraceContext;
 
 #[cfg(feature = "manifest")]
 pub use manifest::{BindingEntry, HostManifest};
@@ -102,26 +115,47 @@ use latticeclient::BusEvent;
 #[cfg(feature = "lattice")]
 use bus::lattice::ControlCommand;
 
+#[cfg(feature = "lattice")]
+pub use bus::lattice::{BlockedHost, ConnectionEvent, LatticeAuth, SecurityEvent};
+
+#[cfg(feature = "lattice")]
+pub use bus::LatticeBroker;
+
+#[cfg(feature = "lattice")]
+pub use authz::{issue_host_token, HostIdentity};
+
+#[cfg(feature = "lattice")]
+use bus::lattice::{ConnectionPolicy, IdentityConfig, NatsOptions};
+#[cfg(feature = "lattice")]
+use bus::LatticeAdmin;
+
 pub use authz::Authorizer;
-pub use middleware::Middleware;
+pub use bus::{InprocBus, MessageBus};
+pub use middleware::{Middleware, MiddlewareErrorPolicy, MiddlewareInfo, MiddlewareScope};
 pub use wapc::WasiParams;
 
 pub type SubjectClaimsPair = (String, Claims<wascap::jwt::Actor>);
 
-use bus::{get_namespace_prefix, MessageBus};
+use bus::get_namespace_prefix;
 use crossbeam::Sender;
 #[cfg(feature = "lattice")]
 use crossbeam_channel as channel;
 use crossbeam_channel::Receiver;
 #[cfg(any(feature = "lattice", feature = "manifest"))]
 use inthost::RESTRICTED_LABELS;
+use metrics::RuntimeStats;
 use plugins::PluginManager;
+use retry::{RetryRules, RetryingBus};
+use secrets::SecretPolicy;
 use std::path::Path;
 use std::str::FromStr;
+#[cfg(feature = "lattice")]
+use std::time::Duration;
 use std::{
     collections::HashMap,
     sync::{Arc, RwLock},
 };
+use uuid::Uuid;
 use wascap::jwt::Claims;
 use wascap::prelude::KeyPair;
 use wascc_codec::{
@@ -153,25 +187,147 @@ impl RouteKey {
 /// A builder pattern implementation for creating a custom-configured host runtime
 pub struct HostBuilder {
     labels: HashMap<String, String>,
+    #[cfg_attr(not(feature = "lattice"), allow(dead_code))]
     ns: Option<String>,
     authorizer: Box<dyn Authorizer + 'static>,
+    seed: Option<String>,
+    strict: bool,
+    secrets: SecretPolicy,
+    retries: RetryRules,
+    #[cfg(feature = "lattice")]
+    identity: IdentityConfig,
+    #[cfg(feature = "lattice")]
+    seal: bus::seal::SealPolicy,
+    #[cfg(feature = "lattice")]
+    broker: Option<LatticeBroker>,
+    #[cfg(feature = "lattice")]
+    connection: ConnectionPolicy,
+    #[cfg(feature = "lattice")]
+    nats: NatsOptions,
+    bus: Option<Arc<dyn MessageBus>>,
 }
 
 impl HostBuilder {
     /// Creates a new host builder. This builder will initialize itself with some defaults
     /// obtained from the environment. The labels list will pre-populate with the `hostcore.*`
     /// labels, the namespace will be gleaned from the `LATTICE_NAMESPACE` environment variable
-    /// (if lattice mode is enabled), and the default authorizer will be set.
+    /// (if lattice mode is enabled), and the default authorizer will be set. In lattice mode, the
+    /// host identity token and trusted issuers are read from the `LATTICE_HOST_TOKEN` and
+    /// `LATTICE_TRUSTED_ISSUERS` (comma-separated) environment variables.
     pub fn new() -> HostBuilder {
         let b = HostBuilder {
             labels: inthost::detect_core_host_labels(),
             ns: get_namespace_prefix(),
             authorizer: Box::new(authz::DefaultAuthorizer::new()),
+            seed: None,
+            strict: false,
+            secrets: SecretPolicy::default(),
+            retries: RetryRules::default(),
+            #[cfg(feature = "lattice")]
+            identity: IdentityConfig::from_env(),
+            #[cfg(feature = "lattice")]
+            seal: bus::seal::SealPolicy::from_env(),
+            #[cfg(feature = "lattice")]
+            broker: None,
+            #[cfg(feature = "lattice")]
+            connection: ConnectionPolicy::from_env(),
+            #[cfg(feature = "lattice")]
+            nats: NatsOptions::from_env(),
+            bus: None,
         };
 
         b
     }
 
+    /// Sets the message bus the host uses to deliver invocations, replacing the default
+    /// in-process bus (or the lattice bus when the `lattice` feature is enabled). Supplying an
+    /// `InprocBus` allows a host built with the `lattice` feature to run without a lattice.
+    /// In strict antiforgery mode, a supplied bus that doesn't validate invocations itself is
+    /// wrapped so that every invocation it delivers has its signed claims validated.
+    pub fn with_bus(self, bus: impl MessageBus + 'static) -> HostBuilder {
+        HostBuilder {
+            bus: Some(Arc::new(bus)),
+            ..self
+        }
+    }
+
+    /// Enables strict antiforgery mode. In strict mode every invocation delivered over the
+    /// in-process bus (or a bus supplied with `with_bus`) has its signed claims validated, and
+    /// every invocation is validated again as the middleware hands it to the actor or provider.
+    /// Middleware that modifies an invocation, in any hook, must re-sign it with the signer
+    /// obtained from `Host::invocation_signer`
+    pub fn with_strict_antiforgery(self) -> HostBuilder {
+        HostBuilder {
+            strict: true,
+            ..self
+        }
+    }
+
+    /// Adds a pattern to the list used to classify binding configuration values as secret. Any
+    /// configuration key that contains the pattern (ignoring case) is treated as secret. Secret
+    /// values are still delivered to the capability provider, but are redacted in lattice
+    /// inventory responses. Common patterns such as `password`, `secret` and `token` are
+    /// included by default
+    pub fn with_secret_pattern(self, pattern: &str) -> HostBuilder {
+        let mut secrets = self.secrets.clone();
+        secrets.add_pattern(pattern);
+        HostBuilder { secrets, ..self }
+    }
+
+    /// Marks an operation as idempotent, allowing invocations of it that fail to reach their
+    /// target (e.g. because of a lattice timeout or a host restarting mid-request) to be retried.
+    /// The target is a capability ID or an actor's public key, and `ANY_OPERATION` marks every
+    /// operation of that target. Invocations of operations that aren't marked are never retried
+    pub fn with_idempotent_operation(self, target: &str, operation: &str) -> HostBuilder {
+        let mut retries = self.retries.clone();
+        retries.mark_idempotent(target, operation);
+        HostBuilder { retries, ..self }
+    }
+
+    /// Sets the retry policy used for idempotent operations that have no more specific policy.
+    /// By default an invocation is retried up to 3 times, with jittered exponential backoff
+    pub fn with_retry_policy(self, policy: RetryPolicy) -> HostBuilder {
+        let mut retries = self.retries.clone();
+        retries.set_default(policy);
+        HostBuilder { retries, ..self }
+    }
+
+    /// Sets the retry policy for the idempotent operations of a target (a capability ID or an
+    /// actor's public key), or for a single one of its operations. An operation's policy takes
+    /// precedence over its target's policy
+    pub fn with_target_retry_policy(
+        self,
+        target: &str,
+        operation: Option<&str>,
+        policy: RetryPolicy,
+    ) -> HostBuilder {
+        let mut retries = self.retries.clone();
+        retries.set_policy(target, operation, policy);
+        HostBuilder { retries, ..self }
+    }
+
+    /// Sets the seed of the server key used as this host's identity. By default a new key is
+    /// generated every time a host starts, which changes the host ID. Supplying the same seed
+    /// keeps the host ID stable across restarts. The seed must be a server nkey seed (prefix `SN`).
+    pub fn with_host_seed(self, seed: &str) -> Result<HostBuilder> {
+        inthost::validate_host_seed(seed)?;
+        Ok(HostBuilder {
+            seed: Some(seed.trim().to_string()),
+            ..self
+        })
+    }
+
+    /// Reads the host's server key seed from the given file. If the file does not exist, a new
+    /// server key is generated and its seed is written to that file, so subsequent starts of the
+    /// host will have the same host ID.
+    pub fn with_host_seed_file(self, path: impl AsRef<Path>) -> Result<HostBuilder> {
+        let seed = inthost::read_or_create_host_seed(path.as_ref())?;
+        Ok(HostBuilder {
+            seed: Some(seed),
+            ..self
+        })
+    }
+
     /// Sets the lattice namespace for this host. A lattice namespace is a unit of multi-tenant
     /// isolation on a network. To reduce the risk of conflicts or subscription failures, the
     /// lattice namespace should not include any non-alphanumeric characters.
@@ -186,6 +342,173 @@ impl HostBuilder {
         }
     }
 
+    /// Sets the host identity token (a JWT) this host presents to the other hosts in the lattice.
+    /// The token's subject must be this host's ID and it must be signed by one of the lattice's
+    /// trusted issuers. Tokens can be created with `issue_host_token`.
+    #[cfg(feature = "lattice")]
+    pub fn with_host_token(self, token: &str) -> HostBuilder {
+        HostBuilder {
+            identity: IdentityConfig {
+                token: Some(token.to_string()),
+                ..self.identity.clone()
+            },
+            ..self
+        }
+    }
+
+    /// Adds an operator or account public key to the list of issuers trusted to sign host identity
+    /// tokens. Once at least one trusted issuer is configured, invocations and control plane
+    /// messages from hosts that cannot present a valid host identity token are rejected, and this
+    /// host will only take part in the lattice if its own token is valid.
+    #[cfg(feature = "lattice")]
+    pub fn with_trusted_issuer(self, issuer: &str) -> HostBuilder {
+        let mut identity = self.identity.clone();
+        if !identity.trusted_issuers.contains(&issuer.to_string()) {
+            identity.trusted_issuers.push(issuer.to_string());
+        }
+        HostBuilder { identity, ..self }
+    }
+
+    /// Enables end-to-end sealing of invocation and response payloads sent over the lattice,
+    /// whichever namespace the host joins. Payloads are encrypted with curve25519 keys derived
+    /// from each host's seed, and those keys are discovered through host inventory. A host's
+    /// key is only used once its identity token has been verified, so sealing between hosts
+    /// requires trusted issuers (see `with_trusted_issuer`). Sealing should be enabled on every
+    /// host in a namespace, because a host with sealing enabled rejects unsealed payloads. This
+    /// can also be enabled by setting the `LATTICE_SEAL_PAYLOADS` environment variable to
+    /// `true`. Has no effect on in-process calls.
+    #[cfg(feature = "lattice")]
+    pub fn with_payload_sealing(self) -> HostBuilder {
+        HostBuilder {
+            seal: bus::seal::SealPolicy::AllNamespaces,
+            ..self
+        }
+    }
+
+    /// Enables payload sealing (see `with_payload_sealing`) only if the host joins the given
+    /// namespace, where `default` names the unnamed namespace. Can be called more than once
+    /// for several namespaces, or set as a comma-separated list of namespaces in the
+    /// `LATTICE_SEAL_PAYLOADS` environment variable
+    #[cfg(feature = "lattice")]
+    pub fn with_namespace_payload_sealing(self, namespace: &str) -> HostBuilder {
+        HostBuilder {
+            seal: self.seal.clone().with_namespace(namespace),
+            ..self
+        }
+    }
+
+    /// Connects the host to an in-memory lattice broker rather than to a NATS server. Every host
+    /// built with the same broker joins the same lattice, which allows lattice behavior such as
+    /// scheduling auctions, inventory and queue-grouped invocations to be exercised by several
+    /// hosts within a single process.
+    #[cfg(feature = "lattice")]
+    pub fn with_lattice_broker(self, broker: &LatticeBroker) -> HostBuilder {
+        HostBuilder {
+            broker: Some(broker.clone()),
+            ..self
+        }
+    }
+
+    /// Sets the NATS servers used to connect to the lattice, replacing the `LATTICE_HOST`
+    /// environment variable (which may contain a comma-separated list). When more than one
+    /// server is given, the host fails over between them.
+    #[cfg(feature = "lattice")]
+    pub fn with_lattice_servers(self, urls: &[&str]) -> HostBuilder {
+        HostBuilder {
+            nats: NatsOptions {
+                servers: urls.iter().map(|u| u.to_string()).collect(),
+                ..self.nats
+            },
+            ..self
+        }
+    }
+
+    /// Sets the credentials used to authenticate with the lattice's NATS servers. By default
+    /// these are read from the environment: a credentials file from `LATTICE_CREDS_FILE`, a
+    /// user nkey seed from `LATTICE_NKEY_SEED`, a user and password from `LATTICE_USER` and
+    /// `LATTICE_PASSWORD`, or a token from `LATTICE_TOKEN`, in that order of precedence.
+    #[cfg(feature = "lattice")]
+    pub fn with_lattice_auth(self, auth: LatticeAuth) -> HostBuilder {
+        HostBuilder {
+            nats: NatsOptions { auth, ..self.nats },
+            ..self
+        }
+    }
+
+    /// Requires TLS for the lattice connection. Also enabled by `LATTICE_TLS_REQUIRED=true`, or
+    /// implicitly by supplying a CA or client certificate
+    #[cfg(feature = "lattice")]
+    pub fn with_lattice_tls_required(self) -> HostBuilder {
+        HostBuilder {
+            nats: NatsOptions {
+                tls_required: true,
+                ..self.nats
+            },
+            ..self
+        }
+    }
+
+    /// Adds a PEM-encoded CA certificate used to verify the lattice's NATS servers. Can also be
+    /// set with the `LATTICE_TLS_CA` environment variable
+    #[cfg(feature = "lattice")]
+    pub fn with_lattice_tls_ca(self, ca: impl AsRef<Path>) -> HostBuilder {
+        HostBuilder {
+            nats: NatsOptions {
+                tls_ca: Some(ca.as_ref().to_path_buf()),
+                ..self.nats
+            },
+            ..self
+        }
+    }
+
+    /// Sets the PEM-encoded client certificate and private key the host presents to the
+    /// lattice's NATS servers. Can also be set with the `LATTICE_TLS_CERT` and `LATTICE_TLS_KEY`
+    /// environment variables
+    #[cfg(feature = "lattice")]
+    pub fn with_lattice_client_cert(
+        self,
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> HostBuilder {
+        HostBuilder {
+            nats: NatsOptions {
+                tls_client_cert: Some((cert.as_ref().to_path_buf(), key.as_ref().to_path_buf())),
+                ..self.nats
+            },
+            ..self
+        }
+    }
+
+    /// Sets the number of times the initial lattice connection is retried before the host starts
+    /// without one. A host that starts disconnected keeps trying to connect in the background.
+    /// Can also be set with the `LATTICE_CONNECT_RETRIES` environment variable (default 5)
+    #[cfg(feature = "lattice")]
+    pub fn with_lattice_connect_retries(self, retries: u32) -> HostBuilder {
+        HostBuilder {
+            connection: ConnectionPolicy {
+                connect_retries: retries,
+                ..self.connection
+            },
+            ..self
+        }
+    }
+
+    /// Sets the exponential backoff used between lattice connection attempts, both when
+    /// first connecting and when reconnecting after the connection is lost. Can also be set
+    /// with the `LATTICE_RECONNECT_BACKOFF_MILLIS` and `LATTICE_RECONNECT_BACKOFF_MAX_MILLIS`
+    /// environment variables (default 250ms, doubling up to 10s)
+    #[cfg(feature = "lattice")]
+    pub fn with_lattice_reconnect_backoff(self, initial: Duration, max: Duration) -> HostBuilder {
+        HostBuilder {
+            connection: ConnectionPolicy {
+                initial_backoff: initial,
+                max_backoff: max,
+                ..self.connection
+            },
+            ..self
+        }
+    }
+
     /// Sets a custom authorizer to be used for authorizing actors, capability providers,
     /// and invocation requests. Note that the authorizer cannot be used to implement _less_
     /// strict measures than the default authorizer, it can only be used to implement
@@ -210,23 +533,19 @@ impl HostBuilder {
 
     /// Converts the transient builder instance into a realized host runtime instance
     pub fn build(self) -> Host {
-        #[cfg(not(feature = "lattice"))]
-        let h = Host::generate(self.authorizer, self.labels, self.ns.clone());
-        #[cfg(feature = "lattice")]
-        let h = Host::generate(self.authorizer, self.labels, self.ns.clone());
-        h
+        Host::generate(self)
     }
 }
 
 /// Represents an instance of a waSCC host runtime
 #[derive(Clone)]
 pub struct Host {
-    bus: Arc<MessageBus>,
+    bus: Arc<dyn MessageBus>,
     claims: Arc<RwLock<HashMap<String, Claims<wascap::jwt::Actor>>>>,
     plugins: Arc<RwLock<PluginManager>>,
     bindings: Arc<RwLock<BindingsList>>,
     caps: Arc<RwLock<HashMap<RouteKey, CapabilityDescriptor>>>,
-    middlewares: Arc<RwLock<Vec<Box<dyn Middleware>>>>,
+    middlewares: Arc<RwLock<Vec<middleware::RegisteredMiddleware>>>,
     // the key to this field is the subscription subject, and not either a pk or a capid
     terminators: Arc<RwLock<HashMap<String, Sender<bool>>>>,
     pk: String,
@@ -235,58 +554,97 @@ pub struct Host {
     labels: Arc<RwLock<HashMap<String, String>>>,
     // mapping between OCI registry image references and the associated unique identity (e.g. "Mxxx" and "Vxxx")
     image_map: Arc<RwLock<HashMap<String, String>>>,
-    ns: Option<String>,
+    secrets: Arc<RwLock<SecretPolicy>>,
+    stats: Arc<RuntimeStats>,
+    // only present when the host is connected to a lattice by the default lattice bus
+    #[cfg(feature = "lattice")]
+    lattice: Option<Arc<dyn LatticeAdmin>>,
 }
 
 impl Host {
     /// Creates a new runtime host using all of the default values. Use the host builder
     /// if you want to provide more customization options
     pub fn new() -> Self {
-        let h = Self::generate(
-            Box::new(authz::DefaultAuthorizer::new()),
-            inthost::detect_core_host_labels(),
-            get_namespace_prefix(),
-        );
-        h
+        HostBuilder::new().build()
     }
 
-    pub(crate) fn generate(
-        authz: Box<dyn Authorizer + 'static>,
-        labels: HashMap<String, String>,
-        ns: Option<String>,
-    ) -> Self {
-        let key = KeyPair::new_server();
+    pub(crate) fn generate(builder: HostBuilder) -> Self {
+        let key = match builder.seed {
+            Some(ref s) => KeyPair::from_seed(s).unwrap(),
+            None => KeyPair::new_server(),
+        };
         let claims = Arc::new(RwLock::new(HashMap::new()));
         let caps = Arc::new(RwLock::new(HashMap::new()));
         let bindings = Arc::new(RwLock::new(HashMap::new()));
-        let labels = Arc::new(RwLock::new(labels));
+        let labels = Arc::new(RwLock::new(builder.labels));
         let terminators = Arc::new(RwLock::new(HashMap::new()));
-        let authz = Arc::new(RwLock::new(authz));
+        let authz = Arc::new(RwLock::new(builder.authorizer));
         let image_map = Arc::new(RwLock::new(HashMap::new()));
+        let secrets = Arc::new(RwLock::new(builder.secrets));
+        let stats = Arc::new(RuntimeStats::default());
 
         #[cfg(feature = "lattice")]
         let (com_s, com_r): (Sender<ControlCommand>, Receiver<ControlCommand>) =
             channel::unbounded();
 
+        // The control plane is only handled by the default lattice bus
+        #[cfg(feature = "lattice")]
+        let com_r = if builder.bus.is_none() {
+            Some(com_r)
+        } else {
+            None
+        };
+
         #[cfg(feature = "lattice")]
-        let bus = Arc::new(bus::new(
-            key.public_key(),
-            claims.clone(),
-            caps.clone(),
-            bindings.clone(),
-            labels.clone(),
-            terminators.clone(),
-            ns.clone(),
-            com_s,
-            authz.clone(),
-            image_map.clone(),
-        ));
-
-        #[cfg(not(feature = "lattice"))]
-        let bus = Arc::new(bus::new());
+        let mut lattice: Option<Arc<dyn LatticeAdmin>> = None;
+        let bus: Arc<dyn MessageBus> = match builder.bus {
+            Some(bus) if builder.strict && !bus.validates_invocations() => {
+                Arc::new(bus::strict::StrictBus::new(bus))
+            }
+            Some(bus) => bus,
+            #[cfg(feature = "lattice")]
+            None => {
+                let lattice_bus = Arc::new(bus::new(
+                    key.public_key(),
+                    key.seed().unwrap(),
+                    builder.identity,
+                    builder.strict,
+                    builder.seal,
+                    claims.clone(),
+                    caps.clone(),
+                    bindings.clone(),
+                    labels.clone(),
+                    terminators.clone(),
+                    builder.ns,
+                    com_s,
+                    authz.clone(),
+                    image_map.clone(),
+                    secrets.clone(),
+                    match builder.broker {
+                        Some(broker) => broker.connector(),
+                        None => bus::lattice::nats_connector(builder.nats),
+                    },
+                    builder.connection,
+                    stats.clone(),
+                ));
+                lattice = Some(lattice_bus.clone());
+                lattice_bus
+            }
+            #[cfg(not(feature = "lattice"))]
+            None if builder.strict => Arc::new(InprocBus::strict()),
+            #[cfg(not(feature = "lattice"))]
+            None => Arc::new(InprocBus::new()),
+        };
+        let bus: Arc<dyn MessageBus> = if builder.retries.is_empty() {
+            bus
+        } else {
+            Arc::new(RetryingBus::new(bus, builder.retries))
+        };
 
         #[cfg(feature = "lattice")]
-        let _ = bus.publish_event(BusEvent::HostStarted(key.public_key()));
+        if let Some(ref lattice) = lattice {
+            let _ = lattice.publish_event(BusEvent::HostStarted(key.public_key()));
+        }
 
         let host = Host {
             terminators: terminators.clone(),
@@ -300,8 +658,11 @@ impl Host {
             sk: key.seed().unwrap(),
             authorizer: authz,
             labels,
-            ns,
             image_map,
+            secrets,
+            stats,
+            #[cfg(feature = "lattice")]
+            lattice,
         };
 
         info!("Host ID is {} (v{})", key.public_key(), VERSION);
@@ -309,7 +670,11 @@ impl Host {
         host.ensure_extras().unwrap();
 
         #[cfg(feature = "lattice")]
-        let _ = bus::lattice::spawn_controlplane(&host, com_r);
+        {
+            if let Some(com_r) = com_r {
+                let _ = bus::lattice::spawn_controlplane(&host, com_r);
+            }
+        }
 
         host
     }
@@ -360,6 +725,9 @@ impl Host {
             self.authorizer.clone(),
             self.image_map.clone(),
             imgref,
+            self.stats.clone(),
+            #[cfg(feature = "lattice")]
+            self.lattice.clone(),
         )?;
         wg.wait();
         if actor.capabilities().contains(&extras::CAPABILITY_ID.into()) {
@@ -415,6 +783,9 @@ impl Host {
             self.authorizer.clone(),
             self.image_map.clone(),
             None,
+            self.stats.clone(),
+            #[cfg(feature = "lattice")]
+            self.lattice.clone(),
         )?;
         wg.wait();
         Ok(())
@@ -426,7 +797,7 @@ impl Host {
     /// kind in the lattice)
     pub fn remove_actor(&self, pk: &str) -> Result<()> {
         self.terminators.read().unwrap()
-            [&bus::actor_subject(self.ns.as_ref().map(String::as_str), pk)]
+            [&self.bus.actor_subject(pk)]
             .send(true)
             .unwrap();
         Ok(())
@@ -439,12 +810,76 @@ impl Host {
     /// the underlying WebAssembly driver (chosen via feature flag) supports hot-swapping module bytes.
     pub fn replace_actor(&self, new_actor: Actor) -> Result<()> {
         let key = KeyPair::from_seed(&self.sk).unwrap();
-        crate::inthost::replace_actor(&key, self.bus.clone(), new_actor)
+        crate::inthost::replace_actor(&key, self.bus.clone(), new_actor)?;
+        self.stats.live_update();
+        Ok(())
     }
 
-    /// Adds a middleware item to the middleware processing pipeline
+    /// Returns a signer that middleware can use to re-sign invocations it has modified
+    pub fn invocation_signer(&self) -> InvocationSigner {
+        InvocationSigner::new(&self.sk)
+    }
+
+    /// Adds a middleware item to the end of the middleware processing pipeline. The middleware
+    /// is installed with the default priority (0) under a generated name, and sees every invocation
     pub fn add_middleware(&self, mid: impl Middleware) {
-        self.middlewares.write().unwrap().push(Box::new(mid));
+        middleware::register(
+            &self.middlewares,
+            MiddlewareInfo {
+                name: Uuid::new_v4().to_string(),
+                priority: 0,
+                scope: MiddlewareScope::All,
+                error_policy: mid.error_policy(),
+            },
+            Arc::new(mid),
+        )
+        .unwrap();
+    }
+
+    /// Adds a middleware item to the middleware processing pipeline under a unique name, which
+    /// can later be used to remove it. Middleware with a higher priority runs before (and wraps)
+    /// middleware with a lower one. A scoped middleware only sees invocations to or from the actors
+    /// and capability providers in its scope, so a diagnostic middleware can be attached to a single
+    /// actor of a running host. The middleware is installed with its own error policy, which can
+    /// be changed with `set_middleware_error_policy`
+    pub fn add_named_middleware(
+        &self,
+        name: &str,
+        priority: i32,
+        scope: MiddlewareScope,
+        mid: impl Middleware,
+    ) -> Result<()> {
+        middleware::register(
+            &self.middlewares,
+            MiddlewareInfo {
+                name: name.to_string(),
+                priority,
+                scope,
+                error_policy: mid.error_policy(),
+            },
+            Arc::new(mid),
+        )
+    }
+
+    /// Changes how the host treats errors returned by the hooks of an installed middleware,
+    /// overriding the policy the middleware declares. Takes effect for the next invocation
+    pub fn set_middleware_error_policy(
+        &self,
+        name: &str,
+        policy: MiddlewareErrorPolicy,
+    ) -> Result<()> {
+        middleware::set_error_policy(&self.middlewares, name, policy)
+    }
+
+    /// Removes a middleware from the processing pipeline. Invocations already passing through
+    /// the middleware are unaffected
+    pub fn remove_middleware(&self, name: &str) -> Result<()> {
+        middleware::unregister(&self.middlewares, name)
+    }
+
+    /// Describes the installed middleware, in the order it runs
+    pub fn middlewares(&self) -> Vec<MiddlewareInfo> {
+        middleware::installed(&self.middlewares)
     }
 
     /// Adds a native capability provider plugin to the host runtime. If running in lattice mode,
@@ -479,6 +914,10 @@ impl Host {
             self.plugins.clone(),
             wg.clone(),
             Arc::new(key),
+            self.authorizer.clone(),
+            self.stats.clone(),
+            #[cfg(feature = "lattice")]
+            self.lattice.clone(),
         )?;
         wg.wait();
         Ok(())
@@ -492,7 +931,7 @@ impl Host {
     ) -> Result<()> {
         let b = binding_name.unwrap_or("default".to_string());
         let subject =
-            bus::provider_subject(self.ns.as_ref().map(String::as_str), capability_id, &b);
+            self.bus.provider_subject(capability_id, &b);
         if let Some(terminator) = self.terminators.read().unwrap().get(&subject) {
             terminator.send(true).unwrap();
             Ok(())
@@ -545,10 +984,25 @@ impl Host {
         binding_name: Option<String>,
         config: HashMap<String, String>,
     ) -> Result<()> {
-        #[cfg(feature = "lattice")]
-        let claims = self.bus.discover_claims(actor);
-        #[cfg(not(feature = "lattice"))]
-        let claims = self.claims.read().unwrap().get(actor).cloned();
+        self.set_binding_with_secrets(actor, capid, binding_name, config, &[])
+    }
+
+    /// Binds an actor to a capability provider, the same as `set_binding`, additionally marking
+    /// the given configuration keys as secret. Secret values are delivered to the provider, but
+    /// are redacted in lattice inventory responses. Keys matching one of the host's secret
+    /// patterns are treated as secret whether or not they are marked.
+    pub fn set_binding_with_secrets(
+        &self,
+        actor: &str,
+        capid: &str,
+        binding_name: Option<String>,
+        config: HashMap<String, String>,
+        secret_keys: &[String],
+    ) -> Result<()> {
+        let claims = self
+            .bus
+            .discover_claims(actor)
+            .or_else(|| self.claims.read().unwrap().get(actor).cloned());
 
         let key = KeyPair::from_seed(&self.sk).unwrap();
 
@@ -587,9 +1041,9 @@ impl Host {
 
         let tgt_subject = if (actor == capid || actor == SYSTEM_ACTOR) && capid.starts_with("M") {
             // manually injected actor configuration
-            bus::actor_subject(self.ns.as_ref().map(String::as_str), actor)
+            self.bus.actor_subject(actor)
         } else {
-            bus::provider_subject(self.ns.as_ref().map(String::as_str), capid, &binding)
+            self.bus.provider_subject(capid, &binding)
         };
         trace!("Binding subject: {}", tgt_subject);
         let inv = inthost::gen_config_invocation(
@@ -608,6 +1062,10 @@ impl Host {
                         binding, capid, e
                     ))))
                 } else {
+                    self.secrets.write().unwrap().mark(
+                        (actor.to_string(), capid.to_string(), binding.to_string()),
+                        secret_keys,
+                    );
                     self.record_binding(
                         actor,
                         capid,
@@ -618,12 +1076,14 @@ impl Host {
                         },
                     )?;
                     #[cfg(feature = "lattice")]
-                    let _ = self.bus.publish_event(BusEvent::ActorBindingCreated {
-                        actor: actor.to_string(),
-                        capid: capid.to_string(),
-                        instance_name: binding.to_string(),
-                        host: self.id(),
-                    });
+                    if let Some(ref lattice) = self.lattice {
+                        let _ = lattice.publish_event(BusEvent::ActorBindingCreated {
+                            actor: actor.to_string(),
+                            capid: capid.to_string(),
+                            instance_name: binding.to_string(),
+                            host: self.id(),
+                        });
+                    }
                     Ok(())
                 }
             }
@@ -653,7 +1113,7 @@ impl Host {
             operation,
             msg.to_vec(),
         );
-        let tgt_subject = bus::actor_subject(self.ns.as_ref().map(String::as_str), actor);
+        let tgt_subject = self.bus.actor_subject(actor);
         match self.bus.invoke(&tgt_subject, inv) {
             Ok(resp) => match resp.error {
                 Some(e) => Err(format!("Invocation failure: {}", e).into()),
@@ -687,22 +1147,24 @@ impl Host {
             self.add_actor_file_first(&actor)?; // If file, add .wasm, otherwise assume it's an OCI ref
         }
         for cap in manifest.capabilities {
-            // for now, supports only file paths
             if Path::new(&cap.path).exists() {
-                self.add_native_capability(NativeCapability::from_file(
+                self.add_native_capability(NativeCapability::from_path(
                     cap.path,
                     cap.binding_name,
                 )?)?;
-            } else {
+            } else if cap.is_registry_reference() {
                 self.add_native_capability_from_registry(&cap.path, cap.binding_name)?;
+            } else {
+                return Err(format!("Capability provider file not found: {}", cap.path).into());
             }
         }
         for config in manifest.bindings {
-            self.set_binding(
+            self.set_binding_with_secrets(
                 &config.actor,
                 &config.capability,
                 config.binding,
                 config.values.unwrap_or(HashMap::new()),
+                &config.secrets.unwrap_or_default(),
             )?;
         }
         Ok(())
@@ -716,6 +1178,20 @@ impl Host {
         }
     }
 
+    #[cfg(feature = "manifest")]
+    fn add_native_capability_from_registry(
+        &self,
+        image_ref: &str,
+        binding_name: Option<String>,
+    ) -> Result<()> {
+        let binding_name = binding_name.unwrap_or_else(|| "default".to_string());
+        self.add_native_capability(inthost::fetch_provider(
+            image_ref,
+            &binding_name,
+            self.labels.clone(),
+        )?)
+    }
+
     /// Returns the list of actors registered in the host. Even if lattice mode is enabled, this function
     /// will only return the list of actors in this specific host
     pub fn actors(&self) -> Vec<SubjectClaimsPair> {
@@ -735,6 +1211,18 @@ impl Host {
         res
     }
 
+    /// Returns the bindings known to this host. The key is a tuple of (actor, capability ID, binding),
+    /// and secret configuration values are redacted
+    pub fn bindings(&self) -> HashMap<(String, String, String), HashMap<String, String>> {
+        let secrets = self.secrets.read().unwrap();
+        self.bindings
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), secrets.redact(k, &v.values)))
+            .collect()
+    }
+
     /// Returns the list of actors in the host that contain all of the tags in the
     /// supplied parameter. This function will not make a lattice-wide tag query
     pub fn actors_by_tag(&self, tags: &[&str]) -> Vec<String> {
@@ -774,4 +1262,59 @@ impl Host {
     pub fn id(&self) -> String {
         self.pk.to_string()
     }
+
+    /// Indicates whether the host's message bus can currently reach the rest of the lattice.
+    /// While a lattice host is disconnected it keeps trying to reconnect, and invocations that
+    /// need the lattice fail immediately. Hosts that don't use a lattice are always connected
+    pub fn connected(&self) -> bool {
+        self.bus.connected()
+    }
+
+    /// Returns the current size of the host's inventory, the depth of its invocation queues, and
+    /// counters of its activity such as live updates and scheduling auctions
+    pub fn runtime_metrics(&self) -> RuntimeMetrics {
+        self.runtime_metrics_source().read()
+    }
+
+    /// Returns a handle from which the host's runtime metrics can be read at any time, e.g. by
+    /// a metrics exporter
+    pub fn runtime_metrics_source(&self) -> RuntimeMetricsSource {
+        RuntimeMetricsSource {
+            claims: self.claims.clone(),
+            caps: self.caps.clone(),
+            bindings: self.bindings.clone(),
+            bus: self.bus.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// Returns the list of hosts that this host currently knows to be in the lattice-wide
+    /// block list. Hosts are added to the block list when invocations they signed fail
+    /// an antiforgery check, and invocations originating from blocked hosts are refused.
+    #[cfg(feature = "lattice")]
+    pub fn blocked_hosts(&self) -> Vec<BlockedHost> {
+        self.lattice
+            .as_ref()
+            .map(|l| l.blocked_hosts())
+            .unwrap_or_default()
+    }
+
+    /// Removes the indicated host from the block list. This operation has a _lattice global_
+    /// scope, and so all hosts in the lattice will stop refusing invocations from that host
+    #[cfg(feature = "lattice")]
+    pub fn unblock_host(&self, host_id: &str) -> Result<()> {
+        match self.lattice {
+            Some(ref l) => l.unblock_host(host_id),
+            None => Ok(()),
+        }
+    }
+
+    /// Clears the block list of every host in the lattice
+    #[cfg(feature = "lattice")]
+    pub fn clear_blocked_hosts(&self) -> Result<()> {
+        match self.lattice {
+            Some(ref l) => l.clear_blocklist(),
+            None => Ok(()),
+        }
+    }
 }
@@ -0,0 +1,149 @@
+// A map that holds at most a fixed number of entries. When it is full, the least recently used
+// entry is evicted to make room for a new one, so that caches keyed by data arriving from outside
+// the host (e.g. host IDs or invocation IDs) can't grow without bound.
+
+#![allow(dead_code)]
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+pub(crate) struct LruCache<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+    // Keys ordered by their last use, oldest first
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub(crate) fn new(capacity: usize) -> LruCache<K, V> {
+        LruCache {
+            capacity: capacity.max(1),
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the entry for the key, marking it as the most recently used
+    pub(crate) fn get(&mut self, key: &K) -> Option<&V> {
+        let tick = self.next_tick();
+        match self.entries.get_mut(key) {
+            Some((_, used)) => {
+                let k = self.order.remove(&*used).unwrap();
+                *used = tick;
+                self.order.insert(tick, k);
+            }
+            None => return None,
+        }
+        self.entries.get(key).map(|(v, _)| v)
+    }
+
+    /// Returns the entry for the key without affecting its eviction order
+    pub(crate) fn peek(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(v, _)| v)
+    }
+
+    pub(crate) fn contains_key(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// Adds or replaces an entry, evicting the least recently used entry if the cache is full.
+    /// Returns the previous value for the key, if any
+    pub(crate) fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let previous = self.remove(&key);
+        if self.entries.len() >= self.capacity {
+            let oldest = self.order.keys().next().cloned();
+            if let Some(k) = oldest.and_then(|t| self.order.remove(&t)) {
+                self.entries.remove(&k);
+            }
+        }
+        let tick = self.next_tick();
+        self.order.insert(tick, key.clone());
+        self.entries.insert(key, (value, tick));
+        previous
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) -> Option<V> {
+        self.entries.remove(key).map(|(v, used)| {
+            self.order.remove(&used);
+            v
+        })
+    }
+
+    /// Removes and returns the least recently used entry
+    pub(crate) fn pop_oldest(&mut self) -> Option<(K, V)> {
+        let oldest = self.order.keys().next().cloned()?;
+        let key = self.order.remove(&oldest)?;
+        self.entries.remove(&key).map(|(v, _)| (key, v))
+    }
+
+    /// Removes every entry for which the predicate returns false
+    pub(crate) fn retain(&mut self, mut f: impl FnMut(&K, &V) -> bool) {
+        let order = &mut self.order;
+        self.entries.retain(|k, (v, used)| {
+            let keep = f(k, v);
+            if !keep {
+                order.remove(&*used);
+            }
+            keep
+        });
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.values().map(|(v, _)| v)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LruCache;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(Some(&1), cache.get(&"a"));
+        cache.insert("c", 3);
+        assert_eq!(2, cache.len());
+        assert!(cache.contains_key(&"a"));
+        assert!(!cache.contains_key(&"b"));
+        assert_eq!(Some(3), cache.insert("c", 4));
+        assert_eq!(Some(&4), cache.peek(&"c"));
+
+        cache.retain(|k, _| *k != "a");
+        assert_eq!(1, cache.len());
+        cache.insert("d", 5);
+        cache.insert("e", 6);
+        assert_eq!(vec![5, 6], {
+            let mut v: Vec<_> = cache.values().cloned().collect();
+            v.sort();
+            v
+        });
+        assert_eq!(Some(("d", 5)), cache.pop_oldest());
+        assert_eq!(Some(6), cache.remove(&"e"));
+        assert_eq!(None, cache.pop_oldest());
+        cache.insert("f", 7);
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
@@ -18,6 +18,33 @@ pub struct Capability {
     pub binding_name: Option<String>,
 }
 
+impl Capability {
+    /// Indicates whether the path is a registry reference (e.g. `wascc.azurecr.io/httpsrv:v0.0.1`)
+    /// rather than a file path. A reference names a registry host, containing a `.` or `:` or
+    /// being `localhost`, followed by a repository, and doesn't start like a relative or absolute
+    /// file path or end in a provider file extension
+    pub fn is_registry_reference(&self) -> bool {
+        let path = self.path.as_str();
+        if path.starts_with('.') || path.starts_with('/') || path.starts_with('~') {
+            return false;
+        }
+        let lower = path.to_lowercase();
+        if [".par", ".so", ".dylib", ".dll"]
+            .iter()
+            .any(|ext| lower.ends_with(ext))
+        {
+            return false;
+        }
+        match path.find('/') {
+            Some(i) if i + 1 < path.len() => {
+                let registry = &path[..i];
+                registry.contains('.') || registry.contains(':') || registry == "localhost"
+            }
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "manifest", derive(serde::Serialize, serde::Deserialize))]
 pub struct BindingEntry {
@@ -25,8 +52,14 @@ pub struct BindingEntry {
     pub capability: String,
     pub binding: Option<String>,
     pub values: Option<HashMap<String, String>>,
+    /// Configuration keys whose values are secret and must not be shared with the lattice
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secrets: Option<Vec<String>>,
 }
 
+#[cfg(feature = "manifest")]
+use crate::{Actor, NativeCapability};
 #[cfg(feature = "manifest")]
 use std::{fs::File, io::Read, path::Path};
 #[cfg(feature = "manifest")]
@@ -58,6 +91,87 @@ impl HostManifest {
         }
     }
 
+    /// Checks the manifest without starting a host. Actor and capability provider files must
+    /// load the same way `Host::apply_manifest` loads them, and a provider path that isn't a file
+    /// must be a registry reference. Actors must carry valid signed claims, and every binding must
+    /// refer to an actor in the manifest that claims the bound capability and, unless the manifest
+    /// also names providers by registry reference (which can't be resolved offline), to a provider
+    /// in the manifest.
+    /// Returns a description of every problem found
+    pub fn validate(&self) -> std::result::Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+
+        let mut actors = HashMap::new();
+        for path in &self.actors {
+            if !Path::new(path).exists() {
+                problems.push(format!("Actor file not found: {}", path));
+                continue;
+            }
+            match Actor::from_file(path) {
+                Ok(actor) => {
+                    if let Err(e) = actor.validate() {
+                        problems.push(format!("Actor {} is not valid: {}", path, e));
+                    }
+                    actors.insert(actor.public_key(), actor);
+                }
+                Err(e) => problems.push(format!("Failed to load actor {}: {}", path, e)),
+            }
+        }
+
+        let mut providers = Vec::new();
+        let mut registry_refs = false;
+        for cap in &self.capabilities {
+            if !Path::new(&cap.path).exists() {
+                if cap.is_registry_reference() {
+                    registry_refs = true;
+                } else {
+                    problems.push(format!("Capability provider file not found: {}", cap.path));
+                }
+                continue;
+            }
+            match NativeCapability::from_path(&cap.path, cap.binding_name.clone()) {
+                Ok(provider) => providers.push((provider.id(), provider.binding_name.to_string())),
+                Err(e) => problems.push(format!(
+                    "Failed to load capability provider {}: {}",
+                    cap.path, e
+                )),
+            }
+        }
+
+        for b in &self.bindings {
+            let binding = b.binding.clone().unwrap_or_else(|| "default".to_string());
+            match actors.get(&b.actor) {
+                Some(actor) => {
+                    if b.actor != b.capability && !actor.capabilities().contains(&b.capability) {
+                        problems.push(format!(
+                            "Actor {} is bound to {}, but does not claim that capability",
+                            b.actor, b.capability
+                        ));
+                    }
+                }
+                None => problems.push(format!(
+                    "Binding refers to actor {}, which is not in the manifest",
+                    b.actor
+                )),
+            }
+            if !registry_refs
+                && b.actor != b.capability
+                && !providers.contains(&(b.capability.to_string(), binding.to_string()))
+            {
+                problems.push(format!(
+                    "Binding of actor {} refers to capability {} ({}), which is not in the manifest",
+                    b.actor, b.capability, binding
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
     fn expand_env(contents: &str) -> String {
         let mut options = envmnt::ExpandOptions::new();
         options.default_to_empty = false; // If environment variable not found, leave unexpanded.
@@ -93,6 +207,7 @@ mod test {
                 binding: Some("default".to_string()),
                 capability: "wascc:one".to_string(),
                 values: Some(gen_values()),
+                secrets: None,
             }],
         };
         let yaml = serde_yaml::to_string(&manifest).unwrap();
@@ -123,12 +238,23 @@ mod test {
                 binding: Some("default".to_string()),
                 capability: "wascc:one".to_string(),
                 values: Some(gen_values()),
+                secrets: None,
             }],
         };
         let yaml = serde_yaml::to_string(&manifest).unwrap();
         assert_eq!(yaml, "---\nlabels:\n  test: value\nactors:\n  - a\n  - b\n  - c\ncapabilities:\n  - path: one\n    binding_name: default\n  - path: two\n    binding_name: default\nbindings:\n  - actor: a\n    capability: \"wascc:one\"\n    binding: default\n    values:\n      ROOT: /tmp");
     }
 
+    #[test]
+    fn binding_secrets() {
+        let yaml = "---\nactors: []\ncapabilities: []\nbindings:\n  - actor: a\n    capability: \"wascc:one\"\n    values:\n      DB_URL: \"postgres://u:p@db\"\n    secrets:\n      - DB_URL";
+        let manifest: super::HostManifest = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            manifest.bindings[0].secrets,
+            Some(vec!["DB_URL".to_string()])
+        );
+    }
+
     #[test]
     fn env_expansion() {
         let values = vec![
@@ -159,6 +285,91 @@ mod test {
         envmnt::remove("TEST_EXPAND_ENV_TEMP");
     }
 
+    #[test]
+    fn validate_reports_problems() {
+        let manifest = super::HostManifest {
+            labels: HashMap::new(),
+            actors: vec![
+                "./examples/.assets/echo.wasm".to_string(),
+                "./examples/.assets/missing.wasm".to_string(),
+            ],
+            capabilities: vec![],
+            bindings: vec![
+                binding(ECHO, "wascc:keyvalue"),
+                binding("Mxxx", "wascc:http_server"),
+            ],
+        };
+        let problems = manifest.validate().unwrap_err();
+        assert_eq!(5, problems.len());
+        assert!(problems[0].starts_with("Actor file not found"));
+        assert!(problems[1].ends_with("does not claim that capability"));
+        assert!(problems[2].contains("wascc:keyvalue (default), which is not in the manifest"));
+        assert!(problems[3].starts_with("Binding refers to actor Mxxx"));
+        assert!(problems[4].contains("wascc:http_server (default), which is not in the manifest"));
+    }
+
+    #[test]
+    fn validate_allows_registry_references() {
+        let manifest = super::HostManifest {
+            labels: HashMap::new(),
+            actors: vec!["./examples/.assets/echo.wasm".to_string()],
+            capabilities: vec![Capability {
+                path: "wascc.azurecr.io/httpsrv:v0.0.1".to_string(),
+                binding_name: None,
+            }],
+            bindings: vec![binding(ECHO, "wascc:http_server")],
+        };
+        assert!(manifest.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_reports_missing_provider_files() {
+        let manifest = super::HostManifest {
+            labels: HashMap::new(),
+            actors: vec!["./examples/.assets/echo.wasm".to_string()],
+            capabilities: vec![Capability {
+                path: "./examples/.assets/libwascc_httpsrv.so".to_string(),
+                binding_name: None,
+            }],
+            bindings: vec![binding(ECHO, "wascc:http_server")],
+        };
+        let problems = manifest.validate().unwrap_err();
+        assert_eq!(2, problems.len());
+        assert!(problems[0].starts_with("Capability provider file not found"));
+        assert!(problems[1].contains("wascc:http_server (default), which is not in the manifest"));
+    }
+
+    #[test]
+    fn recognizes_registry_references() {
+        let is_ref = |path: &str| {
+            Capability {
+                path: path.to_string(),
+                binding_name: None,
+            }
+            .is_registry_reference()
+        };
+        assert!(is_ref("wascc.azurecr.io/httpsrv:v0.0.1"));
+        assert!(is_ref("localhost:5000/httpsrv:v0.0.1"));
+        assert!(is_ref("localhost/httpsrv@sha256:0123"));
+        assert!(!is_ref("libwascc_httpsrv.so"));
+        assert!(!is_ref("./providers/httpsrv:v0.0.1"));
+        assert!(!is_ref("/opt/providers/httpsrv"));
+        assert!(!is_ref("providers/httpsrv"));
+        assert!(!is_ref("my.providers/httpsrv.par"));
+    }
+
+    const ECHO: &str = "MB4OLDIC3TCZ4Q4TGGOVAZC43VXFE2JQVRAXQMQFXUCREOOFEKOKZTY2";
+
+    fn binding(actor: &str, capability: &str) -> BindingEntry {
+        BindingEntry {
+            actor: actor.to_string(),
+            capability: capability.to_string(),
+            binding: None,
+            values: None,
+            secrets: None,
+        }
+    }
+
     fn gen_values() -> HashMap<String, String> {
         let mut hm = HashMap::new();
         hm.insert("ROOT".to_string(), "/tmp".to_string());
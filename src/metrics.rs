@@ -0,0 +1,192 @@
+// Runtime metrics of a host: the size of its inventory and counters of host activity that isn't
+// tied to a single invocation, and so can't be observed by middleware. The counters are kept in a
+// `RuntimeStats` shared by the host, its spawned actor and provider threads, and its message bus.
+
+use crate::bus::MessageBus;
+use crate::{BindingsList, Invocation, RouteKey};
+use crossbeam::Receiver;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use wascap::jwt::{Actor, Claims};
+use wascc_codec::capabilities::CapabilityDescriptor;
+
+/// A point-in-time view of a host's runtime state and activity
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuntimeMetrics {
+    /// The number of actors running in this host
+    pub actors: usize,
+    /// The number of native capability providers, counting each binding name separately
+    pub native_providers: usize,
+    /// The number of portable (WASI) capability providers
+    pub portable_providers: usize,
+    /// The number of bindings between actors and capability providers known to this host
+    pub bindings: usize,
+    /// The number of invocations waiting to be handled, keyed by message bus subject
+    pub queue_depths: HashMap<String, usize>,
+    /// The number of times an actor was started again after having been stopped in this host
+    pub actor_restarts: u64,
+    /// The number of successful live updates of actors
+    pub live_updates: u64,
+    /// Whether the host can currently reach the lattice. Hosts without a lattice are always
+    /// connected
+    pub lattice_connected: bool,
+    /// The number of actor and provider scheduling auctions this host has received
+    pub auction_requests: u64,
+    /// The number of auctions in which this host offered to run the actor or provider
+    pub auction_bids: u64,
+    /// The number of invocations rejected by this host because they failed an antiforgery check
+    pub antiforgery_failures: u64,
+}
+
+/// Reads the runtime metrics of a host. Unlike the host itself, a source doesn't hold on to the
+/// host's middleware, so it can be given to a middleware that exports the metrics
+#[derive(Clone)]
+pub struct RuntimeMetricsSource {
+    pub(crate) claims: Arc<RwLock<HashMap<String, Claims<Actor>>>>,
+    pub(crate) caps: Arc<RwLock<HashMap<RouteKey, CapabilityDescriptor>>>,
+    pub(crate) bindings: Arc<RwLock<BindingsList>>,
+    pub(crate) bus: Arc<dyn MessageBus>,
+    pub(crate) stats: Arc<RuntimeStats>,
+}
+
+impl RuntimeMetricsSource {
+    pub fn read(&self) -> RuntimeMetrics {
+        let stats = &self.stats;
+        RuntimeMetrics {
+            actors: self.claims.read().unwrap().len(),
+            native_providers: self.caps.read().unwrap().len(),
+            portable_providers: stats.portable_providers.read().unwrap().len(),
+            bindings: self.bindings.read().unwrap().len(),
+            queue_depths: stats
+                .queues
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(subject, r)| (subject.to_string(), r.len()))
+                .collect(),
+            actor_restarts: stats.actor_restarts.load(Ordering::Relaxed),
+            live_updates: stats.live_updates.load(Ordering::Relaxed),
+            lattice_connected: self.bus.connected(),
+            auction_requests: stats.auction_requests.load(Ordering::Relaxed),
+            auction_bids: stats.auction_bids.load(Ordering::Relaxed),
+            antiforgery_failures: stats.antiforgery_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct RuntimeStats {
+    // The invocation queue of every subscription, keyed by subject
+    queues: RwLock<HashMap<String, Receiver<Invocation>>>,
+    // The subjects of the running portable capability providers
+    portable_providers: RwLock<HashSet<String>>,
+    // Every actor that has been started in this host
+    started_actors: RwLock<HashSet<String>>,
+    actor_restarts: AtomicU64,
+    live_updates: AtomicU64,
+    auction_requests: AtomicU64,
+    auction_bids: AtomicU64,
+    antiforgery_failures: AtomicU64,
+}
+
+impl RuntimeStats {
+    pub(crate) fn track_queue(&self, subject: &str, queue: Receiver<Invocation>) {
+        self.queues
+            .write()
+            .unwrap()
+            .insert(subject.to_string(), queue);
+    }
+
+    pub(crate) fn untrack_queue(&self, subject: &str) {
+        self.queues.write().unwrap().remove(subject);
+    }
+
+    pub(crate) fn actor_started(&self, pk: &str) {
+        if !self.started_actors.write().unwrap().insert(pk.to_string()) {
+            self.actor_restarts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn portable_provider_started(&self, subject: &str) {
+        self.portable_providers
+            .write()
+            .unwrap()
+            .insert(subject.to_string());
+    }
+
+    pub(crate) fn portable_provider_stopped(&self, subject: &str) {
+        self.portable_providers.write().unwrap().remove(subject);
+    }
+
+    pub(crate) fn live_update(&self) {
+        self.live_updates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn auction_request(&self, bid: bool) {
+        self.auction_requests.fetch_add(1, Ordering::Relaxed);
+        if bid {
+            self.auction_bids.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn antiforgery_failure(&self) {
+        self.antiforgery_failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RuntimeMetricsSource, RuntimeStats};
+    use crate::bus::InprocBus;
+    use crate::{Invocation, WasccEntity};
+    use crossbeam_channel as channel;
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+    use wascap::prelude::KeyPair;
+
+    #[test]
+    fn reads_queues_and_counters() {
+        let stats = Arc::new(RuntimeStats::default());
+        let source = RuntimeMetricsSource {
+            claims: Arc::new(RwLock::new(HashMap::new())),
+            caps: Arc::new(RwLock::new(HashMap::new())),
+            bindings: Arc::new(RwLock::new(HashMap::new())),
+            bus: Arc::new(InprocBus::new()),
+            stats: stats.clone(),
+        };
+
+        let (s, r) = channel::unbounded();
+        stats.track_queue("wasmbus.actor.Mxxx", r);
+        for _ in 0..3 {
+            s.send(Invocation::new(
+                &KeyPair::new_server(),
+                WasccEntity::Actor("system".to_string()),
+                WasccEntity::Actor("Mxxx".to_string()),
+                "testing",
+                vec![],
+            ))
+            .unwrap();
+        }
+        stats.actor_started("Mxxx");
+        stats.actor_started("Myyy");
+        stats.actor_started("Mxxx");
+        stats.portable_provider_started("wasmbus.provider.wasi.default");
+        stats.auction_request(true);
+        stats.auction_request(false);
+
+        let m = source.read();
+        assert_eq!(Some(&3), m.queue_depths.get("wasmbus.actor.Mxxx"));
+        assert_eq!(1, m.actor_restarts);
+        assert_eq!(1, m.portable_providers);
+        assert_eq!(2, m.auction_requests);
+        assert_eq!(1, m.auction_bids);
+        assert!(m.lattice_connected);
+
+        stats.untrack_queue("wasmbus.actor.Mxxx");
+        stats.portable_provider_stopped("wasmbus.provider.wasi.default");
+        let m = source.read();
+        assert!(m.queue_depths.is_empty());
+        assert_eq!(0, m.portable_providers);
+    }
+}
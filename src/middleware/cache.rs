@@ -0,0 +1,420 @@
+//! # Caching Middleware
+//!
+//! Serves repeated read-only capability provider calls from a cache instead of invoking the
+//! provider again. Only operations given a time-to-live are cached, and only successful responses
+//! are kept. Cached responses are keyed by operation and by the invocation's hash, which covers
+//! the target (capability ID and binding), the origin actor and the payload, so actors never see
+//! each other's cached data.
+//!
+//! Operations that change the data behind a provider can be configured to invalidate the cache:
+//! when one passes through, every cached response of the same target (capability ID and binding)
+//! is discarded, and reads that were in flight at the time aren't cached.
+//!
+//! ```
+//! # use std::time::Duration;
+//! use wascc_host::middleware::cache::{CacheConfig, CachingMiddleware};
+//!
+//! let cache = CachingMiddleware::new(CacheConfig::default())
+//!     .with_ttl("wascc:keyvalue", Some("Get"), Duration::from_secs(5))
+//!     .with_invalidating_operation("wascc:keyvalue", "Set")
+//!     .with_invalidating_operation("wascc:keyvalue", "Del");
+//! let host = wascc_host::Host::new();
+//! host.add_middleware(cache);
+//! ```
+
+use crate::lru::LruCache;
+use crate::middleware::{InvocationHandler, MiddlewareResponse};
+use crate::{Invocation, InvocationResponse, Middleware, Result, WasccEntity};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Configuration parameters.
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// The maximum number of cached responses. The default is 10,000.
+    pub max_entries: usize,
+    /// The maximum total size of cached response payloads in bytes. The default is 64MB.
+    pub max_bytes: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_entries: 10_000,
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+struct CacheEntry {
+    msg: Vec<u8>,
+    expires: Instant,
+}
+
+// A cached response is keyed by its target URL (capability ID and binding), operation and
+// invocation hash
+type EntryKey = (String, String, String);
+
+struct Entries {
+    lru: LruCache<EntryKey, CacheEntry>,
+    // The cached keys of each target, so a write can discard them without scanning the cache
+    targets: HashMap<String, HashSet<EntryKey>>,
+    // Bumped whenever a target's responses are discarded. A response is only stored if its
+    // target's generation hasn't changed since its invocation started, so a read that races
+    // with a write can't cache the value the write replaced
+    generations: HashMap<String, u64>,
+    bytes: usize,
+}
+
+impl Entries {
+    fn new(max_entries: usize) -> Entries {
+        Entries {
+            lru: LruCache::new(max_entries),
+            targets: HashMap::new(),
+            generations: HashMap::new(),
+            bytes: 0,
+        }
+    }
+
+    fn generation(&mut self, target: &str) -> u64 {
+        *self.generations.entry(target.to_string()).or_insert(0)
+    }
+
+    fn unindex(&mut self, key: &EntryKey, entry: &CacheEntry) {
+        self.bytes -= entry.msg.len();
+        if let Some(keys) = self.targets.get_mut(&key.0) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.targets.remove(&key.0);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &EntryKey) {
+        if let Some(e) = self.lru.remove(key) {
+            self.unindex(key, &e);
+        }
+    }
+
+    fn remove_target(&mut self, target: &str) {
+        *self.generations.entry(target.to_string()).or_insert(0) += 1;
+        if let Some(keys) = self.targets.remove(target) {
+            for key in keys {
+                if let Some(e) = self.lru.remove(&key) {
+                    self.bytes -= e.msg.len();
+                }
+            }
+        }
+    }
+
+    // Evicts the least recently used entry
+    fn evict(&mut self) -> bool {
+        match self.lru.pop_oldest() {
+            Some((key, e)) => {
+                self.unindex(&key, &e);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A middleware that caches the responses of idempotent capability provider operations. Clones
+/// of this middleware share their cache.
+#[derive(Clone)]
+pub struct CachingMiddleware {
+    config: CacheConfig,
+    ttls: HashMap<(String, Option<String>), Duration>,
+    invalidating: HashSet<(String, String)>,
+    entries: Arc<RwLock<Entries>>,
+}
+
+impl CachingMiddleware {
+    pub fn new(config: CacheConfig) -> Self {
+        let entries = Entries::new(config.max_entries);
+        CachingMiddleware {
+            config,
+            ttls: HashMap::new(),
+            invalidating: HashSet::new(),
+            entries: Arc::new(RwLock::new(entries)),
+        }
+    }
+
+    /// Caches the responses of every operation of a capability, or of a single one of its
+    /// operations, for the given time. An operation's TTL takes precedence over its capability's
+    pub fn with_ttl(self, capid: &str, operation: Option<&str>, ttl: Duration) -> Self {
+        let mut ttls = self.ttls;
+        ttls.insert((capid.to_string(), operation.map(|o| o.to_string())), ttl);
+        CachingMiddleware { ttls, ..self }
+    }
+
+    /// Marks an operation of a capability as a write. Its invocations are never cached, and
+    /// every cached response of the invoked target is discarded when one passes through
+    pub fn with_invalidating_operation(self, capid: &str, operation: &str) -> Self {
+        let mut invalidating = self.invalidating;
+        invalidating.insert((capid.to_string(), operation.to_string()));
+        CachingMiddleware {
+            invalidating,
+            ..self
+        }
+    }
+
+    /// Discards every cached response of a capability provider, across all of its bindings
+    pub fn invalidate(&self, capid: &str) {
+        let target = WasccEntity::Capability {
+            capid: capid.to_string(),
+            binding: String::new(),
+        }
+        .url();
+        let mut lock = self.entries.write().unwrap();
+        let matching: Vec<_> = lock
+            .generations
+            .keys()
+            .filter(|t| t.starts_with(&target))
+            .cloned()
+            .collect();
+        for t in matching {
+            lock.remove_target(&t);
+        }
+    }
+
+    /// Discards every cached response
+    pub fn clear(&self) {
+        let mut lock = self.entries.write().unwrap();
+        let targets: Vec<_> = lock.generations.keys().cloned().collect();
+        for t in targets {
+            lock.remove_target(&t);
+        }
+    }
+
+    /// The number of cached responses
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().lru.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn ttl(&self, capid: &str, operation: &str) -> Option<Duration> {
+        self.ttls
+            .get(&(capid.to_string(), Some(operation.to_string())))
+            .or_else(|| self.ttls.get(&(capid.to_string(), None)))
+            .cloned()
+    }
+
+    // Returns the cached response for the key, or the target's current generation if there
+    // isn't one
+    fn lookup(&self, key: &EntryKey) -> std::result::Result<Vec<u8>, u64> {
+        let mut lock = self.entries.write().unwrap();
+        let expired = match lock.lru.get(key) {
+            Some(e) if e.expires > Instant::now() => return Ok(e.msg.clone()),
+            Some(_) => true,
+            None => false,
+        };
+        if expired {
+            lock.remove(key);
+        }
+        Err(lock.generation(&key.0))
+    }
+
+    fn store(&self, key: EntryKey, generation: u64, msg: Vec<u8>, ttl: Duration) {
+        if msg.len() > self.config.max_bytes || self.config.max_entries == 0 {
+            return;
+        }
+        let mut lock = self.entries.write().unwrap();
+        if lock.generation(&key.0) != generation {
+            return;
+        }
+        lock.remove(&key);
+        while lock.lru.len() >= self.config.max_entries
+            || lock.bytes + msg.len() > self.config.max_bytes
+        {
+            if !lock.evict() {
+                break;
+            }
+        }
+        lock.bytes += msg.len();
+        lock.targets
+            .entry(key.0.to_string())
+            .or_default()
+            .insert(key.clone());
+        lock.lru.insert(
+            key,
+            CacheEntry {
+                msg,
+                expires: Instant::now() + ttl,
+            },
+        );
+    }
+}
+
+impl Middleware for CachingMiddleware {
+    fn actor_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
+        Ok(inv)
+    }
+
+    fn actor_invoke(
+        &self,
+        inv: Invocation,
+        handler: InvocationHandler,
+    ) -> Result<MiddlewareResponse> {
+        Ok(MiddlewareResponse::Continue(handler.invoke(inv)))
+    }
+
+    fn actor_post_invoke(&self, response: InvocationResponse) -> Result<InvocationResponse> {
+        Ok(response)
+    }
+
+    fn capability_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
+        Ok(inv)
+    }
+
+    fn capability_invoke(
+        &self,
+        inv: Invocation,
+        handler: InvocationHandler,
+    ) -> Result<MiddlewareResponse> {
+        let capid = match &inv.target {
+            WasccEntity::Capability { capid, .. } => capid.to_string(),
+            WasccEntity::Actor(_) => return Ok(MiddlewareResponse::Continue(handler.invoke(inv))),
+        };
+        let target = inv.target.url();
+
+        if self
+            .invalidating
+            .contains(&(capid.to_string(), inv.operation.to_string()))
+        {
+            // Discarded both before and after the write, so that reads which start while it
+            // is in flight aren't cached either
+            self.entries.write().unwrap().remove_target(&target);
+            let response = handler.invoke(inv);
+            self.entries.write().unwrap().remove_target(&target);
+            return Ok(MiddlewareResponse::Continue(response));
+        }
+
+        let ttl = match self.ttl(&capid, &inv.operation) {
+            Some(ttl) => ttl,
+            None => return Ok(MiddlewareResponse::Continue(handler.invoke(inv))),
+        };
+        let key = (target, inv.operation.to_string(), inv.hash());
+        let generation = match self.lookup(&key) {
+            Ok(msg) => {
+                trace!("Serving {} on {} from cache", inv.operation, key.0);
+                return Ok(MiddlewareResponse::Halt(InvocationResponse::success(
+                    &inv, msg,
+                )));
+            }
+            Err(generation) => generation,
+        };
+
+        let response = handler.invoke(inv);
+        if response.error.is_none() {
+            self.store(key, generation, response.msg.clone(), ttl);
+        }
+        Ok(MiddlewareResponse::Continue(response))
+    }
+
+    fn capability_post_invoke(&self, response: InvocationResponse) -> Result<InvocationResponse> {
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CacheConfig, CachingMiddleware};
+    use crate::middleware::{InvocationHandler, MiddlewareResponse};
+    use crate::{Invocation, InvocationResponse, Middleware, WasccEntity};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use wascap::prelude::KeyPair;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn call(cache: &CachingMiddleware, op: &str, payload: &[u8]) -> (bool, InvocationResponse) {
+        let handler = |inv: Invocation| {
+            let n = CALLS.fetch_add(1, Ordering::SeqCst);
+            InvocationResponse::success(&inv, n.to_string().into_bytes())
+        };
+        call_with(cache, op, payload, &handler)
+    }
+
+    fn call_with(
+        cache: &CachingMiddleware,
+        op: &str,
+        payload: &[u8],
+        handler: &dyn Fn(Invocation) -> InvocationResponse,
+    ) -> (bool, InvocationResponse) {
+        let inv = Invocation::new(
+            &KeyPair::new_server(),
+            WasccEntity::Actor("Mxxx".to_string()),
+            WasccEntity::Capability {
+                capid: "wascc:keyvalue".to_string(),
+                binding: "default".to_string(),
+            },
+            op,
+            payload.to_vec(),
+        );
+        match cache
+            .capability_invoke(inv, InvocationHandler::new(handler))
+            .unwrap()
+        {
+            MiddlewareResponse::Continue(r) => (false, r),
+            MiddlewareResponse::Halt(r) => (true, r),
+        }
+    }
+
+    #[test]
+    fn caches_until_invalidated_or_expired() {
+        let cache = CachingMiddleware::new(CacheConfig {
+            max_entries: 2,
+            ..Default::default()
+        })
+        .with_ttl("wascc:keyvalue", Some("Get"), Duration::from_millis(100))
+        .with_invalidating_operation("wascc:keyvalue", "Set");
+
+        let (hit, first) = call(&cache, "Get", b"a");
+        assert!(!hit);
+        let (hit, second) = call(&cache, "Get", b"a");
+        assert!(hit);
+        assert_eq!(first.msg, second.msg);
+        assert_ne!(first.invocation_id, second.invocation_id);
+
+        // Different payloads and uncached operations are invoked
+        assert!(!call(&cache, "Get", b"b").0);
+        assert!(!call(&cache, "List", b"a").0);
+        assert_eq!(2, cache.len());
+
+        // The size bound evicts the least recently used entry
+        assert!(!call(&cache, "Get", b"c").0);
+        assert_eq!(2, cache.len());
+        assert!(call(&cache, "Get", b"c").0);
+        assert!(call(&cache, "Get", b"b").0);
+        assert!(!call(&cache, "Get", b"a").0);
+
+        assert!(!call(&cache, "Set", b"a").0);
+        assert!(cache.is_empty());
+
+        assert!(!call(&cache, "Get", b"a").0);
+        std::thread::sleep(Duration::from_millis(120));
+        assert!(!call(&cache, "Get", b"a").0);
+    }
+
+    #[test]
+    fn writes_during_a_read_prevent_caching() {
+        let cache = CachingMiddleware::new(CacheConfig::default())
+            .with_ttl("wascc:keyvalue", Some("Get"), Duration::from_secs(5))
+            .with_invalidating_operation("wascc:keyvalue", "Set");
+
+        // A Set of a different key still invalidates the Get, whose response may predate it
+        let racing_set = |inv: Invocation| {
+            assert!(!call(&cache, "Set", b"b").0);
+            InvocationResponse::success(&inv, b"stale".to_vec())
+        };
+        assert!(!call_with(&cache, "Get", b"a", &racing_set).0);
+        assert!(cache.is_empty());
+        assert!(!call(&cache, "Get", b"a").0);
+        assert!(call(&cache, "Get", b"a").0);
+    }
+}
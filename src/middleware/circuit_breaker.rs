@@ -0,0 +1,315 @@
+//! # Circuit Breaker Middleware
+//!
+//! When the service behind a capability provider (or an actor) stops responding, every call to it
+//! keeps waiting for the full timeout. This middleware tracks the outcome of invocations per
+//! target and, once a target has failed a number of times in a row, _opens_ its circuit: further
+//! invocations of that target fail immediately with an error response instead of being delivered.
+//!
+//! After a cool-down the circuit becomes _half-open_ and a single trial invocation is let through.
+//! If it succeeds the circuit closes again, otherwise it re-opens for another cool-down.
+//!
+//! ```
+//! # use std::time::Duration;
+//! use wascc_host::middleware::circuit_breaker::{CircuitBreakerConfig, CircuitBreakerMiddleware};
+//!
+//! let breaker = CircuitBreakerMiddleware::new(CircuitBreakerConfig {
+//!     failure_threshold: 3,
+//!     cool_down: Duration::from_secs(10),
+//!     ..Default::default()
+//! });
+//! // Clones share their circuits, so a clone can be kept to monitor circuit states
+//! let monitor = breaker.clone();
+//! let host = wascc_host::Host::new();
+//! host.add_middleware(breaker);
+//! assert!(monitor.circuits().is_empty());
+//! ```
+
+use crate::middleware::{InvocationHandler, MiddlewareResponse};
+use crate::{Invocation, InvocationResponse, Middleware, Result, WasccEntity};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Configuration parameters.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// The number of consecutive failures that opens a target's circuit. The default is 5.
+    pub failure_threshold: u32,
+    /// How long a circuit stays open before a trial invocation is allowed. The default is 30s.
+    pub cool_down: Duration,
+    /// Invocations that succeed but take longer than this are counted as failures
+    pub slow_call_threshold: Option<Duration>,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: 5,
+            cool_down: Duration::from_secs(30),
+            slow_call_threshold: None,
+        }
+    }
+}
+
+/// The state of a target's circuit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Invocations are delivered as usual
+    Closed,
+    /// Invocations fail immediately
+    Open,
+    /// The cool-down has elapsed and a trial invocation decides whether the circuit closes
+    HalfOpen,
+}
+
+/// A snapshot of a target's circuit, for monitoring
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitStatus {
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    /// How long the circuit has been open, if it is open or half-open
+    pub open_for: Option<Duration>,
+}
+
+struct Circuit {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    trial_in_flight: bool,
+}
+
+impl Default for Circuit {
+    fn default() -> Self {
+        Circuit {
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            opened_at: None,
+            trial_in_flight: false,
+        }
+    }
+}
+
+/// A middleware that fails invocations fast when their target keeps failing. Clones of this
+/// middleware share their circuits.
+#[derive(Clone)]
+pub struct CircuitBreakerMiddleware {
+    config: CircuitBreakerConfig,
+    circuits: Arc<RwLock<HashMap<WasccEntity, Circuit>>>,
+}
+
+impl CircuitBreakerMiddleware {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        CircuitBreakerMiddleware {
+            config,
+            circuits: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// The state of a target's circuit. Targets that have never been invoked are closed
+    pub fn state(&self, target: &WasccEntity) -> CircuitState {
+        self.circuits
+            .read()
+            .unwrap()
+            .get(target)
+            .map(|c| c.state)
+            .unwrap_or(CircuitState::Closed)
+    }
+
+    /// The status of the circuit of every target invoked through this middleware
+    pub fn circuits(&self) -> HashMap<WasccEntity, CircuitStatus> {
+        self.circuits
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(t, c)| {
+                (
+                    t.clone(),
+                    CircuitStatus {
+                        state: c.state,
+                        consecutive_failures: c.consecutive_failures,
+                        open_for: c.opened_at.map(|o| o.elapsed()),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Closes a target's circuit, e.g. once its backing service is known to be back
+    pub fn reset(&self, target: &WasccEntity) {
+        self.circuits.write().unwrap().remove(target);
+    }
+
+    // Returns an error response if the invocation may not be delivered to its target
+    fn admit(&self, inv: &Invocation) -> Option<InvocationResponse> {
+        let mut lock = self.circuits.write().unwrap();
+        let circuit = lock.entry(inv.target.clone()).or_default();
+        let open_for = circuit.opened_at.map(|o| o.elapsed()).unwrap_or_default();
+        match circuit.state {
+            CircuitState::Closed => None,
+            CircuitState::Open if open_for >= self.config.cool_down => {
+                info!("Circuit for {} is half-open", inv.target.url());
+                circuit.state = CircuitState::HalfOpen;
+                circuit.trial_in_flight = true;
+                None
+            }
+            CircuitState::HalfOpen if !circuit.trial_in_flight => {
+                circuit.trial_in_flight = true;
+                None
+            }
+            _ => Some(InvocationResponse::error(
+                inv,
+                &format!(
+                    "Circuit open for {} after {} consecutive failures, failing fast for {}s",
+                    inv.target.url(),
+                    circuit.consecutive_failures,
+                    self.config.cool_down.saturating_sub(open_for).as_secs()
+                ),
+            )),
+        }
+    }
+
+    fn record(&self, target: &WasccEntity, failed: bool) {
+        let mut lock = self.circuits.write().unwrap();
+        let circuit = lock.entry(target.clone()).or_default();
+        circuit.trial_in_flight = false;
+        if !failed {
+            if circuit.state != CircuitState::Closed {
+                info!("Circuit for {} closed", target.url());
+            }
+            *circuit = Circuit::default();
+            return;
+        }
+        circuit.consecutive_failures = circuit.consecutive_failures.saturating_add(1);
+        if circuit.state == CircuitState::HalfOpen
+            || (circuit.state == CircuitState::Closed
+                && circuit.consecutive_failures >= self.config.failure_threshold)
+        {
+            warn!(
+                "Circuit for {} opened after {} consecutive failures",
+                target.url(),
+                circuit.consecutive_failures
+            );
+            circuit.state = CircuitState::Open;
+            circuit.opened_at = Some(Instant::now());
+        }
+    }
+
+    fn invoke(&self, inv: Invocation, handler: InvocationHandler) -> Result<MiddlewareResponse> {
+        if let Some(r) = self.admit(&inv) {
+            return Ok(MiddlewareResponse::Halt(r));
+        }
+        let target = inv.target.clone();
+        let start = Instant::now();
+        let response = handler.invoke(inv);
+        let slow = match self.config.slow_call_threshold {
+            Some(t) => start.elapsed() > t,
+            None => false,
+        };
+        self.record(&target, response.error.is_some() || slow);
+        Ok(MiddlewareResponse::Continue(response))
+    }
+}
+
+impl Middleware for CircuitBreakerMiddleware {
+    fn actor_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
+        Ok(inv)
+    }
+
+    fn actor_invoke(
+        &self,
+        inv: Invocation,
+        handler: InvocationHandler,
+    ) -> Result<MiddlewareResponse> {
+        self.invoke(inv, handler)
+    }
+
+    fn actor_post_invoke(&self, response: InvocationResponse) -> Result<InvocationResponse> {
+        Ok(response)
+    }
+
+    fn capability_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
+        Ok(inv)
+    }
+
+    fn capability_invoke(
+        &self,
+        inv: Invocation,
+        handler: InvocationHandler,
+    ) -> Result<MiddlewareResponse> {
+        self.invoke(inv, handler)
+    }
+
+    fn capability_post_invoke(&self, response: InvocationResponse) -> Result<InvocationResponse> {
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CircuitBreakerConfig, CircuitBreakerMiddleware, CircuitState};
+    use crate::middleware::{InvocationHandler, MiddlewareResponse};
+    use crate::{Invocation, InvocationResponse, Middleware, WasccEntity};
+    use std::time::Duration;
+    use wascap::prelude::KeyPair;
+
+    fn target() -> WasccEntity {
+        WasccEntity::Capability {
+            capid: "wascc:keyvalue".to_string(),
+            binding: "default".to_string(),
+        }
+    }
+
+    fn call(breaker: &CircuitBreakerMiddleware, fail: bool) -> (bool, InvocationResponse) {
+        let inv = Invocation::new(
+            &KeyPair::new_server(),
+            WasccEntity::Actor("Mxxx".to_string()),
+            target(),
+            "Get",
+            vec![],
+        );
+        let op = |inv: Invocation| {
+            if fail {
+                InvocationResponse::error(&inv, "backing service unavailable")
+            } else {
+                InvocationResponse::success(&inv, vec![])
+            }
+        };
+        match breaker
+            .capability_invoke(inv, InvocationHandler::new(&op))
+            .unwrap()
+        {
+            MiddlewareResponse::Continue(r) => (true, r),
+            MiddlewareResponse::Halt(r) => (false, r),
+        }
+    }
+
+    #[test]
+    fn opens_after_threshold_and_recovers() {
+        let breaker = CircuitBreakerMiddleware::new(CircuitBreakerConfig {
+            failure_threshold: 2,
+            cool_down: Duration::from_millis(50),
+            slow_call_threshold: None,
+        });
+
+        assert!(call(&breaker, true).0);
+        assert_eq!(CircuitState::Closed, breaker.state(&target()));
+        assert!(call(&breaker, true).0);
+        assert_eq!(CircuitState::Open, breaker.state(&target()));
+
+        let (delivered, r) = call(&breaker, false);
+        assert!(!delivered);
+        assert!(r.error.unwrap().starts_with("Circuit open"));
+
+        // A failed trial re-opens the circuit
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(call(&breaker, true).0);
+        assert_eq!(CircuitState::Open, breaker.state(&target()));
+        assert!(!call(&breaker, false).0);
+
+        // A successful trial closes it
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(call(&breaker, false).0);
+        assert_eq!(CircuitState::Closed, breaker.state(&target()));
+        assert_eq!(0, breaker.circuits()[&target()].consecutive_failures);
+    }
+}
@@ -1,11 +1,20 @@
+use crate::errors::{self, Error, ErrorKind};
+use crate::inthost::CallContext;
+use crate::metrics::RuntimeStats;
 use crate::Result;
-use crate::{plugins::PluginManager, Invocation, InvocationResponse};
+use crate::{plugins::PluginManager, Invocation, InvocationResponse, WasccEntity};
+use std::cell::RefCell;
 use std::sync::Arc;
 use std::sync::RwLock;
 use wapc::WapcHost;
 
+pub mod cache;
+pub mod circuit_breaker;
 #[cfg(feature = "prometheus_middleware")]
 pub mod prometheus;
+pub mod rate_limit;
+#[cfg(feature = "tracing_middleware")]
+pub mod tracing;
 
 /// The trait that must be implemented by all waSCC middleware
 pub trait Middleware: Send + Sync + 'static {
@@ -24,13 +33,39 @@ pub trait Middleware: Send + Sync + 'static {
         handler: InvocationHandler,
     ) -> Result<MiddlewareResponse>;
     fn capability_post_invoke(&self, response: InvocationResponse) -> Result<InvocationResponse>;
+
+    /// How the host treats errors returned by this middleware's hooks, unless the middleware is
+    /// installed with a different policy. Middleware that enforces policy, such as authorization
+    /// or validation, should fail closed
+    fn error_policy(&self) -> MiddlewareErrorPolicy {
+        MiddlewareErrorPolicy::FailOpen
+    }
 }
 
+/// What happens to an invocation when one of a middleware's hooks fails
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MiddlewareErrorPolicy {
+    /// The failure is logged and the invocation or response proceeds through the rest of the
+    /// pipeline as if the failed hook had returned it unchanged
+    FailOpen,
+    /// The invocation is abandoned and its caller receives an error response. A failure in a
+    /// pre-invoke hook means the actor or capability provider is never invoked, although the
+    /// post-invoke hooks of the middleware that ran before it still see the error response
+    FailClosed,
+}
+
+/// The result of a middleware's invoke hook. Middleware is nested like the layers of an onion:
+/// each middleware's handler invokes the next middleware in the chain, and the innermost handler
+/// runs the actor or capability provider
 pub enum MiddlewareResponse {
+    /// The response obtained by invoking the handler, possibly altered
     Continue(InvocationResponse),
+    /// A response produced without invoking the handler, so neither the rest of the chain nor
+    /// the actor or capability provider were invoked
     Halt(InvocationResponse),
 }
 
+/// Invokes the rest of the middleware chain, and ultimately the actor or capability provider
 pub struct InvocationHandler<'a> {
     operation: &'a dyn Fn(Invocation) -> InvocationResponse,
 }
@@ -45,218 +80,401 @@ impl<'a> InvocationHandler<'a> {
     }
 }
 
+/// Restricts the invocations that pass through a middleware
+#[derive(Debug, Clone, PartialEq)]
+pub enum MiddlewareScope {
+    /// Every invocation
+    All,
+    /// Only invocations to or from the listed actors (by public key) and capability providers
+    /// (by capability ID)
+    Entities(Vec<String>),
+}
+
+impl MiddlewareScope {
+    fn includes(&self, inv: &Invocation) -> bool {
+        match self {
+            MiddlewareScope::All => true,
+            MiddlewareScope::Entities(entities) => [&inv.origin, &inv.target]
+                .iter()
+                .any(|e| entities.iter().any(|s| s == scope_key(e))),
+        }
+    }
+}
+
+fn scope_key(entity: &WasccEntity) -> &str {
+    match entity {
+        WasccEntity::Actor(pk) => pk,
+        WasccEntity::Capability { capid, .. } => capid,
+    }
+}
+
+/// Describes a middleware installed in a host
+#[derive(Debug, Clone, PartialEq)]
+pub struct MiddlewareInfo {
+    pub name: String,
+    /// Middleware with a higher priority runs first, and so wraps middleware with a lower one.
+    /// Middleware of equal priority runs in the order it was added
+    pub priority: i32,
+    pub scope: MiddlewareScope,
+    /// How the host treats errors returned by the middleware's hooks. Defaults to the
+    /// middleware's own `Middleware::error_policy`
+    pub error_policy: MiddlewareErrorPolicy,
+}
+
+pub(crate) struct RegisteredMiddleware {
+    info: MiddlewareInfo,
+    middleware: Arc<dyn Middleware>,
+}
+
+/// Adds a middleware to the pipeline, after every middleware of the same or higher priority
+pub(crate) fn register(
+    middlewares: &RwLock<Vec<RegisteredMiddleware>>,
+    info: MiddlewareInfo,
+    middleware: Arc<dyn Middleware>,
+) -> Result<()> {
+    let mut lock = middlewares.write().unwrap();
+    if lock.iter().any(|m| m.info.name == info.name) {
+        return Err(errors::new(ErrorKind::Middleware(format!(
+            "A middleware named {} is already installed",
+            info.name
+        ))));
+    }
+    let pos = lock
+        .iter()
+        .position(|m| m.info.priority < info.priority)
+        .unwrap_or_else(|| lock.len());
+    lock.insert(pos, RegisteredMiddleware { info, middleware });
+    Ok(())
+}
+
+pub(crate) fn unregister(
+    middlewares: &RwLock<Vec<RegisteredMiddleware>>,
+    name: &str,
+) -> Result<()> {
+    let mut lock = middlewares.write().unwrap();
+    match lock.iter().position(|m| m.info.name == name) {
+        Some(pos) => {
+            lock.remove(pos);
+            Ok(())
+        }
+        None => Err(no_such_middleware(name)),
+    }
+}
+
+/// Changes the error policy of an installed middleware
+pub(crate) fn set_error_policy(
+    middlewares: &RwLock<Vec<RegisteredMiddleware>>,
+    name: &str,
+    policy: MiddlewareErrorPolicy,
+) -> Result<()> {
+    let mut lock = middlewares.write().unwrap();
+    match lock.iter_mut().find(|m| m.info.name == name) {
+        Some(m) => {
+            m.info.error_policy = policy;
+            Ok(())
+        }
+        None => Err(no_such_middleware(name)),
+    }
+}
+
+fn no_such_middleware(name: &str) -> Error {
+    errors::new(ErrorKind::Middleware(format!(
+        "No middleware named {} is installed",
+        name
+    )))
+}
+
+/// The installed middleware, in the order it runs
+pub(crate) fn installed(middlewares: &RwLock<Vec<RegisteredMiddleware>>) -> Vec<MiddlewareInfo> {
+    middlewares
+        .read()
+        .unwrap()
+        .iter()
+        .map(|m| m.info.clone())
+        .collect()
+}
+
+// A middleware in the chain an invocation passes through, with the error policy it was
+// registered with
+struct Link {
+    middleware: Arc<dyn Middleware>,
+    policy: MiddlewareErrorPolicy,
+}
+
+// The middleware an invocation passes through. The chain is copied out of the lock so the
+// pipeline can change while invocations are in flight, taking effect for the next invocation
+fn chain_for(middlewares: &RwLock<Vec<RegisteredMiddleware>>, inv: &Invocation) -> Vec<Link> {
+    middlewares
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|m| m.info.scope.includes(inv))
+        .map(|m| Link {
+            middleware: m.middleware.clone(),
+            policy: m.info.error_policy,
+        })
+        .collect()
+}
+
 /// Follows a chain of middleware, ultimately executing the native plugin
 pub(crate) fn invoke_native_capability(
-    middlewares: Arc<RwLock<Vec<Box<dyn Middleware>>>>,
+    middlewares: Arc<RwLock<Vec<RegisteredMiddleware>>>,
     inv: Invocation,
     plugins: Arc<RwLock<PluginManager>>,
-) -> Result<InvocationResponse> {
-    let inv = match run_capability_pre_invoke(inv.clone(), &middlewares.read().unwrap()) {
-        Ok(i) => i,
-        Err(e) => {
-            error!("Middleware failure: {}", e);
-            inv
-        }
+    validate: bool,
+    stats: &RuntimeStats,
+) -> InvocationResponse {
+    let chain = chain_for(&middlewares, &inv);
+    let invoke_operation = |inv: Invocation| match plugins.read().unwrap().call(&inv) {
+        Ok(r) => r,
+        Err(e) => InvocationResponse::error(&inv, &format!("failed to invoke capability: {}", e)),
     };
-
-    match run_native_capability_invoke(&middlewares.read().unwrap(), &plugins.read().unwrap(), inv)
-    {
-        Ok(response) => {
-            match run_capability_post_invoke(response.clone(), &middlewares.read().unwrap()) {
-                Ok(r) => Ok(r),
-                Err(e) => {
-                    error!("Middleware failure: {}", e);
-                    Ok(response)
-                }
-            }
-        }
-        Err(e) => Err(e),
-    }
+    run_pipeline(
+        &chain,
+        inv,
+        &invoke_operation,
+        Hook::Capability,
+        validate,
+        stats,
+    )
 }
 
 /// Follows a chain of middleware, ultimately executing a portable capability provider function
 pub(crate) fn invoke_portable_capability(
-    middlewares: Arc<RwLock<Vec<Box<dyn Middleware>>>>,
+    middlewares: Arc<RwLock<Vec<RegisteredMiddleware>>>,
     inv: Invocation,
     guest: &WapcHost,
-) -> Result<InvocationResponse> {
-    let inv = match run_capability_pre_invoke(inv.clone(), &middlewares.read().unwrap()) {
-        Ok(i) => i,
-        Err(e) => {
-            error!("Middleware failure: {}", e);
-            inv
-        }
+    current_call: &RwLock<Option<CallContext>>,
+    validate: bool,
+    stats: &RuntimeStats,
+) -> InvocationResponse {
+    let chain = chain_for(&middlewares, &inv);
+    let invoke_operation = |inv: Invocation| {
+        call_guest(guest, current_call, &inv).unwrap_or_else(|e| {
+            InvocationResponse::error(&inv, &format!("failed to invoke capability: {}", e))
+        })
     };
-
-    match run_portable_capability_invoke(&middlewares.read().unwrap(), inv, guest) {
-        Ok(response) => {
-            match run_capability_post_invoke(response.clone(), &middlewares.read().unwrap()) {
-                Ok(r) => Ok(r),
-                Err(e) => {
-                    error!("Middleware failure: {}", e);
-                    Ok(response)
-                }
-            }
-        }
-        Err(e) => Err(e),
-    }
+    run_pipeline(
+        &chain,
+        inv,
+        &invoke_operation,
+        Hook::Capability,
+        validate,
+        stats,
+    )
 }
 
 pub(crate) fn invoke_actor(
-    middlewares: Arc<RwLock<Vec<Box<dyn Middleware>>>>,
+    middlewares: Arc<RwLock<Vec<RegisteredMiddleware>>>,
     inv: Invocation,
     guest: &WapcHost,
-) -> Result<InvocationResponse> {
-    let inv = match run_actor_pre_invoke(inv.clone(), &middlewares.read().unwrap()) {
-        Ok(i) => i,
-        Err(e) => {
-            error!("Middleware failure: {}", e);
-            inv
-        }
+    current_call: &RwLock<Option<CallContext>>,
+    validate: bool,
+    stats: &RuntimeStats,
+) -> InvocationResponse {
+    let chain = chain_for(&middlewares, &inv);
+    let invoke_operation = |inv: Invocation| {
+        call_guest(guest, current_call, &inv).unwrap_or_else(|e| {
+            InvocationResponse::error(&inv, &format!("failed to invoke actor: {}", e))
+        })
     };
-
-    match run_actor_invoke(&middlewares.read().unwrap(), inv, guest) {
-        Ok(response) => {
-            match run_actor_post_invoke(response.clone(), &middlewares.read().unwrap()) {
-                Ok(r) => Ok(r),
-                Err(e) => {
-                    error!("Middleware failure: {}", e);
-                    Ok(response)
-                }
-            }
-        }
-        Err(e) => Err(e),
-    }
+    run_pipeline(&chain, inv, &invoke_operation, Hook::Actor, validate, stats)
 }
 
-fn run_actor_pre_invoke(
+// Runs an invocation through the pre-invoke hooks, the nested invoke hooks and the post-invoke
+// hooks of a chain. The antiforgery check runs in the innermost handler, on the invocation the
+// invoke hooks pass to it. Every middleware whose pre-invoke hook succeeded sees the response in
+// its post-invoke hook, even if a later pre-invoke hook or the antiforgery check stopped the
+// invocation, so middleware can always release what it set aside for the invocation
+fn run_pipeline(
+    chain: &[Link],
     inv: Invocation,
-    middlewares: &[Box<dyn Middleware>],
-) -> Result<Invocation> {
-    let mut cur_inv = inv;
-    for m in middlewares {
-        match m.actor_pre_invoke(cur_inv) {
-            Ok(i) => cur_inv = i.clone(),
-            Err(e) => return Err(e),
+    invoke_operation: &dyn Fn(Invocation) -> InvocationResponse,
+    hook: Hook,
+    validate: bool,
+    stats: &RuntimeStats,
+) -> InvocationResponse {
+    let id = inv.id.to_string();
+    let inv = match run_pre_invoke(inv, chain, hook) {
+        Ok(inv) => inv,
+        Err((entered, e)) => {
+            return run_post_invoke(middleware_failure(&id, e), &chain[..entered], hook)
         }
-    }
-    Ok(cur_inv)
+    };
+    let checked_operation = |inv: Invocation| match check_antiforgery(&inv, validate) {
+        Some(r) => {
+            stats.antiforgery_failure();
+            r
+        }
+        None => invoke_operation(inv),
+    };
+    let response = run_invoke(chain, inv, &checked_operation, hook);
+    run_post_invoke(response, chain, hook)
 }
 
-fn run_actor_invoke(
-    middlewares: &[Box<dyn Middleware>],
-    inv: Invocation,
-    guest: &WapcHost,
-) -> Result<InvocationResponse> {
-    let invoke_operation = |inv: Invocation| match guest.call(&inv.operation, &inv.msg) {
-        Ok(v) => InvocationResponse::success(&inv, v),
-        Err(e) => InvocationResponse::error(&inv, &format!("failed to invoke actor: {}", e)),
-    };
+// The response returned in place of an invocation's when the middleware pipeline fails
+fn middleware_failure(invocation_id: &str, e: Error) -> InvocationResponse {
+    error!("Middleware failure: {}", e);
+    InvocationResponse {
+        msg: Vec::new(),
+        error: Some(format!("Middleware failure: {}", e)),
+        invocation_id: invocation_id.to_string(),
+    }
+}
 
-    run_invoke(middlewares, inv, &invoke_operation)
+// Applies a middleware's error policy to a failure of one of its hooks, stopping the pipeline
+// if the middleware fails closed
+fn on_hook_error(policy: MiddlewareErrorPolicy, e: Error) -> Result<()> {
+    match policy {
+        MiddlewareErrorPolicy::FailOpen => {
+            error!("Middleware failure (continuing): {}", e);
+            Ok(())
+        }
+        MiddlewareErrorPolicy::FailClosed => Err(e),
+    }
 }
 
-fn run_actor_post_invoke(
-    resp: InvocationResponse,
-    middlewares: &[Box<dyn Middleware>],
-) -> Result<InvocationResponse> {
-    let mut cur_resp = resp;
-    for m in middlewares {
-        match m.actor_post_invoke(cur_resp) {
-            Ok(i) => cur_resp = i.clone(),
-            Err(e) => return Err(e),
+/// When the host is in strict mode, verifies that the invocation that made it through the
+/// middleware still matches its signed claims. Middleware that modified the invocation
+/// without re-signing it will cause the invocation to be rejected
+fn check_antiforgery(inv: &Invocation, validate: bool) -> Option<InvocationResponse> {
+    if !validate {
+        return None;
+    }
+    match inv.validate_antiforgery() {
+        Ok(_) => None,
+        Err(e) => {
+            error!(
+                "Invocation {} failed antiforgery check after middleware: {}",
+                inv.id, e
+            );
+            Some(InvocationResponse::error(
+                inv,
+                &format!("Invocation failed antiforgery check: {}", e),
+            ))
         }
     }
-    Ok(cur_resp)
 }
 
-pub(crate) fn run_capability_pre_invoke(
+// Runs the pre-invoke hooks of a chain. If a middleware that fails closed stops the invocation,
+// returns the number of middleware before it along with its error
+fn run_pre_invoke(
     inv: Invocation,
-    middlewares: &[Box<dyn Middleware>],
-) -> Result<Invocation> {
+    chain: &[Link],
+    hook: Hook,
+) -> std::result::Result<Invocation, (usize, Error)> {
     let mut cur_inv = inv;
-    for m in middlewares {
-        match m.capability_pre_invoke(cur_inv) {
-            Ok(i) => cur_inv = i.clone(),
-            Err(e) => return Err(e),
+    for (i, link) in chain.iter().enumerate() {
+        let m = link.middleware.as_ref();
+        let res = match hook {
+            Hook::Actor => m.actor_pre_invoke(cur_inv.clone()),
+            Hook::Capability => m.capability_pre_invoke(cur_inv.clone()),
+        };
+        match res {
+            Ok(inv) => cur_inv = inv,
+            Err(e) => on_hook_error(link.policy, e).map_err(|e| (i, e))?,
         }
     }
     Ok(cur_inv)
 }
 
-pub(crate) fn run_native_capability_invoke(
-    middlewares: &[Box<dyn Middleware>],
-    plugins: &PluginManager,
-    inv: Invocation,
-) -> Result<InvocationResponse> {
-    let invoke_operation = |inv: Invocation| match plugins.call(&inv) {
-        Ok(r) => r,
-        Err(e) => InvocationResponse::error(&inv, &format!("failed to invoke capability: {}", e)),
-    };
-
-    run_invoke(middlewares, inv, &invoke_operation)
+// Runs the post-invoke hooks of a chain. If a middleware that fails closed rejects the response,
+// the caller receives an error response instead, but the remaining hooks still see it
+fn run_post_invoke(resp: InvocationResponse, chain: &[Link], hook: Hook) -> InvocationResponse {
+    let mut cur_resp = resp;
+    for link in chain {
+        let m = link.middleware.as_ref();
+        let res = match hook {
+            Hook::Actor => m.actor_post_invoke(cur_resp.clone()),
+            Hook::Capability => m.capability_post_invoke(cur_resp.clone()),
+        };
+        match res {
+            Ok(r) => cur_resp = r,
+            Err(e) => {
+                if let Err(e) = on_hook_error(link.policy, e) {
+                    cur_resp = middleware_failure(&cur_resp.invocation_id, e);
+                }
+            }
+        }
+    }
+    cur_resp
 }
 
-pub(crate) fn run_portable_capability_invoke(
-    middlewares: &[Box<dyn Middleware>],
-    inv: Invocation,
+// Calls a guest with the invocation as it arrives at the end of the middleware chain. The calls
+// the guest makes while handling it carry over the headers and trace context that the
+// middleware left on the invocation
+fn call_guest(
     guest: &WapcHost,
-) -> Result<InvocationResponse> {
-    let invoke_operation = |inv: Invocation| match guest.call(&inv.operation, &inv.msg) {
-        Ok(v) => InvocationResponse::success(&inv, v),
-        Err(e) => InvocationResponse::error(&inv, &format!("failed to invoke capability: {}", e)),
-    };
+    current_call: &RwLock<Option<CallContext>>,
+    inv: &Invocation,
+) -> wapc::Result<InvocationResponse> {
+    *current_call.write().unwrap() = Some(CallContext::from_invocation(inv));
+    let res = guest.call(&inv.operation, &inv.msg);
+    *current_call.write().unwrap() = None;
+    res.map(|v| InvocationResponse::success(inv, v))
+}
 
-    run_invoke(middlewares, inv, &invoke_operation)
+// Which of a middleware's hooks a chain calls
+#[derive(Clone, Copy)]
+enum Hook {
+    Actor,
+    Capability,
 }
 
+// Runs the first middleware's invoke hook with a handler that runs the rest of the chain, so the
+// operation itself is only invoked once, by the innermost handler. A failing invoke hook of a
+// middleware that fails open is skipped: its caller receives the response of the rest of the
+// chain, which is only invoked if the failed hook hadn't already done so
 fn run_invoke(
-    middlewares: &[Box<dyn Middleware>],
+    chain: &[Link],
     inv: Invocation,
     invoke_operation: &dyn Fn(Invocation) -> InvocationResponse,
-) -> Result<InvocationResponse> {
-    let mut cur_resp = Ok(InvocationResponse::error(
-        &inv,
-        "No middleware invoked the operation",
-    ));
-
-    for m in middlewares.iter() {
-        match m.capability_invoke(inv.clone(), InvocationHandler::new(&invoke_operation)) {
-            Ok(mr) => match mr {
-                MiddlewareResponse::Continue(res) => cur_resp = Ok(res),
-                MiddlewareResponse::Halt(res) => return Ok(res),
-            },
-            Err(e) => return Err(e),
-        }
-    }
-
-    if middlewares.is_empty() {
-        Ok(invoke_operation(inv))
-    } else {
-        cur_resp
-    }
-}
-
-pub(crate) fn run_capability_post_invoke(
-    resp: InvocationResponse,
-    middlewares: &[Box<dyn Middleware>],
-) -> Result<InvocationResponse> {
-    let mut cur_resp = resp;
-    for m in middlewares {
-        match m.capability_post_invoke(cur_resp) {
-            Ok(i) => cur_resp = i.clone(),
-            Err(e) => return Err(e),
+    hook: Hook,
+) -> InvocationResponse {
+    let (link, rest) = match chain.split_first() {
+        Some(split) => split,
+        None => return invoke_operation(inv),
+    };
+    let id = inv.id.to_string();
+    let fail_open = link.policy == MiddlewareErrorPolicy::FailOpen;
+    let skipped = if fail_open { Some(inv.clone()) } else { None };
+    let handled = RefCell::new(None);
+    let next = |inv: Invocation| {
+        let r = run_invoke(rest, inv, invoke_operation, hook);
+        if fail_open {
+            *handled.borrow_mut() = Some(r.clone());
         }
+        r
+    };
+    let handler = InvocationHandler::new(&next);
+    let m = link.middleware.as_ref();
+    let res = match hook {
+        Hook::Actor => m.actor_invoke(inv, handler),
+        Hook::Capability => m.capability_invoke(inv, handler),
+    };
+    match res {
+        Ok(MiddlewareResponse::Continue(r)) | Ok(MiddlewareResponse::Halt(r)) => r,
+        Err(e) => match skipped {
+            Some(inv) => {
+                error!("Middleware failure (continuing): {}", e);
+                match handled.borrow_mut().take() {
+                    Some(r) => r,
+                    None => run_invoke(rest, inv, invoke_operation, hook),
+                }
+            }
+            None => middleware_failure(&id, e),
+        },
     }
-    Ok(cur_resp)
 }
 
 #[cfg(test)]
 mod tests {
     use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex, RwLock};
 
-    use super::Middleware;
+    use super::{Hook, Link, Middleware, MiddlewareErrorPolicy, MiddlewareInfo, MiddlewareScope};
     use crate::inthost::{Invocation, InvocationResponse, WasccEntity};
+    use crate::metrics::RuntimeStats;
     use crate::middleware::{InvocationHandler, MiddlewareResponse};
     use crate::Result;
     use wascap::prelude::KeyPair;
@@ -304,6 +522,16 @@ mod tests {
         }
     }
 
+    // Links middleware into a chain with the error policies they declare
+    fn links(mids: Vec<Arc<dyn Middleware>>) -> Vec<Link> {
+        mids.into_iter()
+            .map(|m| Link {
+                policy: m.error_policy(),
+                middleware: m,
+            })
+            .collect()
+    }
+
     static PRE: AtomicUsize = AtomicUsize::new(0);
     static POST: AtomicUsize = AtomicUsize::new(0);
     static CAP_PRE: AtomicUsize = AtomicUsize::new(0);
@@ -319,7 +547,7 @@ mod tests {
         };
         let hk = KeyPair::new_server();
 
-        let mids: Vec<Box<dyn Middleware>> = vec![Box::new(inc_mid)];
+        let mids = links(vec![Arc::new(inc_mid)]);
         let inv = Invocation::new(
             &hk,
             WasccEntity::Actor("test".to_string()),
@@ -330,10 +558,453 @@ mod tests {
             "testing",
             b"abc1234".to_vec(),
         );
-        let res = super::run_actor_pre_invoke(inv.clone(), &mids);
+        let res = super::run_pre_invoke(inv.clone(), &mids, Hook::Actor);
         assert!(res.is_ok());
-        let res2 = super::run_actor_pre_invoke(inv, &mids);
+        let res2 = super::run_pre_invoke(inv, &mids, Hook::Actor);
         assert!(res2.is_ok());
         assert_eq!(PRE.fetch_add(0, Ordering::SeqCst), 2);
     }
+
+    // Records the order in which the layers of the chain are entered and left
+    struct LayerMiddleware {
+        name: &'static str,
+        halt: bool,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl LayerMiddleware {
+        fn invoke(
+            &self,
+            hook: &str,
+            inv: Invocation,
+            handler: InvocationHandler,
+        ) -> Result<MiddlewareResponse> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", self.name, hook));
+            if self.halt {
+                return Ok(MiddlewareResponse::Halt(InvocationResponse::error(
+                    &inv, "halted",
+                )));
+            }
+            let r = handler.invoke(inv);
+            self.log.lock().unwrap().push(format!("{} done", self.name));
+            Ok(MiddlewareResponse::Continue(r))
+        }
+    }
+
+    impl Middleware for LayerMiddleware {
+        fn actor_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
+            Ok(inv)
+        }
+        fn actor_invoke(
+            &self,
+            inv: Invocation,
+            handler: InvocationHandler,
+        ) -> Result<MiddlewareResponse> {
+            self.invoke("actor", inv, handler)
+        }
+        fn actor_post_invoke(&self, response: InvocationResponse) -> Result<InvocationResponse> {
+            Ok(response)
+        }
+        fn capability_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
+            Ok(inv)
+        }
+        fn capability_invoke(
+            &self,
+            inv: Invocation,
+            handler: InvocationHandler,
+        ) -> Result<MiddlewareResponse> {
+            self.invoke("capability", inv, handler)
+        }
+        fn capability_post_invoke(
+            &self,
+            response: InvocationResponse,
+        ) -> Result<InvocationResponse> {
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn chain_nests_and_invokes_once() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let layer = |name, halt| -> Arc<dyn Middleware> {
+            Arc::new(LayerMiddleware {
+                name,
+                halt,
+                log: log.clone(),
+            })
+        };
+        let inv = Invocation::new(
+            &KeyPair::new_server(),
+            WasccEntity::Actor("test".to_string()),
+            WasccEntity::Actor("target".to_string()),
+            "testing",
+            vec![],
+        );
+        let op = |inv: Invocation| {
+            log.lock().unwrap().push("guest".to_string());
+            InvocationResponse::success(&inv, vec![])
+        };
+
+        let mids = links(vec![layer("a", false), layer("b", false)]);
+        let r = super::run_invoke(&mids, inv.clone(), &op, Hook::Actor);
+        assert!(r.error.is_none());
+        assert_eq!(
+            vec!["a actor", "b actor", "guest", "b done", "a done"],
+            *log.lock().unwrap()
+        );
+
+        log.lock().unwrap().clear();
+        let mids = links(vec![layer("a", false), layer("b", true), layer("c", false)]);
+        let r = super::run_invoke(&mids, inv, &op, Hook::Capability);
+        assert_eq!(Some("halted".to_string()), r.error);
+        assert_eq!(
+            vec!["a capability", "b capability", "a done"],
+            *log.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn registration_orders_and_scopes() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mids = RwLock::new(Vec::new());
+        let register = |name: &'static str, priority, scope| {
+            super::register(
+                &mids,
+                MiddlewareInfo {
+                    name: name.to_string(),
+                    priority,
+                    scope,
+                    error_policy: MiddlewareErrorPolicy::FailOpen,
+                },
+                Arc::new(LayerMiddleware {
+                    name,
+                    halt: false,
+                    log: log.clone(),
+                }),
+            )
+        };
+        register("a", 0, MiddlewareScope::All).unwrap();
+        register("b", 10, MiddlewareScope::All).unwrap();
+        register("c", 0, MiddlewareScope::All).unwrap();
+        register(
+            "debug",
+            0,
+            MiddlewareScope::Entities(vec!["wascc:keyvalue".to_string()]),
+        )
+        .unwrap();
+        assert!(register("a", 5, MiddlewareScope::All).is_err());
+
+        let names: Vec<_> = super::installed(&mids)
+            .into_iter()
+            .map(|m| m.name)
+            .collect();
+        assert_eq!(vec!["b", "a", "c", "debug"], names);
+
+        let op = |inv: Invocation| InvocationResponse::success(&inv, vec![]);
+        let inv = |capid: &str| {
+            Invocation::new(
+                &KeyPair::new_server(),
+                WasccEntity::Actor("Mxxx".to_string()),
+                WasccEntity::Capability {
+                    capid: capid.to_string(),
+                    binding: "default".to_string(),
+                },
+                "testing",
+                vec![],
+            )
+        };
+        let run = |inv: Invocation| {
+            log.lock().unwrap().clear();
+            let chain = super::chain_for(&mids, &inv);
+            super::run_invoke(&chain, inv, &op, Hook::Capability);
+            log.lock()
+                .unwrap()
+                .iter()
+                .filter(|l| l.ends_with("capability"))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec!["b capability", "a capability", "c capability"],
+            run(inv("wascc:messaging"))
+        );
+        assert_eq!(
+            vec![
+                "b capability",
+                "a capability",
+                "c capability",
+                "debug capability"
+            ],
+            run(inv("wascc:keyvalue"))
+        );
+
+        super::unregister(&mids, "a").unwrap();
+        assert!(super::unregister(&mids, "a").is_err());
+        assert_eq!(
+            vec!["b capability", "c capability"],
+            run(inv("wascc:messaging"))
+        );
+    }
+
+    // Fails every hook. The invoke hooks fail without invoking their handler
+    struct FailingMiddleware(MiddlewareErrorPolicy);
+
+    impl Middleware for FailingMiddleware {
+        fn actor_pre_invoke(&self, _inv: Invocation) -> Result<Invocation> {
+            Err("pre-invoke failed".to_string().into())
+        }
+        fn actor_invoke(
+            &self,
+            _inv: Invocation,
+            _handler: InvocationHandler,
+        ) -> Result<MiddlewareResponse> {
+            Err("invoke failed".to_string().into())
+        }
+        fn actor_post_invoke(&self, _response: InvocationResponse) -> Result<InvocationResponse> {
+            Err("post-invoke failed".to_string().into())
+        }
+        fn capability_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
+            self.actor_pre_invoke(inv)
+        }
+        fn capability_invoke(
+            &self,
+            inv: Invocation,
+            handler: InvocationHandler,
+        ) -> Result<MiddlewareResponse> {
+            self.actor_invoke(inv, handler)
+        }
+        fn capability_post_invoke(
+            &self,
+            response: InvocationResponse,
+        ) -> Result<InvocationResponse> {
+            self.actor_post_invoke(response)
+        }
+        fn error_policy(&self) -> MiddlewareErrorPolicy {
+            self.0
+        }
+    }
+
+    // Marks the invocations and responses that pass through it
+    struct TagMiddleware;
+
+    impl Middleware for TagMiddleware {
+        fn actor_pre_invoke(&self, mut inv: Invocation) -> Result<Invocation> {
+            inv.operation = format!("{}-tagged", inv.operation);
+            Ok(inv)
+        }
+        fn actor_invoke(
+            &self,
+            inv: Invocation,
+            handler: InvocationHandler,
+        ) -> Result<MiddlewareResponse> {
+            Ok(MiddlewareResponse::Continue(handler.invoke(inv)))
+        }
+        fn actor_post_invoke(
+            &self,
+            mut response: InvocationResponse,
+        ) -> Result<InvocationResponse> {
+            response.msg = b"tagged".to_vec();
+            Ok(response)
+        }
+        fn capability_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
+            Ok(inv)
+        }
+        fn capability_invoke(
+            &self,
+            inv: Invocation,
+            handler: InvocationHandler,
+        ) -> Result<MiddlewareResponse> {
+            Ok(MiddlewareResponse::Continue(handler.invoke(inv)))
+        }
+        fn capability_post_invoke(
+            &self,
+            response: InvocationResponse,
+        ) -> Result<InvocationResponse> {
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn error_policy_fails_open_or_closed() {
+        let inv = Invocation::new(
+            &KeyPair::new_server(),
+            WasccEntity::Actor("test".to_string()),
+            WasccEntity::Actor("target".to_string()),
+            "testing",
+            vec![],
+        );
+        let stats = RuntimeStats::default();
+        let invoked = AtomicUsize::new(0);
+        let op = |inv: Invocation| {
+            invoked.fetch_add(1, Ordering::SeqCst);
+            InvocationResponse::success(&inv, b"guest".to_vec())
+        };
+        let run = |chain: &[Link]| {
+            super::run_pipeline(chain, inv.clone(), &op, Hook::Actor, false, &stats)
+        };
+
+        // A middleware that fails open is skipped, and the rest of the pipeline still runs
+        let open = links(vec![
+            Arc::new(FailingMiddleware(MiddlewareErrorPolicy::FailOpen)),
+            Arc::new(TagMiddleware),
+        ]);
+        let i = super::run_pre_invoke(inv.clone(), &open, Hook::Actor).unwrap();
+        assert_eq!("testing-tagged", i.operation);
+        let r = run(&open);
+        assert!(r.error.is_none());
+        assert_eq!(b"tagged".to_vec(), r.msg);
+        assert_eq!(1, invoked.swap(0, Ordering::SeqCst));
+
+        // A middleware that fails closed stops the invocation in any hook
+        let closed = links(vec![
+            Arc::new(FailingMiddleware(MiddlewareErrorPolicy::FailClosed)),
+            Arc::new(TagMiddleware),
+        ]);
+        let r = run(&closed);
+        assert_eq!(inv.id, r.invocation_id);
+        let err = r.error.unwrap();
+        assert!(err.starts_with("Middleware failure"));
+        assert!(err.ends_with("pre-invoke failed"));
+        let r = super::run_invoke(&closed, inv.clone(), &op, Hook::Actor);
+        assert!(r.error.unwrap().ends_with("invoke failed"));
+        assert_eq!(0, invoked.load(Ordering::SeqCst));
+        let resp = InvocationResponse::success(&inv, vec![]);
+        let r = super::run_post_invoke(resp, &closed, Hook::Actor);
+        assert!(r.error.unwrap().ends_with("post-invoke failed"));
+        assert_eq!(b"tagged".to_vec(), r.msg);
+
+        // The post-invoke hooks of middleware whose pre-invoke hook ran still see the response
+        let after = links(vec![
+            Arc::new(TagMiddleware),
+            Arc::new(FailingMiddleware(MiddlewareErrorPolicy::FailClosed)),
+        ]);
+        let r = run(&after);
+        assert_eq!(b"tagged".to_vec(), r.msg);
+        assert!(r.error.unwrap().ends_with("pre-invoke failed"));
+        assert_eq!(0, invoked.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn error_policy_is_set_per_registration() {
+        let mids = RwLock::new(Vec::new());
+        super::register(
+            &mids,
+            MiddlewareInfo {
+                name: "failing".to_string(),
+                priority: 0,
+                scope: MiddlewareScope::All,
+                error_policy: MiddlewareErrorPolicy::FailOpen,
+            },
+            Arc::new(FailingMiddleware(MiddlewareErrorPolicy::FailClosed)),
+        )
+        .unwrap();
+        let inv = Invocation::new(
+            &KeyPair::new_server(),
+            WasccEntity::Actor("test".to_string()),
+            WasccEntity::Actor("target".to_string()),
+            "testing",
+            vec![],
+        );
+        let op = |inv: Invocation| InvocationResponse::success(&inv, vec![]);
+        let run = || {
+            let chain = super::chain_for(&mids, &inv);
+            super::run_invoke(&chain, inv.clone(), &op, Hook::Actor)
+        };
+        assert!(run().error.is_none());
+
+        super::set_error_policy(&mids, "failing", MiddlewareErrorPolicy::FailClosed).unwrap();
+        assert!(run().error.is_some());
+        assert_eq!(
+            MiddlewareErrorPolicy::FailClosed,
+            super::installed(&mids)[0].error_policy
+        );
+        assert!(
+            super::set_error_policy(&mids, "missing", MiddlewareErrorPolicy::FailOpen).is_err()
+        );
+    }
+
+    #[test]
+    fn strict_mode_rejects_unsigned_changes() {
+        let hk = KeyPair::new_server();
+        let inv = Invocation::new(
+            &hk,
+            WasccEntity::Actor("test".to_string()),
+            WasccEntity::Actor("target".to_string()),
+            "testing",
+            b"abc1234".to_vec(),
+        );
+        let mut tampered = inv.clone();
+        tampered.msg = b"xyz".to_vec();
+
+        assert!(super::check_antiforgery(&inv, true).is_none());
+        assert!(super::check_antiforgery(&tampered, false).is_none());
+        let res = super::check_antiforgery(&tampered, true).unwrap();
+        assert!(res.error.is_some());
+        assert_eq!(res.invocation_id, inv.id);
+    }
+
+    struct RewriteMiddleware;
+
+    impl Middleware for RewriteMiddleware {
+        fn actor_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
+            Ok(inv)
+        }
+        fn actor_invoke(
+            &self,
+            mut inv: Invocation,
+            handler: InvocationHandler,
+        ) -> Result<MiddlewareResponse> {
+            inv.msg = b"rewritten".to_vec();
+            Ok(MiddlewareResponse::Continue(handler.invoke(inv)))
+        }
+        fn actor_post_invoke(&self, response: InvocationResponse) -> Result<InvocationResponse> {
+            Ok(response)
+        }
+        fn capability_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
+            Ok(inv)
+        }
+        fn capability_invoke(
+            &self,
+            inv: Invocation,
+            handler: InvocationHandler,
+        ) -> Result<MiddlewareResponse> {
+            Ok(MiddlewareResponse::Continue(handler.invoke(inv)))
+        }
+        fn capability_post_invoke(
+            &self,
+            response: InvocationResponse,
+        ) -> Result<InvocationResponse> {
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn strict_mode_checks_invocations_rewritten_by_invoke_hooks() {
+        let inv = Invocation::new(
+            &KeyPair::new_server(),
+            WasccEntity::Actor("test".to_string()),
+            WasccEntity::Actor("target".to_string()),
+            "testing",
+            b"abc1234".to_vec(),
+        );
+        let stats = RuntimeStats::default();
+        let invoked = AtomicUsize::new(0);
+        let op = |inv: Invocation| {
+            invoked.fetch_add(1, Ordering::SeqCst);
+            InvocationResponse::success(&inv, vec![])
+        };
+        let chain = links(vec![Arc::new(RewriteMiddleware)]);
+
+        let r = super::run_pipeline(&chain, inv.clone(), &op, Hook::Actor, false, &stats);
+        assert!(r.error.is_none());
+        assert_eq!(1, invoked.swap(0, Ordering::SeqCst));
+
+        let r = super::run_pipeline(&chain, inv.clone(), &op, Hook::Actor, true, &stats);
+        let err = r.error.unwrap();
+        assert!(err.starts_with("Invocation failed antiforgery check"));
+        assert_eq!(0, invoked.load(Ordering::SeqCst));
+    }
 }
@@ -17,7 +17,7 @@
 //! let config = wascc_host::middleware::prometheus::PrometheusConfig {
 //!     metrics_server_addr: Some(server_addr),
 //!     pushgateway_config: None,
-//!     moving_average_window_size: None,
+//!     ..Default::default()
 //! };
 //! let middleware = wascc_host::middleware::prometheus::PrometheusMiddleware::new(config).unwrap();
 //! ```
@@ -25,7 +25,32 @@
 //! This will expose metrics at `http://127.0.0.1:9898/metrics`. This can be
 //! used as a scraping target in [Prometheus][prometheus].
 //!
-//! All metrics are prefixed with 'wascc_'.
+//! All metrics are prefixed with 'wascc_'. Invocations of actors and of capability providers are
+//! recorded in separate metric families, labeled with the `actor` and `operation`, or with the
+//! `capid`, `binding` and `operation` that was invoked:
+//!
+//! * `wascc_actor_invocations_total` and `wascc_capability_invocations_total` count invocations
+//! * `wascc_actor_invocation_errors_total` and `wascc_capability_invocation_errors_total` count
+//!   invocations whose response carries an error
+//! * `wascc_actor_invocation_duration_seconds` and `wascc_capability_invocation_duration_seconds`
+//!   are histograms of invocation latency
+//! * `wascc_actor_request_size_bytes`, `wascc_actor_response_size_bytes` and their
+//!   `wascc_capability_` equivalents are histograms of payload sizes
+//!
+//! Setting `runtime_metrics` to the host's `Host::runtime_metrics_source` also exports the host's
+//! inventory (`wascc_host_actors`, `wascc_host_native_providers`, `wascc_host_portable_providers`,
+//! `wascc_host_bindings`), the invocation queue depth of each subscription
+//! (`wascc_host_queue_depth`), whether the host is connected to the lattice
+//! (`wascc_host_lattice_connected`), and counters of actor restarts, live updates, scheduling
+//! auctions and antiforgery failures.
+//!
+//! Percentiles can then be computed in Prometheus, e.g. the 99th percentile latency of each
+//! operation of each actor:
+//!
+//! ```text
+//! histogram_quantile(0.99,
+//!   sum by (actor, operation, le) (rate(wascc_actor_invocation_duration_seconds_bucket[5m])))
+//! ```
 //!
 //! Here is a simple [Prometheus][prometheus] configuration that scrapes the above target and
 //! the [Prometheus Pushgateway][prometheus_pushgateway] (save the file as `prometheus.yml`):
@@ -91,21 +116,27 @@
 //! [grafana]: https://grafana.com/
 
 use crate::middleware::{InvocationHandler, MiddlewareResponse};
-use crate::{errors, Invocation, InvocationResponse, Middleware, Result, WasccEntity};
+use crate::{
+    errors, Invocation, InvocationResponse, Middleware, Result, RuntimeMetricsSource, WasccEntity,
+};
 use hyper::header::CONTENT_TYPE;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
-use prometheus::{labels, Encoder, Gauge, IntCounter, Registry, TextEncoder};
-use std::cmp::min;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    exponential_buckets, labels, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock, RwLockWriteGuard};
+use std::sync::{Arc, RwLock};
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
-// The default number of invocations to include when calculating average invocation times
-const DEFAULT_MOVING_AVERAGE_WINDOW_SIZE: i64 = 100;
 const WASCC: &str = "wascc";
+const ACTOR_LABELS: &[&str] = &["actor", "operation"];
+const CAPABILITY_LABELS: &[&str] = &["capid", "binding", "operation"];
 
 /// A Prometheus middleware that can serve or push metrics.
 pub struct PrometheusMiddleware {
@@ -119,47 +150,43 @@ pub struct PrometheusMiddleware {
 
 /// Holds all the different metrics collected by the middleware.
 struct Metrics {
-    /// Total number of invocations of capabilities
-    cap_total_inv_count: IntCounter,
-    /// Number of invocations per capability
-    cap_inv_count: HashMap<String, IntCounter>,
-    /// Number of invocations per operation on each capability
-    cap_operation_inv_count: HashMap<String, IntCounter>,
-    /// Average invocation time across all capabilities
-    cap_total_average_inv_time: Gauge,
-    /// Average invocation time per capability
-    cap_average_inv_time: HashMap<String, Gauge>,
-    /// Average invocation time per operation on each capability
-    cap_operation_average_inv_time: HashMap<String, Gauge>,
-
-    /// Total number of invocations of actors
-    actor_total_inv_count: IntCounter,
-    /// Number of invocations per actor
-    actor_inv_count: HashMap<String, IntCounter>,
-    /// Number of invocations per operation on each actor
-    actor_operation_inv_count: HashMap<String, IntCounter>,
-    /// Average invocation time across all actors
-    actor_total_average_inv_time: Gauge,
-    /// Average invocation time per actor
-    actor_average_inv_time: HashMap<String, Gauge>,
-    /// Average invocation time per operation on each actor
-    actor_operation_average_inv_time: HashMap<String, Gauge>,
+    /// Invocations of actors, labeled by actor and operation
+    actor: InvocationMetrics,
+    /// Invocations of capabilities, labeled by capability ID, binding and operation
+    cap: InvocationMetrics,
 
     /// State of active invocations
     active_inv_state: HashMap<String, InvocationState>,
+}
 
-    moving_average_window_size: i64,
+/// The metric families recorded for the invocations of either actors or capabilities.
+struct InvocationMetrics {
+    inv_count: IntCounterVec,
+    error_count: IntCounterVec,
+    inv_time: HistogramVec,
+    request_size: HistogramVec,
+    response_size: HistogramVec,
 }
 
 /// Configuration parameters.
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct PrometheusConfig {
     /// The address that Prometheus can scrape (pull model).
     pub metrics_server_addr: Option<SocketAddr>,
     /// Configuration for the Prometheus client (push model).
     pub pushgateway_config: Option<PushgatewayConfig>,
-    /// The number of invocations to include when calculating all average invocation times.
-    /// The default is 100.
+    /// The upper bounds, in seconds, of the invocation time histogram buckets.
+    /// The default is Prometheus' default buckets, from 5ms to 10s.
+    pub latency_buckets: Option<Vec<f64>>,
+    /// The upper bounds, in bytes, of the request and response size histogram buckets.
+    /// The default is 64 bytes to 1MB in powers of 4.
+    pub payload_size_buckets: Option<Vec<f64>>,
+    /// The host whose runtime metrics (inventory, queue depths and activity counters) are
+    /// exported alongside the invocation metrics, obtained from `Host::runtime_metrics_source`.
+    pub runtime_metrics: Option<RuntimeMetricsSource>,
+    /// Ignored. Invocation times are now recorded in histograms rather than moving averages, so
+    /// there is no window to configure. This field will be removed in the next release.
+    #[deprecated(note = "invocation times are recorded in histograms; this value is ignored")]
     pub moving_average_window_size: Option<i64>,
 }
 
@@ -179,15 +206,12 @@ pub struct BasicAuthentication {
     pub password: String,
 }
 
-/// Values needed during an invocation to compute average invocation times.
+/// Values needed during an invocation to record its outcome.
 struct InvocationState {
     start_time: Instant,
-    operation: String,
     target: WasccEntity,
-    /// Key to find metrics for a capability or an actor
-    metric_key: String,
-    /// Key to find metrics for an operation on a capability or an actor
-    operation_metric_key: String,
+    /// Values of the labels of the invoked actor or capability and operation
+    label_values: Vec<String>,
 }
 
 impl PrometheusMiddleware {
@@ -195,8 +219,13 @@ impl PrometheusMiddleware {
     where
         Self: Send + Sync,
     {
+        #[allow(deprecated)]
+        let window_size = config.moving_average_window_size;
+        if window_size.is_some() {
+            warn!("PrometheusConfig::moving_average_window_size is deprecated and has no effect");
+        }
         let metrics = PrometheusMiddleware::init_metrics(&config)?;
-        let registry = PrometheusMiddleware::init_registry(&metrics)?;
+        let registry = PrometheusMiddleware::init_registry(&metrics, &config)?;
         let metrics = Arc::new(RwLock::new(metrics));
         let registry = Arc::new(RwLock::new(registry));
 
@@ -250,55 +279,245 @@ impl PrometheusMiddleware {
         })
     }
 
-    fn init_registry(metrics: &Metrics) -> Result<Registry> {
+    fn init_registry(metrics: &Metrics, config: &PrometheusConfig) -> Result<Registry> {
         let registry = Registry::new();
-        registry.register(Box::new(metrics.cap_total_inv_count.clone()))?;
-        registry.register(Box::new(metrics.cap_total_average_inv_time.clone()))?;
-        registry.register(Box::new(metrics.actor_total_inv_count.clone()))?;
-        registry.register(Box::new(metrics.actor_total_average_inv_time.clone()))?;
+        metrics.actor.register(&registry)?;
+        metrics.cap.register(&registry)?;
+        if let Some(source) = &config.runtime_metrics {
+            registry.register(Box::new(RuntimeCollector::new(source.clone())?))?;
+        }
         Ok(registry)
     }
 
     fn init_metrics(config: &PrometheusConfig) -> Result<Metrics> {
+        let latency_buckets = config
+            .latency_buckets
+            .clone()
+            .unwrap_or_else(|| prometheus::DEFAULT_BUCKETS.to_vec());
+        let payload_size_buckets = match &config.payload_size_buckets {
+            Some(buckets) => buckets.clone(),
+            None => exponential_buckets(64.0, 4.0, 8)?,
+        };
+
         Ok(Metrics {
-            cap_total_inv_count: IntCounter::new(
-                format!("{}_cap_total_inv_count", WASCC),
-                "Total number of capability invocations".to_owned(),
+            actor: InvocationMetrics::new(
+                "actor",
+                ACTOR_LABELS,
+                &latency_buckets,
+                &payload_size_buckets,
             )?,
-            cap_inv_count: HashMap::new(),
-            cap_operation_inv_count: HashMap::new(),
-            cap_total_average_inv_time: Gauge::new(
-                format!("{}_cap_total_average_inv_time", WASCC),
-                "Average invocation time (ms) across all capabilities".to_owned(),
+            cap: InvocationMetrics::new(
+                "capability",
+                CAPABILITY_LABELS,
+                &latency_buckets,
+                &payload_size_buckets,
             )?,
-            cap_average_inv_time: HashMap::new(),
-            cap_operation_average_inv_time: HashMap::new(),
+            active_inv_state: HashMap::new(),
+        })
+    }
+}
+
+impl InvocationMetrics {
+    fn new(
+        kind: &str,
+        labels: &[&str],
+        latency_buckets: &[f64],
+        payload_size_buckets: &[f64],
+    ) -> Result<Self> {
+        let histogram = |name: &str, help: String, buckets: &[f64]| {
+            HistogramVec::new(
+                HistogramOpts::new(format!("{}_{}_{}", WASCC, kind, name), help)
+                    .buckets(buckets.to_vec()),
+                labels,
+            )
+        };
 
-            actor_total_inv_count: IntCounter::new(
-                format!("{}_actor_total_inv_count", WASCC),
-                "Total number of actor invocations".to_owned(),
+        Ok(InvocationMetrics {
+            inv_count: IntCounterVec::new(
+                Opts::new(
+                    format!("{}_{}_invocations_total", WASCC, kind),
+                    format!("Number of {} invocations", kind),
+                ),
+                labels,
             )?,
-            actor_inv_count: HashMap::new(),
-            actor_operation_inv_count: HashMap::new(),
-            actor_total_average_inv_time: Gauge::new(
-                format!("{}_actor_total_average_inv_time", WASCC),
-                "Average invocation time (ms) across all actors".to_owned(),
+            error_count: IntCounterVec::new(
+                Opts::new(
+                    format!("{}_{}_invocation_errors_total", WASCC, kind),
+                    format!("Number of {} invocations that returned an error", kind),
+                ),
+                labels,
             )?,
-            actor_average_inv_time: HashMap::new(),
-            actor_operation_average_inv_time: HashMap::new(),
+            inv_time: histogram(
+                "invocation_duration_seconds",
+                format!("Time (s) to invoke a {}", kind),
+                latency_buckets,
+            )?,
+            request_size: histogram(
+                "request_size_bytes",
+                format!("Size (bytes) of {} invocation payloads", kind),
+                payload_size_buckets,
+            )?,
+            response_size: histogram(
+                "response_size_bytes",
+                format!("Size (bytes) of {} invocation response payloads", kind),
+                payload_size_buckets,
+            )?,
+        })
+    }
 
-            active_inv_state: HashMap::new(),
-            moving_average_window_size: config
-                .moving_average_window_size
-                .unwrap_or_else(|| DEFAULT_MOVING_AVERAGE_WINDOW_SIZE),
+    fn register(&self, registry: &Registry) -> Result<()> {
+        registry.register(Box::new(self.inv_count.clone()))?;
+        registry.register(Box::new(self.error_count.clone()))?;
+        registry.register(Box::new(self.inv_time.clone()))?;
+        registry.register(Box::new(self.request_size.clone()))?;
+        registry.register(Box::new(self.response_size.clone()))?;
+        Ok(())
+    }
+}
+
+impl Metrics {
+    fn for_target(&self, target: &WasccEntity) -> &InvocationMetrics {
+        match target {
+            WasccEntity::Actor(_) => &self.actor,
+            WasccEntity::Capability { .. } => &self.cap,
+        }
+    }
+}
+
+/// Exports a host's runtime metrics, reading them whenever metrics are gathered.
+struct RuntimeCollector {
+    source: RuntimeMetricsSource,
+    actors: IntGauge,
+    native_providers: IntGauge,
+    portable_providers: IntGauge,
+    bindings: IntGauge,
+    queue_depth: IntGaugeVec,
+    lattice_connected: IntGauge,
+    actor_restarts: IntCounter,
+    live_updates: IntCounter,
+    auction_requests: IntCounter,
+    auction_bids: IntCounter,
+    antiforgery_failures: IntCounter,
+}
+
+impl RuntimeCollector {
+    fn new(source: RuntimeMetricsSource) -> Result<Self> {
+        let opts = |name: &str, help: &str| {
+            Opts::new(format!("{}_host_{}", WASCC, name), help.to_string())
+        };
+        Ok(RuntimeCollector {
+            source,
+            actors: IntGauge::with_opts(opts("actors", "Number of actors running in the host"))?,
+            native_providers: IntGauge::with_opts(opts(
+                "native_providers",
+                "Number of native capability providers in the host",
+            ))?,
+            portable_providers: IntGauge::with_opts(opts(
+                "portable_providers",
+                "Number of portable capability providers in the host",
+            ))?,
+            bindings: IntGauge::with_opts(opts(
+                "bindings",
+                "Number of bindings between actors and capability providers",
+            ))?,
+            queue_depth: IntGaugeVec::new(
+                opts(
+                    "queue_depth",
+                    "Number of invocations waiting to be handled per subscription",
+                ),
+                &["subject"],
+            )?,
+            lattice_connected: IntGauge::with_opts(opts(
+                "lattice_connected",
+                "Whether the host can reach the lattice (1) or not (0)",
+            ))?,
+            actor_restarts: IntCounter::with_opts(opts(
+                "actor_restarts_total",
+                "Number of times an actor was started again after being stopped",
+            ))?,
+            live_updates: IntCounter::with_opts(opts(
+                "live_updates_total",
+                "Number of live updates of actors",
+            ))?,
+            auction_requests: IntCounter::with_opts(opts(
+                "auction_requests_total",
+                "Number of scheduling auctions received",
+            ))?,
+            auction_bids: IntCounter::with_opts(opts(
+                "auction_bids_total",
+                "Number of scheduling auctions the host bid in",
+            ))?,
+            antiforgery_failures: IntCounter::with_opts(opts(
+                "antiforgery_failures_total",
+                "Number of invocations rejected by an antiforgery check",
+            ))?,
         })
     }
+
+    fn collectors(&self) -> Vec<&dyn Collector> {
+        vec![
+            &self.actors,
+            &self.native_providers,
+            &self.portable_providers,
+            &self.bindings,
+            &self.queue_depth,
+            &self.lattice_connected,
+            &self.actor_restarts,
+            &self.live_updates,
+            &self.auction_requests,
+            &self.auction_bids,
+            &self.antiforgery_failures,
+        ]
+    }
+}
+
+// Counters only go up, so they're advanced to the value read from the host
+fn advance_counter(counter: &IntCounter, value: u64) {
+    let delta = value as i64 - counter.get();
+    if delta > 0 {
+        counter.inc_by(delta);
+    }
+}
+
+impl Collector for RuntimeCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.collectors()
+            .into_iter()
+            .flat_map(|c| c.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let m = self.source.read();
+        self.actors.set(m.actors as i64);
+        self.native_providers.set(m.native_providers as i64);
+        self.portable_providers.set(m.portable_providers as i64);
+        self.bindings.set(m.bindings as i64);
+        // subscriptions that have gone away must not keep reporting their last depth
+        self.queue_depth.reset();
+        for (subject, depth) in &m.queue_depths {
+            self.queue_depth
+                .with_label_values(&[subject.as_str()])
+                .set(*depth as i64);
+        }
+        self.lattice_connected
+            .set(if m.lattice_connected { 1 } else { 0 });
+        advance_counter(&self.actor_restarts, m.actor_restarts);
+        advance_counter(&self.live_updates, m.live_updates);
+        advance_counter(&self.auction_requests, m.auction_requests);
+        advance_counter(&self.auction_bids, m.auction_bids);
+        advance_counter(&self.antiforgery_failures, m.antiforgery_failures);
+
+        self.collectors()
+            .into_iter()
+            .flat_map(|c| c.collect())
+            .collect()
+    }
 }
 
 impl Middleware for PrometheusMiddleware {
     fn actor_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
-        pre_invoke_count_inv(&self.metrics, &self.registry, &inv.target, &inv.operation);
-        pre_invoke_measure_inv_time(&self.metrics, &inv);
+        pre_invoke_record_inv(&self.metrics, &inv);
         Ok(inv)
     }
 
@@ -311,13 +530,12 @@ impl Middleware for PrometheusMiddleware {
     }
 
     fn actor_post_invoke(&self, response: InvocationResponse) -> Result<InvocationResponse> {
-        post_invoke_measure_inv_time(&self.metrics, &self.registry, &response);
+        post_invoke_record_inv(&self.metrics, &response);
         Ok(response)
     }
 
     fn capability_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
-        pre_invoke_count_inv(&self.metrics, &self.registry, &inv.target, &inv.operation);
-        pre_invoke_measure_inv_time(&self.metrics, &inv);
+        pre_invoke_record_inv(&self.metrics, &inv);
         Ok(inv)
     }
 
@@ -330,7 +548,7 @@ impl Middleware for PrometheusMiddleware {
     }
 
     fn capability_post_invoke(&self, response: InvocationResponse) -> Result<InvocationResponse> {
-        post_invoke_measure_inv_time(&self.metrics, &self.registry, &response);
+        post_invoke_record_inv(&self.metrics, &response);
         Ok(response)
     }
 }
@@ -350,134 +568,35 @@ impl From<prometheus::Error> for errors::Error {
     }
 }
 
-fn pre_invoke_count_inv(
-    metrics: &Arc<RwLock<Metrics>>,
-    registry: &Arc<RwLock<Registry>>,
-    target: &WasccEntity,
-    operation: &str,
-) {
-    let mut metrics = metrics.write().unwrap();
-
+fn label_values(target: &WasccEntity, operation: &str) -> Vec<String> {
     match target {
-        WasccEntity::Actor(actor) => {
-            metrics.actor_total_inv_count.inc();
-
-            let actor_key = get_metric_key(target);
-            if let Some(value) = metrics.actor_inv_count.get(&actor_key) {
-                value.inc();
-            } else {
-                let name = format!("{}_{}_inv_count", WASCC, &actor);
-                let help = format!("Number of invocations of actor '{}'", &actor);
-                register_counter(
-                    &mut metrics.actor_inv_count,
-                    &registry,
-                    actor_key,
-                    &name,
-                    &help,
-                );
-            }
-
-            let actor_operation_key = get_operation_metric_key(target, operation);
-            if let Some(value) = metrics.actor_operation_inv_count.get(&actor_operation_key) {
-                value.inc();
-            } else {
-                let name = format!("{}_{}_{}_inv_count", WASCC, &actor, &operation);
-                let help = format!(
-                    "Number of invocations of operation '{}' on actor '{}'",
-                    &operation, &actor
-                );
-                register_counter(
-                    &mut metrics.actor_operation_inv_count,
-                    &registry,
-                    actor_operation_key,
-                    &name,
-                    &help,
-                )
-            }
-        }
-        WasccEntity::Capability { capid, binding } => {
-            metrics.cap_total_inv_count.inc();
-
-            let cap_key = get_metric_key(target);
-            if let Some(value) = metrics.cap_inv_count.get(&cap_key) {
-                value.inc();
-            } else {
-                let name = format!("{}_{}_{}_inv_count", WASCC, &capid, &binding);
-                let help = format!(
-                    "Number of invocations of capability '{}' with binding '{}'",
-                    &capid, &binding
-                );
-                register_counter(&mut metrics.cap_inv_count, &registry, cap_key, &name, &help);
-            }
-
-            let cap_operation_key = get_operation_metric_key(target, operation);
-            if let Some(value) = metrics.cap_operation_inv_count.get(&cap_operation_key) {
-                value.inc();
-            } else {
-                let name = format!("{}_{}_{}_{}_inv_count", WASCC, &capid, &binding, &operation);
-                let help = format!(
-                    "Number of invocations of operation '{}' on capability '{}' with binding '{}'",
-                    &capid, &binding, &operation
-                );
-                register_counter(
-                    &mut metrics.cap_operation_inv_count,
-                    &registry,
-                    cap_operation_key,
-                    &name,
-                    &help,
-                );
-            }
-        }
-    }
-}
-
-fn register_counter(
-    counters: &mut HashMap<String, IntCounter>,
-    registry: &Arc<RwLock<Registry>>,
-    counter_lookup_key: String,
-    name: &str,
-    help: &str,
-) {
-    let counter = match IntCounter::new(name.to_string(), help.to_string()) {
-        Ok(counter) => {
-            counter.inc();
-            counter
-        }
-        Err(e) => {
-            error!("Error creating counter '{}': {}", &name, e);
-            return;
-        }
-    };
-
-    let reg = registry.write().unwrap();
-    if let Err(e) = reg.register(Box::new(counter.clone())) {
-        error!("Error registering counter '{}': {}", &name, e);
+        WasccEntity::Actor(actor) => vec![actor.to_string(), operation.to_string()],
+        WasccEntity::Capability { capid, binding } => vec![
+            capid.to_string(),
+            binding.to_string(),
+            operation.to_string(),
+        ],
     }
-
-    counters.insert(counter_lookup_key, counter);
-}
-
-fn get_operation_metric_key(target: &WasccEntity, operation: &str) -> String {
-    get_metric_key(target) + operation
 }
 
-fn get_metric_key(target: &WasccEntity) -> String {
-    match target {
-        WasccEntity::Actor(actor) => actor.clone(),
-        WasccEntity::Capability { capid, binding } => capid.clone() + binding,
+fn pre_invoke_record_inv(metrics: &Arc<RwLock<Metrics>>, inv: &Invocation) {
+    let mut metrics = metrics.write().unwrap();
+    let label_values = label_values(&inv.target, &inv.operation);
+    {
+        let values: Vec<&str> = label_values.iter().map(|v| v.as_str()).collect();
+        let inv_metrics = metrics.for_target(&inv.target);
+        inv_metrics.inv_count.with_label_values(&values).inc();
+        inv_metrics
+            .request_size
+            .with_label_values(&values)
+            .observe(inv.msg.len() as f64);
     }
-}
 
-fn pre_invoke_measure_inv_time(metrics: &Arc<RwLock<Metrics>>, inv: &Invocation) {
-    let mut metrics = metrics.write().unwrap();
     let state = InvocationState {
         start_time: Instant::now(),
-        operation: inv.operation.clone(),
         target: inv.target.clone(),
-        metric_key: get_metric_key(&inv.target),
-        operation_metric_key: get_operation_metric_key(&inv.target, &inv.operation),
+        label_values,
     };
-
     if metrics
         .active_inv_state
         .insert(inv.id.clone(), state)
@@ -487,227 +606,29 @@ fn pre_invoke_measure_inv_time(metrics: &Arc<RwLock<Metrics>>, inv: &Invocation)
     }
 }
 
-fn post_invoke_measure_inv_time(
-    metrics: &Arc<RwLock<Metrics>>,
-    registry: &Arc<RwLock<Registry>>,
-    response: &InvocationResponse,
-) {
+fn post_invoke_record_inv(metrics: &Arc<RwLock<Metrics>>, response: &InvocationResponse) {
     let mut metrics = metrics.write().unwrap();
-    let inv_end_time = Instant::now();
 
     // get the state for this invocation
     if let Some(state) = metrics.active_inv_state.remove(&response.invocation_id) {
-        let inv_time = if let Some(time) = inv_end_time.checked_duration_since(state.start_time) {
-            time.as_millis()
-        } else {
-            error!(
-                "Unable to compute invocation time for id '{}'",
-                &response.invocation_id
-            );
-            return;
-        };
-
-        set_new_total_avg(&metrics, &state.target, inv_time);
-
-        // was an actor or a capability invoked?
-        match &state.target {
-            WasccEntity::Actor(actor) => {
-                if let Some(gauge) = metrics.actor_average_inv_time.get(&state.metric_key) {
-                    set_gauge_avg(
-                        gauge,
-                        &metrics.actor_inv_count,
-                        metrics.moving_average_window_size,
-                        inv_time,
-                        &state.metric_key,
-                    );
-                } else {
-                    let name = format!("{}_{}_average_inv_time", WASCC, actor.clone());
-                    let help = format!("Average time (ms) to invoke actor '{}'", actor.clone());
-
-                    register_gauge(
-                        registry,
-                        &mut metrics.actor_average_inv_time,
-                        &state.metric_key,
-                        &name,
-                        &help,
-                        inv_time,
-                    );
-                }
-
-                if let Some(gauge) = metrics
-                    .actor_operation_average_inv_time
-                    .get(&state.operation_metric_key)
-                {
-                    set_gauge_avg(
-                        gauge,
-                        &metrics.actor_operation_inv_count,
-                        metrics.moving_average_window_size,
-                        inv_time,
-                        &state.operation_metric_key,
-                    );
-                } else {
-                    let name = format!(
-                        "{}_{}_{}_average_inv_time",
-                        WASCC,
-                        actor.clone(),
-                        &state.operation
-                    );
-                    let help = format!(
-                        "Average time (ms) to invoke operation '{}' on actor '{}'",
-                        &state.operation,
-                        actor.clone()
-                    );
-
-                    register_gauge(
-                        registry,
-                        &mut metrics.actor_operation_average_inv_time,
-                        &state.operation_metric_key,
-                        &name,
-                        &help,
-                        inv_time,
-                    );
-                }
-            }
-            WasccEntity::Capability { capid, binding } => {
-                if let Some(gauge) = metrics.cap_average_inv_time.get(&state.metric_key) {
-                    set_gauge_avg(
-                        gauge,
-                        &metrics.cap_inv_count,
-                        metrics.moving_average_window_size,
-                        inv_time,
-                        &state.metric_key,
-                    );
-                } else {
-                    let name = format!("{}_{}_{}_average_inv_time", WASCC, capid, binding);
-                    let help = format!(
-                        "Average time (ms) to invoke capability '{}' with binding '{}'",
-                        capid, binding
-                    );
-
-                    register_gauge(
-                        registry,
-                        &mut metrics.cap_average_inv_time,
-                        &state.metric_key,
-                        &name,
-                        &help,
-                        inv_time,
-                    );
-                }
-
-                if let Some(gauge) = metrics
-                    .cap_operation_average_inv_time
-                    .get(&state.operation_metric_key)
-                {
-                    set_gauge_avg(
-                        gauge,
-                        &metrics.cap_operation_inv_count,
-                        metrics.moving_average_window_size,
-                        inv_time,
-                        &state.operation_metric_key,
-                    );
-                } else {
-                    let name = format!(
-                        "{}_{}_{}_{}_average_inv_time",
-                        WASCC, capid, binding, &state.operation
-                    );
-                    let help = format!(
-                        "Average time (ms) to invoke operation '{}' on capability '{}' with binding '{}'",
-                        &state.operation, capid, binding
-                    );
-
-                    register_gauge(
-                        registry,
-                        &mut metrics.cap_operation_average_inv_time,
-                        &state.operation_metric_key,
-                        &name,
-                        &help,
-                        inv_time,
-                    );
-                }
-            }
+        let values: Vec<&str> = state.label_values.iter().map(|v| v.as_str()).collect();
+        let inv_metrics = metrics.for_target(&state.target);
+        inv_metrics
+            .inv_time
+            .with_label_values(&values)
+            .observe(state.start_time.elapsed().as_secs_f64());
+        inv_metrics
+            .response_size
+            .with_label_values(&values)
+            .observe(response.msg.len() as f64);
+        if response.error.is_some() {
+            inv_metrics.error_count.with_label_values(&values).inc();
         }
     } else {
         error!("No active invocation with id '{}'", &response.invocation_id);
     }
 }
 
-fn set_gauge_avg(
-    gauge: &Gauge,
-    inv_count: &HashMap<String, IntCounter>,
-    moving_average_window_size: i64,
-    inv_time: u128,
-    metric_key: &str,
-) {
-    let inv_count = inv_count.get(metric_key).map(|c| c.get()).unwrap_or(1);
-    gauge.set(calc_avg(
-        moving_average_window_size,
-        inv_count,
-        inv_time,
-        gauge.get(),
-    ));
-}
-
-fn register_gauge(
-    registry: &Arc<RwLock<Registry>>,
-    avg_inv_time: &mut HashMap<String, Gauge>,
-    gauge_lookup_key: &str,
-    name: &str,
-    help: &str,
-    initial_value: u128,
-) {
-    let initial_value = initial_value as f64;
-
-    match Gauge::new(name.to_string(), help.to_string()) {
-        Ok(gauge) => {
-            gauge.set(initial_value);
-            avg_inv_time.insert(gauge_lookup_key.to_string(), gauge.clone());
-
-            if let Err(e) = registry.write().unwrap().register(Box::new(gauge)) {
-                error!("Error registering gauge '{}': {}", &name, e);
-            }
-        }
-        Err(e) => error!("Error creating gauge '{}': {}", &name, e),
-    }
-}
-
-// set new average invocation time across all actors or capabilities
-fn set_new_total_avg(metrics: &RwLockWriteGuard<Metrics>, target: &WasccEntity, inv_time: u128) {
-    match target {
-        WasccEntity::Actor(_) => {
-            metrics.actor_total_average_inv_time.set(calc_avg(
-                metrics.moving_average_window_size,
-                metrics.actor_total_inv_count.get(),
-                inv_time,
-                metrics.actor_total_average_inv_time.get(),
-            ));
-        }
-        WasccEntity::Capability {
-            capid: _,
-            binding: _,
-        } => {
-            metrics.cap_total_average_inv_time.set(calc_avg(
-                metrics.moving_average_window_size,
-                metrics.cap_total_inv_count.get(),
-                inv_time,
-                metrics.cap_total_average_inv_time.get(),
-            ));
-        }
-    };
-}
-
-fn calc_avg(
-    moving_average_window_size: i64,
-    inv_count: i64,
-    inv_time: u128,
-    current_avg_inv_time: f64,
-) -> f64 {
-    let inv_time = inv_time as f64;
-    let inv_count = min(inv_count, moving_average_window_size);
-    // invocations are counted in pre_invoke so the `inv_count` _includes_ the current invocation
-    let total_current_inv_time = current_avg_inv_time * (inv_count - 1) as f64;
-    (total_current_inv_time + inv_time as f64) / inv_count as f64
-}
-
 impl Drop for PrometheusMiddleware {
     fn drop(&mut self) {
         if let Some(kill_switch) = self.metrics_server_kill_switch.take() {
@@ -826,15 +747,20 @@ fn push_metrics(
 
 #[cfg(test)]
 mod tests {
-    use super::WASCC;
+    use super::{RuntimeCollector, WASCC};
+    use crate::bus::InprocBus;
+    use crate::metrics::RuntimeStats;
     use crate::middleware::prometheus::{
         PrometheusConfig, PrometheusMiddleware, PushgatewayConfig,
     };
-    use crate::{Invocation, InvocationResponse, Middleware, WasccEntity};
+    use crate::{Invocation, InvocationResponse, Middleware, RuntimeMetricsSource, WasccEntity};
     use mockito::{mock, Matcher};
+    use prometheus::{Encoder, Registry, TextEncoder};
     use rand::random;
+    use std::collections::HashMap;
     use std::net::SocketAddr;
     use std::ops::Mul;
+    use std::sync::{Arc, RwLock};
     use std::time::Duration;
     use wascap::prelude::KeyPair;
 
@@ -900,7 +826,8 @@ mod tests {
         let actor_invocation1 = actor_invocation(ACTOR1, ACTOR_OPERATION1);
         let actor_invocation2 = actor_invocation(ACTOR2, ACTOR_OPERATION2);
         let actor_invocation1_response = invocation_response(&actor_invocation1.id);
-        let actor_invocation2_response = invocation_response(&actor_invocation2.id);
+        let mut actor_invocation2_response = invocation_response(&actor_invocation2.id);
+        actor_invocation2_response.error = Some("failed".to_string());
 
         let cap_invocation1 = cap_invocation(CAPID1, BINDING1, CAP_OPERATION1);
         let cap_invocation2 = cap_invocation(CAPID2, BINDING2, CAP_OPERATION2);
@@ -911,7 +838,8 @@ mod tests {
         let config = PrometheusConfig {
             metrics_server_addr: Some(server_addr),
             pushgateway_config: None,
-            moving_average_window_size: None,
+            latency_buckets: Some(vec![0.001, 0.01, 0.1]),
+            ..Default::default()
         };
         let middleware = PrometheusMiddleware::new(config).unwrap();
 
@@ -930,100 +858,71 @@ mod tests {
 
         let url = format!("http://{}/metrics", &server_addr.to_string());
         let body = reqwest::blocking::get(&url)?.text()?;
-
-        let total_invocations = invocations_op1 + invocations_op2;
+        let has = |line: String| body.lines().any(|l| l == line);
 
         // capabilities: counts
-        assert!(body
-            .find(&format!(
-                "{}_cap_total_inv_count {}",
-                WASCC, total_invocations
-            ))
-            .is_some());
-        assert!(body
-            .find(&format!(
-                "{}_{}_{}_inv_count {}",
-                WASCC, CAPID1, BINDING1, invocations_op1
-            ))
-            .is_some());
-        assert!(body
-            .find(&format!(
-                "{}_{}_{}_inv_count {}",
-                WASCC, CAPID2, BINDING2, invocations_op2
-            ))
-            .is_some());
-        // capabilities: averages
-        assert!(body
-            .find(&format!("{}_cap_total_average_inv_time", WASCC))
-            .is_some());
-        assert!(body
-            .find(&format!(
-                "{}_{}_{}_average_inv_time",
-                WASCC, CAPID1, BINDING1
-            ))
-            .is_some());
-        assert!(body
-            .find(&format!(
-                "{}_{}_{}_average_inv_time",
-                WASCC, CAPID2, BINDING2
-            ))
-            .is_some());
-        // capabilities: averages for operations
-        assert!(body
-            .find(&format!(
-                "{}_{}_{}_{}_average_inv_time",
-                WASCC, CAPID1, BINDING1, CAP_OPERATION1
-            ))
-            .is_some());
-        assert!(body
-            .find(&format!(
-                "{}_{}_{}_{}_average_inv_time",
-                WASCC, CAPID2, BINDING2, CAP_OPERATION2
-            ))
-            .is_some());
-
-        // actors
-        assert!(body
-            .find(&format!(
-                "{}_actor_total_inv_count {}",
-                WASCC, total_invocations
-            ))
-            .is_some());
-        assert!(body
-            .find(&format!(
-                "{}_{}_inv_count {}",
-                WASCC, ACTOR1, invocations_op1
-            ))
-            .is_some());
-        assert!(body
-            .find(&format!(
-                "{}_{}_inv_count {}",
-                WASCC, ACTOR2, invocations_op2
-            ))
-            .is_some());
-        // actors: averages
-        assert!(body
-            .find(&format!("actor_total_average_inv_time"))
-            .is_some());
-        assert!(body
-            .find(&format!("{}_{}_average_inv_time", WASCC, ACTOR1,))
-            .is_some());
-        assert!(body
-            .find(&format!("{}_{}_average_inv_time", WASCC, ACTOR2))
-            .is_some());
-        // actors: averages for operations
-        assert!(body
-            .find(&format!(
-                "{}_{}_{}_average_inv_time",
-                WASCC, ACTOR1, ACTOR_OPERATION1
-            ))
-            .is_some());
-        assert!(body
-            .find(&format!(
-                "{}_{}_{}_average_inv_time",
-                WASCC, ACTOR2, ACTOR_OPERATION2
-            ))
-            .is_some());
+        assert!(has(format!(
+            "{}_capability_invocations_total{{binding=\"{}\",capid=\"{}\",operation=\"{}\"}} {}",
+            WASCC, BINDING1, CAPID1, CAP_OPERATION1, invocations_op1
+        )));
+        assert!(has(format!(
+            "{}_capability_invocations_total{{binding=\"{}\",capid=\"{}\",operation=\"{}\"}} {}",
+            WASCC, BINDING2, CAPID2, CAP_OPERATION2, invocations_op2
+        )));
+        // capabilities: latency histogram with the configured buckets
+        assert!(has(format!(
+            "{}_capability_invocation_duration_seconds_count{{binding=\"{}\",capid=\"{}\",operation=\"{}\"}} {}",
+            WASCC, BINDING1, CAPID1, CAP_OPERATION1, invocations_op1
+        )));
+        assert!(body.contains(&format!(
+            "{}_capability_invocation_duration_seconds_bucket{{binding=\"{}\",capid=\"{}\",operation=\"{}\",le=\"0.01\"}}",
+            WASCC, BINDING1, CAPID1, CAP_OPERATION1
+        )));
+        // capabilities: payload sizes
+        assert!(has(format!(
+            "{}_capability_request_size_bytes_sum{{binding=\"{}\",capid=\"{}\",operation=\"{}\"}} {}",
+            WASCC,
+            BINDING2,
+            CAPID2,
+            CAP_OPERATION2,
+            invocations_op2 * "cap_msg".len()
+        )));
+        assert!(has(format!(
+            "{}_capability_response_size_bytes_sum{{binding=\"{}\",capid=\"{}\",operation=\"{}\"}} {}",
+            WASCC,
+            BINDING2,
+            CAPID2,
+            CAP_OPERATION2,
+            invocations_op2 * "response".len()
+        )));
+
+        // actors: counts
+        assert!(has(format!(
+            "{}_actor_invocations_total{{actor=\"{}\",operation=\"{}\"}} {}",
+            WASCC, ACTOR1, ACTOR_OPERATION1, invocations_op1
+        )));
+        assert!(has(format!(
+            "{}_actor_invocations_total{{actor=\"{}\",operation=\"{}\"}} {}",
+            WASCC, ACTOR2, ACTOR_OPERATION2, invocations_op2
+        )));
+        // actors: errors
+        assert!(has(format!(
+            "{}_actor_invocation_errors_total{{actor=\"{}\",operation=\"{}\"}} {}",
+            WASCC, ACTOR2, ACTOR_OPERATION2, invocations_op2
+        )));
+        assert!(!body.contains(&format!(
+            "{}_actor_invocation_errors_total{{actor=\"{}\"",
+            WASCC, ACTOR1
+        )));
+        // actors: latency histogram
+        assert!(has(format!(
+            "{}_actor_invocation_duration_seconds_count{{actor=\"{}\",operation=\"{}\"}} {}",
+            WASCC, ACTOR1, ACTOR_OPERATION1, invocations_op1
+        )));
+        assert!(has(format!(
+            "{}_actor_invocation_duration_seconds_bucket{{actor=\"{}\",operation=\"{}\",le=\"+Inf\"}} {}",
+            WASCC, ACTOR2, ACTOR_OPERATION2, invocations_op2
+        )));
 
         // check that invocation state is cleaned up
         assert!(middleware
@@ -1036,6 +935,45 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_runtime_metrics() {
+        let stats = Arc::new(RuntimeStats::default());
+        let source = RuntimeMetricsSource {
+            claims: Arc::new(RwLock::new(HashMap::new())),
+            caps: Arc::new(RwLock::new(HashMap::new())),
+            bindings: Arc::new(RwLock::new(HashMap::new())),
+            bus: Arc::new(InprocBus::new()),
+            stats: stats.clone(),
+        };
+        let (_s, r) = crossbeam_channel::unbounded();
+        stats.track_queue("wasmbus.actor.Mxxx", r);
+        stats.live_update();
+        stats.live_update();
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(RuntimeCollector::new(source).unwrap()))
+            .unwrap();
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+        let body = String::from_utf8(buffer).unwrap();
+        assert!(body
+            .lines()
+            .any(|l| l == "wascc_host_queue_depth{subject=\"wasmbus.actor.Mxxx\"} 0"));
+        assert!(body.lines().any(|l| l == "wascc_host_live_updates_total 2"));
+        assert!(body.lines().any(|l| l == "wascc_host_lattice_connected 1"));
+
+        // Subscriptions that have gone away are no longer reported
+        stats.untrack_queue("wasmbus.actor.Mxxx");
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+        assert!(!String::from_utf8(buffer).unwrap().contains("Mxxx"));
+    }
+
     #[test]
     fn test_push_metrics() {
         // The data format that is used is not compatible with any current Mockito
@@ -1062,7 +1000,7 @@ mod tests {
                 job: None,
                 push_basic_auth: None,
             }),
-            moving_average_window_size: None,
+            ..Default::default()
         };
 
         let middleware = PrometheusMiddleware::new(config).unwrap();
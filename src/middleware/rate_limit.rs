@@ -0,0 +1,404 @@
+//! # Rate Limiting Middleware
+//!
+//! Limits how often invocations may be made, so that one actor can't flood a capability provider
+//! that other actors on the same host rely on. Each limit is a token bucket that refills at a
+//! steady rate and holds up to a burst of tokens. An invocation takes a token from every bucket
+//! that applies to it. Limits can be set:
+//!
+//! * host-wide, covering every invocation that passes through the middleware
+//! * per origin actor, either for specific actors or as a default for every actor
+//! * per claims issuer, covering every actor signed by that account
+//! * per target, for a capability ID (or actor) or for one of its operations
+//!
+//! An invocation that is over a limit is either rejected with an error response or delayed until
+//! tokens are available.
+//!
+//! ```
+//! # use std::time::Duration;
+//! use wascc_host::middleware::rate_limit::{OverLimit, RateLimit, RateLimitMiddleware};
+//!
+//! let host = wascc_host::Host::new();
+//! let h = host.clone();
+//! let limiter = RateLimitMiddleware::new(OverLimit::Delay(Duration::from_millis(500)))
+//!     .with_host_limit(RateLimit::per_second(1000.0, 100))
+//!     .with_default_actor_limit(RateLimit::per_second(50.0, 10))
+//!     .with_operation_limit("wascc:keyvalue", Some("Set"), RateLimit::per_second(20.0, 5))
+//!     .with_issuer_resolver(move |actor| h.claims_for_actor(actor).map(|c| c.issuer));
+//! host.add_middleware(limiter);
+//! ```
+
+use crate::middleware::{InvocationHandler, MiddlewareResponse};
+use crate::{Invocation, InvocationResponse, Middleware, Result, WasccEntity};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// The wait reported for a bucket that never refills
+const MAX_WAIT_SECS: f64 = 86_400.0;
+
+/// A token bucket limit: `burst` invocations may be made at once, and tokens are replenished at
+/// `rate` per second
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub fn per_second(rate: f64, burst: u32) -> RateLimit {
+        RateLimit {
+            rate,
+            burst: burst.max(1),
+        }
+    }
+}
+
+/// What happens to an invocation that is over a limit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverLimit {
+    /// The invocation is halted with an error response
+    Reject,
+    /// The invocation waits for tokens, and is rejected if it would wait longer than this
+    Delay(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BucketKey {
+    Host,
+    Actor(String),
+    Issuer(String),
+    Target(String),
+    Operation(String, String),
+}
+
+impl BucketKey {
+    fn describe(&self) -> String {
+        match self {
+            BucketKey::Host => "host".to_string(),
+            BucketKey::Actor(pk) => format!("actor {}", pk),
+            BucketKey::Issuer(iss) => format!("issuer {}", iss),
+            BucketKey::Target(t) => t.to_string(),
+            BucketKey::Operation(t, op) => format!("{} operation {}", t, op),
+        }
+    }
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    // Refills the bucket and returns how long until a token is available
+    fn wait(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst as f64);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            Duration::from_secs(0)
+        } else {
+            let secs = (1.0 - self.tokens) / self.limit.rate;
+            if secs.is_finite() && secs > 0.0 {
+                Duration::from_secs_f64(secs.min(MAX_WAIT_SECS))
+            } else {
+                Duration::from_secs_f64(MAX_WAIT_SECS)
+            }
+        }
+    }
+}
+
+/// A token bucket rate limiting middleware
+pub struct RateLimitMiddleware {
+    over_limit: OverLimit,
+    limits: HashMap<BucketKey, RateLimit>,
+    default_actor: Option<RateLimit>,
+    issuer_resolver: Option<Box<dyn Fn(&str) -> Option<String> + Send + Sync>>,
+    buckets: Mutex<HashMap<BucketKey, TokenBucket>>,
+}
+
+impl RateLimitMiddleware {
+    pub fn new(over_limit: OverLimit) -> Self {
+        RateLimitMiddleware {
+            over_limit,
+            limits: HashMap::new(),
+            default_actor: None,
+            issuer_resolver: None,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Limits the total number of invocations passing through the middleware
+    pub fn with_host_limit(self, limit: RateLimit) -> Self {
+        self.with_limit(BucketKey::Host, limit)
+    }
+
+    /// Limits the invocations made by an actor
+    pub fn with_actor_limit(self, actor: &str, limit: RateLimit) -> Self {
+        self.with_limit(BucketKey::Actor(actor.to_string()), limit)
+    }
+
+    /// Limits the invocations made by every actor that has no limit of its own. Each actor gets
+    /// its own bucket
+    pub fn with_default_actor_limit(self, limit: RateLimit) -> Self {
+        RateLimitMiddleware {
+            default_actor: Some(limit),
+            ..self
+        }
+    }
+
+    /// Limits the invocations made by all actors signed by an issuer (account). Requires an
+    /// issuer resolver
+    pub fn with_issuer_limit(self, issuer: &str, limit: RateLimit) -> Self {
+        self.with_limit(BucketKey::Issuer(issuer.to_string()), limit)
+    }
+
+    /// Limits the invocations of a target (a capability ID or an actor's public key), or of a
+    /// single one of its operations
+    pub fn with_operation_limit(
+        self,
+        target: &str,
+        operation: Option<&str>,
+        limit: RateLimit,
+    ) -> Self {
+        let key = match operation {
+            Some(op) => BucketKey::Operation(target.to_string(), op.to_string()),
+            None => BucketKey::Target(target.to_string()),
+        };
+        self.with_limit(key, limit)
+    }
+
+    /// Sets the function used to look up the issuer of an origin actor's claims, e.g. with
+    /// `Host::claims_for_actor`
+    pub fn with_issuer_resolver(
+        self,
+        resolver: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        RateLimitMiddleware {
+            issuer_resolver: Some(Box::new(resolver)),
+            ..self
+        }
+    }
+
+    fn with_limit(self, key: BucketKey, limit: RateLimit) -> Self {
+        let mut limits = self.limits;
+        limits.insert(key, limit);
+        RateLimitMiddleware { limits, ..self }
+    }
+
+    // The buckets (and their limits) that apply to an invocation
+    fn applicable(&self, inv: &Invocation) -> Vec<(BucketKey, RateLimit)> {
+        let mut keys = vec![BucketKey::Host];
+        if let WasccEntity::Actor(pk) = &inv.origin {
+            keys.push(BucketKey::Actor(pk.to_string()));
+            if let Some(issuer) = self.issuer_resolver.as_ref().and_then(|r| r(pk)) {
+                keys.push(BucketKey::Issuer(issuer));
+            }
+        }
+        let target = match &inv.target {
+            WasccEntity::Actor(pk) => pk,
+            WasccEntity::Capability { capid, .. } => capid,
+        };
+        keys.push(BucketKey::Target(target.to_string()));
+        keys.push(BucketKey::Operation(
+            target.to_string(),
+            inv.operation.to_string(),
+        ));
+
+        keys.into_iter()
+            .filter_map(|k| {
+                let limit = match k {
+                    BucketKey::Actor(_) => self.limits.get(&k).copied().or(self.default_actor),
+                    _ => self.limits.get(&k).copied(),
+                };
+                limit.map(|l| (k, l))
+            })
+            .collect()
+    }
+
+    // Takes a token from every applicable bucket, or returns the longest wait and the bucket
+    // responsible for it when any of them is empty
+    fn try_acquire(
+        &self,
+        limits: &[(BucketKey, RateLimit)],
+    ) -> std::result::Result<(), (Duration, BucketKey)> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut longest: Option<(Duration, BucketKey)> = None;
+        for (key, limit) in limits {
+            let bucket = buckets
+                .entry(key.clone())
+                .or_insert_with(|| TokenBucket::new(*limit));
+            let wait = bucket.wait();
+            let longer = match longest {
+                Some((w, _)) => wait > w,
+                None => wait > Duration::from_secs(0),
+            };
+            if longer {
+                longest = Some((wait, key.clone()));
+            }
+        }
+        match longest {
+            Some(l) => Err(l),
+            None => {
+                for (key, _) in limits {
+                    if let Some(b) = buckets.get_mut(key) {
+                        b.tokens -= 1.0;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn invoke(&self, inv: Invocation, handler: InvocationHandler) -> Result<MiddlewareResponse> {
+        let limits = self.applicable(&inv);
+        let start = Instant::now();
+        loop {
+            match self.try_acquire(&limits) {
+                Ok(_) => return Ok(MiddlewareResponse::Continue(handler.invoke(inv))),
+                Err((wait, key)) => match self.over_limit {
+                    OverLimit::Delay(max) if start.elapsed() + wait <= max => {
+                        std::thread::sleep(wait);
+                    }
+                    _ => {
+                        warn!(
+                            "Invocation of {} on {} rejected: {} rate limit exceeded",
+                            inv.operation,
+                            inv.target.url(),
+                            key.describe()
+                        );
+                        return Ok(MiddlewareResponse::Halt(InvocationResponse::error(
+                            &inv,
+                            &format!("Rate limit exceeded for {}", key.describe()),
+                        )));
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl Middleware for RateLimitMiddleware {
+    fn actor_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
+        Ok(inv)
+    }
+
+    fn actor_invoke(
+        &self,
+        inv: Invocation,
+        handler: InvocationHandler,
+    ) -> Result<MiddlewareResponse> {
+        self.invoke(inv, handler)
+    }
+
+    fn actor_post_invoke(&self, response: InvocationResponse) -> Result<InvocationResponse> {
+        Ok(response)
+    }
+
+    fn capability_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
+        Ok(inv)
+    }
+
+    fn capability_invoke(
+        &self,
+        inv: Invocation,
+        handler: InvocationHandler,
+    ) -> Result<MiddlewareResponse> {
+        self.invoke(inv, handler)
+    }
+
+    fn capability_post_invoke(&self, response: InvocationResponse) -> Result<InvocationResponse> {
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{OverLimit, RateLimit, RateLimitMiddleware};
+    use crate::middleware::{InvocationHandler, MiddlewareResponse};
+    use crate::{Invocation, InvocationResponse, Middleware, WasccEntity};
+    use std::time::{Duration, Instant};
+    use wascap::prelude::KeyPair;
+
+    fn call(limiter: &RateLimitMiddleware, actor: &str, op: &str) -> Option<String> {
+        let inv = Invocation::new(
+            &KeyPair::new_server(),
+            WasccEntity::Actor(actor.to_string()),
+            WasccEntity::Capability {
+                capid: "wascc:keyvalue".to_string(),
+                binding: "default".to_string(),
+            },
+            op,
+            vec![],
+        );
+        let op = |inv: Invocation| InvocationResponse::success(&inv, vec![]);
+        match limiter
+            .capability_invoke(inv, InvocationHandler::new(&op))
+            .unwrap()
+        {
+            MiddlewareResponse::Continue(_) => None,
+            MiddlewareResponse::Halt(r) => r.error,
+        }
+    }
+
+    #[test]
+    fn rejects_over_limit_per_actor_and_issuer() {
+        let limiter = RateLimitMiddleware::new(OverLimit::Reject)
+            .with_default_actor_limit(RateLimit::per_second(0.1, 2))
+            .with_issuer_limit("Axxx", RateLimit::per_second(0.1, 3))
+            .with_issuer_resolver(|pk| {
+                if pk.starts_with("team") {
+                    Some("Axxx".to_string())
+                } else {
+                    None
+                }
+            });
+
+        assert!(call(&limiter, "team1", "Get").is_none());
+        assert!(call(&limiter, "team1", "Get").is_none());
+        assert_eq!(
+            "Rate limit exceeded for actor team1",
+            call(&limiter, "team1", "Get").unwrap()
+        );
+        // Each actor has its own bucket, but they share their issuer's
+        assert!(call(&limiter, "team2", "Get").is_none());
+        assert_eq!(
+            "Rate limit exceeded for issuer Axxx",
+            call(&limiter, "team2", "Get").unwrap()
+        );
+        assert!(call(&limiter, "other", "Get").is_none());
+    }
+
+    #[test]
+    fn limits_operations_and_delays() {
+        let limiter = RateLimitMiddleware::new(OverLimit::Delay(Duration::from_millis(500)))
+            .with_operation_limit(
+                "wascc:keyvalue",
+                Some("Set"),
+                RateLimit::per_second(20.0, 1),
+            );
+
+        let start = Instant::now();
+        assert!(call(&limiter, "Mxxx", "Set").is_none());
+        assert!(call(&limiter, "Mxxx", "Set").is_none());
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert!(call(&limiter, "Mxxx", "Get").is_none());
+
+        let limiter = RateLimitMiddleware::new(OverLimit::Delay(Duration::from_millis(10)))
+            .with_host_limit(RateLimit::per_second(1.0, 1));
+        assert!(call(&limiter, "Mxxx", "Get").is_none());
+        assert_eq!(
+            "Rate limit exceeded for host",
+            call(&limiter, "Mxxx", "Get").unwrap()
+        );
+    }
+}
@@ -0,0 +1,394 @@
+//! # Tracing Middleware
+//!
+//! Every invocation carries a `TraceContext` identifying its span in a distributed trace. Calls
+//! an actor makes while handling an invocation are child spans of that invocation, and trace
+//! contexts travel with invocations across the lattice, so a request that passes from an HTTP
+//! server provider to an actor, on to a key-value provider and then to another actor forms a
+//! single trace, even when those run on different hosts. When a native provider dispatches an
+//! HTTP request carrying a `traceparent` header to an actor, the actor's invocation continues
+//! that trace instead of starting a new one.
+//!
+//! Middleware can adjust an invocation's trace context in its pre-invoke or invoke hook. The
+//! calls an actor makes are child spans of the context the invocation carries when it reaches
+//! the actor, while this middleware records the context as it stands when its own invoke hook
+//! runs, so it should come later in the middleware chain than any middleware that changes
+//! trace contexts.
+//!
+//! This middleware records a span for every invocation it sees and exports the spans in
+//! batches, encoded as [OTLP JSON][otlp]. Spans can be appended to a file (one export request
+//! per line) or posted to a local collector's OTLP/HTTP endpoint.
+//!
+//! Enable this middleware using the feature flag `tracing_middleware`.
+//!
+//! ```
+//! # use std::time::Duration;
+//! use wascc_host::middleware::tracing::{SpanExporter, TracingConfig, TracingMiddleware};
+//!
+//! let config = TracingConfig {
+//!     exporter: SpanExporter::Collector("http://127.0.0.1:4318/v1/traces".to_string()),
+//!     service_name: "wascc-host".to_string(),
+//!     ..Default::default()
+//! };
+//! let middleware = TracingMiddleware::new(config).unwrap();
+//! ```
+//!
+//! [otlp]: https://opentelemetry.io/docs/specs/otlp/#json-protobuf-encoding
+
+use crate::middleware::{InvocationHandler, MiddlewareResponse};
+use crate::{errors, Invocation, InvocationResponse, Middleware, Result, TraceContext};
+use crossbeam::{Receiver, Sender};
+use crossbeam_channel as channel;
+use serde_json::{json, Value};
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_COLLECTOR: &str = "http://127.0.0.1:4318/v1/traces";
+const SPAN_KIND_SERVER: u8 = 2;
+const STATUS_CODE_ERROR: u8 = 2;
+
+/// Where finished spans are sent
+#[derive(Debug, Clone)]
+pub enum SpanExporter {
+    /// Appends each batch of spans to a file as one line of OTLP JSON
+    File(PathBuf),
+    /// Posts each batch of spans to a collector's OTLP/HTTP traces endpoint. Only plain `http`
+    /// endpoints are supported, as the collector is expected to run locally
+    Collector(String),
+}
+
+/// Configuration parameters.
+#[derive(Debug, Clone)]
+pub struct TracingConfig {
+    pub exporter: SpanExporter,
+    /// The `service.name` resource attribute of exported spans. The default is `wascc-host`.
+    pub service_name: String,
+    /// The number of spans that triggers an export. The default is 512.
+    pub batch_size: usize,
+    /// The longest a finished span waits to be exported. The default is 5s.
+    pub flush_interval: Duration,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig {
+            exporter: SpanExporter::Collector(DEFAULT_COLLECTOR.to_string()),
+            service_name: "wascc-host".to_string(),
+            batch_size: 512,
+            flush_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+struct SpanData {
+    context: TraceContext,
+    name: String,
+    start: u128,
+    end: u128,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+/// A middleware that records a span for every invocation and exports them as OTLP JSON
+pub struct TracingMiddleware {
+    spans: Option<Sender<SpanData>>,
+    exporter_handle: Option<JoinHandle<()>>,
+}
+
+impl TracingMiddleware {
+    pub fn new(config: TracingConfig) -> Result<Self> {
+        let mut sink = Sink::new(&config.exporter)?;
+        let (spans_s, spans_r) = channel::unbounded();
+        let handle = std::thread::spawn(move || {
+            export_spans(spans_r, &mut sink, &config);
+        });
+        Ok(TracingMiddleware {
+            spans: Some(spans_s),
+            exporter_handle: Some(handle),
+        })
+    }
+
+    fn invoke(&self, inv: Invocation, handler: InvocationHandler) -> Result<MiddlewareResponse> {
+        if !inv.trace_context.sampled {
+            return Ok(MiddlewareResponse::Continue(handler.invoke(inv)));
+        }
+        let context = inv.trace_context.clone();
+        let name = format!("{} {}", inv.target.url(), inv.operation);
+        let attributes = vec![
+            ("wascc.origin", inv.origin_url()),
+            ("wascc.target", inv.target.url()),
+            ("wascc.operation", inv.operation.to_string()),
+            ("wascc.invocation_id", inv.id.to_string()),
+            ("wascc.host_id", inv.host_id.to_string()),
+        ];
+        let start = unix_nanos();
+        let response = handler.invoke(inv);
+        if let Some(ref spans) = self.spans {
+            let _ = spans.send(SpanData {
+                context,
+                name,
+                start,
+                end: unix_nanos(),
+                attributes,
+                error: response.error.clone(),
+            });
+        }
+        Ok(MiddlewareResponse::Continue(response))
+    }
+}
+
+impl Drop for TracingMiddleware {
+    // Closing the span channel makes the exporter flush its last batch and exit
+    fn drop(&mut self) {
+        self.spans.take();
+        if let Some(h) = self.exporter_handle.take() {
+            let _ = h.join();
+        }
+    }
+}
+
+impl Middleware for TracingMiddleware {
+    fn actor_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
+        Ok(inv)
+    }
+
+    fn actor_invoke(
+        &self,
+        inv: Invocation,
+        handler: InvocationHandler,
+    ) -> Result<MiddlewareResponse> {
+        self.invoke(inv, handler)
+    }
+
+    fn actor_post_invoke(&self, response: InvocationResponse) -> Result<InvocationResponse> {
+        Ok(response)
+    }
+
+    fn capability_pre_invoke(&self, inv: Invocation) -> Result<Invocation> {
+        Ok(inv)
+    }
+
+    fn capability_invoke(
+        &self,
+        inv: Invocation,
+        handler: InvocationHandler,
+    ) -> Result<MiddlewareResponse> {
+        self.invoke(inv, handler)
+    }
+
+    fn capability_post_invoke(&self, response: InvocationResponse) -> Result<InvocationResponse> {
+        Ok(response)
+    }
+}
+
+fn unix_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+}
+
+// Collects finished spans into batches until the middleware is dropped
+fn export_spans(spans: Receiver<SpanData>, sink: &mut Sink, config: &TracingConfig) {
+    let mut batch = Vec::new();
+    let mut deadline = Instant::now() + config.flush_interval;
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let disconnected = match spans.recv_timeout(timeout) {
+            Ok(span) => {
+                batch.push(span);
+                false
+            }
+            Err(channel::RecvTimeoutError::Timeout) => false,
+            Err(channel::RecvTimeoutError::Disconnected) => true,
+        };
+        if batch.len() >= config.batch_size || Instant::now() >= deadline || disconnected {
+            if !batch.is_empty() {
+                let request = encode_spans(&config.service_name, &batch);
+                if let Err(e) = sink.export(&request) {
+                    error!("Failed to export {} spans: {}", batch.len(), e);
+                }
+                batch.clear();
+            }
+            deadline = Instant::now() + config.flush_interval;
+        }
+        if disconnected {
+            break;
+        }
+    }
+}
+
+// Encodes a batch of spans as an OTLP JSON `ExportTraceServiceRequest`
+fn encode_spans(service_name: &str, spans: &[SpanData]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|s| {
+            let mut span = json!({
+                "traceId": s.context.trace_id,
+                "spanId": s.context.span_id,
+                "name": s.name,
+                "kind": SPAN_KIND_SERVER,
+                "startTimeUnixNano": s.start.to_string(),
+                "endTimeUnixNano": s.end.to_string(),
+                "attributes": s.attributes.iter().map(|(k, v)| attribute(k, v)).collect::<Vec<_>>(),
+                "status": match s.error {
+                    Some(ref e) => json!({ "code": STATUS_CODE_ERROR, "message": e }),
+                    None => json!({}),
+                },
+            });
+            if let Some(ref parent) = s.context.parent_span_id {
+                span["parentSpanId"] = json!(parent);
+            }
+            span
+        })
+        .collect();
+    json!({
+        "resourceSpans": [{
+            "resource": { "attributes": [attribute("service.name", service_name)] },
+            "scopeSpans": [{
+                "scope": { "name": "wascc-host", "version": crate::VERSION },
+                "spans": spans,
+            }],
+        }]
+    })
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+enum Sink {
+    File(std::fs::File),
+    Collector {
+        address: String,
+        host: String,
+        path: String,
+    },
+}
+
+impl Sink {
+    fn new(exporter: &SpanExporter) -> Result<Sink> {
+        match exporter {
+            SpanExporter::File(path) => Ok(Sink::File(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            SpanExporter::Collector(url) => {
+                let rest = url.strip_prefix("http://").ok_or_else(|| {
+                    errors::new(errors::ErrorKind::MiscHost(format!(
+                        "Unsupported trace collector endpoint {}, only http:// is supported",
+                        url
+                    )))
+                })?;
+                let (host, path) = match rest.find('/') {
+                    Some(i) => (&rest[..i], &rest[i..]),
+                    None => (rest, "/v1/traces"),
+                };
+                let address = if host.contains(':') {
+                    host.to_string()
+                } else {
+                    format!("{}:80", host)
+                };
+                Ok(Sink::Collector {
+                    address,
+                    host: host.to_string(),
+                    path: path.to_string(),
+                })
+            }
+        }
+    }
+
+    fn export(&mut self, request: &Value) -> Result<()> {
+        let body = serde_json::to_vec(request).map_err(|e| e.to_string())?;
+        match self {
+            Sink::File(f) => {
+                f.write_all(&body)?;
+                f.write_all(b"\n")?;
+                f.flush()?;
+                Ok(())
+            }
+            Sink::Collector {
+                address,
+                host,
+                path,
+            } => {
+                let mut stream = TcpStream::connect(address.as_str())?;
+                stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+                write!(
+                    stream,
+                    "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    path,
+                    host,
+                    body.len()
+                )?;
+                stream.write_all(&body)?;
+                let mut response = String::new();
+                stream.read_to_string(&mut response)?;
+                let status = response.split_whitespace().nth(1).unwrap_or_default();
+                if status.starts_with('2') {
+                    Ok(())
+                } else {
+                    Err(errors::new(errors::ErrorKind::MiscHost(format!(
+                        "Trace collector responded with status {}",
+                        status
+                    ))))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SpanExporter, TracingConfig, TracingMiddleware};
+    use crate::middleware::InvocationHandler;
+    use crate::{Invocation, InvocationResponse, Middleware, WasccEntity};
+    use wascap::prelude::KeyPair;
+
+    #[test]
+    fn exports_spans_to_file() {
+        let path = std::env::temp_dir().join(format!("spans-{}.json", uuid::Uuid::new_v4()));
+        let middleware = TracingMiddleware::new(TracingConfig {
+            exporter: SpanExporter::File(path.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let mut inv = Invocation::new(
+            &KeyPair::new_server(),
+            WasccEntity::Actor("Mxxx".to_string()),
+            WasccEntity::Capability {
+                capid: "wascc:keyvalue".to_string(),
+                binding: "default".to_string(),
+            },
+            "Get",
+            vec![],
+        );
+        let parent = inv.trace_context.clone();
+        inv.trace_context = parent.child();
+        let op = |inv: Invocation| InvocationResponse::error(&inv, "no such key");
+        middleware
+            .capability_invoke(inv, InvocationHandler::new(&op))
+            .unwrap();
+        drop(middleware);
+
+        let exported = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let request: serde_json::Value = serde_json::from_str(exported.trim()).unwrap();
+        let span = &request["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(parent.trace_id, span["traceId"]);
+        assert_eq!(parent.span_id, span["parentSpanId"]);
+        assert_eq!("no such key", span["status"]["message"]);
+    }
+
+    #[test]
+    fn rejects_https_collectors() {
+        assert!(TracingMiddleware::new(TracingConfig {
+            exporter: SpanExporter::Collector("https://collector:4318/v1/traces".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
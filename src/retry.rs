@@ -0,0 +1,372 @@
+// Retrying of bus invocations that fail before reaching their target, e.g. because of a lattice
+// timeout, a subject with no subscribers, or a host restarting mid-request. Only operations that
+// have been marked idempotent are retried, because a failed request may still have been handled.
+
+use crate::bus::MessageBus;
+use crate::{Invocation, InvocationResponse, Result, WasccEntity};
+use crossbeam::{Receiver, Sender};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use wascap::jwt::{Actor, Claims};
+
+/// Matches every operation of a target when marking operations idempotent
+pub const ANY_OPERATION: &str = "*";
+
+/// Describes how many times, and how often, a failed invocation is retried. The delay before
+/// each retry doubles from the initial backoff up to the maximum backoff, and is then reduced
+/// by a random amount of up to `jitter` (a fraction between 0 and 1) of that delay so that
+/// callers failing at the same time don't all retry at the same time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Creates a policy that retries up to `max_retries` times with the default backoff
+    pub fn new(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            ..Default::default()
+        }
+    }
+
+    /// A policy that never retries
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::new(0)
+    }
+
+    /// Sets the delay before the first retry and the cap on the delay between retries
+    pub fn with_backoff(self, initial: Duration, max: Duration) -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: initial,
+            max_backoff: max,
+            ..self
+        }
+    }
+
+    /// Sets the fraction (between 0 and 1) of each delay that may be randomly removed
+    pub fn with_jitter(self, jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            jitter: jitter.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+
+    // The delay before retry number `attempt` (starting at 0), without jitter
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.backoff(attempt);
+        if self.jitter > 0.0 {
+            backoff.mul_f64(1.0 - self.jitter * rand::random::<f64>())
+        } else {
+            backoff
+        }
+    }
+}
+
+/// The retry policies configured for a host. A policy set for a target's operation takes
+/// precedence over one set for the target, which takes precedence over the default. Targets
+/// are capability IDs or actor public keys.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RetryRules {
+    default: RetryPolicy,
+    targets: HashMap<String, RetryPolicy>,
+    operations: HashMap<(String, String), RetryPolicy>,
+    idempotent: HashSet<(String, String)>,
+}
+
+impl RetryRules {
+    pub(crate) fn set_default(&mut self, policy: RetryPolicy) {
+        self.default = policy;
+    }
+
+    pub(crate) fn set_policy(
+        &mut self,
+        target: &str,
+        operation: Option<&str>,
+        policy: RetryPolicy,
+    ) {
+        match operation {
+            Some(op) => {
+                self.operations
+                    .insert((target.to_string(), op.to_string()), policy);
+            }
+            None => {
+                self.targets.insert(target.to_string(), policy);
+            }
+        }
+    }
+
+    pub(crate) fn mark_idempotent(&mut self, target: &str, operation: &str) {
+        self.idempotent
+            .insert((target.to_string(), operation.to_string()));
+    }
+
+    /// Indicates whether any invocation could ever be retried under these rules
+    pub(crate) fn is_empty(&self) -> bool {
+        self.idempotent.is_empty()
+    }
+
+    /// Returns the policy that applies to an invocation, or `None` if the invocation's
+    /// operation isn't idempotent and must not be retried
+    pub(crate) fn policy_for(&self, inv: &Invocation) -> Option<&RetryPolicy> {
+        let target = target_key(&inv.target);
+        let key = (target.to_string(), inv.operation.to_string());
+        if !self.idempotent.contains(&key)
+            && !self
+                .idempotent
+                .contains(&(target.to_string(), ANY_OPERATION.to_string()))
+        {
+            return None;
+        }
+        self.operations
+            .get(&key)
+            .or_else(|| self.targets.get(target))
+            .or(Some(&self.default))
+    }
+}
+
+fn target_key(target: &WasccEntity) -> &str {
+    match target {
+        WasccEntity::Actor(pk) => pk,
+        WasccEntity::Capability { capid, .. } => capid,
+    }
+}
+
+/// A message bus that retries failed invocations of idempotent operations on the bus it wraps.
+/// Only failures to deliver an invocation are retried; a response carrying an error came from
+/// the target and is returned to the caller as-is.
+pub(crate) struct RetryingBus {
+    inner: Arc<dyn MessageBus>,
+    rules: RetryRules,
+}
+
+impl RetryingBus {
+    pub(crate) fn new(inner: Arc<dyn MessageBus>, rules: RetryRules) -> Self {
+        RetryingBus { inner, rules }
+    }
+}
+
+impl MessageBus for RetryingBus {
+    fn subscribe(
+        &self,
+        subject: &str,
+        sender: Sender<Invocation>,
+        receiver: Receiver<InvocationResponse>,
+    ) -> Result<()> {
+        self.inner.subscribe(subject, sender, receiver)
+    }
+
+    fn nqsubscribe(
+        &self,
+        subject: &str,
+        sender: Sender<Invocation>,
+        receiver: Receiver<InvocationResponse>,
+    ) -> Result<()> {
+        self.inner.nqsubscribe(subject, sender, receiver)
+    }
+
+    fn invoke(&self, subject: &str, inv: Invocation) -> Result<InvocationResponse> {
+        let policy = match self.rules.policy_for(&inv) {
+            Some(p) => p,
+            None => return self.inner.invoke(subject, inv),
+        };
+        let mut attempt = 0;
+        loop {
+            match self.inner.invoke(subject, inv.clone()) {
+                Err(e) if attempt < policy.max_retries => {
+                    let delay = policy.delay(attempt);
+                    warn!(
+                        "Invocation of {} on {} failed ({}), retrying in {:?}",
+                        inv.operation, subject, e, delay
+                    );
+                    ::std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                r => return r,
+            }
+        }
+    }
+
+    fn unsubscribe(&self, subject: &str) -> Result<()> {
+        self.inner.unsubscribe(subject)
+    }
+
+    fn disconnect(&self) {
+        self.inner.disconnect()
+    }
+
+    fn namespace(&self) -> Option<String> {
+        self.inner.namespace()
+    }
+
+    fn connected(&self) -> bool {
+        self.inner.connected()
+    }
+
+    fn validates_invocations(&self) -> bool {
+        self.inner.validates_invocations()
+    }
+
+    fn instance_count(&self, actor: &str) -> Result<usize> {
+        self.inner.instance_count(actor)
+    }
+
+    fn discover_claims(&self, actor: &str) -> Option<Claims<Actor>> {
+        self.inner.discover_claims(actor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RetryPolicy, RetryRules, RetryingBus, ANY_OPERATION};
+    use crate::bus::MessageBus;
+    use crate::{errors, Invocation, InvocationResponse, Result, WasccEntity};
+    use crossbeam::{Receiver, Sender};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use wascap::prelude::KeyPair;
+
+    // A bus whose first `failures` invocations fail
+    struct FlakyBus {
+        failures: u32,
+        calls: AtomicU32,
+    }
+
+    impl MessageBus for FlakyBus {
+        fn subscribe(
+            &self,
+            _subject: &str,
+            _sender: Sender<Invocation>,
+            _receiver: Receiver<InvocationResponse>,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        fn nqsubscribe(
+            &self,
+            _subject: &str,
+            _sender: Sender<Invocation>,
+            _receiver: Receiver<InvocationResponse>,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        fn invoke(&self, subject: &str, inv: Invocation) -> Result<InvocationResponse> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                Err(errors::new(errors::ErrorKind::MiscHost(format!(
+                    "No subscribers for {}",
+                    subject
+                ))))
+            } else {
+                Ok(InvocationResponse::success(&inv, vec![]))
+            }
+        }
+
+        fn unsubscribe(&self, _subject: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn disconnect(&self) {}
+    }
+
+    fn invocation(op: &str) -> Invocation {
+        Invocation::new(
+            &KeyPair::new_server(),
+            WasccEntity::Actor("Mxxx".to_string()),
+            WasccEntity::Capability {
+                capid: "wascc:keyvalue".to_string(),
+                binding: "default".to_string(),
+            },
+            op,
+            vec![],
+        )
+    }
+
+    fn fast(retries: u32) -> RetryPolicy {
+        RetryPolicy::new(retries).with_backoff(Duration::from_millis(1), Duration::from_millis(5))
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let policy = RetryPolicy::new(5)
+            .with_backoff(Duration::from_millis(100), Duration::from_millis(300));
+        assert_eq!(Duration::from_millis(100), policy.backoff(0));
+        assert_eq!(Duration::from_millis(200), policy.backoff(1));
+        assert_eq!(Duration::from_millis(300), policy.backoff(2));
+        assert_eq!(Duration::from_millis(300), policy.backoff(40));
+
+        let jittered = policy.with_jitter(0.5);
+        for _ in 0..20 {
+            let d = jittered.delay(1);
+            assert!(d >= Duration::from_millis(100) && d <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn only_idempotent_operations_are_selected() {
+        let mut rules = RetryRules::default();
+        rules.mark_idempotent("wascc:keyvalue", "Get");
+        rules.set_policy("wascc:keyvalue", None, RetryPolicy::new(2));
+        rules.set_policy("wascc:keyvalue", Some("Get"), RetryPolicy::new(7));
+
+        assert_eq!(7, rules.policy_for(&invocation("Get")).unwrap().max_retries);
+        assert!(rules.policy_for(&invocation("Set")).is_none());
+
+        rules.mark_idempotent("wascc:keyvalue", ANY_OPERATION);
+        assert_eq!(2, rules.policy_for(&invocation("Set")).unwrap().max_retries);
+    }
+
+    #[test]
+    fn retries_failed_idempotent_invocations() {
+        let flaky = Arc::new(FlakyBus {
+            failures: 2,
+            calls: AtomicU32::new(0),
+        });
+        let mut rules = RetryRules::default();
+        rules.set_default(fast(3));
+        rules.mark_idempotent("wascc:keyvalue", "Get");
+        let bus = RetryingBus::new(flaky.clone(), rules);
+
+        assert!(bus.invoke("kv", invocation("Get")).is_ok());
+        assert_eq!(3, flaky.calls.load(Ordering::SeqCst));
+
+        // Non-idempotent operations fail on the first error
+        flaky.calls.store(0, Ordering::SeqCst);
+        assert!(bus.invoke("kv", invocation("Set")).is_err());
+        assert_eq!(1, flaky.calls.load(Ordering::SeqCst));
+
+        // Retries give up once the policy is exhausted
+        flaky.calls.store(0, Ordering::SeqCst);
+        let bus = RetryingBus::new(flaky.clone(), {
+            let mut rules = RetryRules::default();
+            rules.set_default(fast(1));
+            rules.mark_idempotent("wascc:keyvalue", ANY_OPERATION);
+            rules
+        });
+        assert!(bus.invoke("kv", invocation("Get")).is_err());
+        assert_eq!(2, flaky.calls.load(Ordering::SeqCst));
+    }
+}
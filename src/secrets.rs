@@ -0,0 +1,119 @@
+// Classification of secret binding configuration values. Secret values are still delivered
+// to capability providers, but they are redacted whenever binding configuration is shared
+// with the rest of the lattice (e.g. in inventory responses).
+
+use crate::BindingTuple;
+use std::collections::{HashMap, HashSet};
+
+/// The value reported in place of a secret binding configuration value
+pub const REDACTED_VALUE: &str = "[REDACTED]";
+
+// Configuration keys containing any of these (case-insensitive) are always treated as secret
+const DEFAULT_SECRET_PATTERNS: [&str; 10] = [
+    "password",
+    "passwd",
+    "secret",
+    "token",
+    "credential",
+    "private_key",
+    "api_key",
+    "apikey",
+    "access_key",
+    "connection_string",
+];
+
+#[derive(Debug, Clone)]
+pub(crate) struct SecretPolicy {
+    patterns: Vec<String>,
+    // Keys explicitly marked as secret for individual bindings
+    marked: HashMap<BindingTuple, HashSet<String>>,
+}
+
+impl Default for SecretPolicy {
+    fn default() -> Self {
+        SecretPolicy {
+            patterns: DEFAULT_SECRET_PATTERNS
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
+            marked: HashMap::new(),
+        }
+    }
+}
+
+impl SecretPolicy {
+    pub(crate) fn add_pattern(&mut self, pattern: &str) {
+        let pattern = pattern.to_lowercase();
+        if !self.patterns.contains(&pattern) {
+            self.patterns.push(pattern);
+        }
+    }
+
+    pub(crate) fn mark(&mut self, binding: BindingTuple, keys: &[String]) {
+        if keys.is_empty() {
+            self.marked.remove(&binding);
+        } else {
+            self.marked
+                .insert(binding, keys.iter().cloned().collect::<HashSet<_>>());
+        }
+    }
+
+    pub(crate) fn is_secret(&self, binding: &BindingTuple, key: &str) -> bool {
+        let lower = key.to_lowercase();
+        self.patterns.iter().any(|p| lower.contains(p))
+            || self
+                .marked
+                .get(binding)
+                .map_or(false, |keys| keys.contains(key))
+    }
+
+    /// Returns a copy of the configuration values with every secret value redacted
+    pub(crate) fn redact(
+        &self,
+        binding: &BindingTuple,
+        values: &HashMap<String, String>,
+    ) -> HashMap<String, String> {
+        values
+            .iter()
+            .map(|(k, v)| {
+                if self.is_secret(binding, k) {
+                    (k.to_string(), REDACTED_VALUE.to_string())
+                } else {
+                    (k.to_string(), v.to_string())
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SecretPolicy;
+
+    fn tuple() -> (String, String, String) {
+        (
+            "Mxxx".to_string(),
+            "wascc:keyvalue".to_string(),
+            "default".to_string(),
+        )
+    }
+
+    #[test]
+    fn classifies_by_pattern_and_marking() {
+        let mut policy = SecretPolicy::default();
+        let binding = tuple();
+        assert!(policy.is_secret(&binding, "REDIS_PASSWORD"));
+        assert!(policy.is_secret(&binding, "Api_Key"));
+        assert!(!policy.is_secret(&binding, "URL"));
+
+        policy.mark(binding.clone(), &["URL".to_string()]);
+        assert!(policy.is_secret(&binding, "URL"));
+        assert!(!policy.is_secret(&binding, "PORT"));
+
+        policy.add_pattern("PORT");
+        assert!(policy.is_secret(&binding, "PORT"));
+
+        policy.mark(binding.clone(), &[]);
+        assert!(!policy.is_secret(&binding, "URL"));
+    }
+}
@@ -1,12 +1,14 @@
 use crate::Result;
 
+#[cfg(feature = "lattice")]
+use crate::bus::LatticeAdmin;
 use crate::inthost::*;
 use crate::BindingsList;
 use crate::{
     bus::MessageBus, dispatch::WasccNativeDispatcher, plugins::PluginManager, Authorizer,
-    Invocation, InvocationResponse, Middleware, RouteKey,
+    Invocation, InvocationResponse, RouteKey,
 };
-use crate::{middleware, NativeCapability};
+use crate::{metrics::RuntimeStats, middleware, middleware::RegisteredMiddleware, NativeCapability};
 
 use crossbeam::{Receiver, Sender};
 use crossbeam_channel as channel;
@@ -36,8 +38,8 @@ pub(crate) fn spawn_actor(
     wasi: Option<WasiParams>,
     actor: bool,
     binding: Option<String>,
-    bus: Arc<MessageBus>,
-    mids: Arc<RwLock<Vec<Box<dyn Middleware>>>>,
+    bus: Arc<dyn MessageBus>,
+    mids: Arc<RwLock<Vec<RegisteredMiddleware>>>,
     caps: Arc<RwLock<HashMap<RouteKey, CapabilityDescriptor>>>,
     bindings: Arc<RwLock<BindingsList>>,
     claimsmap: Arc<RwLock<HashMap<String, Claims<wascap::jwt::Actor>>>>,
@@ -46,6 +48,8 @@ pub(crate) fn spawn_actor(
     auth: Arc<RwLock<Box<dyn Authorizer>>>,
     image_map: Arc<RwLock<HashMap<String, String>>>,
     imgref: Option<String>,
+    stats: Arc<RuntimeStats>,
+    #[cfg(feature = "lattice")] lattice: Option<Arc<dyn LatticeAdmin>>,
 ) -> Result<()> {
     let c = claims.clone();
     let b = bus.clone();
@@ -53,15 +57,21 @@ pub(crate) fn spawn_actor(
     let s = seed.clone();
     let hostkey = KeyPair::from_seed(&hk.seed().unwrap()).unwrap();
     let authorizer = auth.clone();
+    // The context of the invocation the guest is currently handling
+    let current_call: Arc<RwLock<Option<CallContext>>> = Arc::new(RwLock::new(None));
+    let cc = current_call.clone();
 
     thread::spawn(move || {
         let hk = KeyPair::from_seed(&seed).unwrap();
         if actor {
             #[cfg(feature = "lattice")]
-            let _ = bus.publish_event(BusEvent::ActorStarting {
-                host: hostkey.public_key(),
-                actor: claims.subject.to_string(),
-            });
+            publish_event(
+                &lattice,
+                BusEvent::ActorStarting {
+                    host: hostkey.public_key(),
+                    actor: claims.subject.to_string(),
+                },
+            );
         }
         #[cfg(feature = "wasmtime")]
         let engine = wasmtime_provider::WasmtimeEngineProvider::new(&buf, wasi);
@@ -79,6 +89,7 @@ pub(crate) fn spawn_actor(
                 op,
                 payload,
                 authorizer.clone(),
+                cc.read().unwrap().clone(),
             )
         })
         .unwrap();
@@ -97,11 +108,14 @@ pub(crate) fn spawn_actor(
             let capid = d.as_ref().unwrap().id.to_string();
             let bname = binding.clone().unwrap();
             #[cfg(feature = "lattice")]
-            let _ = b.publish_event(BusEvent::ProviderLoaded {
-                host: hostkey.public_key(),
-                capid: capid.to_string(),
-                instance_name: bname.to_string(),
-            });
+            publish_event(
+                &lattice,
+                BusEvent::ProviderLoaded {
+                    host: hostkey.public_key(),
+                    capid: capid.to_string(),
+                    instance_name: bname.to_string(),
+                },
+            );
 
             b.provider_subject(&capid, &bname)
         };
@@ -119,13 +133,22 @@ pub(crate) fn spawn_actor(
             .unwrap()
             .insert(subscribe_subject.clone(), term_s);
         let _ = b.subscribe(&subscribe_subject, inv_s, resp_r).unwrap();
+        stats.track_queue(&subscribe_subject, inv_r.clone());
+        if actor {
+            stats.actor_started(&claims.subject);
+        } else {
+            stats.portable_provider_started(&subscribe_subject);
+        }
         drop(wg); // Let the Host wrapper function return
         if actor {
             #[cfg(feature = "lattice")]
-            let _ = b.publish_event(BusEvent::ActorStarted {
-                host: hostkey.public_key(),
-                actor: claims.subject.to_string(),
-            });
+            publish_event(
+                &lattice,
+                BusEvent::ActorStarted {
+                    host: hostkey.public_key(),
+                    actor: claims.subject.to_string(),
+                },
+            );
             info!("Actor {} up and running.", &claims.subject);
         }
         loop {
@@ -134,12 +157,12 @@ pub(crate) fn spawn_actor(
                 recv(inv_r) -> inv => {
                     if let Ok(inv) = inv {
                         let inv_r = if actor {
-                            middleware::invoke_actor(mids.clone(), inv.clone(), &mut guest).unwrap()
+                            middleware::invoke_actor(mids.clone(), inv.clone(), &mut guest, &current_call, b.validates_invocations(), &stats)
                         } else {
                             if inv.operation != OP_BIND_ACTOR && inv.operation != OP_GET_CAPABILITY_DESCRIPTOR {
                                 InvocationResponse::error(&inv, "Attempted to invoke binding-required operation on unbound provider")
                             } else {
-                                middleware::invoke_portable_capability(mids.clone(), inv.clone(), &mut guest).unwrap()
+                                middleware::invoke_portable_capability(mids.clone(), inv.clone(), &mut guest, &current_call, b.validates_invocations(), &stats)
                             }
                         };
                         resp_s.send(inv_r.clone()).unwrap();
@@ -152,14 +175,16 @@ pub(crate) fn spawn_actor(
                     info!("Terminating {} {}", if actor { "actor" } else { "capability" }, &claims.subject);
                     let _ = b.unsubscribe(&subscribe_subject);
                     terminators.write().unwrap().remove(&subscribe_subject);
+                    stats.untrack_queue(&subscribe_subject);
                     if !actor {
+                        stats.portable_provider_stopped(&subscribe_subject);
                         //#[cfg(feature = "lattice")]
                         //let _ = bus.publish_event(BusEvent::ProviderRemoved{ host: hostkey.public_key(), actor: claims.subject.to_string() });
                         remove_cap(caps.clone(), &d.as_ref().unwrap().id, binding.as_ref().unwrap()); // for cap providers, route key is the capid
                         unbind_all_from_cap(bindings.clone(), &d.unwrap().id, binding.as_ref().unwrap());
                     } else {
                         #[cfg(feature = "lattice")]
-                        let _ = b.publish_event(BusEvent::ActorStopped{ host: hostkey.public_key(), actor: claims.subject.to_string() });
+                        publish_event(&lattice, BusEvent::ActorStopped{ host: hostkey.public_key(), actor: claims.subject.to_string() });
 
                         let mut lock = claimsmap.write().unwrap();
                         let _ = lock.remove(&claims.subject);
@@ -182,13 +207,16 @@ pub(crate) fn spawn_actor(
 
 pub(crate) fn spawn_native_capability(
     capability: NativeCapability,
-    bus: Arc<MessageBus>,
-    mids: Arc<RwLock<Vec<Box<dyn Middleware>>>>,
+    bus: Arc<dyn MessageBus>,
+    mids: Arc<RwLock<Vec<RegisteredMiddleware>>>,
     bindings: Arc<RwLock<BindingsList>>,
     terminators: Arc<RwLock<HashMap<String, Sender<bool>>>>,
     plugins: Arc<RwLock<PluginManager>>,
     wg: WaitGroup,
     hk: Arc<KeyPair>,
+    auth: Arc<RwLock<Box<dyn Authorizer>>>,
+    stats: Arc<RuntimeStats>,
+    #[cfg(feature = "lattice")] lattice: Option<Arc<dyn LatticeAdmin>>,
 ) -> Result<()> {
     let capid = capability.id().to_string();
     let binding = capability.binding_name.to_string();
@@ -202,6 +230,9 @@ pub(crate) fn spawn_native_capability(
     let t2 = terminators.clone();
     let capid2 = capid.clone();
     let bindingname2 = binding.clone();
+    let stats2 = stats.clone();
+    #[cfg(feature = "lattice")]
+    let lattice2 = lattice.clone();
 
     plugins.write().unwrap().add_plugin(capability)?;
 
@@ -213,7 +244,15 @@ pub(crate) fn spawn_native_capability(
         let subscribe_subject = bus.provider_subject(&capid, &binding);
 
         let _ = bus.nqsubscribe(&subscribe_subject, inv_s, resp_r).unwrap();
-        let dispatcher = WasccNativeDispatcher::new(hk.clone(), bus.clone(), &capid, &binding);
+        stats.track_queue(&subscribe_subject, inv_r.clone());
+        let dispatcher = WasccNativeDispatcher::new(
+            hk.clone(),
+            bus.clone(),
+            &capid,
+            &binding,
+            terminators.clone(),
+            auth.clone(),
+        );
         plugins
             .write()
             .unwrap()
@@ -228,11 +267,14 @@ pub(crate) fn spawn_native_capability(
 
         drop(wg);
         #[cfg(feature = "lattice")]
-        let _ = b.publish_event(BusEvent::ProviderLoaded {
-            host: hk.public_key(),
-            capid: capid.to_string(),
-            instance_name: binding.to_string(),
-        });
+        publish_event(
+            &lattice2,
+            BusEvent::ProviderLoaded {
+                host: hk.public_key(),
+                capid: capid.to_string(),
+                instance_name: binding.to_string(),
+            },
+        );
 
         loop {
             select! {
@@ -241,12 +283,14 @@ pub(crate) fn spawn_native_capability(
                         let inv_r = if inv.operation != OP_BIND_ACTOR && inv.operation != OP_GET_CAPABILITY_DESCRIPTOR && inv.operation != OP_REMOVE_ACTOR {
                             InvocationResponse::error(&inv, "Attempted to invoke binding-required operation on unbound provider")
                         } else {
-                            middleware::invoke_native_capability(mids.clone(), inv.clone(), plugins.clone()).unwrap()
+                            middleware::invoke_native_capability(mids.clone(), inv.clone(), plugins.clone(), bus.validates_invocations(), &stats)
                         };
-                        resp_s.send(inv_r.clone()).unwrap();
+                        // The bound subscription is in place before the bind is acknowledged, so the
+                        // provider can dispatch to the actor as soon as the binding is set
                         if inv.operation == OP_BIND_ACTOR && inv_r.error.is_none() {
-                            spawn_bound_native_capability(bus.clone(), inv.clone(), &capid, &binding, mids.clone(), plugins.clone(), terminators.clone(), bindings.clone(), hk.clone());
+                            spawn_bound_native_capability(bus.clone(), inv.clone(), &capid, &binding, mids.clone(), plugins.clone(), terminators.clone(), bindings.clone(), hk.clone(), stats.clone(), #[cfg(feature = "lattice")] lattice2.clone());
                         }
+                        resp_s.send(inv_r.clone()).unwrap();
                         if inv.operation == OP_REMOVE_ACTOR && inv_r.error.is_none() {
                             let actor = actor_from_config(&inv.msg);
                             let key = bus.provider_subject_bound_actor(&capid, &binding, &actor);
@@ -261,8 +305,9 @@ pub(crate) fn spawn_native_capability(
                     let _ = bus.unsubscribe(&subscribe_subject);
                     plugins.write().unwrap().remove_plugin(&binding, &capid).unwrap();
                     terminators.write().unwrap().remove(&subscribe_subject);
+                    stats.untrack_queue(&subscribe_subject);
                     #[cfg(feature="lattice")]
-                    let _ = b.publish_event(BusEvent::ProviderRemoved{ host: hk.public_key(), capid: capid.to_string(), instance_name: binding.to_string()});
+                    publish_event(&lattice2, BusEvent::ProviderRemoved{ host: hk.public_key(), capid: capid.to_string(), instance_name: binding.to_string()});
                     break;
                 }
             }
@@ -271,6 +316,7 @@ pub(crate) fn spawn_native_capability(
 
     #[cfg(feature = "lattice")]
     reestablish_bindings(
+        lattice,
         b2.clone(),
         mid2.clone(),
         binding2.clone(),
@@ -279,25 +325,28 @@ pub(crate) fn spawn_native_capability(
         h2.clone(),
         &capid2,
         &bindingname2,
+        stats2,
     );
     Ok(())
 }
 
 #[cfg(feature = "lattice")]
 fn reestablish_bindings(
-    bus: Arc<MessageBus>,
-    mids: Arc<RwLock<Vec<Box<dyn Middleware>>>>,
+    lattice: Option<Arc<dyn LatticeAdmin>>,
+    bus: Arc<dyn MessageBus>,
+    mids: Arc<RwLock<Vec<RegisteredMiddleware>>>,
     bindings: Arc<RwLock<BindingsList>>,
     plugins: Arc<RwLock<PluginManager>>,
     terminators: Arc<RwLock<HashMap<String, Sender<bool>>>>,
     hk: Arc<KeyPair>,
     capid: &str,
     binding_name: &str,
+    stats: Arc<RuntimeStats>,
 ) {
-    // 1. load pre-existing bindings from bus
+    // 1. load pre-existing bindings from the lattice, if the host is connected to one
     // 2. for each binding, invoke OP_BIND_ACTOR on the root capability
     // 3.    if successful,  spawn the bound actor-capability comms thread
-    if let Ok(blist) = bus.query_bindings() {
+    if let Some(Ok(blist)) = lattice.as_ref().map(|l| l.query_bindings()) {
         for b in blist {
             if b.capability_id == capid && b.binding_name == binding_name {
                 let cfgvals = CapabilityConfiguration {
@@ -319,8 +368,9 @@ fn reestablish_bindings(
                     mids.clone(),
                     inv.clone(),
                     plugins.clone(),
-                )
-                .unwrap();
+                    bus.validates_invocations(),
+                    &stats,
+                );
                 if inv_r.error.is_none() {
                     info!(
                         "Re-establishing binding between {} and {},{}",
@@ -336,6 +386,8 @@ fn reestablish_bindings(
                         terminators.clone(),
                         bindings.clone(),
                         hk.clone(),
+                        stats.clone(),
+                        lattice.clone(),
                     );
                 }
             }
@@ -351,15 +403,17 @@ fn actor_from_config(bytes: &[u8]) -> String {
 // This is a thread that handles the private conversations between an actor and a capability.
 // On the lattice, this means that actor-to-provider requests occur on a topic made up of actor+provider capid+provider instance/binding name
 fn spawn_bound_native_capability(
-    bus: Arc<MessageBus>,
+    bus: Arc<dyn MessageBus>,
     inv: Invocation,
     capid: &str,
     binding: &str,
-    middlewares: Arc<RwLock<Vec<Box<dyn Middleware>>>>,
+    middlewares: Arc<RwLock<Vec<RegisteredMiddleware>>>,
     plugins: Arc<RwLock<PluginManager>>,
     terminators: Arc<RwLock<HashMap<String, Sender<bool>>>>,
     bindings: Arc<RwLock<BindingsList>>,
     hk: Arc<KeyPair>,
+    stats: Arc<RuntimeStats>,
+    #[cfg(feature = "lattice")] lattice: Option<Arc<dyn LatticeAdmin>>,
 ) {
     let capid = capid.to_string();
     let binding = binding.to_string();
@@ -367,42 +421,104 @@ fn spawn_bound_native_capability(
     let config: CapabilityConfiguration = deserialize(&inv.msg).unwrap();
     let actor = config.module.to_string();
     let mids = middlewares.clone();
-    let terms = terminators.clone();
 
-    thread::spawn(move || {
-        let (inv_s, inv_r): (Sender<Invocation>, Receiver<Invocation>) = channel::unbounded();
-        let (resp_s, resp_r): (Sender<InvocationResponse>, Receiver<InvocationResponse>) =
-            channel::unbounded();
-        let subscribe_subject = bus.provider_subject_bound_actor(&capid, &binding, &actor);
-        let (term_s, term_r): (Sender<bool>, Receiver<bool>) = channel::unbounded();
+    // The subscription and its terminator are registered before returning, since the
+    // terminator is what marks the actor as bound to the provider
+    let (inv_s, inv_r): (Sender<Invocation>, Receiver<Invocation>) = channel::unbounded();
+    let (resp_s, resp_r): (Sender<InvocationResponse>, Receiver<InvocationResponse>) =
+        channel::unbounded();
+    let subscribe_subject = bus.provider_subject_bound_actor(&capid, &binding, &actor);
+    let (term_s, term_r): (Sender<bool>, Receiver<bool>) = channel::unbounded();
 
-        let _ = bus.subscribe(&subscribe_subject, inv_s, resp_r).unwrap();
-        terms
-            .write()
-            .unwrap()
-            .insert(subscribe_subject.to_string(), term_s.clone());
+    bus.subscribe(&subscribe_subject, inv_s, resp_r).unwrap();
+    stats.track_queue(&subscribe_subject, inv_r.clone());
+    terminators
+        .write()
+        .unwrap()
+        .insert(subscribe_subject.to_string(), term_s);
 
-        loop {
-            select! {
-                recv(inv_r) -> inv => {
-                    if let Ok(inv) = inv {
-                        let inv_r = middleware::invoke_native_capability(mids.clone(), inv.clone(), plugins.clone()).unwrap();
-                        resp_s.send(inv_r).unwrap();
-                    }
-                },
-                recv(term_r) -> _term => {
-                    let _ = bus.unsubscribe(&subscribe_subject);
-                    remove_binding(bindings.clone(), &actor, &binding, &capid);
-                    terminators.write().unwrap().remove(&subscribe_subject);
-                    #[cfg(feature="lattice")]
-                    let _ = bus.publish_event(BusEvent::ProviderRemoved{ host: hk.public_key(), capid: capid.to_string(), instance_name: binding.to_string()});
-                    break;
+    thread::spawn(move || loop {
+        select! {
+            recv(inv_r) -> inv => {
+                if let Ok(inv) = inv {
+                    let inv_r = middleware::invoke_native_capability(mids.clone(), inv.clone(), plugins.clone(), bus.validates_invocations(), &stats);
+                    resp_s.send(inv_r).unwrap();
                 }
+            },
+            recv(term_r) -> _term => {
+                let _ = bus.unsubscribe(&subscribe_subject);
+                remove_binding(bindings.clone(), &actor, &binding, &capid);
+                terminators.write().unwrap().remove(&subscribe_subject);
+                stats.untrack_queue(&subscribe_subject);
+                #[cfg(feature="lattice")]
+                publish_event(&lattice, BusEvent::ProviderRemoved{ host: hk.public_key(), capid: capid.to_string(), instance_name: binding.to_string()});
+                break;
             }
         }
     });
 }
 
+// Publishes a lattice event, if the host is connected to a lattice
+#[cfg(feature = "lattice")]
+fn publish_event(lattice: &Option<Arc<dyn LatticeAdmin>>, event: BusEvent) {
+    if let Some(ref lattice) = lattice {
+        let _ = lattice.publish_event(event);
+    }
+}
+
 fn spawn_bound_portable_capability() {
     todo!()
 }
+
+#[cfg(test)]
+mod test {
+    use super::spawn_bound_native_capability;
+    use crate::bus::{InprocBus, MessageBus};
+    use crate::metrics::RuntimeStats;
+    use crate::plugins::PluginManager;
+    use crate::{Invocation, WasccEntity};
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+    use wascap::prelude::KeyPair;
+    use wascc_codec::core::{CapabilityConfiguration, OP_BIND_ACTOR};
+    use wascc_codec::{serialize, SYSTEM_ACTOR};
+
+    #[test]
+    fn bound_subscription_is_registered_before_returning() {
+        let bus: Arc<dyn MessageBus> = Arc::new(InprocBus::new());
+        let hk = Arc::new(KeyPair::new_server());
+        let terminators = Arc::new(RwLock::new(HashMap::new()));
+        let config = CapabilityConfiguration {
+            module: "Mxxx".to_string(),
+            values: HashMap::new(),
+        };
+        let inv = Invocation::new(
+            &hk,
+            WasccEntity::Actor(SYSTEM_ACTOR.to_string()),
+            WasccEntity::Capability {
+                capid: "wascc:testing".to_string(),
+                binding: "default".to_string(),
+            },
+            OP_BIND_ACTOR,
+            serialize(&config).unwrap(),
+        );
+        spawn_bound_native_capability(
+            bus.clone(),
+            inv,
+            "wascc:testing",
+            "default",
+            Arc::new(RwLock::new(Vec::new())),
+            Arc::new(RwLock::new(PluginManager::default())),
+            terminators.clone(),
+            Arc::new(RwLock::new(HashMap::new())),
+            hk,
+            Arc::new(RuntimeStats::default()),
+            #[cfg(feature = "lattice")]
+            None,
+        );
+
+        let subject = bus.provider_subject_bound_actor("wascc:testing", "default", "Mxxx");
+        let terminator = terminators.read().unwrap().get(&subject).cloned();
+        terminator.unwrap().send(true).unwrap();
+    }
+}
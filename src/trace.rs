@@ -0,0 +1,162 @@
+// Trace context carried on invocations, following the W3C Trace Context `traceparent` format,
+// so that a request that passes through several actors and providers (and hosts) can be
+// correlated. Every invocation is a span; calls an actor makes while handling an invocation
+// are child spans of that invocation's span.
+
+use data_encoding::HEXLOWER;
+use wascc_codec::http;
+
+const TRACEPARENT_VERSION: &str = "00";
+const TRACEPARENT_HEADER: &str = "traceparent";
+const FLAG_SAMPLED: u8 = 0x01;
+
+/// Identifies the span of an invocation within a distributed trace
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "lattice", derive(serde::Serialize, serde::Deserialize))]
+pub struct TraceContext {
+    /// 32 lowercase hex characters, shared by every span in the trace
+    pub trace_id: String,
+    /// 16 lowercase hex characters identifying this span
+    pub span_id: String,
+    /// The span that caused this one, if it isn't the root of its trace
+    pub parent_span_id: Option<String>,
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// Starts a new trace
+    pub fn new_root() -> TraceContext {
+        TraceContext {
+            trace_id: random_id(16),
+            span_id: random_id(8),
+            parent_span_id: None,
+            sampled: true,
+        }
+    }
+
+    /// Creates a span within the same trace whose parent is this span
+    pub fn child(&self) -> TraceContext {
+        TraceContext {
+            trace_id: self.trace_id.to_string(),
+            span_id: random_id(8),
+            parent_span_id: Some(self.span_id.to_string()),
+            sampled: self.sampled,
+        }
+    }
+
+    /// Parses a W3C `traceparent` value. The resulting context identifies the remote span
+    /// named by the value, so work done on its behalf should be recorded in a `child` of it
+    pub fn from_traceparent(traceparent: &str) -> Option<TraceContext> {
+        let parts: Vec<_> = traceparent.trim().split('-').collect();
+        if parts.len() < 4
+            || parts[0].len() != 2
+            || parts[0] == "ff"
+            || (parts[0] == TRACEPARENT_VERSION && parts.len() != 4)
+            || !is_id(parts[1], 32)
+            || !is_id(parts[2], 16)
+            || parts[3].len() != 2
+        {
+            return None;
+        }
+        let flags = u8::from_str_radix(parts[3], 16).ok()?;
+        Some(TraceContext {
+            trace_id: parts[1].to_string(),
+            span_id: parts[2].to_string(),
+            parent_span_id: None,
+            sampled: flags & FLAG_SAMPLED == FLAG_SAMPLED,
+        })
+    }
+
+    /// The W3C `traceparent` value for this span
+    pub fn traceparent(&self) -> String {
+        format!(
+            "{}-{}-{}-{:02x}",
+            TRACEPARENT_VERSION,
+            self.trace_id,
+            self.span_id,
+            if self.sampled { FLAG_SAMPLED } else { 0 }
+        )
+    }
+}
+
+/// Reads the trace context that a native capability provider received from outside the host
+/// along with a message it dispatches to an actor. HTTP requests continue the trace named by
+/// their `traceparent` header, if they have one
+pub(crate) fn from_dispatch(op: &str, msg: &[u8]) -> Option<TraceContext> {
+    if op != http::OP_HANDLE_REQUEST {
+        return None;
+    }
+    let request: http::Request = wascc_codec::deserialize(msg).ok()?;
+    request
+        .header
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(TRACEPARENT_HEADER))
+        .and_then(|(_, v)| TraceContext::from_traceparent(v))
+}
+
+fn random_id(len: usize) -> String {
+    let bytes: Vec<u8> = (0..len).map(|_| rand::random::<u8>()).collect();
+    HEXLOWER.encode(&bytes)
+}
+
+// An ID must be lowercase hex of the right length, and not all zeroes
+fn is_id(s: &str, len: usize) -> bool {
+    s.len() == len
+        && s.chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+        && s.chars().any(|c| c != '0')
+}
+
+#[cfg(test)]
+mod test {
+    use super::{from_dispatch, TraceContext};
+    use wascc_codec::http::{Request, OP_HANDLE_REQUEST};
+
+    #[test]
+    fn traceparent_round_trip() {
+        let root = TraceContext::new_root();
+        let child = root.child();
+        assert_eq!(root.trace_id, child.trace_id);
+        assert_eq!(Some(root.span_id.clone()), child.parent_span_id);
+        assert_ne!(root.span_id, child.span_id);
+
+        let parsed = TraceContext::from_traceparent(&child.traceparent()).unwrap();
+        assert_eq!(child.trace_id, parsed.trace_id);
+        assert_eq!(child.span_id, parsed.span_id);
+        assert!(parsed.sampled);
+
+        let unsampled = TraceContext::from_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00",
+        )
+        .unwrap();
+        assert!(!unsampled.sampled);
+        assert!(TraceContext::from_traceparent(
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01"
+        )
+        .is_none());
+        assert!(TraceContext::from_traceparent("00-4bf92f35-00f067aa0ba902b7-01").is_none());
+    }
+
+    #[test]
+    fn dispatched_http_requests_continue_their_trace() {
+        let mut request = Request {
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            ..Default::default()
+        };
+        request.header.insert(
+            "Traceparent".to_string(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".to_string(),
+        );
+        let msg = wascc_codec::serialize(&request).unwrap();
+        let remote = from_dispatch(OP_HANDLE_REQUEST, &msg).unwrap();
+        assert_eq!("4bf92f3577b34da6a3ce929d0e0e4736", remote.trace_id);
+        assert_eq!("00f067aa0ba902b7", remote.span_id);
+
+        assert!(from_dispatch("DeliverMessage", &msg).is_none());
+        request.header.clear();
+        let msg = wascc_codec::serialize(&request).unwrap();
+        assert!(from_dispatch(OP_HANDLE_REQUEST, &msg).is_none());
+        assert!(from_dispatch(OP_HANDLE_REQUEST, b"not a request").is_none());
+    }
+}
@@ -1,5 +1,8 @@
 use latticeclient::Client;
+use std::collections::HashMap;
 use std::error::Error;
+use wascc_host::middleware::{InvocationHandler, MiddlewareResponse};
+use wascc_host::{Invocation, InvocationResponse};
 
 pub(crate) fn lattice_single_host() -> Result<(), Box<dyn Error>> {
     use std::time::Duration;
@@ -414,3 +417,417 @@ pub(crate) fn unload_reload_actor_retains_bindings() -> Result<(), Box<dyn Error
 
     Ok(())
 }
+
+fn inventory(
+    broker: &wascc_host::LatticeBroker,
+    ns: &str,
+    kind: &str,
+) -> Vec<latticeclient::InventoryResponse> {
+    broker
+        .request_all(
+            &format!("{}.wasmbus.inventory.{}", ns, kind),
+            &[],
+            std::time::Duration::from_millis(500),
+        )
+        .iter()
+        .map(|r| serde_json::from_slice(r).unwrap())
+        .collect()
+}
+
+pub(crate) fn inmemory_lattice_hosts() -> Result<(), Box<dyn Error>> {
+    use latticeclient::InventoryResponse;
+    use wascc_host::{HostBuilder, LatticeBroker};
+
+    let broker = LatticeBroker::new();
+    let host1 = HostBuilder::new()
+        .with_lattice_broker(&broker)
+        .with_lattice_namespace("memhosts")
+        .with_label("testval", "1")
+        .build();
+    let host2 = HostBuilder::new()
+        .with_lattice_broker(&broker)
+        .with_lattice_namespace("memhosts")
+        .with_label("testval", "2")
+        .build();
+    let other = HostBuilder::new()
+        .with_lattice_broker(&broker)
+        .with_lattice_namespace("memother")
+        .build();
+
+    let mut ids: Vec<_> = inventory(&broker, "memhosts", "hosts")
+        .into_iter()
+        .filter_map(|ir| match ir {
+            InventoryResponse::Host(hp) => Some(hp.id),
+            _ => None,
+        })
+        .collect();
+    ids.sort();
+    let mut expected = vec![host1.id(), host2.id()];
+    expected.sort();
+    assert_eq!(ids, expected);
+    assert_eq!(1, inventory(&broker, "memother", "hosts").len());
+    assert_eq!(0, inventory(&broker, "memnope", "hosts").len());
+
+    host1.shutdown()?;
+    host2.shutdown()?;
+    other.shutdown()?;
+    assert_eq!(0, inventory(&broker, "memhosts", "hosts").len());
+    Ok(())
+}
+
+pub(crate) fn inmemory_lattice_auction() -> Result<(), Box<dyn Error>> {
+    use latticeclient::controlplane::{AUCTION_REQ, CPLANE_PREFIX};
+    use std::time::Duration;
+    use wascc_host::{HostBuilder, LatticeBroker};
+
+    let broker = LatticeBroker::new();
+    let host1 = HostBuilder::new()
+        .with_lattice_broker(&broker)
+        .with_lattice_namespace("memauction")
+        .with_label("zone", "east")
+        .build();
+    let host2 = HostBuilder::new()
+        .with_lattice_broker(&broker)
+        .with_lattice_namespace("memauction")
+        .with_label("zone", "west")
+        .build();
+
+    let subject = format!("memauction.wasmbus.{}.{}", CPLANE_PREFIX, AUCTION_REQ);
+    let req = serde_json::json!({
+        "actor_id": "wascc.azurecr.io/echo:v1",
+        "constraints": { "zone": "west" }
+    });
+    let replies = broker.request_all(
+        &subject,
+        &serde_json::to_vec(&req)?,
+        Duration::from_millis(500),
+    );
+    assert_eq!(1, replies.len());
+    let winner: serde_json::Value = serde_json::from_slice(&replies[0])?;
+    assert_eq!(winner["host_id"], host2.id());
+
+    let req = serde_json::json!({
+        "actor_id": "wascc.azurecr.io/echo:v1",
+        "constraints": {}
+    });
+    let replies = broker.request_all(
+        &subject,
+        &serde_json::to_vec(&req)?,
+        Duration::from_millis(500),
+    );
+    assert_eq!(2, replies.len());
+
+    host1.shutdown()?;
+    host2.shutdown()?;
+    Ok(())
+}
+
+pub(crate) fn inmemory_lattice_instance_count() -> Result<(), Box<dyn Error>> {
+    use latticeclient::{BusEvent, CloudEvent, InventoryResponse};
+    use std::time::Duration;
+    use wascc_host::{HostBuilder, LatticeBroker};
+
+    let broker = LatticeBroker::new();
+    let events = broker.subscribe("memcount.wasmbus.events");
+    let host1 = HostBuilder::new()
+        .with_lattice_broker(&broker)
+        .with_lattice_namespace("memcount")
+        .build();
+    let host2 = HostBuilder::new()
+        .with_lattice_broker(&broker)
+        .with_lattice_namespace("memcount")
+        .build();
+    host1.add_actor(crate::common::get_hello_actor()?)?;
+    host2.add_actor(crate::common::get_hello_actor()?)?;
+    let actor = host1.actors()[0].0.to_string();
+    std::thread::sleep(Duration::from_millis(300));
+
+    let count = |broker: &LatticeBroker| {
+        inventory(broker, "memcount", "actors")
+            .into_iter()
+            .map(|ir| match ir {
+                InventoryResponse::Actors { actors, .. } => {
+                    actors.iter().filter(|c| c.subject == actor).count()
+                }
+                _ => 0,
+            })
+            .sum::<usize>()
+    };
+    assert_eq!(2, count(&broker));
+
+    host2.remove_actor(&actor)?;
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(1, count(&broker));
+    assert_eq!(1, host1.actors().len());
+
+    let started: Vec<_> = events
+        .try_iter()
+        .filter_map(|(_, data)| {
+            let ce: CloudEvent = serde_json::from_slice(&data).ok()?;
+            serde_json::from_str::<BusEvent>(&ce.data).ok()
+        })
+        .filter(|be| match be {
+            BusEvent::ActorStarted { .. } => true,
+            _ => false,
+        })
+        .collect();
+    assert_eq!(2, started.len());
+
+    host1.shutdown()?;
+    host2.shutdown()?;
+    Ok(())
+}
+
+pub(crate) fn inmemory_lattice_reconnect() -> Result<(), Box<dyn Error>> {
+    use std::time::Duration;
+    use wascc_host::{ConnectionEvent, HostBuilder, LatticeBroker};
+
+    let broker = LatticeBroker::new();
+    let events = broker.subscribe("memreconnect.wasmbus.events.connection");
+    let build = || {
+        HostBuilder::new()
+            .with_lattice_broker(&broker)
+            .with_lattice_namespace("memreconnect")
+            .with_lattice_reconnect_backoff(Duration::from_millis(10), Duration::from_millis(100))
+            .build()
+    };
+    let host1 = build();
+    let host2 = build();
+    host1.add_actor(crate::common::get_hello_actor()?)?;
+    assert_eq!(2, inventory(&broker, "memreconnect", "hosts").len());
+
+    broker.interrupt();
+    // Each host reports that it was disconnected, then that it reconnected
+    let mut disconnected = vec![];
+    let mut reconnected = vec![];
+    while reconnected.len() < 2 {
+        let (_, data) = events.recv_timeout(Duration::from_secs(2))?;
+        let event: ConnectionEvent = serde_json::from_slice(&data)?;
+        match event {
+            ConnectionEvent::Disconnected { host, .. } => disconnected.push(host),
+            ConnectionEvent::Reconnected {
+                host, resubscribed, ..
+            } => {
+                assert!(resubscribed >= 2);
+                assert!(disconnected.contains(&host));
+                reconnected.push(host);
+            }
+        }
+    }
+    reconnected.sort();
+    let mut expected = vec![host1.id(), host2.id()];
+    expected.sort();
+    assert_eq!(reconnected, expected);
+    assert!(host1.connected());
+
+    // Control plane, inventory and actor subscriptions are all re-established
+    assert_eq!(2, inventory(&broker, "memreconnect", "hosts").len());
+    assert_eq!(
+        1,
+        inventory(&broker, "memreconnect", "actors")
+            .into_iter()
+            .filter(|ir| match ir {
+                latticeclient::InventoryResponse::Actors { actors, .. } => !actors.is_empty(),
+                _ => false,
+            })
+            .count()
+    );
+
+    host1.shutdown()?;
+    host2.shutdown()?;
+    Ok(())
+}
+
+// A capability provider that answers every operation with the payload it was sent
+struct EchoProvider;
+
+impl wascc_codec::capabilities::CapabilityProvider for EchoProvider {
+    fn configure_dispatch(
+        &self,
+        _dispatcher: Box<dyn wascc_codec::capabilities::Dispatcher>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    fn handle_call(
+        &self,
+        _actor: &str,
+        op: &str,
+        msg: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        use wascc_codec::capabilities::{CapabilityDescriptor, OP_GET_CAPABILITY_DESCRIPTOR};
+        match op {
+            OP_GET_CAPABILITY_DESCRIPTOR => Ok(wascc_codec::serialize(
+                CapabilityDescriptor::builder()
+                    .id("wascc:http_server")
+                    .name("Echo Provider")
+                    .version("0.0.1")
+                    .revision(1)
+                    .build(),
+            )?),
+            _ => Ok(msg.to_vec()),
+        }
+    }
+}
+
+pub(crate) fn inmemory_lattice_reconnect_bindings() -> Result<(), Box<dyn Error>> {
+    use std::time::Duration;
+    use wascap::prelude::KeyPair;
+    use wascc_host::{ConnectionEvent, HostBuilder, LatticeBroker, NativeCapability, WasccEntity};
+
+    let broker = LatticeBroker::new();
+    let events = broker.subscribe("membindings.wasmbus.events.connection");
+    let host = HostBuilder::new()
+        .with_lattice_broker(&broker)
+        .with_lattice_namespace("membindings")
+        .with_lattice_reconnect_backoff(Duration::from_millis(10), Duration::from_millis(100))
+        .build();
+    let actor = crate::common::get_hello_actor()?;
+    let pk = actor.public_key();
+    host.add_actor(actor)?;
+    host.add_native_capability(NativeCapability::from_instance(EchoProvider, None)?)?;
+    host.set_binding(&pk, "wascc:http_server", None, HashMap::new())?;
+
+    // Invokes the provider the same way the bound actor would, from anywhere in the lattice
+    let call_provider = |payload: &[u8]| -> Result<Vec<u8>, Box<dyn Error>> {
+        let inv = Invocation::new(
+            &KeyPair::new_server(),
+            WasccEntity::Actor(pk.to_string()),
+            WasccEntity::Capability {
+                capid: "wascc:http_server".to_string(),
+                binding: "default".to_string(),
+            },
+            "Echo",
+            payload.to_vec(),
+        );
+        let reply = broker.request(
+            &format!(
+                "membindings.wasmbus.provider.wascc.http_server.default.{}",
+                pk
+            ),
+            &wascc_codec::serialize(inv).map_err(|e| e.to_string())?,
+            Duration::from_secs(1),
+        )?;
+        let resp: InvocationResponse =
+            wascc_codec::deserialize(&reply).map_err(|e| e.to_string())?;
+        match resp.error {
+            Some(e) => Err(e.into()),
+            None => Ok(resp.msg),
+        }
+    };
+    assert_eq!(b"before".to_vec(), call_provider(b"before")?);
+
+    broker.interrupt();
+    loop {
+        let (_, data) = events.recv_timeout(Duration::from_secs(2))?;
+        if let ConnectionEvent::Reconnected { .. } = serde_json::from_slice(&data)? {
+            break;
+        }
+    }
+
+    // The bound actor can still reach its provider, and the binding is still in the inventory
+    assert_eq!(b"after".to_vec(), call_provider(b"after")?);
+    let bindings: Vec<_> = inventory(&broker, "membindings", "bindings")
+        .into_iter()
+        .flat_map(|ir| match ir {
+            latticeclient::InventoryResponse::Bindings { bindings, .. } => bindings,
+            _ => vec![],
+        })
+        .collect();
+    assert_eq!(1, bindings.len());
+    assert_eq!(pk, bindings[0].actor);
+    assert_eq!("wascc:http_server", bindings[0].capability_id);
+
+    host.shutdown()?;
+    Ok(())
+}
+
+// Answers actor invocations with the ID of the host it's installed in, without running the actor
+struct HostTag(String);
+
+impl wascc_host::Middleware for HostTag {
+    fn actor_pre_invoke(&self, inv: Invocation) -> wascc_host::Result<Invocation> {
+        Ok(inv)
+    }
+
+    fn actor_invoke(
+        &self,
+        inv: Invocation,
+        _handler: InvocationHandler,
+    ) -> wascc_host::Result<MiddlewareResponse> {
+        Ok(MiddlewareResponse::Halt(InvocationResponse::success(
+            &inv,
+            self.0.as_bytes().to_vec(),
+        )))
+    }
+
+    fn actor_post_invoke(
+        &self,
+        response: InvocationResponse,
+    ) -> wascc_host::Result<InvocationResponse> {
+        Ok(response)
+    }
+
+    fn capability_pre_invoke(&self, inv: Invocation) -> wascc_host::Result<Invocation> {
+        Ok(inv)
+    }
+
+    fn capability_invoke(
+        &self,
+        inv: Invocation,
+        handler: InvocationHandler,
+    ) -> wascc_host::Result<MiddlewareResponse> {
+        Ok(MiddlewareResponse::Continue(handler.invoke(inv)))
+    }
+
+    fn capability_post_invoke(
+        &self,
+        response: InvocationResponse,
+    ) -> wascc_host::Result<InvocationResponse> {
+        Ok(response)
+    }
+}
+
+pub(crate) fn inmemory_lattice_queue_group() -> Result<(), Box<dyn Error>> {
+    use wascc_host::{HostBuilder, LatticeBroker};
+
+    let broker = LatticeBroker::new();
+    let build = || {
+        HostBuilder::new()
+            .with_lattice_broker(&broker)
+            .with_lattice_namespace("memqueue")
+            .build()
+    };
+    let host1 = build();
+    let host2 = build();
+    for host in &[&host1, &host2] {
+        host.add_middleware(HostTag(host.id()));
+        host.add_actor(crate::common::get_hello_actor()?)?;
+    }
+    let pk = "MDFD7XZ5KBOPLPHQKHJEMPR54XIW6RAG5D7NNKN22NP7NSEWNTJZP7JN";
+
+    // Each invocation is handled by exactly one of the hosts running the actor, and both
+    // hosts take a share of them
+    let mut handled = HashMap::new();
+    for _ in 0..40 {
+        let host_id = String::from_utf8(host1.call_actor(pk, "HandleRequest", &[])?)?;
+        *handled.entry(host_id).or_insert(0) += 1;
+    }
+    assert_eq!(40, handled.values().sum::<i32>());
+    assert!(handled[&host1.id()] > 0);
+    assert!(handled[&host2.id()] > 0);
+
+    // Once one host stops running the actor, the other handles every invocation
+    host1.remove_actor(pk)?;
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    for _ in 0..10 {
+        assert_eq!(
+            host2.id().into_bytes(),
+            host2.call_actor(pk, "HandleRequest", &[])?
+        );
+    }
+
+    host1.shutdown()?;
+    host2.shutdown()?;
+    Ok(())
+}
@@ -68,6 +68,42 @@ fn lattice_events() -> Result<(), Box<dyn Error>> {
     lattice::lattice_events()
 }
 
+#[test]
+#[cfg(feature = "lattice")]
+fn inmemory_lattice_hosts() -> Result<(), Box<dyn Error>> {
+    lattice::inmemory_lattice_hosts()
+}
+
+#[test]
+#[cfg(feature = "lattice")]
+fn inmemory_lattice_auction() -> Result<(), Box<dyn Error>> {
+    lattice::inmemory_lattice_auction()
+}
+
+#[test]
+#[cfg(feature = "lattice")]
+fn inmemory_lattice_instance_count() -> Result<(), Box<dyn Error>> {
+    lattice::inmemory_lattice_instance_count()
+}
+
+#[test]
+#[cfg(feature = "lattice")]
+fn inmemory_lattice_reconnect() -> Result<(), Box<dyn Error>> {
+    lattice::inmemory_lattice_reconnect()
+}
+
+#[test]
+#[cfg(feature = "lattice")]
+fn inmemory_lattice_reconnect_bindings() -> Result<(), Box<dyn Error>> {
+    lattice::inmemory_lattice_reconnect_bindings()
+}
+
+#[test]
+#[cfg(feature = "lattice")]
+fn inmemory_lattice_queue_group() -> Result<(), Box<dyn Error>> {
+    lattice::inmemory_lattice_queue_group()
+}
+
 //#[test]
 //fn simple_load() -> Result<(), Box<dyn Error>> {
 //    load::simple_load()